@@ -3,10 +3,13 @@
 // Logical Devices
 pub mod rx;
 pub mod flat;
+pub mod ld;
+pub mod disklabel;
 
 // Physical Images
 pub mod img;
 pub mod imd;
+pub mod tap;
 
 use bytebuffer::ByteBuffer;
 
@@ -38,6 +41,7 @@ pub trait BlockDevice : Send + Sync {
     fn sector_size(&self) -> usize;
     fn sectors(&self) -> usize;
     fn physical_device(&self) -> Box<&dyn PhysicalBlockDevice>;
+    fn physical_device_mut(&mut self) -> &mut dyn PhysicalBlockDevice;
 }
 
 impl BlockDevice for Box<dyn BlockDevice> {
@@ -46,6 +50,7 @@ impl BlockDevice for Box<dyn BlockDevice> {
     fn sector_size(&self) -> usize                                                     { self.as_ref().sector_size() }
     fn sectors(&self) -> usize                                                         { self.as_ref().sectors() }
     fn physical_device(&self) -> Box<&dyn PhysicalBlockDevice>                         { self.as_ref().physical_device() }
+    fn physical_device_mut(&mut self) -> &mut dyn PhysicalBlockDevice                  { self.as_mut().physical_device_mut() }
 }
 
 pub trait PhysicalBlockDevice : Send + Sync {
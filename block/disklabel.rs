@@ -0,0 +1,223 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::fmt::Display;
+use std::ops::Range;
+
+use anyhow::anyhow;
+
+use super::{PhysicalBlockDevice, BlockDevice, BLOCK_SIZE};
+
+// 2.11BSD disk labels. See disklabel(5) and sys/disklabel.h in 2.11BSD. The label lives at the start of sector 1 (the
+// second half of the first 1K block, which 2.11BSD boot blocks leave alone) and divides the disk into up to 8
+// partitions, 'a' through 'h'. Longs are stored PDP-11 style, high word first.
+
+const LABEL_SECTOR: usize = 1;
+const DISKMAGIC: u32 = 0x82564557;
+const PARTITIONS_OFFSET: usize = 130;
+const MAXPARTITIONS: usize = 8;
+
+// d_fstype names from sys/disklabel.h.
+const FSTYPES: [&str; 14] = ["unused", "swap", "Version 6", "Version 7", "System V", "2.11BSD", "Eighth Edition",
+                             "4.2BSD", "MSDOS", "4.4LFS", "unknown", "HPFS", "ISO9660", "boot"];
+
+#[derive(Clone, Debug)]
+pub struct DiskLabel {
+    pub typename: String,
+    pub packname: String,
+    pub secsize: u16,
+    pub nsectors: u16,
+    pub ntracks: u16,
+    pub ncylinders: u16,
+    pub secpercyl: u16,
+    pub secperunit: u32,
+    pub partitions: Vec<PartitionInfo>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PartitionInfo {
+    pub size: u32,   // In sectors
+    pub offset: u32, // In sectors
+    pub fsize: u16,  // Filesystem block ("fragment") size
+    pub fstype: u8,
+    pub frag: u8,
+}
+
+fn word(raw: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([raw[offset], raw[offset + 1]])
+}
+
+fn long(raw: &[u8], offset: usize) -> u32 {
+    (word(raw, offset) as u32) << 16 | word(raw, offset + 2) as u32
+}
+
+fn string(raw: &[u8]) -> String {
+    String::from_utf8_lossy(&raw[0..raw.iter().position(|c| *c == 0).unwrap_or(raw.len())]).to_string()
+}
+
+impl DiskLabel {
+    pub fn read<B: BlockDevice>(dev: &B) -> anyhow::Result<DiskLabel> {
+        let raw = dev.read_blocks(LABEL_SECTOR, 1)?.into_vec();
+        if long(&raw, 0) != DISKMAGIC || long(&raw, 118) != DISKMAGIC { return Err(anyhow!("No disk label")) }
+        let npartitions = word(&raw, 124) as usize;
+        if npartitions > MAXPARTITIONS { return Err(anyhow!("Bad disk label: {} partitions", npartitions)) }
+        let end = PARTITIONS_OFFSET + npartitions * 12;
+        if (0..end).step_by(2).fold(0, |sum, o| sum ^ word(&raw, o)) != 0 { return Err(anyhow!("Bad disk label checksum")) }
+        Ok(DiskLabel {
+            typename: string(&raw[6..22]),
+            packname: string(&raw[22..38]),
+            secsize: word(&raw, 38),
+            nsectors: word(&raw, 40),
+            ntracks: word(&raw, 42),
+            ncylinders: word(&raw, 44),
+            secpercyl: word(&raw, 46),
+            secperunit: long(&raw, 48),
+            partitions: raw[PARTITIONS_OFFSET..end].chunks_exact(12).map(|p| PartitionInfo {
+                size: long(p, 0),
+                offset: long(p, 4),
+                fsize: word(p, 8),
+                fstype: p[10],
+                frag: p[11],
+            }).collect(),
+        })
+    }
+
+    pub fn partition(&self, letter: char) -> Option<&PartitionInfo> {
+        let index = (letter as usize).checked_sub('a' as usize)?;
+        self.partitions.get(index).filter(|p| p.size != 0)
+    }
+}
+
+impl Display for DiskLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "type: {}", self.typename)?;
+        writeln!(f, "disk: {}", self.packname)?;
+        writeln!(f, "bytes/sector: {}", self.secsize)?;
+        writeln!(f, "sectors/track: {}", self.nsectors)?;
+        writeln!(f, "tracks/cylinder: {}", self.ntracks)?;
+        writeln!(f, "sectors/cylinder: {}", self.secpercyl)?;
+        writeln!(f, "cylinders: {}", self.ncylinders)?;
+        writeln!(f, "sectors/unit: {}", self.secperunit)?;
+        writeln!(f)?;
+        writeln!(f, "{} partitions:", self.partitions.len())?;
+        writeln!(f, "#        size   offset    fstype   [fsize bsize]")?;
+        for (letter, p) in ('a'..).zip(self.partitions.iter()).filter(|(_, p)| p.size != 0) {
+            writeln!(f, "  {}: {:8} {:8} {:>10}   {:5} {:5}", letter, p.size, p.offset,
+                     FSTYPES.get(p.fstype as usize).unwrap_or(&"unknown"), p.fsize, p.fsize as usize * p.frag as usize)?;
+        }
+        Ok(())
+    }
+}
+
+// One partition of a labeled disk, as a device of its own.
+pub struct Partition<B: BlockDevice> {
+    pub image: B,
+    pub letter: char,
+    extent: Range<usize>,
+}
+
+impl<B: BlockDevice> Partition<B> {
+    pub fn new(image: B, letter: char) -> anyhow::Result<Partition<B>> {
+        let label = DiskLabel::read(&image)?;
+        let Some(p) = label.partition(letter) else { return Err(anyhow!("No partition '{}' in the disk label", letter)) };
+        let extent = p.offset as usize..p.offset as usize + p.size as usize;
+        if extent.end > image.blocks() { return Err(anyhow!("Partition '{}' goes past the end of the disk ({} > {})", letter, extent.end, image.blocks())) }
+        Ok(Partition { image, letter, extent })
+    }
+
+    fn block(&self, sector: usize) -> anyhow::Result<usize> {
+        if sector >= self.extent.len() { return Err(anyhow!("Access past end of partition '{}': {} >= {}", self.letter, sector, self.extent.len())) }
+        Ok(self.extent.start + sector)
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for Partition<B> {
+    fn read_sector(&self, sector: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(self.image.read_blocks(self.block(sector)?, 1)?.into_vec())
+    }
+
+    fn write_sector(&mut self, sector: usize, buf: &[u8]) -> anyhow::Result<()> {
+        let block = self.block(sector)?;
+        self.image.write_blocks(block, 1, buf)
+    }
+
+    fn sector_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn sectors(&self) -> usize {
+        self.extent.len()
+    }
+
+    fn physical_device(&self) -> Box<&dyn PhysicalBlockDevice> {
+        self.image.physical_device()
+    }
+
+    fn physical_device_mut(&mut self) -> &mut dyn PhysicalBlockDevice {
+        self.image.physical_device_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::FileSystem;
+    use crate::fs::bsd211::Bsd211Fs;
+    use crate::fs::test::*;
+
+    fn put_long(raw: &mut [u8], offset: usize, long: u32) {
+        raw[offset..offset + 2].copy_from_slice(&((long >> 16) as u16).to_le_bytes());
+        raw[offset + 2..offset + 4].copy_from_slice(&(long as u16).to_le_bytes());
+    }
+
+    // An 'a' partition after the first 100 sectors, and a 'b' for the rest.
+    fn labeled(blocks: usize) -> TestDev {
+        let mut raw = vec![0; 512 * blocks];
+        let label = &mut raw[512..1024];
+        put_long(label, 0, DISKMAGIC);
+        label[6..10].copy_from_slice(b"test");
+        label[38..40].copy_from_slice(&512u16.to_le_bytes());
+        put_long(label, 48, blocks as u32);
+        put_long(label, 118, DISKMAGIC);
+        label[124..126].copy_from_slice(&2u16.to_le_bytes());
+        for (i, extent) in [100..blocks - 200, blocks - 200..blocks].iter().enumerate() {
+            let p = &mut label[PARTITIONS_OFFSET + i * 12..PARTITIONS_OFFSET + i * 12 + 12];
+            put_long(p, 0, extent.len() as u32);
+            put_long(p, 4, extent.start as u32);
+            p[8..10].copy_from_slice(&1024u16.to_le_bytes());
+            p[10] = [5, 1][i];
+            p[11] = 1;
+        }
+        let sum = (0..PARTITIONS_OFFSET + 24).step_by(2).fold(0, |sum, o| sum ^ word(label, o));
+        label[122..124].copy_from_slice(&sum.to_le_bytes());
+        TestDev(raw)
+    }
+
+    #[test]
+    fn test_label() {
+        let dev = labeled(2000);
+        let label = DiskLabel::read(&dev).expect("read label");
+        assert_eq!(("test", 2), (label.typename.as_str(), label.partitions.len()));
+        assert_eq!((1700, 100), (label.partition('a').unwrap().size, label.partition('a').unwrap().offset));
+        assert!(label.partition('c').is_none());
+        assert!(label.to_string().contains("  b:      200     1800       swap    1024  1024"));
+
+        let mut bad = TestDev(dev.0.clone());
+        bad.0[512 + 40] ^= 1;
+        assert!(DiskLabel::read(&bad).is_err());
+        assert!(DiskLabel::read(&TestDev(vec![0; 512 * 2000])).is_err());
+    }
+
+    #[test]
+    fn test_partition() {
+        let fs = Bsd211Fs::mkfs(Partition::new(labeled(2000), 'a').expect("partition a")).expect("Create 2.11BSD FS in partition");
+        assert_eq!(1700, fs.image.blocks());
+        let dev = fs.image.image;
+        assert_eq!(2000 * 512, dev.0.len());
+        assert!(DiskLabel::read(&dev).is_ok()); // mkfs left it alone
+
+        let mut fs = Bsd211Fs::new(Partition::new(dev, 'a').expect("partition a")).expect("Reopen 2.11BSD FS");
+        fs.write_file("hello", &incrementing(100)).expect("write_file failed");
+        assert!(fs.image.read_sector(1700).is_err());
+        assert!(Partition::new(fs.image.image, 'c').is_err());
+    }
+}
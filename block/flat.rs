@@ -1,6 +1,36 @@
 // Copyright © 2023 David Caldwell <david@porkrind.org>
 
-use super::{PhysicalBlockDevice, BlockDevice};
+use super::{Geometry, PhysicalBlockDevice, BlockDevice};
+
+pub const RK05_GEOMETRY: Geometry = Geometry {
+    cylinders: 203,
+    heads: 2,
+    sectors: 12,
+    sector_size: 512,
+};
+
+pub const RL02_GEOMETRY: Geometry = Geometry {
+    cylinders: 512,
+    heads: 2,
+    sectors: 40,
+    sector_size: 256,
+};
+
+// DECtape: 578 blocks of 256 words
+pub const TU56_GEOMETRY: Geometry = Geometry {
+    cylinders: 1,
+    heads: 1,
+    sectors: 578,
+    sector_size: 512,
+};
+
+// TU58 DECtape II: 2 tracks of 1024 128 byte records.
+pub const TU58_GEOMETRY: Geometry = Geometry {
+    cylinders: 2,
+    heads: 1,
+    sectors: 1024,
+    sector_size: 128,
+};
 
 #[derive(Clone, Debug)]
 pub struct Flat<B: PhysicalBlockDevice>(pub B);
@@ -27,6 +57,10 @@ impl<B: PhysicalBlockDevice> BlockDevice for Flat<B> {
     fn physical_device(&self) -> Box<&dyn PhysicalBlockDevice> {
         Box::new(&self.0)
     }
+
+    fn physical_device_mut(&mut self) -> &mut dyn PhysicalBlockDevice {
+        &mut self.0
+    }
 }
 
 impl<B: PhysicalBlockDevice> Flat<B> {
@@ -0,0 +1,81 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::ops::Range;
+
+use anyhow::anyhow;
+
+use super::{PhysicalBlockDevice, BlockDevice, BLOCK_SIZE};
+use crate::fs::rt11::RT11FS;
+
+// A whole volume kept in a (contiguous) file on an RT-11 volume, like RT-11's LD handler mounts. Reads and writes go
+// straight to the file's blocks on the outer volume.
+pub struct LogicalDisk<B: BlockDevice> {
+    pub fs: RT11FS<B>,
+    pub name: String,
+    extent: Range<usize>,
+}
+
+impl<B: BlockDevice> LogicalDisk<B> {
+    pub fn new(fs: RT11FS<B>, name: &str) -> anyhow::Result<LogicalDisk<B>> {
+        let Some(extent) = fs.file_extent(name) else { return Err(anyhow!("File not found: {}", name)) };
+        Ok(LogicalDisk { fs, name: name.to_owned(), extent })
+    }
+
+    fn block(&self, sector: usize) -> anyhow::Result<usize> {
+        if sector >= self.extent.len() { return Err(anyhow!("Access past end of logical disk {}: {} >= {}", self.name, sector, self.extent.len())) }
+        Ok(self.extent.start + sector)
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for LogicalDisk<B> {
+    fn read_sector(&self, sector: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(self.fs.image.read_blocks(self.block(sector)?, 1)?.into_vec())
+    }
+
+    fn write_sector(&mut self, sector: usize, buf: &[u8]) -> anyhow::Result<()> {
+        let block = self.block(sector)?;
+        self.fs.image.write_blocks(block, 1, buf)
+    }
+
+    fn sector_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn sectors(&self) -> usize {
+        self.extent.len()
+    }
+
+    // Saving the logical disk means saving the volume it lives on.
+    fn physical_device(&self) -> Box<&dyn PhysicalBlockDevice> {
+        self.fs.image.physical_device()
+    }
+
+    fn physical_device_mut(&mut self) -> &mut dyn PhysicalBlockDevice {
+        self.fs.image.physical_device_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::FileSystem;
+    use crate::fs::test::*;
+
+    #[test]
+    fn test_nested_rt11() {
+        let mut outer = RT11FS::mkfs(TestDev(vec![0;512*60])).expect("Create outer RT-11 FS");
+        let inner = RT11FS::mkfs(TestDev(vec![0;512*20])).expect("Create inner RT-11 FS");
+        outer.write_file("INNER.DSK", &inner.image.0).expect("write inner.dsk");
+        let start = outer.file_extent("INNER.DSK").expect("extent").start;
+
+        let mut inner = RT11FS::new(LogicalDisk::new(outer, "INNER.DSK").expect("LogicalDisk")).expect("Open inner RT-11 FS");
+        assert_eq!(inner.image.blocks(), 20);
+        inner.write_file("TEST.TXT", &incrementing(256)).expect("write test.txt");
+        assert!(inner.image.read_sector(20).is_err());
+
+        let outer = inner.image.fs;
+        assert_eq!(outer.image.read_blocks(start + 14, 1).expect("read").as_bytes()[0..256], incrementing(256));
+        let inner = RT11FS::new(LogicalDisk::new(outer, "INNER.DSK").expect("LogicalDisk")).expect("Reopen inner RT-11 FS");
+        assert!(inner.stat("TEST.TXT").is_some());
+    }
+}
@@ -42,6 +42,10 @@ impl<B: PhysicalBlockDevice> BlockDevice for RX<B> {
     fn physical_device(&self) -> Box<&dyn PhysicalBlockDevice> {
         Box::new(&self.0)
     }
+
+    fn physical_device_mut(&mut self) -> &mut dyn PhysicalBlockDevice {
+        &mut self.0
+    }
 }
 
 impl<B: PhysicalBlockDevice> RX<B> {
@@ -59,4 +63,62 @@ impl<B: PhysicalBlockDevice> RX<B> {
     }
 }
 
+// OS/8's RX handler interleaves the same way RT-11's does (above), but runs the controller in 12 bit mode: each sector
+// holds 64 (RX02: 128) 12 bit words packed 2 to 3 bytes into its first 96 (192) bytes, and the rest is unused. This
+// unpacks them into little endian 16 bit words, the way 12 bit words are stored everywhere else, so a sector is still
+// 128 (RX02: 256) bytes.
+#[derive(Clone)]
+pub struct RX12<B: BlockDevice>(pub B);
+
+impl<B: BlockDevice> BlockDevice for RX12<B> {
+    fn read_sector(&self, sector: usize) -> anyhow::Result<Vec<u8>> {
+        let raw = self.0.read_sector(sector)?;
+        Ok(raw[0..raw.len() / 4 * 3].chunks_exact(3)
+           .flat_map(|b| [(b[0] as u16) << 4 | (b[1] as u16) >> 4, (b[1] as u16 & 0xf) << 8 | b[2] as u16])
+           .flat_map(|w| w.to_le_bytes())
+           .collect())
+    }
+
+    fn write_sector(&mut self, sector: usize, buf: &[u8]) -> anyhow::Result<()> {
+        let mut raw: Vec<u8> = buf.chunks_exact(4)
+            .map(|w| (u16::from_le_bytes([w[0], w[1]]) & 0o7777, u16::from_le_bytes([w[2], w[3]]) & 0o7777))
+            .flat_map(|(a, b)| [(a >> 4) as u8, ((a & 0xf) << 4 | b >> 8) as u8, b as u8])
+            .collect();
+        raw.resize(buf.len(), 0);
+        self.0.write_sector(sector, &raw)
+    }
+
+    fn sector_size(&self) -> usize {
+        self.0.sector_size()
+    }
+
+    fn sectors(&self) -> usize {
+        self.0.sectors()
+    }
+
+    fn physical_device(&self) -> Box<&dyn PhysicalBlockDevice> {
+        self.0.physical_device()
+    }
+
+    fn physical_device_mut(&mut self) -> &mut dyn PhysicalBlockDevice {
+        self.0.physical_device_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::img::IMG;
 
+    #[test]
+    fn test_rx12() {
+        let mut rx = RX12(RX(IMG::from_raw(vec![0; RX01_GEOMETRY.bytes()], RX01_GEOMETRY)));
+        let words: Vec<u8> = (0..64u16).flat_map(|w| (w * 0o101).to_le_bytes()).collect();
+        rx.write_sector(3, &words).expect("write_sector failed");
+        assert_eq!(words, rx.read_sector(3).unwrap());
+        let raw = rx.0.read_sector(3).unwrap();
+        assert_eq!(&[0o000, 0o000, 0o101, 0o010, 0o040, 0o303], &raw[0..6]); // 0000 0101 0202 0303 packed
+        assert!(raw[96..].iter().all(|b| *b == 0));
+        assert_eq!(494, rx.blocks());
+    }
+}
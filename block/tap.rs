@@ -0,0 +1,192 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use anyhow::anyhow;
+
+use super::{Geometry, PhysicalBlockDevice, BlockDevice, BLOCK_SIZE};
+
+// SimH .tap magtape images. Each record is a 32 bit little endian length, the data (padded to an even length) and the
+// length again. A length of 0 is a tape mark and 0xffffffff is the end of the medium. The top 4 bits of the length are
+// the record's class: 0 is a good record and 8 is one that had an error (but still has its data).
+//
+// Tapes are records, not blocks, so as a BlockDevice each record is a "sector" (a tape mark reads as an empty one).
+// Writing a record throws away everything after it, like writing in the middle of a real tape does.
+//
+// SimH's TA11 uses the same format for TU60 cassettes. The images look the same, so a Tap just gets told which it is.
+
+// Tapes don't have a geometry. This is just something no disk has, so tapes can be told apart.
+pub const TAPE_GEOMETRY: Geometry = Geometry {
+    cylinders: 0,
+    heads: 1,
+    sectors: 0,
+    sector_size: BLOCK_SIZE,
+};
+
+// TU60 cassettes. Their records are 128 bytes (at most), which is how they're told apart from magtapes.
+pub const CASSETTE_GEOMETRY: Geometry = Geometry {
+    cylinders: 0,
+    heads: 1,
+    sectors: 0,
+    sector_size: 128,
+};
+
+const TAPE_MARK: u32 = 0;
+const END_OF_MEDIUM: u32 = 0xffffffff;
+const ERASE_GAP: u32 = 0xfffffffe;
+const CLASS_GOOD: u32 = 0;
+const CLASS_BAD: u32 = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TapeRecord {
+    Mark,
+    Data(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Tap {
+    pub records: Vec<TapeRecord>,
+    pub geometry: Geometry, // TAPE_GEOMETRY or CASSETTE_GEOMETRY
+}
+
+impl Tap {
+    pub fn new() -> Tap {
+        Tap { records: vec![], geometry: TAPE_GEOMETRY }
+    }
+
+    pub fn cassette() -> Tap {
+        Tap { records: vec![], geometry: CASSETTE_GEOMETRY }
+    }
+
+    pub fn from_bytes(image: &[u8]) -> anyhow::Result<Tap> {
+        let mut records = vec![];
+        let mut pos = 0;
+        let word = |pos: usize| image.get(pos..pos + 4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+        while let Some(header) = word(pos) {
+            pos += 4;
+            match header {
+                TAPE_MARK     => { records.push(TapeRecord::Mark); continue },
+                END_OF_MEDIUM => break,
+                ERASE_GAP     => continue,
+                _ => {},
+            }
+            let (class, length) = (header >> 28, (header & 0x0fffffff) as usize);
+            if class != CLASS_GOOD && class != CLASS_BAD { return Err(anyhow!("Unsupported SimH record class {:x} at offset {}", class, pos - 4)) }
+            let Some(data) = image.get(pos..pos + length) else { return Err(anyhow!("Record at offset {} runs past the end of the image", pos - 4)) };
+            records.push(TapeRecord::Data(data.to_vec()));
+            pos += length + (length & 1);
+            if word(pos) != Some(header) { return Err(anyhow!("Record at offset {} has a mismatched trailing length", pos - length - 4)) }
+            pos += 4;
+        }
+        Ok(Tap { records, geometry: TAPE_GEOMETRY })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut image = vec![];
+        for r in self.records.iter() {
+            match r {
+                TapeRecord::Mark => image.extend(TAPE_MARK.to_le_bytes()),
+                TapeRecord::Data(data) => {
+                    let length = (data.len() as u32).to_le_bytes();
+                    image.extend(length);
+                    image.extend(data);
+                    if data.len() & 1 == 1 { image.push(0) }
+                    image.extend(length);
+                },
+            }
+        }
+        image
+    }
+}
+
+impl Default for Tap {
+    fn default() -> Tap {
+        Tap::new()
+    }
+}
+
+impl BlockDevice for Tap {
+    fn read_sector(&self, sector: usize) -> anyhow::Result<Vec<u8>> {
+        match self.records.get(sector) {
+            Some(TapeRecord::Mark)       => Ok(vec![]),
+            Some(TapeRecord::Data(data)) => Ok(data.clone()),
+            None => Err(anyhow!("Read past the end of the tape: record {} >= {}", sector, self.records.len())),
+        }
+    }
+
+    fn write_sector(&mut self, sector: usize, buf: &[u8]) -> anyhow::Result<()> {
+        if sector > self.records.len() { return Err(anyhow!("Write past the end of the tape: record {} > {}", sector, self.records.len())) }
+        self.records.truncate(sector);
+        self.records.push(if buf.is_empty() { TapeRecord::Mark } else { TapeRecord::Data(buf.to_vec()) });
+        Ok(())
+    }
+
+    fn sector_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn sectors(&self) -> usize {
+        self.records.len()
+    }
+
+    fn physical_device(&self) -> Box<&dyn PhysicalBlockDevice> {
+        Box::new(self)
+    }
+
+    fn physical_device_mut(&mut self) -> &mut dyn PhysicalBlockDevice {
+        self
+    }
+}
+
+impl PhysicalBlockDevice for Tap {
+    fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    fn read_sector(&self, _cylinder: usize, _head: usize, sector: usize) -> anyhow::Result<Vec<u8>> {
+        BlockDevice::read_sector(self, sector)
+    }
+
+    fn write_sector(&mut self, _cylinder: usize, _head: usize, sector: usize, buf: &[u8]) -> anyhow::Result<()> {
+        BlockDevice::write_sector(self, sector, buf)
+    }
+
+    fn as_vec(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.to_bytes())
+    }
+
+    // `data` is the contents of a .tap file.
+    fn from_raw(data: Vec<u8>, geometry: Geometry) -> Self {
+        Tap { geometry, ..Tap::from_bytes(&data).unwrap_or_else(|_| Tap::new()) }
+    }
+
+    fn to_raw(&self) -> anyhow::Result<(Geometry, Vec<u8>)> {
+        Err(anyhow!("Tapes can't be converted to disk images"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tap() {
+        let mut tap = Tap::new();
+        for (n, r) in [&b"VOL1"[..], b"", b"odd", b"", b""].iter().enumerate() {
+            BlockDevice::write_sector(&mut tap, n, r).expect("write_sector failed");
+        }
+        let bytes = tap.to_bytes();
+        assert_eq!(&[4, 0, 0, 0, b'V', b'O', b'L', b'1', 4, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, b'o', b'd', b'd', 0, 3, 0, 0, 0], &bytes[0..28]);
+        let mut tap = Tap::from_bytes(&[&bytes[..], &END_OF_MEDIUM.to_le_bytes(), b"junk"].concat()).expect("parse");
+        assert_eq!(vec![TapeRecord::Data(b"VOL1".to_vec()), TapeRecord::Mark, TapeRecord::Data(b"odd".to_vec()), TapeRecord::Mark, TapeRecord::Mark],
+                   tap.records);
+
+        BlockDevice::write_sector(&mut tap, 1, b"new").expect("write_sector failed");
+        assert_eq!(2, tap.sectors());
+        assert!(BlockDevice::read_sector(&tap, 2).is_err());
+        assert!(BlockDevice::write_sector(&mut tap, 3, b"gap").is_err());
+
+        let bad = [&8u32.to_le_bytes()[..], b"12345678", &7u32.to_le_bytes()].concat();
+        assert!(Tap::from_bytes(&bad).is_err());
+        let error = [&0x80000002u32.to_le_bytes()[..], b"ok", &0x80000002u32.to_le_bytes()].concat();
+        assert_eq!(vec![TapeRecord::Data(b"ok".to_vec())], Tap::from_bytes(&error).unwrap().records);
+    }
+}
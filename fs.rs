@@ -1,6 +1,17 @@
 // Copyright © 2023 David Caldwell <david@porkrind.org>
 
+pub mod ansitape;
+pub mod bsd211;
+pub mod bup;
+pub mod caps11;
+pub mod dos11;
+pub mod ibm3740;
+pub mod ods1;
+pub mod os8;
+pub mod rsts;
 pub mod rt11;
+pub mod unixv6;
+pub mod unixv7;
 pub mod xxdp;
 
 use std::{ops::{Deref, DerefMut}, fmt::Debug};
@@ -14,6 +25,8 @@ pub trait FileSystem : Send + Sync {
     type BlockDevice: BlockDevice;
 
     fn filesystem_name(&self) -> &str;
+    // Names on the image are used as given rather than uppercased.
+    fn case_sensitive(&self) -> bool { false }
     fn dir_iter<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn DirEntry + 'a>> + 'a>>;
     fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn DirEntry + 'a>> + 'a>>;
     fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn DirEntry + 'a>>;
@@ -21,6 +34,15 @@ pub trait FileSystem : Send + Sync {
     fn used_blocks(&self) -> usize;
     fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer>;
     fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()>;
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        if *options != CreateOptions::default() {
+            return Err(anyhow!("{}: {} filesystems don't support file placement options", name, self.filesystem_name()));
+        }
+        self.write_file(name, contents)
+    }
+    fn append_file(&mut self, name: &str, _contents: &[u8]) -> anyhow::Result<()> {
+        Err(anyhow!("{}: {} filesystems don't support appending to files", name, self.filesystem_name()))
+    }
     fn delete(&mut self, name: &str) -> anyhow::Result<()>;
     fn block_device(&self) -> &Self::BlockDevice;
     fn rename(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
@@ -28,19 +50,39 @@ pub trait FileSystem : Send + Sync {
         // Can't combine this with find_file_named() below because the (segment,entry)
         // might change after we delete the dest (due to coalesce_empty()). And we don't
         // want to delete the dest _before_ error checking.
-        if !self.stat(src).is_some() { return Err(anyhow!("File not found")) };
+        match self.stat(src) {
+            None                     => return Err(anyhow!("File not found")),
+            Some(f) if f.protected() => return Err(anyhow!("{} is protected", src)),
+            Some(_)                  => {},
+        }
         if self.stat(dest).is_some() {
             self.delete(dest)?;
         }
         self.rename_unchecked(src, dest)
     }
     fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()>;
+    fn set_protected(&mut self, name: &str, _protected: bool) -> anyhow::Result<()> {
+        Err(anyhow!("{}: {} filesystems don't support protected files", name, self.filesystem_name()))
+    }
+    fn set_readonly(&mut self, name: &str, _readonly: bool) -> anyhow::Result<()> {
+        Err(anyhow!("{}: {} filesystems don't support read-only files", name, self.filesystem_name()))
+    }
+    // A description of what the volume boots, or None if it isn't bootable.
+    fn boot_info(&self) -> Option<String> { None }
+    // Deleted files whose data hasn't been reused yet.
+    fn deleted_iter<'a>(&'a self) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn DirEntry + 'a>> + 'a>> {
+        Err(anyhow!("{} filesystems don't support undelete", self.filesystem_name()))
+    }
+    fn undelete(&mut self, name: &str, _new_name: &str) -> anyhow::Result<()> {
+        Err(anyhow!("{}: {} filesystems don't support undelete", name, self.filesystem_name()))
+    }
 }
 
 // It's really a shame this isn't automatic or derivable or something.
 impl<B: BlockDevice+Send+Sync> FileSystem for Box<dyn FileSystem<BlockDevice = B>> {
     type BlockDevice = B;
     fn filesystem_name(&self) -> &str { self.deref().filesystem_name() }
+    fn case_sensitive(&self) -> bool { self.deref().case_sensitive() }
     fn dir_iter<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn DirEntry + 'a>> + 'a>> { self.deref().dir_iter(&path) }
     fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn DirEntry + 'a>> + 'a>> { self.deref().read_dir(&path) }
     fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn DirEntry + 'a>> { self.deref().stat(name) }
@@ -48,11 +90,35 @@ impl<B: BlockDevice+Send+Sync> FileSystem for Box<dyn FileSystem<BlockDevice = B
     fn used_blocks(&self) -> usize { self.deref().used_blocks() }
     fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> { self.deref().read_file(name) }
     fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> { self.deref_mut().write_file(name, contents) }
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> { self.deref_mut().write_file_with_options(name, contents, options) }
+    fn append_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> { self.deref_mut().append_file(name, contents) }
     fn delete(&mut self, name: &str) -> anyhow::Result<()> { self.deref_mut().delete(name) }
     fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> { self.deref_mut().rename_unchecked(src, dest) }
+    fn set_protected(&mut self, name: &str, protected: bool) -> anyhow::Result<()> { self.deref_mut().set_protected(name, protected) }
+    fn set_readonly(&mut self, name: &str, readonly: bool) -> anyhow::Result<()> { self.deref_mut().set_readonly(name, readonly) }
+    fn boot_info(&self) -> Option<String> { self.deref().boot_info() }
+    fn deleted_iter<'a>(&'a self) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn DirEntry + 'a>> + 'a>> { self.deref().deleted_iter() }
+    fn undelete(&mut self, name: &str, new_name: &str) -> anyhow::Result<()> { self.deref_mut().undelete(name, new_name) }
     fn block_device(&self) -> &B { self.deref().block_device() }
 }
 
+// Where a new file's blocks come from.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Placement {
+    #[default]
+    FirstFit,   // The first free area that's big enough
+    BestFit,    // The smallest free area that's big enough
+    Largest,    // The biggest free area
+    At(usize),  // Starting at exactly this block
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CreateOptions {
+    pub placement: Placement,
+    pub prefix: Vec<u8>, // RT-11 prefix block contents. Empty for no prefix blocks.
+    pub contiguous: bool, // XXDP: keep the file's blocks together (RT-11 files always are).
+}
+
 #[allow(dead_code)]
 pub enum Timestamp {
     Date(chrono::NaiveDate),
@@ -75,11 +141,12 @@ pub trait DirEntry : Debug {
     fn blocks(&self) -> u64;
 
     fn readonly(&self) -> bool;
+    fn protected(&self) -> bool;
 }
 
 
 #[cfg(test)]
-mod test { // No tests here, just helpful stuff that filesystem tests can use
+pub(crate) mod test { // No tests here, just helpful stuff that filesystem (and block device) tests can use
     use super::*;
     use crate::block::PhysicalBlockDevice;
 
@@ -107,6 +174,9 @@ mod test { // No tests here, just helpful stuff that filesystem tests can use
         fn physical_device(&self) -> Box<&dyn crate::block::PhysicalBlockDevice> {
             Box::new(self)
         }
+        fn physical_device_mut(&mut self) -> &mut dyn crate::block::PhysicalBlockDevice {
+            self
+        }
     }
     impl PhysicalBlockDevice for TestDev {
         fn geometry(&self) -> &crate::block::Geometry {unimplemented!()}
@@ -0,0 +1,362 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+use bytebuffer::ByteBuffer;
+use chrono::{Datelike, NaiveDate};
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use super::{CreateOptions, FileSystem};
+
+// ANSI labelled magtapes (ANSI X3.27), the way RT-11's file structured magtape handler writes them. See the "RT-11
+// Device Handlers Manual", chapter 5.
+//
+// The tape starts with a VOL1 label. Each file is then a HDR1 label (maybe followed by more HDRn labels), a tape
+// mark, the file's data in 512 byte records, a tape mark, an EOF1 label (plus any EOFn labels) and a tape mark. Two
+// tape marks in a row mark the end of the volume. Labels are 80 byte ASCII records.
+//
+// Tapes can only be written sequentially--writing a record loses everything after it. So files are added at the end,
+// and replacing, renaming or deleting a file rewrites the tape from that file on (everything is kept in memory).
+
+const LABEL_SIZE: usize = 80;
+const MAX_NAME: usize = 17;
+const SYSTEM_CODE: &str = "DECRT11A";
+const DEFAULT_VOLUME_ID: &str = "RT11A";
+// Tapes don't fill up the way disks do. This is roughly what a 2400' reel holds at 800 bpi, so `ls` has something
+// to show.
+const REEL_BLOCKS: usize = 20000;
+
+#[derive(Clone)]
+pub struct AnsiTapeFs<B: BlockDevice> {
+    pub image: B,
+    pub volume_id: String,
+    pub owner: String,
+    pub files: Vec<TapeFile>,
+}
+
+#[derive(Clone)]
+pub struct TapeFile {
+    pub name: String,
+    pub created: Option<NaiveDate>,
+    pub data: Vec<Vec<u8>>,   // The data records
+    label: Vec<u8>,           // HDR1. The rest of the fields, kept as they were.
+    more_headers: Vec<Vec<u8>>,
+    more_trailers: Vec<Vec<u8>>,
+}
+
+impl<B: BlockDevice> AnsiTapeFs<B> {
+    pub fn new(image: B) -> anyhow::Result<AnsiTapeFs<B>> {
+        let vol1 = label_text(&image.read_sector(0)?).ok_or(anyhow!("No VOL1 label"))?;
+        if !vol1.starts_with("VOL1") { return Err(anyhow!("No VOL1 label")) }
+
+        // Running off the end of the image is treated like a tape mark, so truncated tapes can still be read.
+        let record = |r: usize| if r < image.sectors() { image.read_sector(r) } else { Ok(vec![]) };
+        let until_mark = |r: &mut usize| -> anyhow::Result<Vec<Vec<u8>>> {
+            let mut records = vec![];
+            loop {
+                let data = record(*r)?;
+                *r += 1;
+                if data.is_empty() { return Ok(records) }
+                records.push(data);
+            }
+        };
+        let mut files = vec![];
+        let mut r = 1;
+        while r < image.sectors() {
+            let mut headers = until_mark(&mut r)?;
+            if headers.is_empty() { break } // Two tape marks in a row
+            if label_text(&headers[0]).is_none_or(|l| !l.starts_with("HDR1")) { return Err(anyhow!("Expected a HDR1 label at record {}", r - headers.len() - 1)) }
+            let data = until_mark(&mut r)?;
+            let mut trailers = until_mark(&mut r)?;
+            match trailers.first().and_then(|t| label_text(t)) {
+                Some(l) if l.starts_with("EOF1") => {},
+                Some(l) if l.starts_with("EOV1") => return Err(anyhow!("Multi-volume tapes aren't supported")),
+                _ => return Err(anyhow!("Expected an EOF1 label at record {}", r - trailers.len() - 1)),
+            }
+            files.push(TapeFile::from_labels(headers.remove(0), headers, trailers.split_off(1), data));
+        }
+        Ok(AnsiTapeFs { volume_id: vol1[4..10].trim_end().to_string(), owner: vol1[37..51].trim_end().to_string(), image, files })
+    }
+
+    pub fn image_is(image: &B) -> bool {
+        image.sectors() > 0 && image.read_sector(0).is_ok_and(|r| label_text(&r).is_some_and(|l| l.starts_with("VOL1")))
+    }
+
+    pub fn mkfs(mut image: B) -> anyhow::Result<AnsiTapeFs<B>> {
+        let vol1 = format!("{:<79}3", format!("VOL1{}", DEFAULT_VOLUME_ID)); // ANSI label version 3
+        image.write_sector(0, vol1.as_bytes())?;
+        image.write_sector(1, &[])?;
+        image.write_sector(2, &[])?;
+        Self::new(image)
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        let name = name.trim_start_matches('/');
+        self.files.iter().position(|f| f.name == name)
+    }
+
+    // The record the file's HDR1 label is in.
+    fn start_record(&self, file: usize) -> usize {
+        1 + self.files[0..file].iter().map(|f| f.records()).sum::<usize>()
+    }
+
+    // Writes `file` and everything after it, and then the end of the volume.
+    fn rewrite_from(&mut self, file: usize) -> anyhow::Result<()> {
+        let mut r = self.start_record(file);
+        let mut write = |image: &mut B, data: &[u8]| { r += 1; image.write_sector(r - 1, data) };
+        for (i, f) in self.files.iter().enumerate().skip(file) {
+            write(&mut self.image, &f.to_label("HDR1", &self.volume_id, i + 1, 0))?;
+            for h in f.more_headers.iter() { write(&mut self.image, h)? }
+            write(&mut self.image, &[])?;
+            for d in f.data.iter() { write(&mut self.image, d)? }
+            write(&mut self.image, &[])?;
+            write(&mut self.image, &f.to_label("EOF1", &self.volume_id, i + 1, f.data.len()))?;
+            for t in f.more_trailers.iter() { write(&mut self.image, t)? }
+            write(&mut self.image, &[])?;
+        }
+        if self.files.is_empty() { write(&mut self.image, &[])? }
+        write(&mut self.image, &[])
+    }
+}
+
+// Labels are exactly 80 bytes of ASCII. Anything else isn't a label.
+fn label_text(record: &[u8]) -> Option<String> {
+    if record.len() != LABEL_SIZE || !record.is_ascii() { return None }
+    Some(String::from_utf8_lossy(record).into_owned())
+}
+
+// " YYDDD": A century (' ' is 19xx, '0' is 20xx, etc), a year and a day of the year.
+fn parse_date(field: &str) -> Option<NaiveDate> {
+    let century = match field.get(0..1)? { " " => 1900, c => 2000 + 100 * c.parse::<i32>().ok()? };
+    NaiveDate::from_yo_opt(century + field.get(1..3)?.parse::<i32>().ok()?, field.get(3..6)?.parse().ok()?)
+}
+
+fn format_date(date: Option<NaiveDate>) -> String {
+    match date {
+        Some(d) if d.year() >= 1900 => format!("{}{:02}{:03}", match d.year() / 100 { 19 => ' ', c => char::from_digit((c - 20) as u32, 10).unwrap_or('9') },
+                                              d.year() % 100, d.ordinal()),
+        _ => " 00000".to_string(),
+    }
+}
+
+impl TapeFile {
+    fn from_labels(label: Vec<u8>, more_headers: Vec<Vec<u8>>, more_trailers: Vec<Vec<u8>>, data: Vec<Vec<u8>>) -> TapeFile {
+        let text = String::from_utf8_lossy(&label).into_owned();
+        TapeFile {
+            name: text[4..21].trim_end().to_string(),
+            created: parse_date(&text[41..47]),
+            data,
+            label,
+            more_headers,
+            more_trailers,
+        }
+    }
+
+    fn new(name: &str, data: Vec<Vec<u8>>) -> TapeFile {
+        // File section, generation and version numbers, expiration date, accessibility and system code
+        let label = format!("{:<27}0001{:<4}000100{:<6} 00000 {:<6}{:<20}", "HDR1", "", "", "", SYSTEM_CODE).into_bytes();
+        TapeFile { name: name.to_string(), created: Some(Local::now().naive_local().date()), data, label, more_headers: vec![], more_trailers: vec![] }
+    }
+
+    fn to_label(&self, kind: &str, volume_id: &str, sequence: usize, blocks: usize) -> Vec<u8> {
+        let mut text = String::from_utf8_lossy(&self.label).into_owned();
+        let mut set = |range: std::ops::Range<usize>, value: &str| text.replace_range(range.clone(), &format!("{:<1$.1$}", value, range.len()));
+        set(0..4, kind);
+        set(4..21, &self.name);
+        set(21..27, volume_id);
+        set(31..35, &format!("{:04}", sequence % 10000));
+        set(41..47, &format_date(self.created));
+        set(54..60, &format!("{:06}", blocks % 1000000));
+        text.into_bytes()
+    }
+
+    // Labels, data and the 3 tape marks between them.
+    fn records(&self) -> usize {
+        1 + self.more_headers.len() + 1 + self.data.len() + 1 + 1 + self.more_trailers.len() + 1
+    }
+
+    fn len(&self) -> usize {
+        self.data.iter().map(|d| d.len()).sum()
+    }
+}
+
+impl<B: BlockDevice> FileSystem for AnsiTapeFs<B> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        "ANSI magtape"
+    }
+
+    fn dir_iter<'a>(&'a self, _path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.files.iter().map(|f| -> Box<dyn super::DirEntry> { Box::new(f) })))
+    }
+
+    fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        self.dir_iter(path)
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        Some(Box::new(&self.files[self.find(name)?]))
+    }
+
+    fn free_blocks(&self) -> usize {
+        REEL_BLOCKS.saturating_sub(self.used_blocks())
+    }
+
+    fn used_blocks(&self) -> usize {
+        self.files.iter().map(|f| f.len().div_ceil(BLOCK_SIZE)).sum()
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let Some(f) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        Ok(ByteBuffer::from_vec(self.files[f].data.concat()))
+    }
+
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    // Tape files are always contiguous, so `contiguous` doesn't change anything.
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        if options.placement != Default::default() || !options.prefix.is_empty() {
+            return Err(anyhow!("{}: ANSI magtape filesystems don't support placement or prefix blocks", name));
+        }
+        let name = name.trim_start_matches('/');
+        check_name(name)?;
+        // RT-11 only reads and writes whole blocks.
+        let data = contents.chunks(BLOCK_SIZE).map(|c| { let mut c = c.to_vec(); c.resize(BLOCK_SIZE, 0); c }).collect();
+        let f = match self.find(name) {
+            Some(f) => { self.files[f] = TapeFile::new(name, data); f },
+            None    => { self.files.push(TapeFile::new(name, data)); self.files.len() - 1 },
+        };
+        self.rewrite_from(f)
+    }
+
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(f) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.files.remove(f);
+        self.rewrite_from(f)
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let Some(f) = self.find(src) else { return Err(anyhow!("File not found: {}", src)) };
+        let dest = dest.trim_start_matches('/');
+        check_name(dest)?;
+        self.files[f].name = dest.to_string();
+        self.rewrite_from(f)
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME || !name.chars().all(|c| c.is_ascii_graphic() && !c.is_ascii_lowercase()) {
+        return Err(anyhow!("Bad tape file name {:?} (should be up to {} characters)", name, MAX_NAME));
+    }
+    Ok(())
+}
+
+impl<B: BlockDevice> Debug for AnsiTapeFs<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "ANSI magtape volume {:?}, owner {:?}", self.volume_id, self.owner)?;
+        for file in self.files.iter() {
+            writeln!(f, "{:#?}", file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for TapeFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = self.created.map(|d| d.to_string()).unwrap_or(" No Date".to_string());
+        if f.alternate() {
+            let system = String::from_utf8_lossy(self.label.get(60..73).unwrap_or_default()).trim_end().to_string();
+            write!(f, "{:<10} {:6} {:7} {:13} +{}/+{} labels {}", date, self.data.len(), self.len(), system,
+                   self.more_headers.len(), self.more_trailers.len(), self.name)
+        } else {
+            write!(f, "{:10} {:6} {}", date, self.len().div_ceil(BLOCK_SIZE), self.name)
+        }
+    }
+}
+
+impl super::DirEntry for &TapeFile {
+    fn path(&self)       -> &str                             { &self.name }
+    fn file_name(&self)  -> &str                             { &self.name }
+    fn is_dir(&self)     -> bool                             { false }
+    fn is_file(&self)    -> bool                             { true }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { TapeFile::len(self) as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { self.created.map(super::Timestamp::Date).ok_or(anyhow!("Bad Date")) }
+    fn blocks(&self)     -> u64                              { TapeFile::len(self).div_ceil(BLOCK_SIZE) as u64 }
+    fn readonly(&self)   -> bool                             { false }
+    fn protected(&self)  -> bool                             { false }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::tap::{Tap, TapeRecord};
+    use crate::fs::test::incrementing;
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(NaiveDate::from_ymd_opt(1985, 2, 1), parse_date(" 85032"));
+        assert_eq!(NaiveDate::from_ymd_opt(2023, 1, 19), parse_date("023019"));
+        assert_eq!(None, parse_date(" 00000"));
+        assert_eq!("023019", format_date(NaiveDate::from_ymd_opt(2023, 1, 19)));
+        assert_eq!(" 85032", format_date(NaiveDate::from_ymd_opt(1985, 2, 1)));
+        assert_eq!(" 00000", format_date(None));
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut fs = AnsiTapeFs::mkfs(Tap::new()).expect("Create ANSI tape FS");
+        assert_eq!(vec![TapeRecord::Mark, TapeRecord::Mark], fs.image.records[1..]);
+        let big = incrementing(1000);
+        fs.write_file("SWAP.SYS", &big).expect("write_file failed");
+        fs.write_file("README.TXT", b"Hello\n").expect("write_file failed");
+        assert!(fs.write_file("lower.txt", b"").is_err());
+        assert!(fs.write_file("A.NAME.THATS.TOO.LONG", b"").is_err());
+
+        let records = &fs.image.records;
+        assert_eq!(1 + 2 * 6 + 1 + 1, records.len());
+        let TapeRecord::Data(hdr1) = &records[1] else { panic!("no HDR1") };
+        assert_eq!("HDR1SWAP.SYS         RT11A 00010001000100023019 00000 000000DECRT11A            ", String::from_utf8_lossy(hdr1));
+        assert_eq!(TapeRecord::Mark, records[2]);
+        assert_eq!(TapeRecord::Data(big[0..512].to_vec()), records[3]);
+        let TapeRecord::Data(eof1) = &records[6] else { panic!("no EOF1") };
+        assert_eq!("EOF1SWAP.SYS         RT11A 00010001000100023019 00000 000002DECRT11A            ", String::from_utf8_lossy(eof1));
+        let TapeRecord::Data(hdr1) = &records[8] else { panic!("no HDR1") };
+        assert_eq!("HDR1README.TXT       RT11A 00010002", String::from_utf8_lossy(&hdr1[0..35]));
+        assert_eq!(vec![TapeRecord::Mark, TapeRecord::Mark], records[13..]);
+
+        let mut fs = AnsiTapeFs::new(Tap::from_bytes(&fs.image.to_bytes()).unwrap()).expect("Reopen ANSI tape FS");
+        assert!(AnsiTapeFs::image_is(&fs.image));
+        assert_eq!(("RT11A", 2), (fs.volume_id.as_str(), fs.files.len()));
+        assert_eq!(big, fs.read_file("SWAP.SYS").unwrap().into_vec()[0..1000]);
+        assert_eq!(1024, fs.stat("SWAP.SYS").unwrap().len());
+        assert_eq!(3, fs.used_blocks());
+
+        fs.delete("SWAP.SYS").expect("delete failed");
+        fs.rename("README.TXT", "HELLO.TXT").expect("rename failed");
+        fs.write_file("EMPTY.DAT", b"").expect("write_file failed");
+        let fs = AnsiTapeFs::new(fs.image).expect("Reopen ANSI tape FS");
+        assert_eq!(vec![("HELLO.TXT", 1), ("EMPTY.DAT", 0)], fs.files.iter().map(|f| (f.name.as_str(), f.data.len())).collect::<Vec<_>>());
+        assert_eq!(1 + 6 + 5 + 1, fs.image.records.len());
+        assert_eq!(b"Hello\n", &fs.read_file("HELLO.TXT").unwrap().into_vec()[0..6]);
+
+        let mut fs = AnsiTapeFs::mkfs(Tap::new()).expect("Create ANSI tape FS");
+        fs.write_file("X", b"x").expect("write_file failed");
+        fs.delete("X").expect("delete failed");
+        assert_eq!(vec![TapeRecord::Mark, TapeRecord::Mark], fs.image.records[1..]);
+    }
+}
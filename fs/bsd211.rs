@@ -0,0 +1,127 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use super::unixv7::{Flavor, UnixFs, name_from};
+use super::unixv6::{read_long, write_long};
+
+use bytebuffer::{ByteBuffer, Endian};
+
+// 2.11BSD's filesystem. See fs(5) and dir(5) in the 2.11BSD manual, and sys/fs.h, sys/inode.h and sys/dir.h.
+//
+// It's the V7 filesystem (see unixv7.rs) with 1K blocks, 4 direct blocks, block addresses stored as plain longs
+// and 4.2BSD style directories: variable length entries that may not cross a 512 byte boundary.
+
+const DIRBLKSIZ: usize = 512;
+const NADDR: usize = 7;
+
+#[derive(Clone, Debug)]
+pub struct Bsd211;
+
+impl Flavor for Bsd211 {
+    const NAME: &'static str = "2.11BSD";
+    const BLOCK_SIZE: usize = 1024;
+    const DIRECT_ADDRS: usize = 4;
+    const MAX_NAME: usize = 63;
+    const KEEPS_TFREE: bool = true;
+
+    fn decode_addrs(raw: &[u8]) -> Vec<u32> {
+        let mut buf = ByteBuffer::from_bytes(raw);
+        buf.set_endian(Endian::LittleEndian);
+        (0..NADDR).map(|_| read_long(&mut buf).expect("can't happen")).collect()
+    }
+
+    // di_reserved and di_flags come after the addresses, and are left alone.
+    fn encode_addrs(addrs: &[u32], raw: &mut [u8]) {
+        let mut buf = ByteBuffer::new();
+        buf.set_endian(Endian::LittleEndian);
+        for a in addrs { write_long(&mut buf, *a) }
+        raw[0..NADDR * 4].copy_from_slice(buf.as_bytes());
+    }
+
+    // struct direct { ino_t d_ino; short d_reclen; short d_namlen; char d_name[d_namlen+1] }. A block's free
+    // space is tacked onto the reclen of the entry before it (or is a first entry with a d_ino of 0).
+    fn parse_dir(raw: &[u8]) -> Vec<(u16, String)> {
+        let mut records = vec![];
+        for chunk in raw.chunks_exact(DIRBLKSIZ) {
+            let mut offset = 0;
+            while offset + 6 <= DIRBLKSIZ {
+                let word = |o: usize| u16::from_le_bytes([chunk[offset + o], chunk[offset + o + 1]]) as usize;
+                let (ino, reclen, namlen) = (word(0), word(2), word(4));
+                if reclen < 6 || offset + reclen > DIRBLKSIZ { break }
+                let name = &chunk[offset + 6..std::cmp::min(offset + 6 + namlen, offset + reclen)];
+                if ino != 0 { records.push((ino as u16, name_from(name))) }
+                offset += reclen;
+            }
+        }
+        records
+    }
+
+    // Everything gets packed up again, so there are no holes to reuse.
+    fn dir_repr(records: &[(u16, String)]) -> Vec<u8> {
+        let mut repr: Vec<u8> = vec![];
+        let mut chunk_start = 0;
+        let mut last_entry = None;
+        for (num, name) in records.iter().filter(|(num, _)| *num != 0) {
+            let reclen = 6 + ((name.len() + 1 + 3) & !3);
+            if repr.len() + reclen > chunk_start + DIRBLKSIZ {
+                extend_last(&mut repr, last_entry, chunk_start + DIRBLKSIZ);
+                chunk_start += DIRBLKSIZ;
+            }
+            last_entry = Some(repr.len());
+            repr.extend_from_slice(&num.to_le_bytes());
+            repr.extend_from_slice(&(reclen as u16).to_le_bytes());
+            repr.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            repr.extend_from_slice(name.as_bytes());
+            repr.resize(last_entry.unwrap() + reclen, 0);
+        }
+        extend_last(&mut repr, last_entry, chunk_start + DIRBLKSIZ);
+        repr
+    }
+}
+
+// Give the rest of the directory block to the entry at `entry`.
+fn extend_last(repr: &mut Vec<u8>, entry: Option<usize>, end: usize) {
+    repr.resize(end, 0);
+    match entry {
+        Some(entry) => repr[entry + 2..entry + 4].copy_from_slice(&((end - entry) as u16).to_le_bytes()),
+        None => repr[end - DIRBLKSIZ + 2..end - DIRBLKSIZ + 4].copy_from_slice(&(DIRBLKSIZ as u16).to_le_bytes()),
+    }
+}
+
+pub type Bsd211Fs<B> = UnixFs<B, Bsd211>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::FileSystem;
+    use crate::fs::test::*;
+
+    #[test]
+    fn test_dir_repr() {
+        let records: Vec<(u16, String)> = [(2, "."), (2, ".."), (3, "a_rather_long_name_for_a_file_on_a_pdp11")].iter()
+            .map(|(n, s)| (*n, s.to_string())).chain((0..40).map(|i| (10 + i, format!("file{}", i)))).collect();
+        let repr = Bsd211::dir_repr(&records);
+        assert_eq!(2 * DIRBLKSIZ, repr.len());
+        assert_eq!(&[2, 0, 10, 0, 1, 0, b'.', 0], &repr[0..8]);
+        assert_eq!(records, Bsd211::parse_dir(&repr));
+        let empty = Bsd211::dir_repr(&[]);
+        assert_eq!((DIRBLKSIZ, DIRBLKSIZ as u16), (empty.len(), u16::from_le_bytes([empty[2], empty[3]])));
+        assert!(Bsd211::parse_dir(&empty).is_empty());
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut fs = Bsd211Fs::mkfs(TestDev(vec![0; 512*4000])).expect("Create 2.11BSD FS");
+        let big = incrementing(300 * 1024);
+        fs.write_file("usr/src/sys/a_rather_long_name_for_a_file.c", &big).expect("write_file failed");
+        for i in 0..50 { fs.write_file(&format!("usr/file{}", i), &[i as u8; 10]).expect("write_file failed") }
+
+        let mut fs = Bsd211Fs::new(TestDev(fs.image.0.clone())).expect("Reopen 2.11BSD FS");
+        assert!(!crate::fs::unixv7::UnixV7Fs::image_is(&fs.image));
+        assert_eq!(big, fs.read_file("/usr/src/sys/a_rather_long_name_for_a_file.c").unwrap().into_vec());
+        assert_eq!(vec![7; 10], fs.read_file("/usr/file7").unwrap().into_vec());
+        assert_eq!(51, fs.read_dir("usr").unwrap().count());
+        let free = fs.free_blocks();
+        fs.delete("usr/src/sys/a_rather_long_name_for_a_file.c").expect("delete failed");
+        assert_eq!(free + (300 + 1 + 2) * 2, fs.free_blocks()); // Plus the single indirect block and the double's 2
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use anyhow::anyhow;
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, Geometry, BLOCK_SIZE};
+use crate::block::flat::Flat;
+use crate::block::img::IMG;
+use super::FileSystem;
+use super::rt11::RT11FS;
+
+// RT-11 BUP (BACKUP) volume sets: a volume too big for one disk saved across several smaller ones.
+//
+// Each backup volume is an ordinary RT-11 volume with "BUQ" and its volume number (starting at 1) in the home block
+// (see HomeBlock::bup_volume). It holds a single file, named the same on every volume of the set, with the next
+// piece of the saved volume. The pieces, in volume order, are the saved volume. Every volume of a set has the same
+// volume id (the time the set was made), which is how we can tell sets apart.
+
+pub const SAVESET_NAME: &str = "BACKUP.BUP";
+
+// The saved volume, put back together.
+pub fn restore<B: BlockDevice>(volumes: &[RT11FS<B>]) -> anyhow::Result<Vec<u8>> {
+    let Some(first) = volumes.first() else { return Err(anyhow!("No backup volumes")) };
+    let piece_name = |v: &RT11FS<B>| -> anyhow::Result<String> {
+        let mut files = v.read_dir("/")?;
+        match (files.next(), files.next()) {
+            (Some(f), None) => Ok(f.file_name().to_string()),
+            _ => Err(anyhow!("Backup volumes should have exactly one file")),
+        }
+    };
+    let name = piece_name(first)?;
+    let mut data = vec![];
+    for (i, v) in volumes.iter().enumerate() {
+        match v.home.bup_volume {
+            None => return Err(anyhow!("Volume {} isn't a BACKUP volume", i + 1)),
+            Some(n) if n as usize != i + 1 => return Err(anyhow!("Volume {} is BACKUP volume #{}, expected #{}", i + 1, n, i + 1)),
+            Some(_) => {},
+        }
+        let this_name = piece_name(v).map_err(|e| anyhow!("Volume {}: {}", i + 1, e))?;
+        if this_name != name || v.home.volume_id != first.home.volume_id {
+            return Err(anyhow!("Volume {} ({} from set {:?}) isn't part of the same set as volume 1 ({} from set {:?})",
+                               i + 1, this_name, v.home.volume_id.trim_end(), name, first.home.volume_id.trim_end()));
+        }
+        data.extend(v.read_file(&name)?.into_vec());
+    }
+
+    // Nothing says how many volumes there should be, but if the saved volume was RT-11 its directory knows how big it was.
+    let blocks = data.len() / BLOCK_SIZE;
+    if let Ok(saved) = RT11FS::new(Flat(IMG::from_vec(data.clone(), Geometry { cylinders: 1, heads: 1, sectors: blocks, sector_size: BLOCK_SIZE }))) {
+        let size = saved.dir.first().map(|seg| seg.data_block as usize).unwrap_or(0) + saved.used_blocks() + saved.free_blocks();
+        if size > blocks {
+            return Err(anyhow!("The saved volume has {} blocks but the backup set only has {}. Is a volume missing?", size, blocks));
+        }
+    }
+    Ok(data)
+}
+
+// Saves `data` (a whole volume) across as many backup volumes as it takes. `new_volume` makes a blank device for the
+// next one.
+pub fn backup<B: BlockDevice>(data: &[u8], mut new_volume: impl FnMut() -> anyhow::Result<B>) -> anyhow::Result<Vec<RT11FS<B>>> {
+    let set_id = Local::now().format("%y%m%d%H%M%S").to_string();
+    let mut volumes = vec![];
+    let mut rest = data;
+    while !rest.is_empty() || volumes.is_empty() {
+        if volumes.len() == u8::MAX as usize { return Err(anyhow!("BACKUP sets can't have more than {} volumes", u8::MAX)) }
+        let mut fs = RT11FS::mkfs(new_volume()?)?;
+        fs.home.bup_volume = Some(volumes.len() as u8 + 1);
+        fs.home.volume_id = set_id.clone();
+        fs.write_homeblock()?;
+        let piece = std::cmp::min(rest.len(), fs.free_blocks() * BLOCK_SIZE);
+        if piece == 0 { return Err(anyhow!("Backup volumes don't have room for any data")) }
+        fs.write_file(SAVESET_NAME, &rest[0..piece])?;
+        rest = &rest[piece..];
+        volumes.push(fs);
+    }
+    Ok(volumes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::test::TestDev;
+
+    #[test]
+    fn test_backup_restore() {
+        let mut saved = RT11FS::mkfs(TestDev(vec![0; 200 * BLOCK_SIZE])).expect("Create RT-11 FS");
+        let contents: Vec<u8> = (0..150 * BLOCK_SIZE).map(|x| (x * 7 % 251) as u8).collect();
+        saved.write_file("BIG.DAT", &contents).expect("write_file failed");
+        let data = saved.image.0.clone();
+
+        let volumes = backup(&data, || Ok(TestDev(vec![0; 82 * BLOCK_SIZE]))).expect("backup failed");
+        assert_eq!(3, volumes.len()); // 82 blocks less the home block and directory is 68 blocks each
+        assert_eq!(vec![Some(1), Some(2), Some(3)], volumes.iter().map(|v| v.home.bup_volume).collect::<Vec<_>>());
+        assert_eq!("230119121314", volumes[2].home.volume_id);
+        let volumes: Vec<_> = volumes.into_iter().map(|v| RT11FS::new(v.image).expect("Reopen backup volume")).collect();
+        assert_eq!(Some(2), volumes[1].home.bup_volume);
+        assert!(RT11FS::image_is(&volumes[0].image)); // Full, so it ends with a zero length empty entry
+
+        let restored = restore(&volumes).expect("restore failed");
+        assert_eq!(data, restored);
+        let restored = RT11FS::new(TestDev(restored)).expect("Open restored volume");
+        assert_eq!(contents, restored.read_file("BIG.DAT").unwrap().into_vec());
+
+        let copy = |v: &RT11FS<TestDev>| RT11FS::new(TestDev(v.image.0.clone())).expect("Copy backup volume");
+        let out_of_order = [copy(&volumes[1]), copy(&volumes[0]), copy(&volumes[2])];
+        assert!(restore(&out_of_order).is_err());
+        assert!(restore(&volumes[0..2]).is_err()); // Missing the last volume
+        let mut other_set: Vec<_> = volumes.iter().map(copy).collect();
+        other_set[1].home.volume_id = "230119000000".to_string();
+        assert!(restore(&other_set).is_err());
+        assert!(restore(&[saved]).is_err()); // Not a backup volume
+    }
+}
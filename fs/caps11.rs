@@ -0,0 +1,381 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+use bytebuffer::ByteBuffer;
+use chrono::{Datelike, NaiveDate};
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use super::{CreateOptions, FileSystem};
+
+// CAPS-11 cassettes (TU60, on a TA11). See the "CAPS-11 User's Guide" (DEC-11-OTUGA).
+//
+// Files are one after another: a 32 byte header record, the data in 128 byte records and a tape mark. The header is
+//
+//     0-5   name (ASCII, space padded)     12    sequence number (for files continued on another cassette)
+//     6-8   extension                      13    level (1)
+//     9     file type                      14-19 date, as ASCII DDMMYY
+//     10-11 record length (little endian)  20-31 unused
+//
+// A header whose name starts with '*' is the sentinel that marks the end of the cassette. Deleted files have a NUL
+// in place of the first character of their names.
+//
+// Cassettes can only be written sequentially, so new files go on the end (before the sentinel). Writing a file that
+// already exists renames the old one out of the way (by deleting it) and adds the new one to the end, like CAPS-11's
+// PIP does. Renaming, deleting and appending rewrite the cassette from that file on (everything is kept in memory).
+
+const HEADER_SIZE: usize = 32;
+const RECORD_SIZE: usize = 128;
+const SENTINEL: u8 = b'*';
+const DELETED: u8 = 0;
+const LEVEL: u8 = 1;
+const ASCII_FILE: u8 = 1;
+// A 300' cassette holds about 92K bytes.
+const CASSETTE_BYTES: usize = 92 * 1024;
+
+#[derive(Clone)]
+pub struct Caps11Fs<B: BlockDevice> {
+    pub image: B,
+    pub files: Vec<CassetteFile>,
+}
+
+#[derive(Clone)]
+pub struct CassetteFile {
+    pub name: String,
+    pub deleted: bool,
+    pub kind: u8,
+    pub record_length: usize,
+    pub created: Option<NaiveDate>,
+    pub data: Vec<Vec<u8>>,   // The data records
+    header: Vec<u8>,          // The rest of the fields, kept as they were
+}
+
+impl<B: BlockDevice> Caps11Fs<B> {
+    pub fn new(image: B) -> anyhow::Result<Caps11Fs<B>> {
+        let mut files = vec![];
+        let mut r = 0;
+        // Running off the end of the image is treated like the sentinel, so truncated cassettes can still be read.
+        while r < image.sectors() {
+            let header = image.read_sector(r)?;
+            r += 1;
+            if header.is_empty() { continue } // Stray tape marks
+            if header.len() != HEADER_SIZE { return Err(anyhow!("Expected a {} byte file header at record {}, found {} bytes", HEADER_SIZE, r - 1, header.len())) }
+            if header[0] == SENTINEL { break }
+            let mut data = vec![];
+            while r < image.sectors() {
+                let record = image.read_sector(r)?;
+                r += 1;
+                if record.is_empty() { break }
+                data.push(record);
+            }
+            files.push(CassetteFile::from_header(header, data));
+        }
+        Ok(Caps11Fs { image, files })
+    }
+
+    pub fn image_is(image: &B) -> bool {
+        image.sectors() > 0 && image.read_sector(0).is_ok_and(|r| r.len() == HEADER_SIZE)
+    }
+
+    pub fn mkfs(mut image: B) -> anyhow::Result<Caps11Fs<B>> {
+        image.write_sector(0, &sentinel())?;
+        image.write_sector(1, &[])?;
+        Self::new(image)
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        let name = name.trim_start_matches('/');
+        self.files.iter().position(|f| !f.deleted && f.name == name)
+    }
+
+    // The record the file's header is in.
+    fn start_record(&self, file: usize) -> usize {
+        self.files[0..file].iter().map(|f| 1 + f.data.len() + 1).sum()
+    }
+
+    fn check_room(&self, name: &str, bytes: usize) -> anyhow::Result<()> {
+        let used: usize = self.files.iter().map(|f| f.len()).sum(); // Deleted files still take up room
+        if used + bytes.div_ceil(RECORD_SIZE) * RECORD_SIZE > CASSETTE_BYTES {
+            return Err(anyhow!("Not enough room on the cassette for {} ({} bytes, {} free)", name, bytes, CASSETTE_BYTES.saturating_sub(used)));
+        }
+        Ok(())
+    }
+
+    // Writes `file` and everything after it, and then the sentinel.
+    fn rewrite_from(&mut self, file: usize) -> anyhow::Result<()> {
+        let mut r = self.start_record(file);
+        let mut write = |image: &mut B, data: &[u8]| { r += 1; image.write_sector(r - 1, data) };
+        for f in self.files.iter().skip(file) {
+            write(&mut self.image, &f.to_header())?;
+            for d in f.data.iter() { write(&mut self.image, d)? }
+            write(&mut self.image, &[])?;
+        }
+        write(&mut self.image, &sentinel())?;
+        write(&mut self.image, &[])
+    }
+}
+
+fn sentinel() -> Vec<u8> {
+    let mut header = vec![0; HEADER_SIZE];
+    header[0] = SENTINEL;
+    header
+}
+
+// DDMMYY
+fn parse_date(field: &[u8]) -> Option<NaiveDate> {
+    let field = std::str::from_utf8(field).ok()?;
+    let year: i32 = field.get(4..6)?.parse().ok()?;
+    NaiveDate::from_ymd_opt(if year < 70 { 2000 + year } else { 1900 + year }, field.get(2..4)?.parse().ok()?, field.get(0..2)?.parse().ok()?)
+}
+
+fn format_date(date: Option<NaiveDate>) -> String {
+    date.map(|d| format!("{:02}{:02}{:02}", d.day(), d.month(), d.year() % 100)).unwrap_or("      ".to_string())
+}
+
+// The name as we show it. Names can have anything in them on a cassette, so this might not be how to write it back.
+fn header_name(header: &[u8]) -> String {
+    let text = |range: std::ops::Range<usize>| String::from_utf8_lossy(&header[range]).trim_end_matches([' ', '\0']).to_string();
+    let (name, ext) = (text(1..6), text(6..9));
+    let first = if header[0] == DELETED { '?' } else { header[0] as char };
+    format!("{}{}{}{}", first, name, if ext.is_empty() { "" } else { "." }, ext)
+}
+
+impl CassetteFile {
+    fn from_header(header: Vec<u8>, data: Vec<Vec<u8>>) -> CassetteFile {
+        CassetteFile {
+            name: header_name(&header),
+            deleted: header[0] == DELETED,
+            kind: header[9],
+            record_length: u16::from_le_bytes([header[10], header[11]]) as usize,
+            created: parse_date(&header[14..20]),
+            data,
+            header,
+        }
+    }
+
+    fn new(name: &str, data: Vec<Vec<u8>>) -> CassetteFile {
+        let mut header = vec![0; HEADER_SIZE];
+        header[13] = LEVEL;
+        CassetteFile { name: name.to_string(), deleted: false, kind: ASCII_FILE, record_length: RECORD_SIZE,
+                       created: Some(Local::now().naive_local().date()), data, header }
+    }
+
+    fn to_header(&self) -> Vec<u8> {
+        let mut header = self.header.clone();
+        // Names from the cassette keep their bytes. New ones have been through check_name(), so they're plain ASCII.
+        if self.name != header_name(&self.header) {
+            let (name, ext) = self.name.split_once('.').unwrap_or((&self.name, ""));
+            header[0..9].copy_from_slice(format!("{:<6}{:<3}", name, ext).as_bytes());
+        }
+        if self.deleted { header[0] = DELETED }
+        header[9] = self.kind;
+        header[10..12].copy_from_slice(&(self.record_length as u16).to_le_bytes());
+        header[14..20].copy_from_slice(format_date(self.created).as_bytes());
+        header
+    }
+
+    fn len(&self) -> usize {
+        self.data.iter().map(|d| d.len()).sum()
+    }
+
+    fn records(contents: &[u8]) -> Vec<Vec<u8>> {
+        contents.chunks(RECORD_SIZE).map(|c| { let mut c = c.to_vec(); c.resize(RECORD_SIZE, 0); c }).collect()
+    }
+}
+
+impl<B: BlockDevice> FileSystem for Caps11Fs<B> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        "CAPS-11"
+    }
+
+    fn dir_iter<'a>(&'a self, _path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.files.iter().map(|f| -> Box<dyn super::DirEntry> { Box::new(f) })))
+    }
+
+    fn read_dir<'a>(&'a self, _path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.files.iter().filter(|f| !f.deleted).map(|f| -> Box<dyn super::DirEntry> { Box::new(f) })))
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        Some(Box::new(&self.files[self.find(name)?]))
+    }
+
+    fn free_blocks(&self) -> usize {
+        (CASSETTE_BYTES / BLOCK_SIZE).saturating_sub(self.used_blocks())
+    }
+
+    // Deleted files still take up room on the cassette.
+    fn used_blocks(&self) -> usize {
+        self.files.iter().map(|f| f.len()).sum::<usize>().div_ceil(BLOCK_SIZE)
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let Some(f) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        Ok(ByteBuffer::from_vec(self.files[f].data.concat()))
+    }
+
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    // Cassette files are always contiguous, so `contiguous` doesn't change anything.
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        if options.placement != Default::default() || !options.prefix.is_empty() {
+            return Err(anyhow!("{}: CAPS-11 filesystems don't support placement or prefix blocks", name));
+        }
+        let name = name.trim_start_matches('/');
+        check_name(name)?;
+        self.check_room(name, contents.len())?;
+        let old = self.find(name);
+        if let Some(old) = old { self.files[old].deleted = true }
+        self.files.push(CassetteFile::new(name, CassetteFile::records(contents)));
+        self.rewrite_from(old.unwrap_or(self.files.len() - 1))
+    }
+
+    // Whole records only, like the rest of the file.
+    fn append_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let Some(f) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.check_room(name, contents.len())?;
+        self.files[f].data.extend(CassetteFile::records(contents));
+        self.rewrite_from(f)
+    }
+
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(f) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.files[f].deleted = true;
+        self.rewrite_from(f)
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let Some(f) = self.find(src) else { return Err(anyhow!("File not found: {}", src)) };
+        let dest = dest.trim_start_matches('/');
+        check_name(dest)?;
+        self.files[f].name = dest.to_string();
+        self.rewrite_from(f)
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+// 6.3
+fn check_name(name: &str) -> anyhow::Result<()> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    let ok = |s: &str, max: usize| s.len() <= max && s.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+    if base.is_empty() || !ok(base, 6) || !ok(ext, 3) {
+        return Err(anyhow!("Bad CAPS-11 file name {:?} (should be up to 6 letters and digits, and a 3 character extension)", name));
+    }
+    Ok(())
+}
+
+impl<B: BlockDevice> Debug for Caps11Fs<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "CAPS-11 cassette")?;
+        for file in self.files.iter() {
+            writeln!(f, "{:#?}", file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for CassetteFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = self.created.map(|d| d.to_string()).unwrap_or(" No Date".to_string());
+        if f.alternate() {
+            write!(f, "{:<10} {:4} x {:3} {:6} type {:#05o} {} {}", date, self.data.len(), self.record_length, self.len(), self.kind,
+                   if self.deleted { "(deleted)" } else { "         " }, self.name)
+        } else {
+            write!(f, "{:10} {:6} {}", date, self.len().div_ceil(BLOCK_SIZE), self.name)
+        }
+    }
+}
+
+impl super::DirEntry for &CassetteFile {
+    fn path(&self)       -> &str                             { &self.name }
+    fn file_name(&self)  -> &str                             { &self.name }
+    fn is_dir(&self)     -> bool                             { false }
+    fn is_file(&self)    -> bool                             { true }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { CassetteFile::len(self) as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { self.created.map(super::Timestamp::Date).ok_or(anyhow!("Bad Date")) }
+    fn blocks(&self)     -> u64                              { CassetteFile::len(self).div_ceil(BLOCK_SIZE) as u64 }
+    fn readonly(&self)   -> bool                             { false }
+    fn protected(&self)  -> bool                             { false }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::tap::{Tap, TapeRecord};
+    use crate::fs::test::incrementing;
+
+    #[test]
+    fn test_write_read() {
+        let mut fs = Caps11Fs::mkfs(Tap::cassette()).expect("Create CAPS-11 FS");
+        assert_eq!(vec![TapeRecord::Data(sentinel()), TapeRecord::Mark], fs.image.records);
+        let big = incrementing(200);
+        fs.write_file("PROG.BIN", &big).expect("write_file failed");
+        fs.write_file("README", b"Hello\n").expect("write_file failed");
+        assert!(fs.write_file("TOOLONG.TXT", b"").is_err());
+        assert!(fs.write_file("NAME.LONG", b"").is_err());
+
+        let records = &fs.image.records;
+        assert_eq!(4 + 3 + 2, records.len());
+        let TapeRecord::Data(header) = &records[0] else { panic!("no header") };
+        assert_eq!(b"PROG  BIN\x01\x80\x00\x00\x01190123", &header[0..20]);
+        assert_eq!(TapeRecord::Data(big[128..200].iter().copied().chain([0; 56]).collect()), records[2]);
+        assert_eq!(TapeRecord::Mark, records[3]);
+        let TapeRecord::Data(header) = &records[4] else { panic!("no header") };
+        assert_eq!(b"README   ", &header[0..9]);
+        assert_eq!(vec![TapeRecord::Data(sentinel()), TapeRecord::Mark], records[7..]);
+
+        let mut fs = Caps11Fs::new(Tap::from_bytes(&fs.image.to_bytes()).unwrap()).expect("Reopen CAPS-11 FS");
+        assert!(Caps11Fs::image_is(&fs.image));
+        assert_eq!(vec!["PROG.BIN", "README"], fs.files.iter().map(|f| f.name.as_str()).collect::<Vec<_>>());
+        assert_eq!(big, fs.read_file("PROG.BIN").unwrap().into_vec()[0..200]);
+        assert_eq!(NaiveDate::from_ymd_opt(2023, 1, 19), fs.files[0].created);
+
+        fs.write_file("PROG.BIN", b"new").expect("write_file failed"); // The old one is deleted, the new one goes on the end
+        fs.append_file("README", b"More\n").expect("append_file failed");
+        fs.rename("README", "READ.ME").expect("rename failed");
+        let fs = Caps11Fs::new(fs.image).expect("Reopen CAPS-11 FS");
+        assert_eq!(vec![("?ROG.BIN", true), ("READ.ME", false), ("PROG.BIN", false)],
+                   fs.files.iter().map(|f| (f.name.as_str(), f.deleted)).collect::<Vec<_>>());
+        assert_eq!(2, fs.files[1].data.len());
+        assert_eq!(b"More\n", &fs.read_file("READ.ME").unwrap().into_vec()[128..133]);
+        assert_eq!(b"new\0", &fs.read_file("PROG.BIN").unwrap().into_vec()[0..4]);
+        assert_eq!(2, fs.read_dir("/").unwrap().count());
+    }
+
+    #[test]
+    fn test_odd_names_and_room() {
+        let mut fs = Caps11Fs::mkfs(Tap::cassette()).expect("Create CAPS-11 FS");
+        fs.write_file("ODD", b"odd").expect("write_file failed");
+        let TapeRecord::Data(ref mut header) = fs.image.records[0] else { panic!("no header") };
+        header[1] = 0xc4; // Not ASCII
+        let mut fs = Caps11Fs::new(fs.image).expect("Reopen CAPS-11 FS");
+        assert_eq!("O\u{fffd}D", fs.files[0].name);
+        fs.write_file("NEXT", b"next").expect("write_file failed");
+        fs.delete("O\u{fffd}D").expect("delete failed"); // Rewrites it
+        let TapeRecord::Data(ref header) = fs.image.records[0] else { panic!("no header") };
+        assert_eq!(b"\0\xc4D      ", &header[0..9]);
+
+        let mut fs = Caps11Fs::mkfs(Tap::cassette()).expect("Create CAPS-11 FS");
+        assert!(fs.write_file("BIG", &vec![0; CASSETTE_BYTES + 1]).is_err());
+        fs.write_file("BIG", &vec![0; CASSETTE_BYTES - RECORD_SIZE]).expect("write_file failed");
+        fs.append_file("BIG", &[0; RECORD_SIZE]).expect("append_file failed");
+        assert!(fs.append_file("BIG", b"x").is_err());
+        assert!(fs.write_file("BIG", b"x").is_err()); // The old copy still takes up room
+        assert_eq!(0, fs.free_blocks());
+    }
+}
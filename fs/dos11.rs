@@ -0,0 +1,794 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::{mem::size_of, fmt::{Debug, Display}};
+
+use anyhow::{anyhow, Context};
+use bytebuffer::{Endian, ByteBuffer};
+use chrono::NaiveDate;
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use super::{CreateOptions, FileSystem, Placement};
+use super::xxdp::{self, XxdpFs, round_up, USABLE_BLOCK_SIZE, BITMAP_WORDS_PER_MAP_BLOCK, ENTRIES_PER_UFD_BLOCK};
+
+// DOS/BATCH (DOS-11 and BATCH-11 share the same file structure). XXDP's filesystem is a cut down version of this
+// one, so names, dates, the bitmap and linked file blocks all work the same way and the code for them lives in
+// xxdp.rs. What DOS adds is a UFD per user (UIC), contiguous files and protection codes.
+// disk operating system monitor: systems programmer's manual: https://bitsavers.org/pdf/dec/pdp11/dos-batch/DEC-11-OSPMA-A-D_PDP-11_DOS_Monitor_V004A_System_Programmers_Manual_May72.pdf
+
+const MFD_ENTRY_WORDS: usize = 4; // UIC, first UFD block, UFD entry size, (unused)
+const MFD_ENTRIES_PER_BLOCK: usize = (BLOCK_SIZE / size_of::<u16>() - 1/*link*/) / MFD_ENTRY_WORDS;
+const UFD_ENTRY_WORDS: u16 = 9;
+
+// DECtapes have no MFD1. Their directory sits in the middle of the tape to keep the seeks short: the MFD at block
+// 100 (octal), then the UFDs, then the bitmap.
+const DECTAPE_BLOCKS: usize = 578;
+const DECTAPE_MFD_BLOCK: u16 = 0o100;
+const DECTAPE_BITMAP_BLOCK: u16 = 0o104;
+
+const CONTIGUOUS_FLAG: u16 = 1 << 15; // In the date word
+const DEFAULT_PROTECTION: u8 = 0o233;
+const OWNER_WRITE_PROTECT: u8 = 0o100;
+
+// The date only gets 15 bits (the top one is the contiguous flag), which ran out at the end of 2002. Files made after
+// that don't get a date.
+fn today() -> Option<NaiveDate> {
+    let today = Local::now().date_naive();
+    xxdp::DirEntry::encode_date(Some(today)).is_ok_and(|d| d & CONTIGUOUS_FLAG == 0).then_some(today)
+}
+
+pub const SYSTEM_UIC: Uic = Uic { group: 1, member: 1 };
+const USER_UIC: Uic = Uic { group: 0o200, member: 0o200 }; // Where DOS logs people in unless told otherwise
+
+#[derive(Clone)]
+pub struct Dos11Fs<B: BlockDevice> {
+    pub image: B,
+    pub mfd: Mfd,
+    pub ufds: Vec<Ufd>,
+    pub bitmap: Vec<bool>,
+    pub bitmap_block_list: Vec<u16>,
+}
+
+impl<B: BlockDevice> Dos11Fs<B> {
+    pub fn new(image: B) -> anyhow::Result<Dos11Fs<B>> {
+        let (mfd, ufds, bitmap, bitmap_block_list) = Self::try_new(&image)?;
+        Ok(Dos11Fs {
+            image,
+            mfd,
+            ufds,
+            bitmap,
+            bitmap_block_list,
+        })
+    }
+
+    // XXDP reads DOS disks just fine (it only looks at [1,1]), so only claim the ones that have something XXDP
+    // doesn't: another UIC, a contiguous file or a protection code. DECtapes don't look like XXDP at all.
+    pub fn image_is(image: &B) -> bool {
+        let Ok((mfd, ufds, _, _)) = Self::try_new(image) else { return false };
+        mfd.dectape || ufds.iter().any(|u| u.uic != SYSTEM_UIC ||
+                                           u.entries.iter().any(|e| e.name.is_some() && (e.contiguous || e.protection != 0)))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn try_new(image: &B) -> anyhow::Result<(Mfd, Vec<Ufd>, Vec<bool>, Vec<u16>)> {
+        let (mfd, uics) = Self::read_master_file_directory(image)?;
+        let ufds = uics.into_iter().map(|(uic, block)| {
+            let (entries, block_list) = Self::read_user_file_directory(image, block).with_context(|| format!("UFD for {}", uic))?;
+            Ok(Ufd { uic, entries, block_list })
+        }).collect::<anyhow::Result<Vec<Ufd>>>()?;
+        let (mut bitmap, bitmap_block_list) = XxdpFs::read_bitmap(image, mfd.bitmap_block)?;
+        if image.blocks() > bitmap.len() {
+            return Err(anyhow!("Bitmap is too short {} < {}", bitmap.len(), image.blocks()));
+        }
+        bitmap.truncate(image.blocks());
+        Ok((mfd,
+            ufds,
+            bitmap,
+            bitmap_block_list))
+    }
+
+    // Makes UFDs for [1,1] and [200,200] (the default login UIC). Block 0 is left for a boot block.
+    pub fn mkfs(image: B) -> anyhow::Result<Dos11Fs<B>> {
+        let blocks = image.blocks();
+        let bitmap_blocks = round_up(blocks, 16 * BITMAP_WORDS_PER_MAP_BLOCK) / (16 * BITMAP_WORDS_PER_MAP_BLOCK);
+        let (mfd, ufd_blocks) = if blocks == DECTAPE_BLOCKS {
+            (Mfd {
+                interleave_factor: 1,
+                bitmap_block: DECTAPE_BITMAP_BLOCK,
+                bitmap_pointer: vec![DECTAPE_BITMAP_BLOCK],
+                block_list: vec![DECTAPE_MFD_BLOCK],
+                dectape: true,
+            }, [DECTAPE_MFD_BLOCK + 2, DECTAPE_MFD_BLOCK + 3])
+        } else {
+            // MFD1, MFD2, the bitmap then the UFDs.
+            let bitmap_block = 3;
+            let ufd_block = bitmap_block + bitmap_blocks as u16;
+            (Mfd {
+                interleave_factor: 1,
+                bitmap_block,
+                bitmap_pointer: (bitmap_block..ufd_block).collect(),
+                block_list: vec![2],
+                dectape: false,
+            }, [ufd_block, ufd_block + 1])
+        };
+        let bitmap_block_list: Vec<u16> = (mfd.bitmap_block..mfd.bitmap_block + bitmap_blocks as u16).collect();
+        let mut bitmap = vec![false; blocks];
+        for b in [0].iter().chain(if mfd.dectape { &[][..] } else { &[1][..] })
+                           .chain(mfd.block_list.iter())
+                           .chain(ufd_blocks.iter())
+                           .chain(bitmap_block_list.iter()) {
+            bitmap[*b as usize] = true;
+        }
+        let mut fs = Dos11Fs {
+            image,
+            mfd,
+            ufds: [SYSTEM_UIC, USER_UIC].into_iter().zip(ufd_blocks).map(|(uic, block)| Ufd {
+                uic,
+                entries: vec![DirEntry::default(); ENTRIES_PER_UFD_BLOCK],
+                block_list: vec![block],
+            }).collect(),
+            bitmap,
+            bitmap_block_list,
+        };
+        for u in 0..fs.ufds.len() {
+            fs.write_ufd(u)?;
+        }
+        fs.write_bitmap()?;
+        fs.write_mfd()?;
+        Ok(fs)
+    }
+
+    // Returns the MFD and the (UIC, first UFD block) pairs in it.
+    pub fn read_master_file_directory(image: &B) -> anyhow::Result<(Mfd, Vec<(Uic, u16)>)> {
+        let blocks = image.blocks();
+        let dectape = blocks == DECTAPE_BLOCKS;
+        let (mfd2_block, interleave_factor, bitmap_block, bitmap_pointer) = if dectape {
+            (DECTAPE_MFD_BLOCK, 1, DECTAPE_BITMAP_BLOCK, vec![DECTAPE_BITMAP_BLOCK])
+        } else {
+            let mut buf = image.read_blocks(1, 1)?;
+            buf.set_endian(Endian::LittleEndian);
+            (buf.read_u16()?,
+             buf.read_u16()?,
+             buf.read_u16()?,
+             {
+                 let mut b = vec![];
+                 while buf.get_rpos() < BLOCK_SIZE {
+                     match buf.read_u16()? {
+                         0 => break,
+                         w => b.push(w),
+                     }
+                 }
+                 b
+             })
+        };
+        for (what, block) in [("MFD2", mfd2_block), ("bitmap", bitmap_block)] {
+            if block == 0 || block as usize >= blocks { return Err(anyhow!("Bad {} pointer in MFD: {}", what, block)) }
+        }
+
+        let mut uics = vec![];
+        let mut block_list = vec![];
+        for (block, mut buf) in XxdpFs::read_chain_raw(image, mfd2_block)? {
+            block_list.push(block);
+            for _ in 0..MFD_ENTRIES_PER_BLOCK {
+                let (uic, ufd_block, entry_words, _) = (buf.read_u16()?, buf.read_u16()?, buf.read_u16()?, buf.read_u16()?);
+                if uic == 0 { continue }
+                let uic = Uic::from_word(uic);
+                if entry_words != UFD_ENTRY_WORDS { return Err(anyhow!("MFD entry for {} has {} word UFD entries, not {}", uic, entry_words, UFD_ENTRY_WORDS)) }
+                if ufd_block as usize >= blocks { return Err(anyhow!("MFD entry for {} has a bad UFD pointer: {}", uic, ufd_block)) }
+                if uics.iter().any(|(u, _)| *u == uic) { return Err(anyhow!("{} is in the MFD twice", uic)) }
+                uics.push((uic, ufd_block));
+            }
+        }
+        if uics.is_empty() { return Err(anyhow!("MFD has no UICs in it")) }
+        Ok((Mfd {
+            interleave_factor,
+            bitmap_block,
+            bitmap_pointer,
+            block_list,
+            dectape,
+        }, uics))
+    }
+
+    // A UIC can be in the MFD before it has any UFD blocks (start_block is 0 then).
+    pub fn read_user_file_directory(image: &B, start_block: u16) -> anyhow::Result<(Vec<DirEntry>, Vec<u16>)> {
+        if start_block == 0 { return Ok((vec![], vec![])) }
+        let mut entries = vec![];
+        let mut block_list = vec![];
+        for (block, mut buf) in XxdpFs::read_chain_raw(image, start_block)? {
+            buf.set_rpos(size_of::<u16>());
+            block_list.push(block);
+            while let Some(entry) = DirEntry::from_repr(&mut buf)? {
+                entries.push(entry);
+            }
+        }
+        Ok((entries, block_list))
+    }
+
+    // Returns (ufd index, entry index)
+    fn raw_stat(&self, path: &str) -> Option<(usize, usize)> {
+        let (uic, name) = Uic::split_path(path).ok()?;
+        let u = self.ufds.iter().position(|u| u.uic == uic)?;
+        let e = self.ufds[u].entries.iter().position(|e| e.name.as_deref() == Some(name))?;
+        Some((u, e))
+    }
+
+    fn listing<'a>(&'a self, path: &str, all: bool) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        // "/" is every file on the volume, "[g,m]" is just the files in that UIC.
+        let ufds: Vec<&Ufd> = match path.trim_start_matches('/') {
+            "" => self.ufds.iter().collect(),
+            p => {
+                let (uic, rest) = Uic::split_path(p)?;
+                if !p.starts_with('[') || !rest.is_empty() { return Err(anyhow!("Bad path")) }
+                vec![self.ufds.iter().find(|u| u.uic == uic).ok_or_else(|| anyhow!("No UFD for {}", uic))?]
+            },
+        };
+        Ok(Box::new(ufds.into_iter().flat_map(move |u| {
+            u.entries.iter().filter(move |e| all || e.name.is_some())
+                            .map(move |e| -> Box<dyn super::DirEntry> { Box::new(Listing::new(u.uic, e)) })
+        })))
+    }
+
+    // The index of the UFD for `uic`, adding one to the MFD if there isn't one yet and `create` is set.
+    fn ufd_for(&mut self, uic: Uic, create: bool) -> anyhow::Result<usize> {
+        if let Some(u) = self.ufds.iter().position(|u| u.uic == uic) { return Ok(u) }
+        if !create { return Err(anyhow!("No UFD for {}", uic)) }
+        if self.ufds.len() >= self.mfd.block_list.len() * MFD_ENTRIES_PER_BLOCK {
+            let block = xxdp::allocate_blocks_in(&mut self.bitmap, 1)?[0];
+            self.mfd.block_list.push(block);
+        }
+        self.ufds.push(Ufd { uic, entries: vec![], block_list: vec![] });
+        self.write_mfd()?;
+        self.write_bitmap()?;
+        Ok(self.ufds.len() - 1)
+    }
+
+    fn allocate_dir_entry(&mut self, u: usize) -> anyhow::Result<usize> {
+        if let Some(i) = self.ufds[u].entries.iter().position(|e| e.name.is_none()) {
+            return Ok(i);
+        }
+        // No more room! Need to allocate a new UFD block
+        let block = xxdp::allocate_blocks_in(&mut self.bitmap, 1)?[0];
+        let ufd = &mut self.ufds[u];
+        ufd.block_list.push(block);
+        let new_dir_entry = ufd.entries.len();
+        ufd.entries.extend((0..ENTRIES_PER_UFD_BLOCK).map(|_| DirEntry::default()));
+        self.write_ufd(u)?;
+        self.write_bitmap()?;
+        if self.ufds[u].block_list.len() == 1 {
+            self.write_mfd()?; // The MFD entry didn't point anywhere until now
+        }
+        Ok(new_dir_entry)
+    }
+
+    fn file_blocks(&self, entry: &DirEntry) -> anyhow::Result<Vec<usize>> {
+        if entry.contiguous {
+            return Ok((entry.first_block..entry.first_block + entry.length).collect());
+        }
+        Ok(XxdpFs::read_chain_raw(&self.image, entry.first_block as u16)?.into_iter().map(|(b, _)| b as usize).collect())
+    }
+
+    fn write_ufd(&mut self, u: usize) -> anyhow::Result<()> {
+        let mut buf = ByteBuffer::new();
+        buf.set_endian(Endian::LittleEndian);
+        for entries in self.ufds[u].entries.chunks(ENTRIES_PER_UFD_BLOCK) {
+            for e in entries.iter() {
+                buf.write_bytes(&e.repr()?);
+            }
+            buf.write_bytes(&vec![0; USABLE_BLOCK_SIZE - buf.len() % USABLE_BLOCK_SIZE]);
+        }
+        XxdpFs::write_block_chain(&mut self.image, &self.ufds[u].block_list, buf.as_bytes())
+    }
+
+    fn write_bitmap(&mut self) -> anyhow::Result<()> {
+        XxdpFs::write_bitmap_chain(&mut self.image, &self.bitmap, &self.bitmap_block_list)
+    }
+
+    fn write_mfd(&mut self) -> anyhow::Result<()> {
+        if !self.mfd.dectape {
+            let mut mfd1 = ByteBuffer::new();
+            mfd1.set_endian(Endian::LittleEndian);
+            mfd1.write_u16(self.mfd.block_list[0]);
+            mfd1.write_u16(self.mfd.interleave_factor);
+            mfd1.write_u16(self.mfd.bitmap_block);
+            for b in self.mfd.bitmap_pointer.iter() {
+                mfd1.write_u16(*b);
+            }
+            mfd1.write_bytes(&vec![0; BLOCK_SIZE - mfd1.len()]);
+            self.image.write_blocks(1, 1, mfd1.as_bytes())?;
+        }
+
+        let mut buf = ByteBuffer::new();
+        buf.set_endian(Endian::LittleEndian);
+        for ufds in self.ufds.chunks(MFD_ENTRIES_PER_BLOCK) {
+            for u in ufds {
+                buf.write_u16(u.uic.word());
+                buf.write_u16(u.block_list.first().copied().unwrap_or(0));
+                buf.write_u16(UFD_ENTRY_WORDS);
+                buf.write_u16(0);
+            }
+            buf.write_bytes(&vec![0; USABLE_BLOCK_SIZE - buf.len() % USABLE_BLOCK_SIZE]);
+        }
+        XxdpFs::write_block_chain(&mut self.image, &self.mfd.block_list, buf.as_bytes())
+    }
+}
+
+impl<B: BlockDevice> FileSystem for Dos11Fs<B> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        "DOS-11"
+    }
+
+    fn dir_iter<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        self.listing(path, true)
+    }
+
+    fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        self.listing(path, false)
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        let (u, e) = self.raw_stat(name)?;
+        Some(Box::new(Listing::new(self.ufds[u].uic, &self.ufds[u].entries[e])))
+    }
+
+    fn free_blocks(&self) -> usize {
+        self.bitmap.iter().filter(|b| !**b).count()
+    }
+
+    fn used_blocks(&self) -> usize {
+        self.image.blocks() - self.free_blocks()
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let Some((u, e)) = self.raw_stat(name) else {
+            return Err(anyhow!("File not found: {}", name));
+        };
+        let entry = &self.ufds[u].entries[e];
+        if entry.contiguous {
+            // Contiguous files use the whole block--there's no link word.
+            if entry.length == 0 { return Ok(ByteBuffer::new()) }
+            return self.image.read_blocks(entry.first_block, entry.length);
+        }
+        let mut contents = Vec::with_capacity(entry.length * USABLE_BLOCK_SIZE);
+        for (_, b) in XxdpFs::read_chain_raw(&self.image, entry.first_block as u16)? {
+            contents.extend_from_slice(&b.as_bytes()[2..]);
+        }
+        Ok(ByteBuffer::from_vec(contents))
+    }
+
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        if options.placement != Placement::default() || !options.prefix.is_empty() {
+            return Err(anyhow!("{}: DOS-11 filesystems don't support placement or prefix blocks", name));
+        }
+        let (uic, file) = Uic::split_path(name)?;
+        xxdp::DirEntry::encode_filename(file)?;
+        let old = self.raw_stat(name);
+        if old.is_some_and(|(u, e)| self.ufds[u].entries[e].protected()) { return Err(anyhow!("{} is protected", name)) }
+        let protection = old.map(|(u, e)| self.ufds[u].entries[e].protection).unwrap_or(DEFAULT_PROTECTION);
+        let (u, slot) = match old {
+            Some(old) => old,
+            None      => { let u = self.ufd_for(uic, true)?; (u, self.allocate_dir_entry(u)?) },
+        };
+        // The old file's blocks can be reused, but it mustn't go anywhere until we know the new one fits.
+        let mut bitmap = self.bitmap.clone();
+        if old.is_some() {
+            for b in self.file_blocks(&self.ufds[u].entries[slot])? {
+                bitmap[b] = false;
+            }
+        }
+        let block_size = if options.contiguous { BLOCK_SIZE } else { USABLE_BLOCK_SIZE };
+        let blocks = std::cmp::max(1, contents.len().div_ceil(block_size));
+        let block_list = if options.contiguous { xxdp::allocate_contiguous_blocks_in(&mut bitmap, blocks as u16)? }
+                         else                  { xxdp::allocate_blocks_in(&mut bitmap, blocks as u16)? };
+        self.bitmap = bitmap;
+
+        self.ufds[u].entries[slot] = DirEntry {
+            name: Some(file.to_owned()),
+            date: today(),
+            contiguous: options.contiguous,
+            first_block: block_list[0] as usize,
+            length: blocks,
+            last_block: *block_list.last().unwrap() as usize,
+            protection,
+            ..DirEntry::default()
+        };
+        self.write_ufd(u)?;
+        self.write_bitmap()?;
+
+        if options.contiguous {
+            let mut data = contents.to_vec();
+            data.resize(blocks * BLOCK_SIZE, 0);
+            self.image.write_blocks(block_list[0] as usize, blocks, &data)
+        } else {
+            XxdpFs::write_block_chain(&mut self.image, &block_list, contents)
+        }
+    }
+
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some((u, e)) = self.raw_stat(name) else {
+            return Err(anyhow!("File not found: {}", name));
+        };
+        if self.ufds[u].entries[e].protected() { return Err(anyhow!("{} is protected", name)) }
+        for b in self.file_blocks(&self.ufds[u].entries[e])? {
+            self.bitmap[b] = false;
+        }
+        self.ufds[u].entries[e] = DirEntry::default();
+        self.write_ufd(u)?;
+        self.write_bitmap()?;
+        Ok(())
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let (uic, file) = Uic::split_path(dest)?;
+        xxdp::DirEntry::encode_filename(file)?;
+        let (u, e) = self.raw_stat(src).unwrap(/*we already checked*/);
+        if self.ufds[u].entries[e].protected() { return Err(anyhow!("{} is protected", src)) }
+        if self.ufds[u].uic == uic {
+            self.ufds[u].entries[e].name = Some(file.to_owned());
+        } else {
+            // Moving to another UIC just moves the directory entry. The data stays put.
+            let d = self.ufd_for(uic, true)?;
+            let slot = self.allocate_dir_entry(d)?;
+            let entry = std::mem::take(&mut self.ufds[u].entries[e]);
+            self.ufds[d].entries[slot] = DirEntry { name: Some(file.to_owned()), ..entry };
+            self.write_ufd(d)?;
+        }
+        self.write_ufd(u)?;
+        self.write_bitmap()?; // Might have deleted something
+        Ok(())
+    }
+
+    fn set_protected(&mut self, name: &str, protected: bool) -> anyhow::Result<()> {
+        let Some((u, e)) = self.raw_stat(name) else {
+            return Err(anyhow!("File not found: {}", name));
+        };
+        let entry = &mut self.ufds[u].entries[e];
+        entry.protection = if protected { entry.protection | OWNER_WRITE_PROTECT } else { entry.protection & !OWNER_WRITE_PROTECT };
+        self.write_ufd(u)
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+impl<B: BlockDevice> Debug for Dos11Fs<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            write!(f, r#"DOS-11 FS
+Image: blocks={}, bytes={}
+MFD:
+{:#?}
+"#,
+                self.image.blocks(), self.image.blocks() * BLOCK_SIZE, &self.mfd)?;
+            for u in self.ufds.iter() {
+                writeln!(f, "UFD {} @ {:?}:", u.uic, u.block_list)?;
+                for e in u.entries.iter() {
+                    writeln!(f, "{:#?}", Listing::new(u.uic, e))?;
+                }
+            }
+            writeln!(f, "Bitmap:")?;
+            for (i, b) in self.bitmap.iter().enumerate() {
+                if i > 0 && (i % 64 == 0) { writeln!(f)? }
+                write!(f, "{}", if *b { "X" } else { "_" })?;
+            }
+            Ok(())
+        } else {
+            f.debug_struct("Dos11Fs")
+                .field("mfd",    &self.mfd    )
+                .field("ufds",   &self.ufds   )
+                .field("bitmap", &self.bitmap )
+                .finish()
+        }
+    }
+}
+
+// User Identification Code: [group,member], both in octal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Uic {
+    pub group: u8,
+    pub member: u8,
+}
+
+impl Uic {
+    pub fn from_word(word: u16) -> Uic {
+        Uic { group: (word >> 8) as u8, member: word as u8 }
+    }
+
+    pub fn word(&self) -> u16 {
+        (self.group as u16) << 8 | self.member as u16
+    }
+
+    // "[200,200]FOO.MAC" -> ([200,200], "FOO.MAC"). Names without a UIC are in [1,1].
+    pub fn split_path(path: &str) -> anyhow::Result<(Uic, &str)> {
+        let path = path.trim_start_matches('/');
+        let Some(rest) = path.strip_prefix('[') else { return Ok((SYSTEM_UIC, path)) };
+        let Some((uic, name)) = rest.split_once(']') else { return Err(anyhow!("Missing ] in {}", path)) };
+        let bad = || anyhow!("Bad UIC [{}] (should be [group,member], both octal from 1 to 377)", uic);
+        let Some((group, member)) = uic.split_once(',') else { return Err(bad()) };
+        let parse = |n: &str| u8::from_str_radix(n.trim(), 8).ok().filter(|n| *n != 0).ok_or_else(bad);
+        Ok((Uic { group: parse(group)?, member: parse(member)? }, name))
+    }
+}
+
+impl Display for Uic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{:o},{:o}]", self.group, self.member)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mfd {
+    pub interleave_factor: u16,
+    pub bitmap_block: u16,
+    pub bitmap_pointer: Vec<u16>,
+    pub block_list: Vec<u16>, // The MFD2 chain (the blocks with the UIC entries in them)
+    pub dectape: bool,        // No MFD1
+}
+
+#[derive(Clone, Debug)]
+pub struct Ufd {
+    pub uic: Uic,
+    pub entries: Vec<DirEntry>,
+    pub block_list: Vec<u16>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DirEntry {
+    name: Option<String>,
+    date: Option<NaiveDate>,
+    contiguous: bool,
+    next_free_byte: u16, // Unused, but kept intact
+    first_block: usize,
+    length: usize,
+    last_block: usize,
+    protection: u8,
+    usage: u8, // Lock/usage count
+}
+
+impl DirEntry {
+    // Protection codes are 3 octal digits: owner, group and everyone else. We only look at the owner's write
+    // protect bit.
+    fn protected(&self) -> bool {
+        self.protection & OWNER_WRITE_PROTECT != 0
+    }
+
+    pub fn from_repr(buf: &mut ByteBuffer) -> anyhow::Result<Option<DirEntry>> {
+        if buf.get_rpos() + size_of::<[u16; 9]>() > buf.len() { return Ok(None) }
+        buf.set_endian(Endian::LittleEndian);
+        let r50_name = [buf.read_u16()?, buf.read_u16()?, buf.read_u16()?];
+        let date = buf.read_u16()?;
+        let entry = DirEntry {
+            name: if r50_name == [0, 0, 0] {
+                None
+            } else {
+                let raw = radix50::pdp11::decode(&r50_name);
+                let (name, ext) = raw.split_at(6);
+                Some(format!("{}.{}", name.trim(), ext.trim()))
+            },
+            date: xxdp::DirEntry::decode_date(date & !CONTIGUOUS_FLAG)?,
+            contiguous: date & CONTIGUOUS_FLAG != 0,
+            next_free_byte: buf.read_u16()?,
+            first_block: buf.read_u16()?.into(),
+            length: buf.read_u16()?.into(),
+            last_block: buf.read_u16()?.into(),
+            protection: buf.read_u8()?,
+            usage: buf.read_u8()?,
+        };
+        Ok(Some(entry))
+    }
+
+    pub fn repr(&self) -> anyhow::Result<[u8; size_of::<[u16; 9]>()]> {
+        let mut repr = ByteBuffer::new();
+        repr.set_endian(Endian::LittleEndian);
+        for r50 in if let Some(ref name) = self.name { xxdp::DirEntry::encode_filename(name)? } else { [0,0,0] } {
+            repr.write_u16(r50);
+        }
+        let date = xxdp::DirEntry::encode_date(self.date)?;
+        if date & CONTIGUOUS_FLAG != 0 { return Err(anyhow!("Date {} is after 2002", self.date.unwrap())) }
+        repr.write_u16(date | if self.contiguous { CONTIGUOUS_FLAG } else { 0 });
+        repr.write_u16(self.next_free_byte);
+        repr.write_u16(self.first_block as u16);
+        repr.write_u16(self.length as u16);
+        repr.write_u16(self.last_block as u16);
+        repr.write_u8(self.protection);
+        repr.write_u8(self.usage);
+        Ok(repr.as_bytes().try_into()?)
+    }
+}
+
+// A directory entry along with the UIC it's in, which is what the rest of the world sees.
+struct Listing<'a> {
+    path: String,
+    entry: &'a DirEntry,
+}
+
+impl<'a> Listing<'a> {
+    fn new(uic: Uic, entry: &'a DirEntry) -> Listing<'a> {
+        Listing { path: format!("{}{}", uic, entry.name.as_deref().unwrap_or("")), entry }
+    }
+}
+
+impl Debug for Listing<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let e = self.entry;
+        let name = if e.name.is_some() { &self.path[..] } else { " --deleted--" };
+        if f.alternate() {
+            write!(f, "{:<10} {:5}{} <{:03o}> @ {:<5} -> {:<5} {}",
+                   e.date.map(|d| format!("{}", d)).unwrap_or(" No Date".to_string()),
+                   e.length, if e.contiguous { "C" } else { " " },
+                   e.protection,
+                   e.first_block,
+                   e.last_block,
+                   name)
+        } else {
+            write!(f, "{:10} {:6}{} <{:03o}> {}",
+                   e.date.map(|d| d.to_string()).unwrap_or(" No Date".to_string()),
+                   e.length, if e.contiguous { "C" } else { " " },
+                   e.protection,
+                   name)
+        }
+    }
+}
+
+impl super::DirEntry for Listing<'_> {
+    fn path(&self)       -> &str                             { &self.path }
+    fn file_name(&self)  -> &str                             { self.entry.name.as_deref().unwrap_or("") }
+    fn is_dir(&self)     -> bool                             { false }
+    fn is_file(&self)    -> bool                             { self.entry.name.is_some() }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { (self.entry.length * if self.entry.contiguous { BLOCK_SIZE } else { USABLE_BLOCK_SIZE }) as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { self.entry.date.map(super::Timestamp::Date).ok_or(anyhow!("Bad Date")) }
+    fn blocks(&self)     -> u64                              { self.entry.length as u64 }
+    fn readonly(&self)   -> bool                             { false }
+    fn protected(&self)  -> bool                             { self.entry.protected() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::test::*;
+    use crate::assert_block_eq;
+
+    use pretty_hex::PrettyHex;
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!((SYSTEM_UIC, "FOO.MAC"), Uic::split_path("FOO.MAC").unwrap());
+        assert_eq!((USER_UIC, "FOO.MAC"), Uic::split_path("[200,200]FOO.MAC").unwrap());
+        assert_eq!((Uic { group: 0o1, member: 0o377 }, ""), Uic::split_path("/[1, 377]").unwrap());
+        assert!(Uic::split_path("[200,200FOO.MAC").is_err());
+        assert!(Uic::split_path("[8,1]FOO.MAC").is_err());
+        assert!(Uic::split_path("[0,1]FOO.MAC").is_err());
+        assert!(Uic::split_path("[400,1]FOO.MAC").is_err());
+        assert_eq!("[200,200]", USER_UIC.to_string());
+    }
+
+    #[test]
+    fn test_mkfs() {
+        let dev = TestDev(vec![0;512*20]);
+        let fs = Dos11Fs::mkfs(dev).expect("Create DOS-11 FS");
+        assert_block_eq!(fs.image, 1,  // MFD1
+                         vec![0x02, 0x00, 0x01, 0x00, 0x03, 0x00, 0x03, 0x00, 0x00, 0x00],
+                         vec![0; 512-10]);
+        assert_block_eq!(fs.image, 2,  // MFD2
+                         vec![0x00, 0x00, 0x01, 0x01, 0x04, 0x00, 0x09, 0x00, 0x00, 0x00,
+                                                  0x80, 0x80, 0x05, 0x00, 0x09, 0x00, 0x00, 0x00],
+                         vec![0; 512-18]);
+        assert_block_eq!(fs.image, 3,  // Bitmap
+                         vec![0x00, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x03, 0x00, 0x3F, 0x00],
+                         vec![0; 512-10]);
+        let fs = Dos11Fs::new(TestDev(fs.image.0.clone())).expect("Reopen DOS-11 FS");
+        assert_eq!(vec![SYSTEM_UIC, USER_UIC], fs.ufds.iter().map(|u| u.uic).collect::<Vec<_>>());
+        assert!(Dos11Fs::image_is(&fs.image));
+        assert!(XxdpFs::image_is(&fs.image)); // XXDP only sees [1,1]
+    }
+
+    #[test]
+    fn test_mkfs_dectape() {
+        let dev = TestDev(vec![0;512*DECTAPE_BLOCKS]);
+        let fs = Dos11Fs::mkfs(dev).expect("Create DOS-11 FS");
+        assert_block_eq!(fs.image, 1, vec![0; 512]);
+        assert_block_eq!(fs.image, 0o100,  // MFD
+                         vec![0x00, 0x00, 0x01, 0x01, 0o102, 0x00, 0x09, 0x00, 0x00, 0x00,
+                                                  0x80, 0x80, 0o103, 0x00, 0x09, 0x00, 0x00, 0x00],
+                         vec![0; 512-18]);
+        let fs = Dos11Fs::new(TestDev(fs.image.0.clone())).expect("Reopen DOS-11 FS");
+        assert_eq!(vec![0o104], fs.bitmap_block_list);
+        assert!(Dos11Fs::image_is(&fs.image));
+        assert!(!XxdpFs::image_is(&fs.image));
+    }
+
+    #[test]
+    fn test_write() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = Dos11Fs::mkfs(dev).expect("Create DOS-11 FS");
+        fs.write_file("[200,200]LINKED.MAC", &incrementing(1000)).expect("write_file failed");
+        fs.write_file_with_options("CONTIG.SAV", &incrementing(1000), &CreateOptions { contiguous: true, ..CreateOptions::default() })
+            .expect("write_file failed");
+
+        let fs = Dos11Fs::new(TestDev(fs.image.0.clone())).expect("Reopen DOS-11 FS");
+        assert_eq!(incrementing(1000), fs.read_file("[200,200]LINKED.MAC").unwrap().as_bytes()[..1000]);
+        assert_eq!(incrementing(1000), fs.read_file("[1,1]CONTIG.SAV").unwrap().as_bytes()[..1000]);
+        assert!(fs.read_file("LINKED.MAC").is_err());
+
+        let linked = &fs.ufds[1].entries[0];
+        assert_eq!((Some("LINKED.MAC"), false, 2, 0o233), (linked.name.as_deref(), linked.contiguous, linked.length, linked.protection));
+        let contig = &fs.ufds[0].entries[0];
+        assert_eq!((Some("CONTIG.SAV"), true, 2, 0o233), (contig.name.as_deref(), contig.contiguous, contig.length, contig.protection));
+        assert_eq!(contig.first_block + 1, contig.last_block);
+        assert_block_eq!(fs.image, contig.first_block, incrementing(512)); // No link word
+        assert_eq!(None, contig.date); // 2023 doesn't fit
+
+        assert_eq!(vec!["[1,1]CONTIG.SAV", "[200,200]LINKED.MAC"], fs.read_dir("/").unwrap().map(|e| e.path().to_owned()).collect::<Vec<_>>());
+        assert_eq!(vec!["LINKED.MAC"], fs.read_dir("[200,200]").unwrap().map(|e| e.file_name().to_owned()).collect::<Vec<_>>());
+        assert!(fs.read_dir("[300,300]").is_err());
+    }
+
+    #[test]
+    fn test_new_uic() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = Dos11Fs::mkfs(dev).expect("Create DOS-11 FS");
+        fs.write_file("[30,4]TEST.TXT", &incrementing(10)).expect("write_file failed");
+        let ufd_block = fs.ufds[2].block_list[0];
+        assert_block_eq!(fs.image, 2,  // MFD2
+                         vec![0x00, 0x00, 0x01, 0x01, 0x04, 0x00, 0x09, 0x00, 0x00, 0x00,
+                                                  0x80, 0x80, 0x05, 0x00, 0x09, 0x00, 0x00, 0x00,
+                                                  0x04, 0x18, ufd_block as u8, 0x00, 0x09, 0x00, 0x00, 0x00],
+                         vec![0; 512-26]);
+        let fs = Dos11Fs::new(TestDev(fs.image.0.clone())).expect("Reopen DOS-11 FS");
+        assert_eq!(incrementing(10), fs.read_file("[30,4]TEST.TXT").unwrap().as_bytes()[..10]);
+    }
+
+    #[test]
+    fn test_delete_and_rename() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = Dos11Fs::mkfs(dev).expect("Create DOS-11 FS");
+        let free = fs.free_blocks();
+        fs.write_file_with_options("A.SAV", &incrementing(1024), &CreateOptions { contiguous: true, ..CreateOptions::default() })
+            .expect("write_file failed");
+        fs.write_file("B.TXT", &incrementing(1024)).expect("write_file failed");
+        assert_eq!(free - 5, fs.free_blocks());
+        // Overwriting with something that won't fit even in the old file's blocks leaves the old file alone
+        assert!(fs.write_file("B.TXT", &incrementing(510 * (free - 5 + 3 + 1))).is_err());
+        assert_eq!(incrementing(1024), fs.read_file("B.TXT").unwrap().as_bytes()[..1024]);
+        assert_eq!(free - 5, fs.free_blocks());
+
+        fs.rename("B.TXT", "[200,200]C.TXT").expect("rename failed");
+        assert!(fs.stat("B.TXT").is_none());
+        assert_eq!(incrementing(1024), fs.read_file("[200,200]C.TXT").unwrap().as_bytes()[..1024]);
+
+        fs.delete("A.SAV").expect("delete failed");
+        fs.delete("[200,200]C.TXT").expect("delete failed");
+        assert_eq!(free, fs.free_blocks());
+        let fs = Dos11Fs::new(TestDev(fs.image.0.clone())).expect("Reopen DOS-11 FS");
+        assert_eq!(0, fs.read_dir("/").unwrap().count());
+    }
+
+    #[test]
+    fn test_protection() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = Dos11Fs::mkfs(dev).expect("Create DOS-11 FS");
+        fs.write_file("A.TXT", &incrementing(10)).expect("write_file failed");
+        assert!(!fs.stat("A.TXT").unwrap().protected());
+        fs.set_protected("A.TXT", true).expect("set_protected failed");
+        assert_eq!(0o333, fs.ufds[0].entries[0].protection);
+        assert!(fs.stat("A.TXT").unwrap().protected());
+        assert!(fs.rename("A.TXT", "B.TXT").is_err());
+        assert!(fs.delete("A.TXT").is_err());
+        assert!(fs.write_file("A.TXT", &incrementing(20)).is_err());
+        fs.ufds[0].entries[0].protection = 0o204;
+        fs.write_file("A.TXT", &incrementing(20)).expect("write_file failed"); // Overwriting keeps the protection code
+        assert_eq!(0o204, fs.ufds[0].entries[0].protection);
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+use bytebuffer::ByteBuffer;
+use chrono::{Datelike, NaiveDate};
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, Geometry};
+use crate::block::rx::RX01_GEOMETRY;
+use super::{CreateOptions, FileSystem, Placement};
+
+// IBM 3740 "basic data exchange" diskettes. See IBM's "The IBM Diskette for Standard Data Interchange" (GA21-9182).
+//
+// Track 0 is the index track: sector 7 holds the VOL1 volume label and sectors 8-26 hold a HDR1 label for each data
+// set (a 'D' in place of the 'H' means it was deleted). Data sets are contiguous runs of sectors on tracks 1-73 (74-76
+// are spares), one fixed length record of up to 128 bytes per sector. Labels and data are EBCDIC.
+//
+// This is why RT-11 leaves track 0 alone, and it's all addressed by physical track and sector, so it goes straight to
+// the physical device and skips the RX interleave. Data sets are read as text: each record is translated to ASCII,
+// trimmed and given a newline. Writing goes the other way.
+
+const SECTORS: usize = 26;
+const RECORD_SIZE: usize = 128;
+const LABEL_SIZE: usize = 80;
+const VOL1_SECTOR: usize = 7;
+const HDR1_SECTORS: std::ops::RangeInclusive<usize> = 8..=26;
+const FIRST_DATA_TRACK: usize = 1;
+const DATA_TRACKS: usize = 73;
+const DATA_RECORDS: usize = DATA_TRACKS * SECTORS;
+const DEFAULT_RECORD_LENGTH: usize = 80;
+const MAX_NAME: usize = 8; // The label has room for 17, but the 3740 only uses 8.
+
+#[derive(Clone)]
+pub struct Ibm3740Fs<B: BlockDevice> {
+    pub image: B,
+    pub volume_id: String,
+    pub owner: String,
+    pub datasets: Vec<DataSet>,
+}
+
+#[derive(Clone)]
+pub struct DataSet {
+    pub slot: usize,          // Which track 0 sector the label is in
+    pub name: String,
+    pub record_length: usize,
+    pub extent: std::ops::Range<usize>, // Records, counting from the start of track 1
+    pub end_of_data: usize,   // The first unused record
+    pub write_protected: bool,
+    pub created: Option<NaiveDate>,
+    label: Vec<u8>,           // The rest of the fields, kept as they were
+}
+
+impl<B: BlockDevice> Ibm3740Fs<B> {
+    pub fn new(image: B) -> anyhow::Result<Ibm3740Fs<B>> {
+        Self::check_geometry(image.physical_device().geometry())?;
+        let vol1 = to_ascii(&read_physical(&image, 0, VOL1_SECTOR)?[0..LABEL_SIZE]);
+        if !vol1.starts_with("VOL1") { return Err(anyhow!("No VOL1 label")) }
+        let mut datasets = vec![];
+        for slot in HDR1_SECTORS {
+            let label = read_physical(&image, 0, slot)?[0..LABEL_SIZE].to_vec();
+            if to_ascii(&label[0..4]) != "HDR1" { continue }
+            datasets.push(DataSet::from_label(slot, label)?);
+        }
+        Ok(Ibm3740Fs { image, volume_id: vol1[4..10].trim_end().to_string(), owner: vol1[37..51].trim_end().to_string(), datasets })
+    }
+
+    pub fn image_is(image: &B) -> bool {
+        Self::check_geometry(image.physical_device().geometry()).is_ok()
+            && read_physical(image, 0, VOL1_SECTOR).is_ok_and(|s| to_ascii(&s[0..4]) == "VOL1")
+    }
+
+    fn check_geometry(g: &Geometry) -> anyhow::Result<()> {
+        if (g.cylinders, g.heads, g.sectors, g.sector_size) != (RX01_GEOMETRY.cylinders, RX01_GEOMETRY.heads, RX01_GEOMETRY.sectors, RX01_GEOMETRY.sector_size) {
+            return Err(anyhow!("IBM 3740 diskettes are single density, single sided (RX01)"));
+        }
+        Ok(())
+    }
+
+    pub fn mkfs(mut image: B) -> anyhow::Result<Ibm3740Fs<B>> {
+        Self::check_geometry(image.physical_device().geometry())?;
+        let vol1 = format!("{:<79}W", "VOL1PDPFS"); // Volume "PDPFS", label version W
+        write_physical(&mut image, 0, VOL1_SECTOR, &to_ebcdic(&vol1)?)?;
+        for slot in HDR1_SECTORS {
+            write_physical(&mut image, 0, slot, &to_ebcdic(&format!("{:<80}", "DDR1"))?)?;
+        }
+        Self::new(image)
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        let name = name.trim_start_matches('/');
+        self.datasets.iter().position(|d| d.name == name)
+    }
+
+    fn write_label(&mut self, d: usize) -> anyhow::Result<()> {
+        let (slot, label) = (self.datasets[d].slot, self.datasets[d].to_label()?);
+        write_physical(&mut self.image, 0, slot, &label)
+    }
+
+    // First fit, in the gaps between the other data sets.
+    fn allocate(&self, records: usize) -> Option<usize> {
+        let mut used: Vec<_> = self.datasets.iter().map(|d| d.extent.clone()).collect();
+        used.sort_by_key(|e| e.start);
+        let mut start = 0;
+        for extent in used.iter().chain([&(DATA_RECORDS..DATA_RECORDS)]) {
+            if extent.start >= start + records { return Some(start) }
+            start = std::cmp::max(start, extent.end);
+        }
+        None
+    }
+}
+
+fn read_physical<B: BlockDevice>(image: &B, track: usize, sector: usize) -> anyhow::Result<Vec<u8>> {
+    image.physical_device().read_sector(track, 0, sector - 1)
+}
+
+fn write_physical<B: BlockDevice>(image: &mut B, track: usize, sector: usize, data: &[u8]) -> anyhow::Result<()> {
+    let mut data = data.to_vec();
+    data.resize(RECORD_SIZE, EBCDIC_SPACE);
+    image.physical_device_mut().write_sector(track, 0, sector - 1, &data)
+}
+
+// Record numbers to track and (1 based) sector, and back. Labels write them as TT0SS.
+fn address(record: usize) -> (usize, usize) {
+    (FIRST_DATA_TRACK + record / SECTORS, record % SECTORS + 1)
+}
+
+fn format_address(record: usize) -> String {
+    let (track, sector) = address(record);
+    format!("{:02}0{:02}", track, sector)
+}
+
+fn parse_address(field: &str) -> anyhow::Result<usize> {
+    let bad = || anyhow!("Bad address {:?}", field);
+    let track: usize = field.get(0..2).and_then(|t| t.parse().ok()).ok_or_else(bad)?;
+    let sector: usize = field.get(3..5).and_then(|t| t.parse().ok()).ok_or_else(bad)?;
+    if !(FIRST_DATA_TRACK..=FIRST_DATA_TRACK + DATA_TRACKS).contains(&track) || !(1..=SECTORS).contains(&sector) { return Err(bad()) }
+    Ok((track - FIRST_DATA_TRACK) * SECTORS + sector - 1)
+}
+
+impl DataSet {
+    fn from_label(slot: usize, label: Vec<u8>) -> anyhow::Result<DataSet> {
+        let text = to_ascii(&label);
+        let record_length = text[22..27].trim().parse().unwrap_or(DEFAULT_RECORD_LENGTH);
+        if record_length == 0 || record_length > RECORD_SIZE { return Err(anyhow!("Bad record length {} in label {}", record_length, slot)) }
+        let begin = parse_address(&text[28..33])?;
+        let end = parse_address(&text[34..39])?;
+        let end_of_data = parse_address(&text[74..79]).unwrap_or(begin);
+        if end < begin || end >= DATA_RECORDS || !(begin..=end + 1).contains(&end_of_data) { return Err(anyhow!("Bad extent in label {}: {}", slot, &text[28..39])) }
+        Ok(DataSet {
+            slot,
+            name: text[5..22].trim_end().to_string(),
+            record_length,
+            extent: begin..end + 1,
+            end_of_data,
+            write_protected: &text[42..43] == "P",
+            created: match (text[47..49].parse::<i32>(), text[49..51].parse(), text[51..53].parse()) {
+                (Ok(y), Ok(m), Ok(d)) => NaiveDate::from_ymd_opt(if y < 70 { 2000 + y } else { 1900 + y }, m, d),
+                _ => None,
+            },
+            label,
+        })
+    }
+
+    fn new(slot: usize, name: &str, record_length: usize, extent: std::ops::Range<usize>) -> DataSet {
+        DataSet { slot, name: name.to_string(), record_length, end_of_data: extent.start, extent, write_protected: false,
+                  created: Some(Local::now().naive_local().date()), label: to_ebcdic(&format!("{:<80}", "HDR1")).expect("can't happen") }
+    }
+
+    fn to_label(&self) -> anyhow::Result<Vec<u8>> {
+        let mut text = to_ascii(&self.label);
+        let mut set = |range: std::ops::Range<usize>, value: &str| text.replace_range(range.clone(), &format!("{:<1$}", value, range.len()));
+        set(5..22, &self.name);
+        set(22..27, &format!("{:05}", self.record_length));
+        set(28..33, &format_address(self.extent.start));
+        set(34..39, &format_address(self.extent.end - 1));
+        set(42..43, if self.write_protected { "P" } else { " " });
+        set(47..53, &self.created.map(|d| format!("{:02}{:02}{:02}", d.year() % 100, d.month(), d.day())).unwrap_or_default());
+        set(74..79, &format_address(self.end_of_data));
+        to_ebcdic(&text)
+    }
+
+    fn records(&self) -> usize {
+        self.end_of_data - self.extent.start
+    }
+}
+
+impl<B: BlockDevice> FileSystem for Ibm3740Fs<B> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        "IBM 3740"
+    }
+
+    fn dir_iter<'a>(&'a self, _path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.datasets.iter().map(|d| -> Box<dyn super::DirEntry> { Box::new(d) })))
+    }
+
+    fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        self.dir_iter(path)
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        Some(Box::new(&self.datasets[self.find(name)?]))
+    }
+
+    // In 512 byte blocks, like everywhere else, though a record is a sector (128 bytes). Each data set's extent is
+    // rounded up so even a one record data set shows as used.
+    fn free_blocks(&self) -> usize {
+        (DATA_RECORDS * RECORD_SIZE / crate::block::BLOCK_SIZE).saturating_sub(self.used_blocks())
+    }
+
+    fn used_blocks(&self) -> usize {
+        self.datasets.iter().map(|d| (d.extent.len() * RECORD_SIZE).div_ceil(crate::block::BLOCK_SIZE)).sum()
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let Some(d) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        let d = &self.datasets[d];
+        let mut text = String::new();
+        for record in d.extent.start..d.end_of_data {
+            let (track, sector) = address(record);
+            let data = read_physical(&self.image, track, sector)?;
+            text += to_ascii(&data[0..d.record_length]).trim_end_matches([' ', '\0']);
+            text.push('\n');
+        }
+        Ok(ByteBuffer::from_vec(text.into_bytes()))
+    }
+
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    // Data sets are always contiguous, so `contiguous` doesn't change anything.
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        if options.placement != Placement::default() || !options.prefix.is_empty() {
+            return Err(anyhow!("{}: IBM 3740 filesystems don't support placement or prefix blocks", name));
+        }
+        let name = name.trim_start_matches('/');
+        check_name(name)?;
+        let text = String::from_utf8_lossy(contents);
+        let records = text.lines().map(|l| to_ebcdic(l.trim_end_matches('\r'))).collect::<anyhow::Result<Vec<_>>>()?;
+        let record_length = std::cmp::max(DEFAULT_RECORD_LENGTH, records.iter().map(|r| r.len()).max().unwrap_or(0));
+        if record_length > RECORD_SIZE { return Err(anyhow!("{}: Lines can't be longer than {} characters", name, RECORD_SIZE)) }
+
+        let old = self.find(name);
+        if old.is_some_and(|d| self.datasets[d].write_protected) { return Err(anyhow!("{} is protected", name)) }
+        let old = old.map(|d| self.datasets.remove(d));
+        let Some(slot) = old.as_ref().map(|d| d.slot).or_else(|| HDR1_SECTORS.into_iter().find(|s| self.datasets.iter().all(|d| d.slot != *s))) else {
+            return Err(anyhow!("No room for {} (all {} labels are in use)", name, HDR1_SECTORS.count()));
+        };
+        let Some(start) = self.allocate(std::cmp::max(records.len(), 1)) else {
+            self.datasets.extend(old);
+            return Err(anyhow!("No room for {} ({} records)", name, records.len()));
+        };
+        for (i, record) in records.iter().enumerate() {
+            let (track, sector) = address(start + i);
+            write_physical(&mut self.image, track, sector, record)?;
+        }
+        let mut d = DataSet::new(slot, name, record_length, start..start + std::cmp::max(records.len(), 1));
+        d.end_of_data = start + records.len();
+        self.datasets.push(d);
+        self.datasets.sort_by_key(|d| d.slot);
+        let d = self.find(name).expect("can't happen");
+        self.write_label(d)
+    }
+
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(d) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        if self.datasets[d].write_protected { return Err(anyhow!("{} is protected", name)) }
+        let d = self.datasets.remove(d);
+        let mut label = d.to_label()?;
+        label[0] = to_ebcdic("D")?[0];
+        write_physical(&mut self.image, 0, d.slot, &label)
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let Some(d) = self.find(src) else { return Err(anyhow!("File not found: {}", src)) };
+        let dest = dest.trim_start_matches('/');
+        check_name(dest)?;
+        self.datasets[d].name = dest.to_string();
+        self.write_label(d)
+    }
+
+    fn set_protected(&mut self, name: &str, protected: bool) -> anyhow::Result<()> {
+        let Some(d) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.datasets[d].write_protected = protected;
+        self.write_label(d)
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME || !name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || "$#@.".contains(c)) {
+        return Err(anyhow!("Bad IBM 3740 data set name {:?} (should be up to {} letters and digits)", name, MAX_NAME));
+    }
+    Ok(())
+}
+
+impl<B: BlockDevice> Debug for Ibm3740Fs<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "IBM 3740 volume {:?}, owner {:?}", self.volume_id, self.owner)?;
+        for d in self.datasets.iter() {
+            writeln!(f, "{:#?}", d)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for DataSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = self.created.map(|d| d.to_string()).unwrap_or(" No Date".to_string());
+        if f.alternate() {
+            write!(f, "{:<10} {:5}/{:<5} {:3}{} @ {}-{} (sector {:2}) {}", date, self.records(), self.extent.len(),
+                   self.record_length, if self.write_protected { "P" } else { " " },
+                   format_address(self.extent.start), format_address(self.extent.end - 1), self.slot, self.name)
+        } else {
+            write!(f, "{:10} {:6} {}", date, self.records(), self.name)
+        }
+    }
+}
+
+impl super::DirEntry for &DataSet {
+    fn path(&self)       -> &str                             { &self.name }
+    fn file_name(&self)  -> &str                             { &self.name }
+    fn is_dir(&self)     -> bool                             { false }
+    fn is_file(&self)    -> bool                             { true }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { (self.records() * self.record_length) as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { self.created.map(super::Timestamp::Date).ok_or(anyhow!("Bad Date")) }
+    fn blocks(&self)     -> u64                              { (self.records() * RECORD_SIZE).div_ceil(crate::block::BLOCK_SIZE) as u64 }
+    fn readonly(&self)   -> bool                             { false }
+    fn protected(&self)  -> bool                             { self.write_protected }
+}
+
+// EBCDIC (code page 037) for the characters ASCII has. The rest come out as '?'.
+const EBCDIC_SPACE: u8 = 0x40;
+const ASCII_TO_EBCDIC: [u8; 128] = [
+    0x00, 0x01, 0x02, 0x03, 0x37, 0x2d, 0x2e, 0x2f, 0x16, 0x05, 0x25, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x3c, 0x3d, 0x32, 0x26, 0x18, 0x19, 0x3f, 0x27, 0x1c, 0x1d, 0x1e, 0x1f,
+    0x40, 0x5a, 0x7f, 0x7b, 0x5b, 0x6c, 0x50, 0x7d, 0x4d, 0x5d, 0x5c, 0x4e, 0x6b, 0x60, 0x4b, 0x61,
+    0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0x7a, 0x5e, 0x4c, 0x7e, 0x6e, 0x6f,
+    0x7c, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xd1, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6,
+    0xd7, 0xd8, 0xd9, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xba, 0xe0, 0xbb, 0xb0, 0x6d,
+    0x79, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96,
+    0x97, 0x98, 0x99, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xc0, 0x4f, 0xd0, 0xa1, 0x07,
+];
+
+pub fn to_ebcdic(text: &str) -> anyhow::Result<Vec<u8>> {
+    text.chars().map(|c| match c {
+        c if c.is_ascii() => Ok(ASCII_TO_EBCDIC[c as usize]),
+        c => Err(anyhow!("'{}' has no EBCDIC equivalent", c)),
+    }).collect()
+}
+
+pub fn to_ascii(ebcdic: &[u8]) -> String {
+    ebcdic.iter().map(|e| ASCII_TO_EBCDIC.iter().position(|a| a == e).map(|a| a as u8 as char).unwrap_or('?')).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::PhysicalBlockDevice;
+    use crate::block::img::IMG;
+    use crate::block::rx::RX;
+
+    fn rx01() -> RX<IMG> {
+        RX(IMG::from_raw(vec![0; RX01_GEOMETRY.bytes()], RX01_GEOMETRY))
+    }
+
+    #[test]
+    fn test_ebcdic() {
+        let ascii: String = (0x20..0x7f).map(|c| c as u8 as char).collect();
+        assert_eq!(ascii, to_ascii(&to_ebcdic(&ascii).unwrap()));
+        assert_eq!(vec![0xe5, 0xd6, 0xd3, 0xf1], to_ebcdic("VOL1").unwrap());
+        assert!(to_ebcdic("£").is_err());
+        assert_eq!("?", to_ascii(&[0x4a])); // ¢
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut fs = Ibm3740Fs::mkfs(rx01()).expect("Create IBM 3740 FS");
+        let text = "HELLO, WORLD\r\n\nA LONGER LINE\n".to_string() + &"X".repeat(100) + "\n";
+        fs.write_file("DATA", text.as_bytes()).expect("write_file failed");
+        fs.write_file("MORE", b"MORE DATA\n").expect("write_file failed");
+        assert!(fs.write_file("LONG", "Y".repeat(129).as_bytes()).is_err());
+        assert!(fs.write_file("TOOLONGNAME", b"").is_err());
+
+        let raw = fs.image.0.as_vec().unwrap();
+        assert_eq!(to_ebcdic("VOL1PDPFS").unwrap(), raw[6 * 128..6 * 128 + 9]);
+        assert_eq!("HDR1 DATA             00100 01001 01004", to_ascii(&raw[7 * 128..7 * 128 + 39]));
+        assert_eq!("01005", to_ascii(&raw[7 * 128 + 74..7 * 128 + 79]));
+        assert_eq!(to_ebcdic("HELLO, WORLD").unwrap(), raw[26 * 128..26 * 128 + 12]);
+
+        let mut fs = Ibm3740Fs::new(RX(IMG::from_raw(raw, RX01_GEOMETRY))).expect("Reopen IBM 3740 FS");
+        assert!(Ibm3740Fs::image_is(&fs.image));
+        assert!(!crate::fs::rt11::RT11FS::image_is(&fs.image));
+        assert_eq!(("PDPFS", 2), (fs.volume_id.as_str(), fs.datasets.len()));
+        assert_eq!(text.replace('\r', ""), String::from_utf8(fs.read_file("DATA").unwrap().into_vec()).unwrap());
+        assert_eq!(4..5, fs.datasets[1].extent);
+        assert_eq!(NaiveDate::from_ymd_opt(2023, 1, 19), fs.datasets[0].created);
+
+        fs.set_protected("DATA", true).expect("set_protected failed");
+        assert!(fs.delete("DATA").is_err());
+        fs.set_protected("DATA", false).expect("set_protected failed");
+        fs.delete("DATA").expect("delete failed");
+        fs.rename("MORE", "LESS").expect("rename failed");
+        fs.write_file("NEW", b"1\n2\n").expect("write_file failed"); // Goes where DATA was
+        let fs = Ibm3740Fs::new(fs.image).expect("Reopen IBM 3740 FS");
+        assert_eq!(vec![("NEW", 8, 0..2), ("LESS", 9, 4..5)],
+                   fs.datasets.iter().map(|d| (d.name.as_str(), d.slot, d.extent.clone())).collect::<Vec<_>>());
+        assert_eq!("1\n2\n", String::from_utf8(fs.read_file("NEW").unwrap().into_vec()).unwrap());
+        assert_eq!(2, fs.used_blocks()); // 2 records and 1 record, each rounded up to a block
+        assert_eq!(DATA_RECORDS / 4 - 2, fs.free_blocks());
+    }
+}
@@ -0,0 +1,1311 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::{fmt::Debug, ops::Range};
+
+use anyhow::{anyhow, Context};
+use bytebuffer::{Endian, ByteBuffer};
+use chrono::{NaiveDate, NaiveDateTime, Datelike, Timelike};
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use super::{CreateOptions, FileSystem, Placement};
+use super::dos11::Uic;
+
+// Files-11 On-Disk Structure Level 1 (RSX-11M, RSX-11M-PLUS, IAS and P/OS).
+// Files-11 On-Disk Structure Specification (ODS-1): http://bitsavers.org/pdf/dec/pdp11/rsx11/Files-11_ODS-1_Spec_Jun75.pdf
+//
+// Everything is a file, including the directories and the structures that keep track of the volume:
+//   INDEXF.SYS holds the boot block, the home block, the index file bitmap and then every file's header (which
+//              has the file's name, owner, protection and retrieval pointers--the list of blocks the file is in).
+//   BITMAP.SYS is the storage bitmap (a set bit means the block is *free*).
+//   000000.DIR is the MFD, which lists the UFDs (named after their UIC, like 001001.DIR for [1,1]).
+// File data is copied as-is: records aren't converted to or from host text files.
+
+const HOME_BLOCK: usize = 1;
+const STRUCTURE_LEVEL: u16 = 0o401;
+const FORMAT_TYPE: &[u8; 12] = b"DECFILE11A  ";
+
+const INDEX_FILE:  u16 = 1;
+const BITMAP_FILE: u16 = 2;
+const BADBLK_FILE: u16 = 3;
+const MFD_FILE:    u16 = 4;
+const CORIMG_FILE: u16 = 5;
+const RESERVED_FILES: u16 = CORIMG_FILE;
+const CONTIGUOUS_HEADERS: u16 = 16; // Headers for the first 16 files come right after the index file bitmap
+
+const IDENT_OFFSET: u8 = 0o27; // Words
+const MAP_OFFSET: u8 = 0o56;   // Words
+const POINTERS_PER_HEADER: usize = 102; // (0o314 map words available) / (2 words per pointer)
+const MAX_EXTENT: usize = 256;          // The count field is 1 byte (and holds count-1)
+const DIR_RECORD_SIZE: usize = 16;
+const BITS_PER_BITMAP_BLOCK: usize = BLOCK_SIZE * 8;
+
+const UC_CONTIGUOUS: u16 = 0o200; // User characteristics (low byte of H.FCHA)
+// Protection is 4 bits each (deny read, write, extend, delete) for system, owner, group and world, from the bottom up.
+const OWNER_DENY_WRITE: u16 = 0o000040;
+const OWNER_DENY_DELETE: u16 = 0o000200;
+const DEFAULT_PROTECTION: u16 = 0o160000; // [RWED,RWED,RWED,R]
+
+pub const MFD_UIC: Uic = Uic { group: 0, member: 0 };
+const DEFAULT_UIC: Uic = Uic { group: 1, member: 1 };
+
+#[derive(Clone)]
+pub struct Ods1Fs<B: BlockDevice> {
+    pub image: B,
+    pub home: HomeBlock,
+    pub index_map: Vec<bool>, // true = file number in use
+    pub bitmap: Vec<bool>,    // true = block in use (the opposite of what's on disk)
+    bitmap_lbns: Vec<usize>,  // BITMAP.SYS, starting with the storage control block
+}
+
+impl<B: BlockDevice> Ods1Fs<B> {
+    pub fn new(image: B) -> anyhow::Result<Ods1Fs<B>> {
+        let (home, index_map, bitmap, bitmap_lbns) = Self::try_new(&image)?;
+        Ok(Ods1Fs {
+            image,
+            home,
+            index_map,
+            bitmap,
+            bitmap_lbns,
+        })
+    }
+
+    pub fn image_is(image: &B) -> bool {
+        Self::try_new(image).is_ok()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn try_new(image: &B) -> anyhow::Result<(HomeBlock, Vec<bool>, Vec<bool>, Vec<usize>)> {
+        let home = HomeBlock::from_repr(&mut image.read_blocks(HOME_BLOCK, 1)?)?;
+        if home.cluster_factor != 1 { return Err(anyhow!("Cluster factor {} isn't supported", home.cluster_factor)) }
+        let mut index_map = bits(image.read_blocks(home.index_bitmap_lbn as usize, home.index_bitmap_blocks as usize)?.as_bytes());
+        index_map.truncate(home.max_files as usize);
+
+        // BITMAP.SYS is the storage control block followed by the bitmap itself.
+        let header = Self::read_header_in(image, &home, BITMAP_FILE)?;
+        let bitmap_lbns = Self::extents_in(image, &home, &header)?.into_iter().flatten().collect::<Vec<usize>>();
+        let Some((_scb, bitmap_blocks)) = bitmap_lbns.split_first() else { return Err(anyhow!("BITMAP.SYS is empty")) };
+        let mut bitmap = vec![];
+        for lbn in bitmap_blocks {
+            bitmap.extend(bits(image.read_blocks(*lbn, 1)?.as_bytes()).into_iter().map(|free| !free));
+        }
+        if bitmap.len() < image.blocks() { return Err(anyhow!("Storage bitmap is too short {} < {}", bitmap.len(), image.blocks())) }
+        bitmap.truncate(image.blocks());
+        Ok((home, index_map, bitmap, bitmap_lbns))
+    }
+
+    pub fn mkfs(image: B) -> anyhow::Result<Ods1Fs<B>> {
+        let blocks = image.blocks();
+        let max_files = std::cmp::min(blocks / 16, u16::MAX as usize).max(CONTIGUOUS_HEADERS as usize) as u16;
+        let index_bitmap_blocks = (max_files as usize).div_ceil(BITS_PER_BITMAP_BLOCK);
+        let index_bitmap_lbn = 2;
+        let index_blocks = index_bitmap_lbn + index_bitmap_blocks + CONTIGUOUS_HEADERS as usize; // Boot and home blocks, bitmap, headers
+        let bitmap_blocks = blocks.div_ceil(BITS_PER_BITMAP_BLOCK);
+        let bitmap_lbn = index_blocks;
+        let mfd_lbn = bitmap_lbn + 1 + bitmap_blocks;
+        let now = Local::now().naive_local();
+
+        let home = HomeBlock {
+            index_bitmap_blocks: index_bitmap_blocks as u16,
+            index_bitmap_lbn: index_bitmap_lbn as u32,
+            max_files,
+            cluster_factor: 1,
+            device_type: 0,
+            structure_level: STRUCTURE_LEVEL,
+            volume_name: "PDPFS".to_string(),
+            owner: DEFAULT_UIC,
+            protection: 0,
+            characteristics: 0,
+            default_file_protection: DEFAULT_PROTECTION,
+            window_size: 7,
+            extend: 5,
+            lru_limit: 3,
+            revision_count: 1,
+            created: Some(now),
+        };
+        let mut fs = Ods1Fs {
+            image,
+            home,
+            index_map: vec![false; max_files as usize],
+            bitmap: vec![false; blocks],
+            bitmap_lbns: (bitmap_lbn..mfd_lbn).collect(),
+        };
+        for b in 0..=mfd_lbn {
+            fs.bitmap[b] = true;
+        }
+        fs.image.write_blocks(HOME_BLOCK, 1, &fs.home.repr())?;
+
+        // The reserved files all have their sequence number the same as their file number.
+        let reserved = [(INDEX_FILE,  "INDEXF.SYS", Some(Extent { lbn: 0, count: index_blocks })),
+                        (BITMAP_FILE, "BITMAP.SYS", Some(Extent { lbn: bitmap_lbn, count: 1 + bitmap_blocks })),
+                        (BADBLK_FILE, "BADBLK.SYS", None),
+                        (MFD_FILE,    "000000.DIR", Some(Extent { lbn: mfd_lbn, count: 1 })),
+                        (CORIMG_FILE, "CORIMG.SYS", None)];
+        let mut mfd = vec![];
+        for (num, name, extent) in reserved {
+            let fid = FileId { num, seq: num };
+            let mut header = FileHeader::new(fid, name, 1, DEFAULT_UIC, DEFAULT_PROTECTION, now);
+            if num == MFD_FILE { header.set_directory_attributes() }
+            if let Some(extent) = extent {
+                header.map.pointers = extent.split();
+                header.set_allocated(extent.count);
+                header.set_eof_bytes(if num == MFD_FILE { 0 } else { extent.count * BLOCK_SIZE });
+            }
+            fs.index_map[num as usize - 1] = true;
+            fs.write_header(&header)?;
+            mfd.push(DirRecord { fid, name: name.to_string(), version: 1 });
+        }
+        fs.write_index_map()?;
+        fs.write_storage_bitmap()?;
+        fs.write_directory(FileId { num: MFD_FILE, seq: MFD_FILE }, &mfd)?;
+        fs.ufd(DEFAULT_UIC, true)?;
+        Ok(fs)
+    }
+
+    fn read_header_in(image: &B, home: &HomeBlock, num: u16) -> anyhow::Result<FileHeader> {
+        let lbn = Self::header_lbn_in(image, home, num)?;
+        let header = FileHeader::from_repr(&mut image.read_blocks(lbn, 1)?).with_context(|| format!("File header {}", num))?;
+        if header.fid.num != num { return Err(anyhow!("File header {} at block {} is for file {}", num, lbn, header.fid.num)) }
+        Ok(header)
+    }
+
+    fn header_lbn_in(image: &B, home: &HomeBlock, num: u16) -> anyhow::Result<usize> {
+        if num == 0 || num > home.max_files { return Err(anyhow!("Bad file number {}", num)) }
+        let first_header = home.index_bitmap_lbn as usize + home.index_bitmap_blocks as usize;
+        if num <= CONTIGUOUS_HEADERS { return Ok(first_header + num as usize - 1) }
+        // The rest have to be looked up in INDEXF.SYS: boot block, home block, index file bitmap, then headers.
+        let vbn = 2 + home.index_bitmap_blocks as usize + num as usize; // 1 based
+        // INDEXF.SYS's own extension headers have to be found with the part of it that's been mapped so far (or
+        // this would go round in circles).
+        let header_lbn = |lbns: &[usize], num: u16| lbns.get(1 + home.index_bitmap_blocks as usize + num as usize).copied();
+        let mut index = Self::read_header_in(image, home, INDEX_FILE)?;
+        let mut lbns: Vec<usize> = index.map.pointers.iter().flat_map(|e| e.lbn..e.lbn + e.count).collect();
+        let mut segments = 0;
+        while lbns.len() < vbn {
+            let Some(next) = index.map.extension else { break };
+            segments += 1;
+            if segments > home.max_files { return Err(anyhow!("Extension header loop in INDEXF.SYS")) }
+            let lbn = match next.num {
+                n if n <= CONTIGUOUS_HEADERS => first_header + n as usize - 1,
+                n => header_lbn(&lbns, n).ok_or_else(|| anyhow!("INDEXF.SYS extension header {} isn't in INDEXF.SYS", n))?,
+            };
+            index = FileHeader::from_repr(&mut image.read_blocks(lbn, 1)?).with_context(|| format!("File header {}", next.num))?;
+            if index.fid != next { return Err(anyhow!("INDEXF.SYS: bad extension header {}", next.num)) }
+            lbns.extend(index.map.pointers.iter().flat_map(|e| e.lbn..e.lbn + e.count));
+        }
+        header_lbn(&lbns, num).ok_or_else(|| anyhow!("File header {} is past the end of INDEXF.SYS", num))
+    }
+
+    // The primary header and all its extension headers.
+    fn header_chain_in(image: &B, home: &HomeBlock, header: &FileHeader) -> anyhow::Result<Vec<FileHeader>> {
+        let mut chain = vec![header.clone()];
+        while let Some(next) = chain.last().unwrap().map.extension {
+            if chain.len() > home.max_files as usize { return Err(anyhow!("Extension header loop in {}", header.name)) }
+            let ext = Self::read_header_in(image, home, next.num)?;
+            if ext.fid != next { return Err(anyhow!("{}: extension header {} has sequence number {} and not {}", header.name, next.num, ext.fid.seq, next.seq)) }
+            chain.push(ext);
+        }
+        Ok(chain)
+    }
+
+    fn extents_in(image: &B, home: &HomeBlock, header: &FileHeader) -> anyhow::Result<Vec<Range<usize>>> {
+        Ok(Self::header_chain_in(image, home, header)?.iter()
+           .flat_map(|h| h.map.pointers.iter().map(|e| e.lbn..e.lbn + e.count)).collect())
+    }
+
+    fn read_header(&self, num: u16) -> anyhow::Result<FileHeader> {
+        Self::read_header_in(&self.image, &self.home, num)
+    }
+
+    fn header_chain(&self, header: &FileHeader) -> anyhow::Result<Vec<FileHeader>> {
+        Self::header_chain_in(&self.image, &self.home, header)
+    }
+
+    // Every block in the file, in order.
+    fn file_lbns(&self, header: &FileHeader) -> anyhow::Result<Vec<usize>> {
+        Ok(Self::extents_in(&self.image, &self.home, header)?.into_iter().flatten().collect())
+    }
+
+    fn read_contents(&self, header: &FileHeader) -> anyhow::Result<Vec<u8>> {
+        let bytes = header.eof_bytes();
+        let mut contents = Vec::with_capacity(bytes);
+        for lbn in self.file_lbns(header)?.into_iter().take(bytes.div_ceil(BLOCK_SIZE)) {
+            contents.extend_from_slice(self.image.read_blocks(lbn, 1)?.as_bytes());
+        }
+        contents.truncate(bytes);
+        Ok(contents)
+    }
+
+    fn write_header(&mut self, header: &FileHeader) -> anyhow::Result<()> {
+        let lbn = Self::header_lbn_in(&self.image, &self.home, header.fid.num)?;
+        self.image.write_blocks(lbn, 1, &header.repr()?)
+    }
+
+    fn write_index_map(&mut self) -> anyhow::Result<()> {
+        let mut map = self.index_map.clone();
+        map.resize(self.home.index_bitmap_blocks as usize * BITS_PER_BITMAP_BLOCK, false);
+        self.image.write_blocks(self.home.index_bitmap_lbn as usize, self.home.index_bitmap_blocks as usize, &bytes(&map))
+    }
+
+    fn write_storage_bitmap(&mut self) -> anyhow::Result<()> {
+        let blocks = self.bitmap_lbns.len() - 1;
+        let mut free: Vec<bool> = self.bitmap.iter().map(|used| !used).collect();
+        free.resize(blocks * BITS_PER_BITMAP_BLOCK, false);
+
+        // Storage control block: 3 unused bytes, the bitmap block count, then (free blocks, 0) for each bitmap
+        // block and the size of the volume.
+        let mut scb = ByteBuffer::new();
+        scb.set_endian(Endian::LittleEndian);
+        scb.write_bytes(&[0, 0, 0, blocks as u8]);
+        for chunk in free.chunks(BITS_PER_BITMAP_BLOCK) {
+            scb.write_u16(chunk.iter().filter(|f| **f).count() as u16);
+            scb.write_u16(0);
+        }
+        write_u32(&mut scb, self.bitmap.len() as u32);
+        scb.write_bytes(&vec![0; BLOCK_SIZE - scb.len()]);
+        self.image.write_blocks(self.bitmap_lbns[0], 1, scb.as_bytes())?;
+
+        for (i, chunk) in free.chunks(BITS_PER_BITMAP_BLOCK).enumerate() {
+            self.image.write_blocks(self.bitmap_lbns[1 + i], 1, &bytes(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn free_spans(&self) -> Vec<Range<usize>> {
+        let mut spans = vec![];
+        let mut start = None;
+        for (i, used) in self.bitmap.iter().enumerate() {
+            match (start, used) {
+                (None,    false) => start = Some(i),
+                (Some(s), true ) => { spans.push(s..i); start = None },
+                (_,       _    ) => {},
+            }
+        }
+        if let Some(s) = start { spans.push(s..self.bitmap.len()) }
+        spans
+    }
+
+    // Takes the first free area that holds the whole thing. When there isn't one (and it doesn't need to be
+    // contiguous), takes the biggest areas until there's enough.
+    fn allocate_blocks(&mut self, blocks: usize, contiguous: bool) -> anyhow::Result<Vec<Extent>> {
+        let mut spans = self.free_spans();
+        let extents = match spans.iter().find(|s| s.len() >= blocks) {
+            Some(span) => vec![Extent { lbn: span.start, count: blocks }],
+            None if contiguous => return Err(anyhow!("No contiguous space for {} blocks", blocks)),
+            None => {
+                spans.sort_by_key(|s| std::cmp::Reverse(s.len()));
+                let mut extents = vec![];
+                let mut needed = blocks;
+                for s in spans {
+                    if needed == 0 { break }
+                    let count = std::cmp::min(needed, s.len());
+                    extents.push(Extent { lbn: s.start, count });
+                    needed -= count;
+                }
+                if needed > 0 { return Err(anyhow!("No space for {} blocks", blocks)) }
+                extents
+            },
+        };
+        for e in extents.iter() {
+            for b in e.lbn..e.lbn + e.count { self.bitmap[b] = true }
+        }
+        Ok(extents)
+    }
+
+    // Picks a free file number and works out its new sequence number, growing INDEXF.SYS when the header would
+    // be past its end. It grows by a bunch of headers at once so it doesn't end up in hundreds of pieces.
+    fn allocate_header(&mut self) -> anyhow::Result<FileId> {
+        let Some(i) = self.index_map.iter().enumerate().skip(RESERVED_FILES as usize).find(|(_, used)| !**used).map(|(i, _)| i) else {
+            return Err(anyhow!("The index file is full ({} files)", self.home.max_files));
+        };
+        let num = i as u16 + 1;
+        let index = self.read_header(INDEX_FILE)?;
+        let have = self.file_lbns(&index)?.len();
+        let need = 2 + self.home.index_bitmap_blocks as usize + num as usize;
+        if need > have {
+            let most = 2 + self.home.index_bitmap_blocks as usize + self.home.max_files as usize;
+            let want = std::cmp::min(need + CONTIGUOUS_HEADERS as usize - 1, most);
+            let added = self.extend(&index, want - have, false)
+                .or_else(|_| self.extend(&index, need - have, false))?;
+            let blocks = have + added.len();
+            for lbn in added {
+                self.image.write_blocks(lbn, 1, &[0; BLOCK_SIZE])?;
+            }
+            let mut index = self.read_header(INDEX_FILE)?;
+            index.set_eof_bytes(blocks * BLOCK_SIZE);
+            self.write_header(&index)?;
+        }
+        // A header that's been used before still has its old sequence number.
+        let lbn = Self::header_lbn_in(&self.image, &self.home, num)?;
+        let old_seq = u16::from_le_bytes(self.image.read_blocks(lbn, 1)?.as_bytes()[4..6].try_into()?);
+        self.index_map[i] = true;
+        Ok(FileId { num, seq: match old_seq.wrapping_add(1) { 0 => 1, seq => seq } })
+    }
+
+    // Adds `blocks` blocks to the end of the file, making extension headers when the retrieval pointers don't
+    // fit. Returns the new blocks. The index file and storage bitmap are written.
+    fn extend(&mut self, header: &FileHeader, blocks: usize, contiguous: bool) -> anyhow::Result<Vec<usize>> {
+        if blocks == 0 { return Ok(vec![]) }
+        let extents = self.allocate_blocks(blocks, contiguous)?;
+        let mut chain = self.header_chain(header)?;
+        let mut dirty = vec![false; chain.len()];
+        for extent in extents.iter().flat_map(|e| e.split()) {
+            let last = chain.len() - 1;
+            let pointers = &mut chain[last].map.pointers;
+            let full = pointers.len() >= POINTERS_PER_HEADER;
+            match pointers.last_mut() {
+                Some(p) if p.lbn + p.count == extent.lbn && p.count + extent.count <= MAX_EXTENT => p.count += extent.count,
+                _ if !full => pointers.push(extent),
+                _ if header.fid.num == INDEX_FILE => {
+                    // Its extension headers would have to come from INDEXF.SYS itself.
+                    for e in extents.iter() {
+                        for b in e.lbn..e.lbn + e.count { self.bitmap[b] = false }
+                    }
+                    return Err(anyhow!("INDEXF.SYS is too fragmented to extend"));
+                },
+                _ => {
+                    let fid = self.allocate_header()?;
+                    let mut ext = chain[last].clone();
+                    ext.fid = fid;
+                    ext.map.segment += 1;
+                    ext.map.extension = None;
+                    ext.map.pointers = vec![extent];
+                    chain[last].map.extension = Some(fid);
+                    chain.push(ext);
+                    dirty.push(true);
+                },
+            }
+            dirty[chain.len() - 1] = true;
+            if chain.len() > 1 { dirty[chain.len() - 2] = true }
+        }
+        let allocated = chain[0].allocated() + blocks;
+        chain[0].set_allocated(allocated);
+        dirty[0] = true;
+        for (h, _) in chain.iter().zip(dirty).filter(|(_, d)| *d) {
+            self.write_header(h)?;
+        }
+        self.write_index_map()?;
+        self.write_storage_bitmap()?;
+        Ok(extents.iter().flat_map(|e| e.lbn..e.lbn + e.count).collect())
+    }
+
+    // Writes `data` starting at the beginning of the file, growing the file if it needs to.
+    fn write_contents(&mut self, header: &FileHeader, data: &[u8], contiguous: bool) -> anyhow::Result<()> {
+        let have = self.file_lbns(header)?.len();
+        self.extend(header, data.len().div_ceil(BLOCK_SIZE).saturating_sub(have), contiguous)?;
+        let mut header = self.read_header(header.fid.num)?;
+        for (lbn, chunk) in self.file_lbns(&header)?.into_iter().zip(data.chunks(BLOCK_SIZE)) {
+            let mut block = chunk.to_vec();
+            block.resize(BLOCK_SIZE, 0);
+            self.image.write_blocks(lbn, 1, &block)?;
+        }
+        header.set_eof_bytes(data.len());
+        self.write_header(&header)
+    }
+
+    fn create(&mut self, owner: Uic, name: &str, version: u16, contents: &[u8], contiguous: bool) -> anyhow::Result<FileHeader> {
+        let fid = self.allocate_header()?;
+        let mut header = FileHeader::new(fid, name, version, owner, self.home.default_file_protection, Local::now().naive_local());
+        if contiguous { header.characteristics |= UC_CONTIGUOUS }
+        if name.ends_with(".DIR") { header.set_directory_attributes() }
+        self.write_header(&header)?;
+        self.write_index_map()?;
+        if let Err(e) = self.write_contents(&header, contents, contiguous) {
+            // Give back the header (and whatever it got) so it isn't lost.
+            let header = self.read_header(fid.num)?;
+            self.delete_file(&header)?;
+            return Err(e);
+        }
+        self.read_header(fid.num)
+    }
+
+    fn delete_file(&mut self, header: &FileHeader) -> anyhow::Result<()> {
+        for mut h in self.header_chain(header)? {
+            for e in h.map.pointers.iter() {
+                for b in e.lbn..e.lbn + e.count { self.bitmap[b] = false }
+            }
+            self.index_map[h.fid.num as usize - 1] = false;
+            // A zero file number marks a free header. The sequence number stays so the next file gets a new one.
+            let num = h.fid.num;
+            h.fid.num = 0;
+            let lbn = Self::header_lbn_in(&self.image, &self.home, num)?;
+            self.image.write_blocks(lbn, 1, &h.repr()?)?;
+        }
+        self.write_index_map()?;
+        self.write_storage_bitmap()
+    }
+
+    fn read_directory(&self, fid: FileId) -> anyhow::Result<Vec<DirRecord>> {
+        let header = self.read_header(fid.num)?;
+        if header.fid != fid { return Err(anyhow!("Directory {} has been deleted", fid.num)) }
+        self.read_contents(&header)?.chunks_exact(DIR_RECORD_SIZE).filter_map(|r| DirRecord::from_repr(r).transpose()).collect()
+    }
+
+    fn write_directory(&mut self, fid: FileId, records: &[DirRecord]) -> anyhow::Result<()> {
+        let mut data = vec![];
+        for r in records {
+            data.extend_from_slice(&r.repr()?);
+        }
+        let header = self.read_header(fid.num)?;
+        self.write_contents(&header, &data, false)
+    }
+
+    // The UFD for `uic` (or the MFD for [0,0]), making one when `create` is set.
+    fn ufd(&mut self, uic: Uic, create: bool) -> anyhow::Result<FileId> {
+        if let Some(fid) = self.find_ufd(uic)? { return Ok(fid) }
+        if !create { return Err(anyhow!("No UFD for {}", uic)) }
+        let header = self.create(uic, &ufd_name(uic), 1, &[], false)?;
+        let mfd = FileId { num: MFD_FILE, seq: MFD_FILE };
+        let mut records = self.read_directory(mfd)?;
+        records.push(DirRecord { fid: header.fid, name: header.name.clone(), version: 1 });
+        self.write_directory(mfd, &records)?;
+        Ok(header.fid)
+    }
+
+    fn find_ufd(&self, uic: Uic) -> anyhow::Result<Option<FileId>> {
+        let mfd = FileId { num: MFD_FILE, seq: MFD_FILE };
+        if uic == MFD_UIC { return Ok(Some(mfd)) }
+        let name = ufd_name(uic);
+        Ok(self.read_directory(mfd)?.into_iter().find(|r| r.name == name).map(|r| r.fid))
+    }
+
+    // Every (UIC, UFD) on the volume, starting with the MFD.
+    fn ufds(&self) -> anyhow::Result<Vec<(Uic, FileId)>> {
+        let mfd = FileId { num: MFD_FILE, seq: MFD_FILE };
+        let mut ufds = vec![(MFD_UIC, mfd)];
+        for r in self.read_directory(mfd)? {
+            if let Some(uic) = ufd_uic(&r.name) { ufds.push((uic, r.fid)) }
+        }
+        Ok(ufds)
+    }
+
+    // Finds a file. Without a version it's the newest one.
+    fn lookup(&self, path: &str) -> anyhow::Result<(Uic, FileId, DirRecord)> {
+        let (uic, name, version) = split_path(path)?;
+        let dir = self.find_ufd(uic)?.ok_or_else(|| anyhow!("No UFD for {}", uic))?;
+        let record = self.read_directory(dir)?.into_iter()
+            .filter(|r| r.name == name && version.is_none_or(|v| r.version == v))
+            .max_by_key(|r| r.version)
+            .ok_or_else(|| anyhow!("File not found: {}", path))?;
+        Ok((uic, dir, record))
+    }
+
+    fn entry(&self, uic: Uic, record: &DirRecord) -> anyhow::Result<DirEntry> {
+        let header = self.read_header(record.fid.num)?;
+        if header.fid != record.fid { return Err(anyhow!("{}{};{:o}: directory entry points to a deleted file", uic, record.name, record.version)) }
+        Ok(DirEntry {
+            path: format!("{}{};{:o}", uic, record.name, record.version),
+            file_name: format!("{};{:o}", record.name, record.version),
+            is_dir: uic == MFD_UIC && ufd_uic(&record.name).is_some(),
+            blocks: self.file_lbns(&header)?.len(),
+            header,
+        })
+    }
+
+    fn entries(&self, path: &str) -> anyhow::Result<Vec<DirEntry>> {
+        // "/" is every file on the volume, "[g,m]" is just the files in that UIC.
+        let ufds = match path.trim_start_matches('/') {
+            "" => self.ufds()?,
+            p => {
+                let (uic, name, _) = split_path(p)?;
+                if !p.starts_with('[') || !name.is_empty() { return Err(anyhow!("Bad path")) }
+                vec![(uic, self.find_ufd(uic)?.ok_or_else(|| anyhow!("No UFD for {}", uic))?)]
+            },
+        };
+        let mut entries = vec![];
+        for (uic, fid) in ufds {
+            // A record pointing at a deleted or unreadable header shouldn't hide everything else.
+            entries.extend(self.read_directory(fid)?.iter().filter_map(|r| self.entry(uic, r).ok()));
+        }
+        Ok(entries)
+    }
+
+    fn set_protection(&mut self, name: &str, bits: u16, set: bool) -> anyhow::Result<()> {
+        let (_, _, record) = self.lookup(name)?;
+        let mut header = self.read_header(record.fid.num)?;
+        header.protection = if set { header.protection | bits } else { header.protection & !bits };
+        self.write_header(&header)
+    }
+}
+
+impl<B: BlockDevice> FileSystem for Ods1Fs<B> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        "Files-11 ODS-1"
+    }
+
+    fn dir_iter<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        self.read_dir(path)
+    }
+
+    fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.entries(path)?.into_iter().map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        let (uic, _, record) = self.lookup(name).ok()?;
+        Some(Box::new(self.entry(uic, &record).ok()?))
+    }
+
+    fn free_blocks(&self) -> usize {
+        self.bitmap.iter().filter(|used| !**used).count()
+    }
+
+    fn used_blocks(&self) -> usize {
+        self.image.blocks() - self.free_blocks()
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let (_, _, record) = self.lookup(name)?;
+        let header = self.read_header(record.fid.num)?;
+        Ok(ByteBuffer::from_vec(self.read_contents(&header)?))
+    }
+
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    // Makes a new version, unless a version is given (which replaces that version if it's there).
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        if options.placement != Placement::default() || !options.prefix.is_empty() {
+            return Err(anyhow!("{}: Files-11 filesystems don't support placement or prefix blocks", name));
+        }
+        let (uic, file, version) = split_path(name)?;
+        if uic == MFD_UIC { return Err(anyhow!("{}: Can't create files in the MFD", name)) }
+        encode_filename(&file)?;
+        let dir = self.ufd(uic, true)?;
+        let mut records = self.read_directory(dir)?;
+        let (version, old) = match version {
+            Some(v) => {
+                let old = records.iter().position(|r| r.name == file && r.version == v);
+                if let Some(i) = old {
+                    let old = self.read_header(records[i].fid.num)?;
+                    if old.protection & OWNER_DENY_WRITE != 0 { return Err(anyhow!("{} is read-only", name)) }
+                    if old.protection & OWNER_DENY_DELETE != 0 { return Err(anyhow!("{} is protected", name)) }
+                }
+                (v, old)
+            },
+            None => (records.iter().filter(|r| r.name == file).map(|r| r.version).max().unwrap_or(0) + 1, None),
+        };
+        // The old version stays until the new one has been made, so running out of room doesn't lose it.
+        let header = self.create(uic, &file, version, contents, options.contiguous)?;
+        let record = DirRecord { fid: header.fid, name: file, version };
+        let Some(i) = old else {
+            records.push(record);
+            return self.write_directory(dir, &records);
+        };
+        let old = self.read_header(records[i].fid.num)?;
+        records[i] = record;
+        self.write_directory(dir, &records)?;
+        self.delete_file(&old)
+    }
+
+    fn append_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let (_, _, record) = self.lookup(name)?;
+        let header = self.read_header(record.fid.num)?;
+        if header.protection & OWNER_DENY_WRITE != 0 { return Err(anyhow!("{} is read-only", name)) }
+        let mut data = self.read_contents(&header)?;
+        data.extend_from_slice(contents);
+        self.write_contents(&header, &data, header.characteristics & UC_CONTIGUOUS != 0)
+    }
+
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let (uic, dir, record) = self.lookup(name)?;
+        if uic == MFD_UIC && record.fid.num <= RESERVED_FILES { return Err(anyhow!("Can't delete {}", name)) }
+        if let Some(ufd) = ufd_uic(&record.name).filter(|_| uic == MFD_UIC) {
+            if !self.read_directory(record.fid)?.is_empty() { return Err(anyhow!("{} isn't empty", ufd)) }
+        }
+        let header = self.read_header(record.fid.num)?;
+        if header.protection & OWNER_DENY_DELETE != 0 { return Err(anyhow!("{} is protected", name)) }
+        self.delete_file(&header)?;
+        let records: Vec<DirRecord> = self.read_directory(dir)?.into_iter().filter(|r| r.fid != record.fid).collect();
+        self.write_directory(dir, &records)
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let (src_uic, src_dir, record) = self.lookup(src)?;
+        let (uic, file, version) = split_path(dest)?;
+        if src_uic == MFD_UIC || uic == MFD_UIC { return Err(anyhow!("Can't rename files in or into the MFD")) }
+        encode_filename(&file)?;
+        let mut header = self.read_header(record.fid.num)?;
+        if header.protection & OWNER_DENY_DELETE != 0 { return Err(anyhow!("{} is protected", src)) }
+
+        // Everything gets checked before the file leaves its old directory.
+        let dir = self.ufd(uic, true)?;
+        let mut records: Vec<DirRecord> = self.read_directory(dir)?.into_iter().filter(|r| r.fid != record.fid).collect();
+        let version = version.unwrap_or_else(|| records.iter().filter(|r| r.name == file).map(|r| r.version).max().unwrap_or(0) + 1);
+        if records.iter().any(|r| r.name == file && r.version == version) { return Err(anyhow!("{} already exists", dest)) }
+        records.push(DirRecord { fid: record.fid, name: file.clone(), version });
+        self.write_directory(dir, &records)?;
+        if src_dir != dir {
+            let records: Vec<DirRecord> = self.read_directory(src_dir)?.into_iter().filter(|r| r.fid != record.fid).collect();
+            self.write_directory(src_dir, &records)?;
+        }
+
+        // The header has the name in it too.
+        header.name = file;
+        header.version = version;
+        self.write_header(&header)
+    }
+
+    fn set_protected(&mut self, name: &str, protected: bool) -> anyhow::Result<()> {
+        self.set_protection(name, OWNER_DENY_DELETE, protected)
+    }
+
+    fn set_readonly(&mut self, name: &str, readonly: bool) -> anyhow::Result<()> {
+        self.set_protection(name, OWNER_DENY_WRITE, readonly)
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+impl<B: BlockDevice> Debug for Ods1Fs<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ods1Fs")
+            .field("home",      &self.home      )
+            .field("index_map", &self.index_map )
+            .field("bitmap",    &self.bitmap    )
+            .finish()
+    }
+}
+
+// "[200,200]FOO.TXT;3" -> ([200,200], "FOO.TXT", Some(3)). Versions are octal. Names without a UIC are in [1,1].
+fn split_path(path: &str) -> anyhow::Result<(Uic, String, Option<u16>)> {
+    let path = path.trim_start_matches('/');
+    let (uic, rest) = match path.strip_prefix("[0,0]") {
+        Some(rest) => (MFD_UIC, rest),
+        None       => match Uic::split_path(path)? {
+            (uic, rest) if path.starts_with('[') => (uic, rest),
+            (_,   rest)                          => (DEFAULT_UIC, rest),
+        },
+    };
+    let (name, version) = match rest.split_once(';') {
+        Some((name, version)) => (name, Some(u16::from_str_radix(version, 8).map_err(|_| anyhow!("Bad version number: {}", version))?)),
+        None                  => (rest, None),
+    };
+    let name = if name.contains('.') || name.is_empty() { name.to_string() } else { format!("{}.", name) };
+    Ok((uic, name, version))
+}
+
+// Names are 9.3, radix-50 encoded.
+fn encode_filename(name: &str) -> anyhow::Result<[u16; 4]> {
+    let Some((name, ext)) = name.split_once('.') else { return Err(anyhow!("Bad filename: {}", name)) };
+    if name.is_empty() || name.len() > 9 || ext.len() > 3 { return Err(anyhow!("Filenames should be 1 to 9 characters, extensions 0 to 3")) }
+    let name_w = radix50::pdp11::encode(&format!("{:<9}", name))?;
+    Ok([name_w[0], name_w[1], name_w[2], radix50::pdp11::encode_word(&format!("{:<3}", ext))?])
+}
+
+fn decode_filename(words: &[u16; 4]) -> String {
+    format!("{}.{}", radix50::pdp11::decode(&words[0..3]).trim(), radix50::pdp11::decode_word(words[3]).trim())
+}
+
+fn ufd_name(uic: Uic) -> String {
+    format!("{:03o}{:03o}.DIR", uic.group, uic.member)
+}
+
+fn ufd_uic(name: &str) -> Option<Uic> {
+    let digits = name.strip_suffix(".DIR").filter(|d| d.len() == 6 && d.chars().all(|c| c.is_digit(8)))?;
+    let uic = Uic { group: u8::from_str_radix(&digits[0..3], 8).ok()?, member: u8::from_str_radix(&digits[3..6], 8).ok()? };
+    (uic != MFD_UIC).then_some(uic)
+}
+
+// Bitmaps are LSB first.
+fn bits(bytes: &[u8]) -> Vec<bool> {
+    bytes.iter().flat_map(|b| (0..8).map(move |bit| b & (1 << bit) != 0)).collect()
+}
+
+fn bytes(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8).map(|b| b.iter().enumerate().fold(0, |acc, (n, set)| if *set { acc | 1 << n } else { acc })).collect()
+}
+
+// 32 bit numbers are stored high word first.
+fn read_u32(buf: &mut ByteBuffer) -> anyhow::Result<u32> {
+    let high = buf.read_u16()? as u32;
+    Ok(high << 16 | buf.read_u16()? as u32)
+}
+
+fn write_u32(buf: &mut ByteBuffer, value: u32) {
+    buf.write_u16((value >> 16) as u16);
+    buf.write_u16(value as u16);
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes.chunks(2).fold(0u16, |sum, w| sum.wrapping_add(u16::from_le_bytes([w[0], w[1]])))
+}
+
+const MONTHS: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+
+// Dates are ASCII "DDMMMYY" and times "HHMMSS". Two digit years before 70 are taken to be in the 2000s.
+fn decode_date(date: &[u8]) -> Option<NaiveDate> {
+    let date = std::str::from_utf8(date).ok()?;
+    let month = date.get(2..5)?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let year: i32 = date.get(5..7)?.parse().ok()?;
+    NaiveDate::from_ymd_opt(if year < 70 { 2000 + year } else { 1900 + year }, month, date.get(0..2)?.parse().ok()?)
+}
+
+fn decode_datetime(date: &[u8], time: &[u8]) -> Option<NaiveDateTime> {
+    let time = std::str::from_utf8(time).ok()?;
+    decode_date(date)?.and_hms_opt(time.get(0..2)?.parse().ok()?, time.get(2..4)?.parse().ok()?, time.get(4..6)?.parse().ok()?)
+}
+
+fn encode_date(date: NaiveDate) -> [u8; 7] {
+    format!("{:02}{}{:02}", date.day(), MONTHS[date.month0() as usize], date.year() % 100).into_bytes().try_into().expect("can't happen")
+}
+
+fn encode_time(time: NaiveDateTime) -> [u8; 6] {
+    format!("{:02}{:02}{:02}", time.hour(), time.minute(), time.second()).into_bytes().try_into().expect("can't happen")
+}
+
+#[derive(Clone, Debug)]
+pub struct HomeBlock {
+    pub index_bitmap_blocks: u16,
+    pub index_bitmap_lbn: u32,
+    pub max_files: u16,
+    pub cluster_factor: u16,
+    pub device_type: u16,
+    pub structure_level: u16,
+    pub volume_name: String,
+    pub owner: Uic,
+    pub protection: u16,
+    pub characteristics: u16,
+    pub default_file_protection: u16,
+    pub window_size: u8,
+    pub extend: u8,
+    pub lru_limit: u8,
+    pub revision_count: u16,
+    pub created: Option<NaiveDateTime>,
+}
+
+impl HomeBlock {
+    pub fn from_repr(buf: &mut ByteBuffer) -> anyhow::Result<HomeBlock> {
+        buf.set_endian(Endian::LittleEndian);
+        let raw = buf.as_bytes();
+        if raw.len() < BLOCK_SIZE { return Err(anyhow!("Short home block")) }
+        if &raw[496..508] != FORMAT_TYPE { return Err(anyhow!("Not a Files-11 home block")) }
+        if checksum(&raw[0..58]) != u16::from_le_bytes([raw[58], raw[59]]) { return Err(anyhow!("Bad home block checksum 1")) }
+        if checksum(&raw[0..510]) != u16::from_le_bytes([raw[510], raw[511]]) { return Err(anyhow!("Bad home block checksum 2")) }
+        let home = HomeBlock {
+            index_bitmap_blocks: buf.read_u16()?,
+            index_bitmap_lbn: read_u32(buf)?,
+            max_files: buf.read_u16()?,
+            cluster_factor: buf.read_u16()?,
+            device_type: buf.read_u16()?,
+            structure_level: buf.read_u16()?,
+            volume_name: String::from_utf8_lossy(&buf.read_bytes(12)?).trim_end_matches(['\0', ' ']).to_string(),
+            owner: { buf.read_bytes(4)?; Uic::from_word(buf.read_u16()?) },
+            protection: buf.read_u16()?,
+            characteristics: buf.read_u16()?,
+            default_file_protection: buf.read_u16()?,
+            window_size: { buf.read_bytes(6)?; buf.read_u8()? },
+            extend: buf.read_u8()?,
+            lru_limit: buf.read_u8()?,
+            revision_count: { buf.read_bytes(7)?; buf.read_u16()? },
+            created: { buf.read_bytes(4)?; let date = buf.read_bytes(14)?; decode_datetime(&date[0..7], &date[7..13]) },
+        };
+        if home.structure_level != STRUCTURE_LEVEL && home.structure_level != STRUCTURE_LEVEL + 1 {
+            return Err(anyhow!("Unknown structure level {:#o}", home.structure_level));
+        }
+        if home.index_bitmap_blocks == 0 || home.max_files == 0 { return Err(anyhow!("Bad index file bitmap in home block")) }
+        Ok(home)
+    }
+
+    pub fn repr(&self) -> [u8; BLOCK_SIZE] {
+        let mut repr = ByteBuffer::new();
+        repr.set_endian(Endian::LittleEndian);
+        let now = Local::now().naive_local();
+        let name = format!("{:<12}", self.volume_name).into_bytes();
+        repr.write_u16(self.index_bitmap_blocks);
+        write_u32(&mut repr, self.index_bitmap_lbn);
+        repr.write_u16(self.max_files);
+        repr.write_u16(self.cluster_factor);
+        repr.write_u16(self.device_type);
+        repr.write_u16(self.structure_level);
+        repr.write_bytes(&name);
+        repr.write_bytes(&[0; 4]);
+        repr.write_u16(self.owner.word());
+        repr.write_u16(self.protection);
+        repr.write_u16(self.characteristics);
+        repr.write_u16(self.default_file_protection);
+        repr.write_bytes(&[0; 6]);
+        repr.write_u8(self.window_size);
+        repr.write_u8(self.extend);
+        repr.write_u8(self.lru_limit);
+        repr.write_bytes(&encode_date(now.date()));  // Revision date
+        repr.write_u16(self.revision_count);
+        repr.write_u16(0);
+        repr.write_u16(checksum(&repr.as_bytes()[0..58]));
+        let created = self.created.unwrap_or(now);
+        repr.write_bytes(&encode_date(created.date()));
+        repr.write_bytes(&encode_time(created));
+        repr.write_u8(0);
+        repr.write_bytes(&vec![0; 456 - repr.len()]);
+        repr.write_bytes(&[0; 4]);                    // Pack serial number
+        repr.write_bytes(&[0; 12]);
+        repr.write_bytes(&name);
+        repr.write_bytes(&format!("{:<12}", format!("{}", self.owner)).into_bytes()[0..12]);
+        repr.write_bytes(FORMAT_TYPE);
+        repr.write_u16(0);
+        repr.write_u16(checksum(&repr.as_bytes()[0..510]));
+        repr.into_vec().try_into().expect("can't happen")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileId {
+    pub num: u16,
+    pub seq: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Extent {
+    pub lbn: usize,
+    pub count: usize,
+}
+
+impl Extent {
+    // Retrieval pointers can only count to 256.
+    fn split(&self) -> Vec<Extent> {
+        (0..self.count).step_by(MAX_EXTENT).map(|o| Extent { lbn: self.lbn + o, count: std::cmp::min(MAX_EXTENT, self.count - o) }).collect()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Map {
+    pub segment: u8,
+    pub extension: Option<FileId>,
+    pub pointers: Vec<Extent>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FileHeader {
+    pub fid: FileId,
+    pub level: u16,
+    pub owner: Uic,
+    pub protection: u16,
+    pub characteristics: u16,
+    pub attributes: [u8; 32], // FCS record attributes
+    pub name: String,
+    pub version: u16,
+    pub revision: u16,
+    pub revised: Option<NaiveDateTime>,
+    pub created: Option<NaiveDateTime>,
+    pub expires: Option<NaiveDate>,
+    pub map: Map,
+}
+
+impl FileHeader {
+    fn new(fid: FileId, name: &str, version: u16, owner: Uic, protection: u16, now: NaiveDateTime) -> FileHeader {
+        let mut header = FileHeader {
+            fid,
+            level: STRUCTURE_LEVEL,
+            owner,
+            protection,
+            characteristics: 0,
+            attributes: [0; 32],
+            name: name.to_string(),
+            version,
+            revision: 1,
+            revised: Some(now),
+            created: Some(now),
+            expires: None,
+            map: Map::default(),
+        };
+        // Fixed length 512 byte records, which is as close as we can get to "just bytes".
+        header.attributes[0] = 1;
+        header.attributes[2..4].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        header.set_eof_bytes(0);
+        header
+    }
+
+    pub fn from_repr(buf: &mut ByteBuffer) -> anyhow::Result<FileHeader> {
+        buf.set_endian(Endian::LittleEndian);
+        let raw = buf.as_bytes().to_vec();
+        if raw.len() < BLOCK_SIZE { return Err(anyhow!("Short file header")) }
+        if checksum(&raw[0..510]) != u16::from_le_bytes([raw[510], raw[511]]) { return Err(anyhow!("Bad file header checksum")) }
+        let (ident, map) = (raw[0] as usize * 2, raw[1] as usize * 2);
+        if ident < 46 || map < ident + 46 || map + 10 > 510 { return Err(anyhow!("Bad file header offsets {}/{}", ident, map)) }
+        buf.set_rpos(2);
+        let mut header = FileHeader {
+            fid: FileId { num: buf.read_u16()?, seq: buf.read_u16()? },
+            level: buf.read_u16()?,
+            owner: Uic::from_word(buf.read_u16()?),
+            protection: buf.read_u16()?,
+            characteristics: buf.read_u16()?,
+            attributes: buf.read_bytes(32)?.try_into().expect("can't happen"),
+            name: { buf.set_rpos(ident); decode_filename(&[buf.read_u16()?, buf.read_u16()?, buf.read_u16()?, buf.read_u16()?]) },
+            version: buf.read_u16()?,
+            revision: buf.read_u16()?,
+            revised: decode_datetime(&raw[ident+12..ident+19], &raw[ident+19..ident+25]),
+            created: decode_datetime(&raw[ident+25..ident+32], &raw[ident+32..ident+38]),
+            expires: decode_date(&raw[ident+38..ident+45]),
+            map: Map::default(),
+        };
+        if header.level != STRUCTURE_LEVEL && header.level != STRUCTURE_LEVEL + 1 {
+            return Err(anyhow!("Unknown file structure level {:#o}", header.level));
+        }
+        buf.set_rpos(map);
+        header.map.segment = buf.read_u8()?;
+        let _rvn = buf.read_u8()?;
+        header.map.extension = match (FileId { num: buf.read_u16()?, seq: buf.read_u16()? }) {
+            FileId { num: 0, .. } => None,
+            fid                   => Some(fid),
+        };
+        let (count_size, lbn_size, in_use) = (buf.read_u8()?, buf.read_u8()?, buf.read_u8()?);
+        let _available = buf.read_u8()?;
+        if (count_size, lbn_size) != (1, 3) { return Err(anyhow!("Unsupported retrieval pointer format {}/{}", count_size, lbn_size)) }
+        if map + 10 + in_use as usize * 2 > 510 { return Err(anyhow!("Too many retrieval pointers ({} words)", in_use)) }
+        for _ in 0..in_use / 2 {
+            let (high, count, low) = (buf.read_u8()?, buf.read_u8()?, buf.read_u16()?);
+            header.map.pointers.push(Extent { lbn: (high as usize) << 16 | low as usize, count: count as usize + 1 });
+        }
+        Ok(header)
+    }
+
+    pub fn repr(&self) -> anyhow::Result<[u8; BLOCK_SIZE]> {
+        let mut repr = ByteBuffer::new();
+        repr.set_endian(Endian::LittleEndian);
+        repr.write_u8(IDENT_OFFSET);
+        repr.write_u8(MAP_OFFSET);
+        repr.write_u16(self.fid.num);
+        repr.write_u16(self.fid.seq);
+        repr.write_u16(self.level);
+        repr.write_u16(self.owner.word());
+        repr.write_u16(self.protection);
+        repr.write_u16(self.characteristics);
+        repr.write_bytes(&self.attributes);
+
+        for w in encode_filename(&self.name)? {
+            repr.write_u16(w);
+        }
+        repr.write_u16(self.version);
+        repr.write_u16(self.revision);
+        for (date, len) in [(self.revised, 13), (self.created, 13)] {
+            match date {
+                Some(d) => { repr.write_bytes(&encode_date(d.date())); repr.write_bytes(&encode_time(d)) },
+                None    => repr.write_bytes(&vec![0; len]),
+            }
+        }
+        repr.write_bytes(&self.expires.map(encode_date).unwrap_or([0; 7]));
+        repr.write_u8(0);
+
+        if self.map.pointers.len() > POINTERS_PER_HEADER { return Err(anyhow!("Too many retrieval pointers for one header")) }
+        repr.write_u8(self.map.segment);
+        repr.write_u8(0); // Relative volume number
+        let ext = self.map.extension.unwrap_or_default();
+        repr.write_u16(ext.num);
+        repr.write_u16(ext.seq);
+        repr.write_u8(1); // Count size
+        repr.write_u8(3); // LBN size
+        repr.write_u8(self.map.pointers.len() as u8 * 2);
+        repr.write_u8(POINTERS_PER_HEADER as u8 * 2);
+        for e in self.map.pointers.iter() {
+            repr.write_u8((e.lbn >> 16) as u8);
+            repr.write_u8((e.count - 1) as u8);
+            repr.write_u16(e.lbn as u16);
+        }
+        repr.write_bytes(&vec![0; 510 - repr.len()]);
+        repr.write_u16(checksum(repr.as_bytes()));
+        Ok(repr.into_vec().try_into().expect("can't happen"))
+    }
+
+    fn attribute_u32(&self, offset: usize) -> usize {
+        let a = &self.attributes;
+        (u16::from_le_bytes([a[offset], a[offset+1]]) as usize) << 16 | u16::from_le_bytes([a[offset+2], a[offset+3]]) as usize
+    }
+
+    fn set_attribute_u32(&mut self, offset: usize, value: usize) {
+        self.attributes[offset..offset+2].copy_from_slice(&((value >> 16) as u16).to_le_bytes());
+        self.attributes[offset+2..offset+4].copy_from_slice(&(value as u16).to_le_bytes());
+    }
+
+    // F.HIBK: the number of blocks allocated
+    fn allocated(&self) -> usize {
+        self.attribute_u32(4)
+    }
+
+    fn set_allocated(&mut self, blocks: usize) {
+        self.set_attribute_u32(4, blocks)
+    }
+
+    // F.EFBK (the block with the end of file in it, 1 based) and F.FFBY (the first free byte in it).
+    pub fn eof_bytes(&self) -> usize {
+        let (block, byte) = (self.attribute_u32(8), u16::from_le_bytes([self.attributes[12], self.attributes[13]]) as usize);
+        block.saturating_sub(1) * BLOCK_SIZE + byte
+    }
+
+    fn set_eof_bytes(&mut self, bytes: usize) {
+        self.set_attribute_u32(8, bytes / BLOCK_SIZE + 1);
+        self.attributes[12..14].copy_from_slice(&((bytes % BLOCK_SIZE) as u16).to_le_bytes());
+    }
+
+    fn set_directory_attributes(&mut self) {
+        self.attributes[0] = 1; // Fixed length
+        self.attributes[1] = 0;
+        self.attributes[2..4].copy_from_slice(&(DIR_RECORD_SIZE as u16).to_le_bytes());
+    }
+
+    // "[RWED,RWED,RWED,R]" for system, owner, group and world
+    pub fn protection_string(&self) -> String {
+        let groups: Vec<String> = (0..4).map(|g| {
+            let deny = self.protection >> (g * 4);
+            "RWED".chars().enumerate().filter(|(bit, _)| deny & (1 << bit) == 0).map(|(_, c)| c).collect()
+        }).collect();
+        format!("[{}]", groups.join(","))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirRecord {
+    pub fid: FileId,
+    pub name: String,
+    pub version: u16,
+}
+
+impl DirRecord {
+    fn from_repr(raw: &[u8]) -> anyhow::Result<Option<DirRecord>> {
+        let w: Vec<u16> = raw.chunks(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect();
+        if w[0] == 0 { return Ok(None) }
+        Ok(Some(DirRecord {
+            fid: FileId { num: w[0], seq: w[1] },
+            name: decode_filename(&[w[3], w[4], w[5], w[6]]),
+            version: w[7],
+        }))
+    }
+
+    fn repr(&self) -> anyhow::Result<[u8; DIR_RECORD_SIZE]> {
+        let mut repr = ByteBuffer::new();
+        repr.set_endian(Endian::LittleEndian);
+        repr.write_u16(self.fid.num);
+        repr.write_u16(self.fid.seq);
+        repr.write_u16(0); // Relative volume number
+        for w in encode_filename(&self.name)? {
+            repr.write_u16(w);
+        }
+        repr.write_u16(self.version);
+        Ok(repr.into_vec().try_into().expect("can't happen"))
+    }
+}
+
+pub struct DirEntry {
+    path: String,
+    file_name: String,
+    #[allow(unused)] // Used by the viewer
+    is_dir: bool,
+    blocks: usize,
+    header: FileHeader,
+}
+
+impl Debug for DirEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let h = &self.header;
+        if f.alternate() {
+            write!(f, "{:<19} {:6}/{:<6}{} ({},{}) {} {} {}",
+                   h.created.map(|d| d.to_string()).unwrap_or(" No Date".to_string()),
+                   h.eof_bytes().div_ceil(BLOCK_SIZE), self.blocks,
+                   if h.characteristics & UC_CONTIGUOUS != 0 { "C" } else { " " },
+                   h.fid.num, h.fid.seq,
+                   h.owner, h.protection_string(),
+                   self.path)
+        } else {
+            write!(f, "{:10} {:6}{} {}",
+                   h.created.map(|d| d.date().to_string()).unwrap_or(" No Date".to_string()),
+                   self.blocks,
+                   if h.characteristics & UC_CONTIGUOUS != 0 { "C" } else { " " },
+                   self.path)
+        }
+    }
+}
+
+impl super::DirEntry for DirEntry {
+    fn path(&self)       -> &str                             { &self.path }
+    fn file_name(&self)  -> &str                             { &self.file_name }
+    fn is_dir(&self)     -> bool                             { self.is_dir }
+    fn is_file(&self)    -> bool                             { !self.is_dir }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { self.header.eof_bytes() as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { self.header.revised.map(super::Timestamp::DateTime).ok_or(anyhow!("Bad Date")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { self.header.created.map(super::Timestamp::DateTime).ok_or(anyhow!("Bad Date")) }
+    fn blocks(&self)     -> u64                              { self.blocks as u64 }
+    fn readonly(&self)   -> bool                             { self.header.protection & OWNER_DENY_WRITE != 0 }
+    fn protected(&self)  -> bool                             { self.header.protection & OWNER_DENY_DELETE != 0 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::test::*;
+
+    fn names(fs: &Ods1Fs<TestDev>, path: &str) -> Vec<String> {
+        fs.read_dir(path).unwrap().map(|e| e.path().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!((DEFAULT_UIC, "FOO.TXT".to_string(), None), split_path("FOO.TXT").unwrap());
+        assert_eq!((Uic { group: 0o200, member: 0o200 }, "FOO.TXT".to_string(), Some(0o12)), split_path("[200,200]FOO.TXT;12").unwrap());
+        assert_eq!((MFD_UIC, "001001.DIR".to_string(), None), split_path("[0,0]001001.DIR").unwrap());
+        assert_eq!((DEFAULT_UIC, "FOO.".to_string(), None), split_path("FOO").unwrap());
+        assert!(split_path("FOO.TXT;9").is_err());
+        assert_eq!(Some(Uic { group: 0o30, member: 0o4 }), ufd_uic("030004.DIR"));
+        assert_eq!(None, ufd_uic("000000.DIR"));
+        assert_eq!("030004.DIR", ufd_name(Uic { group: 0o30, member: 0o4 }));
+    }
+
+    #[test]
+    fn test_dates() {
+        let d = NaiveDate::from_ymd_opt(2023, 1, 19).unwrap().and_hms_opt(12, 13, 14).unwrap();
+        assert_eq!(*b"19JAN23", encode_date(d.date()));
+        assert_eq!(*b"121314", encode_time(d));
+        assert_eq!(Some(d), decode_datetime(b"19JAN23", b"121314"));
+        assert_eq!(Some(NaiveDate::from_ymd_opt(1979, 12, 1).unwrap()), decode_date(b"01DEC79"));
+        assert_eq!(None, decode_date(b"\0\0\0\0\0\0\0"));
+    }
+
+    #[test]
+    fn test_mkfs() {
+        let fs = Ods1Fs::mkfs(TestDev(vec![0; 512*2000])).expect("Create ODS-1 FS");
+        let raw = fs.image.read_blocks(HOME_BLOCK, 1).unwrap();
+        assert_eq!(FORMAT_TYPE, &raw.as_bytes()[496..508]);
+        let fs = Ods1Fs::new(TestDev(fs.image.0.clone())).expect("Reopen ODS-1 FS");
+        assert_eq!(125, fs.home.max_files);
+        assert_eq!(vec!["[0,0]INDEXF.SYS;1", "[0,0]BITMAP.SYS;1", "[0,0]BADBLK.SYS;1", "[0,0]000000.DIR;1", "[0,0]CORIMG.SYS;1", "[0,0]001001.DIR;1"],
+                   names(&fs, "/"));
+        assert!(fs.stat("[0,0]001001.DIR").unwrap().is_dir());
+        assert_eq!(19 * BLOCK_SIZE, fs.read_file("[0,0]INDEXF.SYS").unwrap().len());
+        assert_eq!(fs.image.read_blocks(HOME_BLOCK, 1).unwrap().as_bytes(), &fs.read_file("[0,0]INDEXF.SYS").unwrap().as_bytes()[512..1024]);
+        assert_eq!(vec![true, true, true, true, true, true, false], fs.index_map[0..7]);
+        assert_eq!(2000 - 22, fs.free_blocks()); // INDEXF (19), BITMAP (2) and the MFD. The [1,1] UFD is empty.
+        assert!(Ods1Fs::image_is(&fs.image));
+        assert!(!Ods1Fs::image_is(&crate::fs::rt11::RT11FS::mkfs(TestDev(vec![0; 512*2000])).unwrap().image));
+    }
+
+    #[test]
+    fn test_write_versions() {
+        let mut fs = Ods1Fs::mkfs(TestDev(vec![0; 512*2000])).expect("Create ODS-1 FS");
+        fs.write_file("A.TXT", &incrementing(1000)).expect("write_file failed");
+        fs.write_file("A.TXT", &incrementing(100)).expect("write_file failed");
+        fs.write_file("[200,200]B.TXT", &incrementing(2000)).expect("write_file failed");
+        fs.write_file_with_options("C.SAV", &incrementing(5000), &CreateOptions { contiguous: true, ..CreateOptions::default() })
+            .expect("write_file failed");
+        // Replacing a version that won't fit leaves the old one (and doesn't use up a header)
+        let (free, headers) = (fs.free_blocks(), fs.index_map.iter().filter(|u| **u).count());
+        assert!(fs.write_file("A.TXT;1", &vec![0; (free + 1) * BLOCK_SIZE]).is_err());
+        assert_eq!((free, headers), (fs.free_blocks(), fs.index_map.iter().filter(|u| **u).count()));
+        assert_eq!(incrementing(1000), fs.read_file("A.TXT;1").unwrap().into_vec());
+        fs.write_file("A.TXT;1", &incrementing(1000)).expect("write_file failed");
+
+        let fs = Ods1Fs::new(TestDev(fs.image.0.clone())).expect("Reopen ODS-1 FS");
+        assert_eq!(vec!["[1,1]A.TXT;1", "[1,1]A.TXT;2", "[1,1]C.SAV;1"], names(&fs, "[1,1]"));
+        assert_eq!(free, fs.free_blocks());
+        assert_eq!(vec!["[200,200]B.TXT;1"], names(&fs, "[200,200]"));
+        assert!(fs.stat("[0,0]200200.DIR").is_some());
+        assert_eq!(incrementing(100), fs.read_file("A.TXT").unwrap().into_vec());
+        assert_eq!(incrementing(1000), fs.read_file("A.TXT;1").unwrap().into_vec());
+        assert_eq!(incrementing(2000), fs.read_file("[200,200]B.TXT").unwrap().into_vec());
+        assert_eq!(incrementing(5000), fs.read_file("C.SAV").unwrap().into_vec());
+        assert_eq!(1000, fs.stat("A.TXT;1").unwrap().len());
+        let c = fs.lookup("C.SAV").unwrap().2;
+        let header = fs.read_header(c.fid.num).unwrap();
+        assert_eq!(1, header.map.pointers.len());
+        let now = Local::now().naive_local().with_nanosecond(0).unwrap();
+        assert!(matches!(fs.stat("C.SAV").unwrap().created().unwrap(), crate::fs::Timestamp::DateTime(d) if d == now));
+    }
+
+    #[test]
+    fn test_delete_and_rename() {
+        let mut fs = Ods1Fs::mkfs(TestDev(vec![0; 512*2000])).expect("Create ODS-1 FS");
+        fs.write_file("X.TXT", &incrementing(10)).expect("write_file failed"); // So the UFD has a block already
+        let free = fs.free_blocks();
+        fs.write_file("A.TXT", &incrementing(1000)).expect("write_file failed");
+        let first = fs.lookup("A.TXT").unwrap().2.fid;
+        fs.delete("A.TXT").expect("delete failed");
+        assert_eq!(free, fs.free_blocks());
+        assert!(fs.stat("A.TXT").is_none());
+        assert!(fs.delete("[0,0]BITMAP.SYS").is_err());
+
+        fs.write_file("B.TXT", &incrementing(10)).expect("write_file failed");
+        let second = fs.lookup("B.TXT").unwrap().2.fid;
+        assert_eq!((first.num, first.seq + 1), (second.num, second.seq)); // Same header, new sequence number
+
+        fs.write_file("[30,4]C.TXT", &incrementing(20)).expect("write_file failed");
+        assert!(fs.rename_unchecked("B.TXT", "[30,4]C.TXT;1").is_err());
+        assert_eq!(incrementing(10), fs.read_file("B.TXT").unwrap().into_vec());
+        fs.delete("[30,4]C.TXT").expect("delete failed");
+        fs.rename("B.TXT", "[30,4]C.TXT").expect("rename failed");
+        let fs = Ods1Fs::new(TestDev(fs.image.0.clone())).expect("Reopen ODS-1 FS");
+        assert!(fs.stat("B.TXT").is_none());
+        assert_eq!(incrementing(10), fs.read_file("[30,4]C.TXT;1").unwrap().into_vec());
+        assert_eq!("C.TXT", fs.read_header(second.num).unwrap().name);
+
+        // A record for a file that's gone doesn't stop the rest from being listed
+        let mut fs = fs;
+        let x = fs.lookup("X.TXT").unwrap().2;
+        let header = fs.read_header(x.fid.num).unwrap();
+        fs.delete_file(&header).expect("delete_file failed");
+        assert_eq!(vec!["[30,4]C.TXT;1"], names(&fs, "/").into_iter().filter(|n| !n.starts_with("[0,0]")).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_append() {
+        let mut fs = Ods1Fs::mkfs(TestDev(vec![0; 512*2000])).expect("Create ODS-1 FS");
+        fs.write_file("A.TXT", &incrementing(700)).expect("write_file failed");
+        fs.write_file("B.TXT", &incrementing(10)).expect("write_file failed"); // So A can't just grow in place
+        fs.append_file("A.TXT", &incrementing(1000)).expect("append failed");
+        let mut expected = incrementing(700);
+        expected.extend(incrementing(1000));
+        assert_eq!(expected, fs.read_file("A.TXT").unwrap().into_vec());
+        let a = fs.lookup("A.TXT").unwrap().2;
+        let header = fs.read_header(a.fid.num).unwrap();
+        assert_eq!(2, header.map.pointers.len());
+        assert_eq!(4, header.allocated());
+    }
+
+    #[test]
+    fn test_multi_header() {
+        let mut fs = Ods1Fs::mkfs(TestDev(vec![0; 512*4000])).expect("Create ODS-1 FS");
+        for i in 0..220 {
+            fs.write_file(&format!("F{}.TXT", i), &incrementing(10)).expect("write_file failed");
+        }
+        fs.write_file("FILLER.TXT", &[]).expect("write_file failed"); // Make the header first, in case INDEXF.SYS grows
+        let filler = fs.free_blocks();
+        fs.append_file("FILLER.TXT", &vec![0; filler * BLOCK_SIZE]).expect("append failed");
+        for i in (0..220).step_by(2) {
+            fs.delete(&format!("F{}.TXT", i)).expect("delete failed");
+        }
+        // 110 one block holes, but only 102 retrieval pointers fit in a header.
+        let data: Vec<u8> = (0..110 * BLOCK_SIZE).map(|b| (b / BLOCK_SIZE) as u8).collect();
+        fs.write_file("BIG.TXT", &data).expect("write_file failed");
+
+        let fs = Ods1Fs::new(TestDev(fs.image.0.clone())).expect("Reopen ODS-1 FS");
+        let big = fs.lookup("BIG.TXT").unwrap().2;
+        let chain = fs.header_chain(&fs.read_header(big.fid.num).unwrap()).unwrap();
+        assert_eq!(vec![102, 8], chain.iter().map(|h| h.map.pointers.len()).collect::<Vec<_>>());
+        assert_eq!(1, chain[1].map.segment);
+        assert_eq!(data, fs.read_file("BIG.TXT").unwrap().into_vec());
+        assert_eq!(0, fs.free_blocks());
+    }
+
+    #[test]
+    fn test_protection() {
+        let mut fs = Ods1Fs::mkfs(TestDev(vec![0; 512*2000])).expect("Create ODS-1 FS");
+        fs.write_file("A.TXT", &incrementing(10)).expect("write_file failed");
+        let a = fs.lookup("A.TXT").unwrap().2;
+        assert_eq!("[RWED,RWED,RWED,R]", fs.read_header(a.fid.num).unwrap().protection_string());
+        fs.set_protected("A.TXT", true).expect("set_protected failed");
+        assert!(fs.stat("A.TXT").unwrap().protected());
+        assert!(fs.delete("A.TXT").is_err());
+        assert!(fs.rename("A.TXT", "B.TXT").is_err());
+        fs.set_protected("A.TXT", false).expect("set_protected failed");
+        fs.set_readonly("A.TXT", true).expect("set_readonly failed");
+        assert_eq!("[RWED,RED,RWED,R]", fs.read_header(a.fid.num).unwrap().protection_string());
+        assert!(fs.append_file("A.TXT", &incrementing(10)).is_err());
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+use bytebuffer::ByteBuffer;
+use chrono::{Datelike, NaiveDate};
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use super::{CreateOptions, FileSystem, Placement};
+
+// OS/8, the PDP-8's disk operating system. See the OS/8 Software Support Manual, chapter 4.
+//
+// The PDP-8 has 12 bit words. Blocks are 256 of them, which images (and block::rx::RX12) store as little endian 16 bit
+// words, so an OS/8 block is a 512 byte block here. Block 0 is the boot block and blocks 1-6 are the directory
+// segments. Files are contiguous, like RT-11's (whose directory is a descendant of this one), and are listed in the
+// order they sit on the disk: each segment says where its first file starts and the rest follow.
+//
+// Names are 6.2 SIXBIT characters. Text is packed 3 8 bit characters to 2 words, with the third character split
+// across the top 4 bits of both. Since that packing uses all 24 bits, it doubles as a lossless way to turn any file
+// into bytes: that's what reading and writing files does here, so a block of a file is 384 bytes.
+
+const WORDS_PER_BLOCK: usize = 256;
+const BYTES_PER_BLOCK: usize = WORDS_PER_BLOCK / 2 * 3;
+const SEGMENT_HEADER_WORDS: usize = 5;
+const FIRST_SEGMENT: usize = 1;
+const MAX_SEGMENTS: usize = 6;
+const FIRST_DATA_BLOCK: usize = FIRST_SEGMENT + MAX_SEGMENTS;
+const MAX_EXTRA_WORDS: usize = 8;
+const MAX_BLOCKS: usize = 3248; // OS/8 splits an RK05 into 2 units this size (RKA and RKB). mkfs makes one at most.
+
+#[derive(Clone)]
+pub struct Os8Fs<B: BlockDevice> {
+    pub image: B,
+    pub extra_words: usize, // Extra information words per file. The first is the date.
+    pub entries: Vec<DirEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryKind {
+    Permanent,
+    Empty,
+}
+
+#[derive(Clone)]
+pub struct DirEntry {
+    pub kind: EntryKind,
+    pub name: String,
+    pub extra: Vec<u16>,
+    pub block: usize,
+    pub length: usize,
+}
+
+impl<B: BlockDevice> Os8Fs<B> {
+    pub fn new(image: B) -> anyhow::Result<Os8Fs<B>> {
+        let (extra_words, entries) = Self::read_directory(&image)?;
+        Ok(Os8Fs { image, extra_words, entries })
+    }
+
+    pub fn image_is(image: &B) -> bool {
+        Self::read_directory(image).is_ok()
+    }
+
+    pub fn mkfs(mut image: B) -> anyhow::Result<Os8Fs<B>> {
+        let blocks = std::cmp::min(image.blocks(), MAX_BLOCKS);
+        if blocks <= FIRST_DATA_BLOCK { return Err(anyhow!("Device is too small for OS/8 ({} blocks)", blocks)) }
+        image.write_blocks(0, FIRST_DATA_BLOCK, &vec![0; FIRST_DATA_BLOCK * BLOCK_SIZE])?;
+        let mut fs = Os8Fs {
+            image,
+            extra_words: 1,
+            entries: vec![DirEntry { kind: EntryKind::Empty, name: String::new(), extra: vec![], block: FIRST_DATA_BLOCK, length: blocks - FIRST_DATA_BLOCK }],
+        };
+        fs.write_directory()?;
+        Ok(fs)
+    }
+
+    fn read_directory(image: &B) -> anyhow::Result<(usize, Vec<DirEntry>)> {
+        if image.blocks() <= FIRST_DATA_BLOCK { return Err(anyhow!("Too small for OS/8")) }
+        let mut entries = vec![];
+        let mut extra_words = None;
+        let mut block = FIRST_DATA_BLOCK;
+        let mut visited = vec![];
+        let mut segment = FIRST_SEGMENT;
+        loop {
+            visited.push(segment);
+            let words = read_words(image, segment, 1)?;
+            if words.iter().any(|w| *w > 0o7777) { return Err(anyhow!("Segment {} isn't 12 bit data", segment)) }
+            let count = negative(words[0]);
+            let extra = negative(words[4]);
+            if count == 0 || count > (WORDS_PER_BLOCK - SEGMENT_HEADER_WORDS) / 2 { return Err(anyhow!("Segment {} has a bad entry count ({:04o})", segment, words[0])) }
+            if words[1] as usize != block { return Err(anyhow!("Segment {} starts at block {}, not {}", segment, words[1], block)) }
+            if extra > MAX_EXTRA_WORDS || *extra_words.get_or_insert(extra) != extra { return Err(anyhow!("Segment {} has a bad extra word count ({:04o})", segment, words[4])) }
+            let mut w = SEGMENT_HEADER_WORDS;
+            for _ in 0..count {
+                let (kind, size) = if words.get(w) == Some(&0) { (EntryKind::Empty, 2) } else { (EntryKind::Permanent, 4 + extra + 1) };
+                if w + size > WORDS_PER_BLOCK { return Err(anyhow!("Segment {} entries run past its end", segment)) }
+                let entry = &words[w..w + size];
+                let length = negative(entry[size - 1]);
+                entries.push(match kind {
+                    EntryKind::Empty     => DirEntry { kind, name: String::new(), extra: vec![], block, length },
+                    EntryKind::Permanent => DirEntry { kind, name: decode_name(&entry[0..4]), extra: entry[4..4 + extra].to_vec(), block, length },
+                });
+                block += length;
+                w += size;
+            }
+            if block > image.blocks() { return Err(anyhow!("Files in segment {} run past the end of the device ({} > {})", segment, block, image.blocks())) }
+            segment = match words[2] as usize {
+                0 => break,
+                next if next > MAX_SEGMENTS || visited.contains(&next) => return Err(anyhow!("Segment {} has a bad link ({})", segment, next)),
+                next => next,
+            };
+        }
+        Ok((extra_words.unwrap_or(0), entries))
+    }
+
+    // Packs the entries into as few segments as they fit in.
+    fn write_directory(&mut self) -> anyhow::Result<()> {
+        let mut segments: Vec<(usize, Vec<u16>, usize)> = vec![]; // (first block, entry words, entries)
+        for e in self.entries.iter() {
+            let words: Vec<u16> = match e.kind {
+                EntryKind::Empty     => vec![0, negative(e.length as u16) as u16],
+                EntryKind::Permanent => encode_name(&e.name)?.into_iter().chain(e.extra.iter().copied()).chain([negative(e.length as u16) as u16]).collect(),
+            };
+            match segments.last_mut() {
+                Some((_, segment, count)) if SEGMENT_HEADER_WORDS + segment.len() + words.len() <= WORDS_PER_BLOCK => {
+                    segment.extend(words);
+                    *count += 1;
+                },
+                _ => segments.push((e.block, words, 1)),
+            }
+        }
+        if segments.len() > MAX_SEGMENTS { return Err(anyhow!("Directory full")) }
+        for (i, (block, entries, count)) in segments.iter().enumerate() {
+            let next = if i + 1 < segments.len() { FIRST_SEGMENT + i + 1 } else { 0 };
+            let mut words = vec![negative(*count as u16) as u16, *block as u16, next as u16, 0, negative(self.extra_words as u16) as u16];
+            words.extend(entries);
+            words.resize(WORDS_PER_BLOCK, 0);
+            write_words(&mut self.image, FIRST_SEGMENT + i, &words)?;
+        }
+        Ok(())
+    }
+
+    // The entries in the directory have to be in block order with no gaps, so this fixes up their block numbers and
+    // merges empty areas that end up next to each other.
+    fn tidy(&mut self) {
+        let mut block = FIRST_DATA_BLOCK;
+        let mut tidied: Vec<DirEntry> = vec![];
+        for mut e in std::mem::take(&mut self.entries) {
+            e.block = block;
+            block += e.length;
+            match tidied.last_mut() {
+                Some(last) if last.kind == EntryKind::Empty && e.kind == EntryKind::Empty => last.length += e.length,
+                _ if e.kind == EntryKind::Empty && e.length == 0 => {},
+                _ => tidied.push(e),
+            }
+        }
+        self.entries = tidied;
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        let name = name.trim_start_matches('/');
+        self.entries.iter().position(|e| e.kind == EntryKind::Permanent && e.name == name)
+    }
+
+    pub fn volume_blocks(&self) -> usize {
+        self.entries.last().map(|e| e.block + e.length).unwrap_or(FIRST_DATA_BLOCK)
+    }
+}
+
+fn read_words<B: BlockDevice>(image: &B, block: usize, count: usize) -> anyhow::Result<Vec<u16>> {
+    Ok(image.read_blocks(block, count)?.as_bytes().chunks_exact(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect())
+}
+
+fn write_words<B: BlockDevice>(image: &mut B, block: usize, words: &[u16]) -> anyhow::Result<()> {
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    image.write_blocks(block, words.len() / WORDS_PER_BLOCK, &bytes)
+}
+
+// Lengths and counts are stored as 12 bit 2's complement negative numbers.
+fn negative(word: u16) -> usize {
+    (0o10000 - word as usize) & 0o7777
+}
+
+// 3 characters in 2 words: the 1st and 2nd in the low 8 bits and the 3rd split between the high 4 bits of each.
+pub fn unpack_text(words: &[u16]) -> Vec<u8> {
+    words.chunks(2)
+        .flat_map(|w| {
+            let (a, b) = (w[0] & 0o7777, w.get(1).copied().unwrap_or(0) & 0o7777);
+            [a as u8, b as u8, ((a >> 8) << 4 | b >> 8) as u8]
+        })
+        .collect()
+}
+
+pub fn pack_text(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks(3)
+        .flat_map(|c| {
+            let c: Vec<u16> = (0..3).map(|i| c.get(i).copied().unwrap_or(0) as u16).collect();
+            [(c[2] >> 4) << 8 | c[0], (c[2] & 0xf) << 8 | c[1]]
+        })
+        .collect()
+}
+
+fn sixbit(c: char) -> anyhow::Result<u16> {
+    match c {
+        'A'..='Z' | '0'..='9' => Ok(c as u16 & 0o77),
+        _ => Err(anyhow!("Bad character '{}' (OS/8 names can only have letters and digits)", c)),
+    }
+}
+
+// "NAME.EX" -> 4 words (NA ME .. EX). Unused characters are 0.
+pub fn encode_name(name: &str) -> anyhow::Result<Vec<u16>> {
+    let name = name.trim_start_matches('/');
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 6 || ext.len() > 2 { return Err(anyhow!("Bad OS/8 filename {:?} (should be 6.2 characters)", name)) }
+    let mut chars = [0; 8];
+    for (i, c) in base.chars().enumerate() { chars[i] = sixbit(c)? }
+    for (i, c) in ext.chars().enumerate() { chars[6 + i] = sixbit(c)? }
+    Ok(chars.chunks(2).map(|c| c[0] << 6 | c[1]).collect())
+}
+
+pub fn decode_name(words: &[u16]) -> String {
+    let chars = |words: &[u16]| -> String {
+        words.iter().flat_map(|w| [w >> 6 & 0o77, w & 0o77])
+            .filter(|c| *c != 0)
+            .map(|c| if c < 0o40 { (c + 0o100) as u8 as char } else { c as u8 as char })
+            .collect()
+    };
+    let ext = chars(&words[3..4]);
+    if ext.is_empty() { chars(&words[0..3]) } else { format!("{}.{}", chars(&words[0..3]), ext) }
+}
+
+// Month, day and the year mod 8 (since 1970): MMMMDDDDDYYY.
+pub fn encode_date(date: NaiveDate) -> u16 {
+    (date.month() as u16) << 8 | (date.day() as u16) << 3 | ((date.year() - 1970) & 7) as u16
+}
+
+pub fn decode_date(word: u16) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt(1970 + (word & 7) as i32, (word >> 8 & 0o17) as u32, (word >> 3 & 0o37) as u32)
+}
+
+impl<B: BlockDevice> FileSystem for Os8Fs<B> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        "OS/8"
+    }
+
+    fn dir_iter<'a>(&'a self, _path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.entries.iter().map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn read_dir<'a>(&'a self, _path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.entries.iter().filter(|e| e.kind == EntryKind::Permanent).map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        Some(Box::new(&self.entries[self.find(name)?]))
+    }
+
+    fn free_blocks(&self) -> usize {
+        self.entries.iter().filter(|e| e.kind == EntryKind::Empty).map(|e| e.length).sum()
+    }
+
+    fn used_blocks(&self) -> usize {
+        self.volume_blocks() - self.free_blocks()
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let Some(i) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        let e = &self.entries[i];
+        Ok(ByteBuffer::from_vec(unpack_text(&read_words(&self.image, e.block, e.length)?)))
+    }
+
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    // Files are always contiguous, so `contiguous` doesn't change anything.
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        if options.placement != Placement::default() || !options.prefix.is_empty() {
+            return Err(anyhow!("{}: OS/8 filesystems don't support placement or prefix blocks", name));
+        }
+        let name = name.trim_start_matches('/');
+        encode_name(name)?;
+        let saved = self.entries.clone();
+        if let Some(i) = self.find(name) {
+            self.entries[i].kind = EntryKind::Empty;
+            self.tidy();
+        }
+        let mut words = pack_text(contents);
+        let length = words.len().div_ceil(WORDS_PER_BLOCK);
+        words.resize(length * WORDS_PER_BLOCK, 0);
+        let Some(i) = self.entries.iter().position(|e| e.kind == EntryKind::Empty && e.length >= length) else {
+            self.entries = saved;
+            return Err(anyhow!("No room for {} ({} blocks)", name, length));
+        };
+        let block = self.entries[i].block;
+        let mut extra = vec![0; self.extra_words];
+        if let Some(date) = extra.first_mut() { *date = encode_date(Local::now().naive_local().date()) }
+        self.entries[i].length -= length;
+        self.entries.insert(i, DirEntry { kind: EntryKind::Permanent, name: name.to_string(), extra, block, length });
+        self.tidy();
+        if let Err(e) = self.write_directory() {
+            self.entries = saved;
+            return Err(e);
+        }
+        write_words(&mut self.image, block, &words)
+    }
+
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(i) = self.find(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.entries[i].kind = EntryKind::Empty;
+        self.entries[i].name.clear();
+        self.tidy();
+        self.write_directory()
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let Some(i) = self.find(src) else { return Err(anyhow!("File not found: {}", src)) };
+        let dest = dest.trim_start_matches('/');
+        encode_name(dest)?;
+        self.entries[i].name = dest.to_string();
+        self.write_directory()
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+impl<B: BlockDevice> Debug for Os8Fs<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "OS/8 FS: {} blocks, {} extra words", self.volume_blocks(), self.extra_words)?;
+        for e in self.entries.iter() {
+            writeln!(f, "{:#?}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl DirEntry {
+    pub fn date(&self) -> Option<NaiveDate> {
+        self.extra.first().and_then(|d| decode_date(*d))
+    }
+}
+
+impl Debug for DirEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = self.date().map(|d| d.to_string()).unwrap_or(" No Date".to_string());
+        if f.alternate() {
+            write!(f, "{:<9} {:<10} {:5} @ {:<5} {}",
+                   match self.kind { EntryKind::Permanent => "Permanent", EntryKind::Empty => "Empty" },
+                   date, self.length, self.block,
+                   if self.extra.len() <= 1 { self.name.clone() } else { format!("{:<9} [{}]", self.name, self.extra[1..].iter().map(|w| format!("{:04o}", w)).collect::<Vec<_>>().join(",")) })
+        } else {
+            write!(f, "{:10} {:6} {}", date, self.length,
+                   match self.kind { EntryKind::Permanent => &self.name, EntryKind::Empty => " <empty>" })
+        }
+    }
+}
+
+impl super::DirEntry for &DirEntry {
+    fn path(&self)       -> &str                             { &self.name }
+    fn file_name(&self)  -> &str                             { &self.name }
+    fn is_dir(&self)     -> bool                             { false }
+    fn is_file(&self)    -> bool                             { self.kind == EntryKind::Permanent }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { (self.length * BYTES_PER_BLOCK) as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { self.date().map(super::Timestamp::Date).ok_or(anyhow!("Bad Date")) }
+    fn blocks(&self)     -> u64                              { self.length as u64 }
+    fn readonly(&self)   -> bool                             { false }
+    fn protected(&self)  -> bool                             { false }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::test::*;
+    use crate::block::PhysicalBlockDevice;
+    use crate::block::img::IMG;
+    use crate::block::rx::{RX, RX12, RX01_GEOMETRY};
+
+    fn rx01() -> RX12<RX<IMG>> {
+        RX12(RX(IMG::from_raw(vec![0; RX01_GEOMETRY.bytes()], RX01_GEOMETRY)))
+    }
+
+    #[test]
+    fn test_names_and_text() {
+        assert_eq!(vec![0o1617, 0o2401, 0o6100, 0o2001], encode_name("NOTA1.PA").unwrap());
+        assert_eq!("NOTA1.PA", decode_name(&encode_name("NOTA1.PA").unwrap()));
+        assert_eq!("PIP", decode_name(&encode_name("PIP").unwrap()));
+        assert!(encode_name("TOOLONGX.PA").is_err());
+        assert!(encode_name("FOO.BAR").is_err());
+        assert!(encode_name("FOO-1.PA").is_err());
+
+        assert_eq!(vec![0o2101, 0o1502], pack_text(b"ABC"));
+        assert_eq!(b"ABC".to_vec(), unpack_text(&[0o2101, 0o1502]));
+        let bytes = incrementing(3 * 256);
+        assert_eq!(bytes, unpack_text(&pack_text(&bytes)));
+
+        let date = NaiveDate::from_ymd_opt(1977, 12, 31).unwrap();
+        assert_eq!(Some(date), decode_date(encode_date(date)));
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut fs = Os8Fs::mkfs(rx01()).expect("Create OS/8 FS");
+        assert_eq!((494 - 7, 494), (fs.free_blocks(), fs.volume_blocks()));
+        let big = incrementing(10 * 384 + 1);
+        fs.write_file("BIG.PA", &big).expect("write_file failed");
+        fs.write_file("SMALL.TX", b"Hello\r\n\x1a").expect("write_file failed");
+        fs.write_file("EMPTY", &[]).expect("write_file failed");
+
+        let mut fs = Os8Fs::new(RX12(RX(IMG::from_raw(fs.image.physical_device().as_vec().unwrap(), RX01_GEOMETRY)))).expect("Reopen OS/8 FS");
+        assert!(!crate::fs::rt11::RT11FS::image_is(&fs.image));
+        assert!(!Os8Fs::image_is(&fs.image.0)); // The raw sectors don't look like OS/8
+        let mut padded = big.clone();
+        padded.resize(11 * 384, 0);
+        assert_eq!(padded, fs.read_file("BIG.PA").unwrap().into_vec());
+        assert_eq!(b"Hello\r\n\x1a", &fs.read_file("SMALL.TX").unwrap().as_bytes()[0..8]);
+        assert_eq!(vec!["BIG.PA", "SMALL.TX", "EMPTY"], fs.read_dir("").unwrap().map(|e| e.path().to_owned()).collect::<Vec<_>>());
+        assert_eq!((7, 18), (fs.entries[0].block, fs.entries[1].block));
+        assert_eq!(NaiveDate::from_ymd_opt(1975, 1, 19), fs.entries[0].date()); // 2023 only fits as 1975
+
+        fs.delete("BIG.PA").expect("delete failed");
+        fs.rename("SMALL.TX", "HELLO.TX").expect("rename failed");
+        fs.write_file("NEW", &incrementing(2 * 384)).expect("write_file failed");
+        let fs = Os8Fs::new(fs.image).expect("Reopen OS/8 FS");
+        assert_eq!(vec![(EntryKind::Permanent, "NEW", 7, 2), (EntryKind::Empty, "", 9, 9), (EntryKind::Permanent, "HELLO.TX", 18, 1),
+                        (EntryKind::Permanent, "EMPTY", 19, 0), (EntryKind::Empty, "", 19, 494 - 19)],
+                   fs.entries.iter().map(|e| (e.kind, e.name.as_str(), e.block, e.length)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut fs = Os8Fs::mkfs(TestDev(vec![0; 512 * 4000])).expect("Create OS/8 FS");
+        assert_eq!(MAX_BLOCKS, fs.volume_blocks());
+        // Permanent entries are 6 words, so 41 fit in a segment (and the last one has room for the empty entry too).
+        for i in 0..6 * 41 {
+            fs.write_file(&format!("F{}", i), &[i as u8; 10]).expect("write_file failed");
+        }
+        assert!(fs.write_file("F246", &[1]).is_err());
+        assert_eq!(6 * 41 + 1, fs.entries.len());
+        let fs = Os8Fs::new(TestDev(fs.image.0.clone())).expect("Reopen OS/8 FS");
+        assert_eq!(6 * 41, fs.read_dir("").unwrap().count());
+        assert_eq!(vec![57; 10], fs.read_file("F57").unwrap().as_bytes()[0..10]);
+        assert_eq!(MAX_BLOCKS - FIRST_DATA_BLOCK - 6 * 41, fs.free_blocks());
+        let links: Vec<u16> = (1..=6).map(|s| read_words(&fs.image, s, 1).unwrap()[2]).collect();
+        assert_eq!(vec![2, 3, 4, 5, 6, 0], links);
+    }
+}
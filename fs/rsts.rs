@@ -0,0 +1,1035 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::fmt::{Debug, Display};
+
+use anyhow::{anyhow, Context};
+use bytebuffer::ByteBuffer;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use super::{CreateOptions, FileSystem, Placement};
+use super::xxdp;
+
+// RSTS/E's disk structure (RDS). RDS 0.0 is what RSTS/E used up to V7; V8 brought RDS 1.1 (and V9 RDS 1.2), which
+// moved the accounts from the MFD into a GFD per group. See the RSTS/E System Manager's Guide and Internals Manual,
+// and Paul Koning's rstsflx.
+//
+// Everything is addressed by device cluster number (DCN): the disk is split into device clusters that are the
+// smallest power of 2 blocks that keeps the count under 65536. Space is allocated in pack clusters (a power of 2
+// multiple of the device cluster size, starting at DCN 1), and the SATT.SYS file in [0,1] holds a bit per pack
+// cluster. Files are made of file clusters, which are a power of 2 multiple of the pack cluster size.
+//
+// Directories are made of up to 7 clusters and are read as blocks of 31 16 byte entries. The last 16 bytes of every
+// block is a cluster map: the directory's cluster size and the DCNs of its clusters. Entries point at each other with
+// links that say which cluster, block and entry they're in. The first entry is the label, which links to a chain of
+// name entries, one per file. Each name entry links to an accounting entry (size, dates, cluster size) and a chain of
+// retrieval entries, each of which lists 7 of the file's clusters.
+//
+// RDS 0.0 disks have the pack label as the label of the MFD, at DCN 1, and the MFD holds a name entry per account
+// that points at its UFD. RDS 1.x disks keep the MFD wherever the pack label says. Its second block is a table of the
+// GFDs' DCNs, by group, and the second block of each GFD is a table of its UFDs' DCNs (the third is a table of links
+// to the accounts' name entries).
+
+const ENTRY_WORDS: usize = 8;
+const ENTRIES_PER_BLOCK: usize = 31; // The 32nd is the cluster map
+const MAP_OFFSET: usize = 0o760;
+const MAX_DIR_CLUSTERS: usize = 7;
+const LINK_FLAGS: u16 = 0o17;
+const UL_USE: u16 = 0o1;       // The entry is in use
+const LABEL_MARKER: u16 = 0o177777;
+
+// Name entry status bits
+const US_NOX: u8 = 0o20;  // Contiguous (no extending)
+const US_NOK: u8 = 0o40;  // Can't be deleted or renamed
+const US_UFD: u8 = 0o100; // An account (MFD and GFD only)
+
+const RDS0: u16 = 0;
+const RDS11: u16 = 0x0101;
+const RDS12: u16 = 0x0102;
+
+// Protection codes are a byte of bits, read and write for owner, group and world, then executable and privileged.
+const DEFAULT_PROTECTION: u8 = 60; // Nobody but the owner gets to read or write
+const DEFAULT_RTS: &str = "RT11  ";
+
+pub const SYSTEM_ACCOUNT: Ppn = Ppn { project: 0, programmer: 1 };
+pub const LIBRARY_ACCOUNT: Ppn = Ppn { project: 1, programmer: 2 }; // SY:$, where names without an account go
+
+type Entry = [u16; ENTRY_WORDS];
+
+#[derive(Clone)]
+pub struct RstsFs<B: BlockDevice> {
+    pub image: B,
+    pub label: PackLabel,
+    pub dcs: usize, // Device cluster size
+    pub mfd: Dir,
+    pub gfds: Vec<(u8, Dir)>, // RDS 1.x only
+    pub accounts: Vec<Account>,
+    satt: Vec<bool>,          // A bit per pack cluster, set when it's used
+    satt_blocks: Vec<usize>,  // Where SATT.SYS is
+}
+
+// Which directory to work on.
+#[derive(Clone, Copy, Debug)]
+enum Which {
+    Mfd,
+    Gfd(usize),
+    Ufd(usize),
+}
+
+impl<B: BlockDevice> RstsFs<B> {
+    pub fn new(image: B) -> anyhow::Result<RstsFs<B>> {
+        let dcs = Self::device_cluster_size(image.blocks());
+        let label = PackLabel::from_entry(&read_entry(&image, dcs)?)?;
+        if label.pcs < dcs { return Err(anyhow!("Pack cluster size {} is smaller than the device cluster size {}", label.pcs, dcs)) }
+        let mfd = match label.level {
+            RDS0 => Dir::read(&image, dcs, 1, 0),
+            _    => Dir::read(&image, dcs, label.mfd_dcn, 2),
+        }.with_context(|| "MFD")?;
+        let mut fs = RstsFs { image, label, dcs, mfd, gfds: vec![], accounts: vec![], satt: vec![], satt_blocks: vec![] };
+        fs.read_accounts()?;
+
+        let system = fs.accounts.iter().position(|a| a.ppn == SYSTEM_ACCOUNT).ok_or_else(|| anyhow!("No [0,1] account"))?;
+        let satt = fs.files(system)?.into_iter().find(|f| f.name == "SATT.SYS").ok_or_else(|| anyhow!("No [0,1]SATT.SYS"))?;
+        fs.satt_blocks = fs.file_blocks(&satt);
+        let bits = fs.image.read_blocks_list(&fs.satt_blocks)?;
+        let pack_clusters = fs.pack_clusters();
+        if bits.len() * 8 < pack_clusters { return Err(anyhow!("SATT.SYS is too short ({} bytes for {} pack clusters)", bits.len(), pack_clusters)) }
+        fs.satt = (0..pack_clusters).map(|pcn| bits[pcn / 8] & 1 << (pcn % 8) != 0).collect();
+        Ok(fs)
+    }
+
+    pub fn image_is(image: &B) -> bool {
+        Self::new_ref(image).is_ok()
+    }
+
+    // Just enough of new() to be sure, without taking the image.
+    fn new_ref(image: &B) -> anyhow::Result<()> {
+        let dcs = Self::device_cluster_size(image.blocks());
+        let label = PackLabel::from_entry(&read_entry(image, dcs)?)?;
+        let mfd = match label.level {
+            RDS0 => Dir::read(image, dcs, 1, 0)?,
+            _    => Dir::read(image, dcs, label.mfd_dcn, 2)?,
+        };
+        if label.level != RDS0 && mfd.entry(0)?[1] != LABEL_MARKER { return Err(anyhow!("Bad MFD label")) }
+        Ok(())
+    }
+
+    fn device_cluster_size(blocks: usize) -> usize {
+        let mut dcs = 1;
+        while blocks / dcs > 0xffff { dcs *= 2 }
+        dcs
+    }
+
+    fn pack_clusters(&self) -> usize {
+        (self.image.blocks() / self.dcs - 1) / (self.label.pcs / self.dcs)
+    }
+
+    // Directories get a few blocks per cluster so they can hold a good number of files.
+    fn dir_cluster_size(&self) -> usize {
+        std::cmp::max(self.label.pcs, 4)
+    }
+
+    // RDS 1.1, with [0,1] (holding SATT.SYS and BADB.SYS) and [1,2].
+    pub fn mkfs(image: B) -> anyhow::Result<RstsFs<B>> {
+        Self::mkfs_with_level(image, RDS11)
+    }
+
+    fn mkfs_with_level(image: B, level: u16) -> anyhow::Result<RstsFs<B>> {
+        let dcs = Self::device_cluster_size(image.blocks());
+        let label = PackLabel { first_link: UL_USE, mfd_dcn: 0, level, pcs: dcs, status: 0, id: "PDPFS".to_string() };
+        let mut fs = RstsFs { image, label, dcs, mfd: Dir::new(1, 0, 0), gfds: vec![], accounts: vec![], satt: vec![], satt_blocks: vec![] };
+        fs.satt = vec![false; fs.pack_clusters()];
+        let dir_cs = fs.dir_cluster_size();
+        if level == RDS0 {
+            // The MFD starts at DCN 1 and its label is the pack label.
+            fs.satt[0..dir_cs / fs.label.pcs].fill(true);
+            fs.mfd = Dir::new(dir_cs, 1, 0);
+            fs.mfd.set_entry(0, &fs.label.entry())?;
+        } else {
+            fs.satt[0] = true;
+            fs.label.mfd_dcn = fs.alloc_clusters(dir_cs)?;
+            let mut label_block = Dir::new(fs.label.pcs, 1, 0);
+            label_block.set_entry(0, &fs.label.entry())?;
+            label_block.write(&mut fs.image, dcs)?;
+            fs.mfd = Dir::new(dir_cs, fs.label.mfd_dcn, 2);
+            fs.mfd.set_entry(0, &dir_label(Ppn { project: 255, programmer: 255 }, "MFD")?)?;
+        }
+        fs.write_dir(Which::Mfd)?;
+        fs.account_for(SYSTEM_ACCOUNT, true)?;
+        fs.account_for(LIBRARY_ACCOUNT, true)?;
+
+        let contiguous = CreateOptions { contiguous: true, ..CreateOptions::default() };
+        let satt_bytes = fs.satt.len().div_ceil(8).next_multiple_of(BLOCK_SIZE);
+        fs.write_file_with_options("[0,1]SATT.SYS", &vec![0; satt_bytes], &contiguous)?;
+        fs.write_file_with_options("[0,1]BADB.SYS", &[], &contiguous)?;
+        for f in ["[0,1]SATT.SYS", "[0,1]BADB.SYS"] { fs.set_protected(f, true)? }
+        let satt = fs.raw_stat("[0,1]SATT.SYS").expect("can't happen").1;
+        fs.satt_blocks = fs.file_blocks(&satt);
+        fs.write_satt()?;
+        Ok(fs)
+    }
+
+    fn read_accounts(&mut self) -> anyhow::Result<()> {
+        let mut ufds = vec![];
+        if self.label.level == RDS0 {
+            for link in self.mfd.chain(self.mfd.entry(0)?[0])? {
+                let ne = self.mfd.entry(link)?;
+                if ne[4] as u8 & US_UFD != 0 && ne[7] != 0 { ufds.push((Ppn::from_word(ne[1]), ne[7])) }
+            }
+        } else {
+            for group in 0..255 {
+                let dcn = self.mfd.table(1, group);
+                if dcn == 0 { continue }
+                let gfd = Dir::read(&self.image, self.dcs, dcn, 2).with_context(|| format!("GFD for group {}", group))?;
+                for programmer in 0..255 {
+                    let dcn = gfd.table(1, programmer);
+                    if dcn != 0 { ufds.push((Ppn { project: group as u8, programmer: programmer as u8 }, dcn)) }
+                }
+                self.gfds.push((group as u8, gfd));
+            }
+        }
+        for (ppn, dcn) in ufds {
+            let ufd = Dir::read(&self.image, self.dcs, dcn, 0).with_context(|| format!("UFD for {}", ppn))?;
+            if ufd.entry(0)?[1] != LABEL_MARKER { return Err(anyhow!("Bad UFD label for {}", ppn)) }
+            self.accounts.push(Account { ppn, ufd });
+        }
+        Ok(())
+    }
+
+    fn dir(&self, which: Which) -> &Dir {
+        match which {
+            Which::Mfd    => &self.mfd,
+            Which::Gfd(g) => &self.gfds[g].1,
+            Which::Ufd(a) => &self.accounts[a].ufd,
+        }
+    }
+
+    fn dir_mut(&mut self, which: Which) -> &mut Dir {
+        match which {
+            Which::Mfd    => &mut self.mfd,
+            Which::Gfd(g) => &mut self.gfds[g].1,
+            Which::Ufd(a) => &mut self.accounts[a].ufd,
+        }
+    }
+
+    fn write_dir(&mut self, which: Which) -> anyhow::Result<()> {
+        let dcs = self.dcs;
+        match which {
+            Which::Mfd    => self.mfd.write(&mut self.image, dcs),
+            Which::Gfd(g) => self.gfds[g].1.write(&mut self.image, dcs),
+            Which::Ufd(a) => self.accounts[a].ufd.write(&mut self.image, dcs),
+        }
+    }
+
+    // Does nothing until mkfs has made SATT.SYS.
+    fn write_satt(&mut self) -> anyhow::Result<()> {
+        if self.satt_blocks.is_empty() { return Ok(()) }
+        let mut bytes = vec![0xff; self.satt_blocks.len() * BLOCK_SIZE]; // Clusters past the end are never free
+        for (pcn, used) in self.satt.iter().enumerate() {
+            if !used { bytes[pcn / 8] &= !(1 << (pcn % 8)) }
+        }
+        for (block, data) in self.satt_blocks.iter().zip(bytes.chunks(BLOCK_SIZE)) {
+            self.image.write_blocks(*block, 1, data)?;
+        }
+        Ok(())
+    }
+
+    // First fit. `blocks` is a multiple of the pack cluster size. Returns the first DCN.
+    fn alloc_clusters(&mut self, blocks: usize) -> anyhow::Result<u16> {
+        let want = blocks / self.label.pcs;
+        let mut run = 0;
+        for pcn in 0..self.satt.len() {
+            run = if self.satt[pcn] { 0 } else { run + 1 };
+            if run == want {
+                let start = pcn + 1 - want;
+                self.satt[start..=pcn].fill(true);
+                return Ok((1 + start * (self.label.pcs / self.dcs)) as u16);
+            }
+        }
+        Err(anyhow!("No room for {} contiguous blocks", blocks))
+    }
+
+    fn free_clusters(&mut self, dcn: u16, blocks: usize) {
+        let start = (dcn as usize - 1) / (self.label.pcs / self.dcs);
+        self.satt[start..start + blocks / self.label.pcs].fill(false);
+    }
+
+    // A free entry in the directory, growing it by a cluster if it's full. The entry is marked used.
+    fn alloc_entry(&mut self, which: Which) -> anyhow::Result<u16> {
+        let link = match self.dir(which).free_entry() {
+            Some(link) => link,
+            None => {
+                if self.dir(which).clusters.len() >= MAX_DIR_CLUSTERS { return Err(anyhow!("Directory is full")) }
+                let cluster_size = self.dir(which).cluster_size;
+                let dcn = self.alloc_clusters(cluster_size)?;
+                self.dir_mut(which).add_cluster(dcn);
+                self.dir(which).free_entry().expect("can't happen")
+            },
+        };
+        self.dir_mut(which).set_entry(link, &[UL_USE, 0, 0, 0, 0, 0, 0, 0])?;
+        Ok(link)
+    }
+
+    // The index of the account, making it (and its UFD, and on RDS 1.x its GFD) if it isn't there and `create` is set.
+    fn account_for(&mut self, ppn: Ppn, create: bool) -> anyhow::Result<usize> {
+        if let Some(a) = self.accounts.iter().position(|a| a.ppn == ppn) { return Ok(a) }
+        if !create { return Err(anyhow!("No account {}", ppn)) }
+        let dir_cs = self.dir_cluster_size();
+        let ufd_dcn = self.alloc_clusters(dir_cs)?;
+        let (which, ne_link) = if self.label.level == RDS0 {
+            let existing = self.mfd.chain(self.mfd.entry(0)?[0])?.into_iter()
+                .find(|l| self.mfd.entry(*l).is_ok_and(|ne| ne[4] as u8 & US_UFD != 0 && ne[1] == ppn.word()));
+            (Which::Mfd, existing)
+        } else {
+            let g = match self.gfds.iter().position(|(group, _)| *group == ppn.project) {
+                Some(g) => g,
+                None => {
+                    let dcn = self.alloc_clusters(dir_cs)?;
+                    let mut gfd = Dir::new(dir_cs, dcn, 2);
+                    gfd.set_entry(0, &dir_label(Ppn { project: ppn.project, programmer: 255 }, "GFD")?)?;
+                    self.mfd.set_table(1, ppn.project as usize, dcn);
+                    self.gfds.push((ppn.project, gfd));
+                    self.write_dir(Which::Mfd)?;
+                    self.gfds.len() - 1
+                },
+            };
+            self.gfds[g].1.set_table(1, ppn.programmer as usize, ufd_dcn);
+            (Which::Gfd(g), None)
+        };
+        match ne_link {
+            Some(link) => {
+                let mut ne = self.dir(which).entry(link)?;
+                ne[7] = ufd_dcn;
+                self.dir_mut(which).set_entry(link, &ne)?;
+            },
+            None => {
+                let ne_link = self.alloc_entry(which)?;
+                let ae_link = self.alloc_entry(which)?;
+                self.dir_mut(which).set_entry(ne_link, &[UL_USE, ppn.word(), 0, 0, US_UFD as u16, 0, ae_link, ufd_dcn])?;
+                self.dir_mut(which).append(ne_link)?;
+                if let Which::Gfd(g) = which { self.gfds[g].1.set_table(2, ppn.programmer as usize, ne_link) }
+            },
+        }
+        self.write_dir(which)?;
+        let mut ufd = Dir::new(dir_cs, ufd_dcn, 0);
+        ufd.set_entry(0, &dir_label(ppn, "UFD")?)?;
+        self.accounts.push(Account { ppn, ufd });
+        self.write_dir(Which::Ufd(self.accounts.len() - 1))?;
+        self.write_satt()?;
+        Ok(self.accounts.len() - 1)
+    }
+
+    pub fn files(&self, a: usize) -> anyhow::Result<Vec<FileEntry>> {
+        let ufd = &self.accounts[a].ufd;
+        // One damaged entry shouldn't hide the rest of the account.
+        Ok(ufd.chain(ufd.entry(0)?[0])?.into_iter().filter_map(|link| FileEntry::from_ufd(ufd, self.accounts[a].ppn, link).ok()).collect())
+    }
+
+    fn file_blocks(&self, f: &FileEntry) -> Vec<usize> {
+        f.clusters.iter().flat_map(|dcn| *dcn as usize * self.dcs..*dcn as usize * self.dcs + f.cluster_size).take(f.size).collect()
+    }
+
+    // Returns (account index, file)
+    fn raw_stat(&self, path: &str) -> Option<(usize, FileEntry)> {
+        let (ppn, name) = Ppn::split_path(path).ok()?;
+        let a = self.accounts.iter().position(|a| a.ppn == ppn)?;
+        Some((a, self.files(a).ok()?.into_iter().find(|f| f.name == name)?))
+    }
+
+    fn listing(&self, path: &str) -> anyhow::Result<Vec<FileEntry>> {
+        // "/" is every file on the volume, "[p,pn]" is just the files in that account.
+        let accounts = match path.trim_start_matches('/') {
+            "" => (0..self.accounts.len()).collect(),
+            p => {
+                let (ppn, rest) = Ppn::split_path(p)?;
+                if !p.starts_with('[') || !rest.is_empty() { return Err(anyhow!("Bad path")) }
+                vec![self.account_for_ref(ppn)?]
+            },
+        };
+        let mut files = vec![];
+        for a in accounts { files.extend(self.files(a)?) }
+        Ok(files)
+    }
+
+    fn account_for_ref(&self, ppn: Ppn) -> anyhow::Result<usize> {
+        self.accounts.iter().position(|a| a.ppn == ppn).ok_or_else(|| anyhow!("No account {}", ppn))
+    }
+
+    // Takes the file's entries out of its UFD (leaving its clusters allocated).
+    fn take_file(&mut self, a: usize, ne_link: u16) -> anyhow::Result<FileRecord> {
+        let ufd = &mut self.accounts[a].ufd;
+        let ne = ufd.entry(ne_link)?;
+        let ae = ufd.entry(ne[6])?;
+        let attr_links = ufd.chain(ae[0])?;
+        let re_links = ufd.chain(ne[7])?;
+        let record = FileRecord {
+            ne,
+            ae,
+            attrs: attr_links.iter().map(|l| ufd.entry(*l)).collect::<anyhow::Result<_>>()?,
+            clusters: re_links.iter().map(|l| ufd.entry(*l)).collect::<anyhow::Result<Vec<_>>>()?
+                              .into_iter().flat_map(|re| re[1..].to_vec()).filter(|dcn| *dcn != 0).collect(),
+        };
+        ufd.unlink(ne_link)?;
+        for link in [ne_link, ne[6]].into_iter().chain(attr_links).chain(re_links) {
+            ufd.set_entry(link, &[0; ENTRY_WORDS])?;
+        }
+        Ok(record)
+    }
+
+    // Puts the file's entries in the UFD, at the end of the name entry chain.
+    fn put_file(&mut self, a: usize, mut record: FileRecord) -> anyhow::Result<()> {
+        let which = Which::Ufd(a);
+        let ne_link = self.alloc_entry(which)?;
+        let ae_link = self.alloc_entry(which)?;
+        let attr_links = (0..record.attrs.len()).map(|_| self.alloc_entry(which)).collect::<anyhow::Result<Vec<_>>>()?;
+        let re_links = (0..record.clusters.len().div_ceil(ENTRY_WORDS - 1)).map(|_| self.alloc_entry(which)).collect::<anyhow::Result<Vec<_>>>()?;
+        let next = |links: &[u16], i: usize| links.get(i + 1).copied().unwrap_or(0) | UL_USE;
+
+        let ufd = &mut self.accounts[a].ufd;
+        for (i, (link, mut attr)) in attr_links.iter().zip(record.attrs).enumerate() {
+            attr[0] = next(&attr_links, i);
+            ufd.set_entry(*link, &attr)?;
+        }
+        for (i, (link, dcns)) in re_links.iter().zip(record.clusters.chunks(ENTRY_WORDS - 1)).enumerate() {
+            let mut re = [0; ENTRY_WORDS];
+            re[0] = next(&re_links, i);
+            re[1..=dcns.len()].copy_from_slice(dcns);
+            ufd.set_entry(*link, &re)?;
+        }
+        record.ae[0] = attr_links.first().copied().unwrap_or(0) | UL_USE;
+        ufd.set_entry(ae_link, &record.ae)?;
+        record.ne[0] = UL_USE;
+        record.ne[6] = ae_link;
+        record.ne[7] = re_links.first().copied().unwrap_or(0);
+        ufd.set_entry(ne_link, &record.ne)?;
+        ufd.append(ne_link)
+    }
+
+    // The name entry's protection code and status share a word.
+    fn set_status(&mut self, name: &str, f: impl FnOnce(&mut u8, &mut u8)) -> anyhow::Result<()> {
+        let Some((a, file)) = self.raw_stat(name) else { return Err(anyhow!("File not found: {}", name)) };
+        let ufd = &mut self.accounts[a].ufd;
+        let mut ne = ufd.entry(file.link)?;
+        let (mut protection, mut status) = ((ne[4] >> 8) as u8, ne[4] as u8);
+        f(&mut protection, &mut status);
+        ne[4] = (protection as u16) << 8 | status as u16;
+        ufd.set_entry(file.link, &ne)?;
+        self.write_dir(Which::Ufd(a))
+    }
+
+    // Like PIP's /PR switch. New files get <60>, and replacing a file keeps its code.
+    pub fn set_protection_code(&mut self, name: &str, code: u8) -> anyhow::Result<()> {
+        self.set_status(name, |protection, _| *protection = code)
+    }
+}
+
+trait ReadBlockList {
+    fn read_blocks_list(&self, blocks: &[usize]) -> anyhow::Result<Vec<u8>>;
+}
+
+impl<B: BlockDevice> ReadBlockList for B {
+    fn read_blocks_list(&self, blocks: &[usize]) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(blocks.len() * BLOCK_SIZE);
+        for b in blocks { data.extend_from_slice(self.read_blocks(*b, 1)?.as_bytes()) }
+        Ok(data)
+    }
+}
+
+fn read_entry<B: BlockDevice>(image: &B, dcs: usize) -> anyhow::Result<Entry> {
+    let block = image.read_blocks(dcs, 1)?;
+    Ok(entry_from(&block.as_bytes()[0..ENTRY_WORDS * 2]))
+}
+
+fn entry_from(raw: &[u8]) -> Entry {
+    let mut entry = [0; ENTRY_WORDS];
+    for (w, b) in entry.iter_mut().zip(raw.chunks_exact(2)) { *w = u16::from_le_bytes([b[0], b[1]]) }
+    entry
+}
+
+fn dir_label(ppn: Ppn, kind: &str) -> anyhow::Result<Entry> {
+    Ok([UL_USE, LABEL_MARKER, 0, 0, 0, 0, ppn.word(), radix50::pdp11::encode_word(kind)?])
+}
+
+fn today() -> (u16, u16) {
+    let now = Local::now().naive_local();
+    (xxdp::DirEntry::encode_date(Some(now.date())).unwrap_or(0),
+     (24 * 60 - (now.hour() * 60 + now.minute())) as u16) // Minutes until midnight
+}
+
+impl<B: BlockDevice> FileSystem for RstsFs<B> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        "RSTS/E"
+    }
+
+    fn dir_iter<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.listing(path)?.into_iter().map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        self.dir_iter(path)
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        Some(Box::new(self.raw_stat(name)?.1))
+    }
+
+    fn free_blocks(&self) -> usize {
+        self.satt.iter().filter(|used| !**used).count() * self.label.pcs
+    }
+
+    fn used_blocks(&self) -> usize {
+        self.image.blocks() - self.free_blocks()
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let Some((_, file)) = self.raw_stat(name) else { return Err(anyhow!("File not found: {}", name)) };
+        Ok(ByteBuffer::from_vec(self.image.read_blocks_list(&self.file_blocks(&file))?))
+    }
+
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        if options.placement != Placement::default() || !options.prefix.is_empty() {
+            return Err(anyhow!("{}: RSTS/E filesystems don't support placement or prefix blocks", name));
+        }
+        let (ppn, file) = Ppn::split_path(name)?;
+        let r50 = super::rt11::DirEntry::encode_filename(file)?;
+        let old = self.raw_stat(name);
+        if old.as_ref().is_some_and(|(_, f)| f.protected()) { return Err(anyhow!("{} is protected", name)) }
+        let protection = old.as_ref().map(|(_, f)| f.protection).unwrap_or(DEFAULT_PROTECTION);
+
+        let fcs = self.label.pcs;
+        let blocks = contents.len().div_ceil(BLOCK_SIZE);
+        let count = blocks.div_ceil(fcs);
+        let old_blocks = old.as_ref().map(|(_, f)| f.clusters.len() * f.cluster_size).unwrap_or(0);
+        if count * fcs > self.free_blocks() + old_blocks { return Err(anyhow!("Not enough room for {} ({} blocks)", name, blocks)) }
+        let a = self.account_for(ppn, true)?;
+
+        let (date, time) = today();
+        let rts = radix50::pdp11::encode(DEFAULT_RTS)?;
+        let rts = if blocks > 0xffff { [0, (blocks >> 16) as u16] } else { [rts[0], rts[1]] }; // Large files keep the top of the size here
+        let status = if options.contiguous { US_NOX } else { 0 };
+        // The old file is only taken out in memory, so if there turn out to be no clusters (or no directory entries)
+        // for the new one, putting the SATT and UFD back the way they were leaves it untouched.
+        let (satt, ufd) = (self.satt.clone(), self.accounts[a].ufd.clone());
+        let mut replace = || -> anyhow::Result<Vec<u16>> {
+            if let Some((_, f)) = &old {
+                for dcn in self.take_file(a, f.link)?.clusters {
+                    self.free_clusters(dcn, f.cluster_size);
+                }
+            }
+            let clusters: Vec<u16> = if options.contiguous {
+                match count {
+                    0 => vec![],
+                    _ => { let start = self.alloc_clusters(count * fcs)?; (0..count).map(|c| start + (c * fcs / self.dcs) as u16).collect() },
+                }
+            } else {
+                (0..count).map(|_| self.alloc_clusters(fcs)).collect::<anyhow::Result<_>>()?
+            };
+            self.put_file(a, FileRecord {
+                ne: [0, r50[0], r50[1], r50[2], (protection as u16) << 8 | status as u16, 0, 0, 0],
+                ae: [0, date, blocks as u16, date, time, rts[0], rts[1], fcs as u16],
+                attrs: vec![],
+                clusters: clusters.clone(),
+            })?;
+            Ok(clusters)
+        };
+        let clusters = match replace() {
+            Ok(clusters) => clusters,
+            Err(e) => { self.satt = satt; self.accounts[a].ufd = ufd; return Err(e) },
+        };
+        let mut data = contents.to_vec();
+        data.resize(blocks * BLOCK_SIZE, 0);
+        for (dcn, chunk) in clusters.iter().zip(data.chunks(fcs * BLOCK_SIZE)) {
+            self.image.write_blocks(*dcn as usize * self.dcs, chunk.len() / BLOCK_SIZE, chunk)?;
+        }
+        self.write_dir(Which::Ufd(a))?;
+        self.write_satt()
+    }
+
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some((a, file)) = self.raw_stat(name) else { return Err(anyhow!("File not found: {}", name)) };
+        if file.protected() { return Err(anyhow!("{} is protected", name)) }
+        let record = self.take_file(a, file.link)?;
+        for dcn in record.clusters {
+            self.free_clusters(dcn, file.cluster_size);
+        }
+        self.write_dir(Which::Ufd(a))?;
+        self.write_satt()
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let (ppn, file) = Ppn::split_path(dest)?;
+        let r50 = super::rt11::DirEntry::encode_filename(file)?;
+        let Some((a, entry)) = self.raw_stat(src) else { return Err(anyhow!("File not found: {}", src)) };
+        if entry.protected() { return Err(anyhow!("{} is protected", src)) }
+        // Moving to another account moves the entries. The data stays put. The account is made first since it can
+        // fail, and by then the entries would be gone.
+        let d = self.account_for(ppn, true)?;
+        let mut record = self.take_file(a, entry.link)?;
+        record.ne[1..4].copy_from_slice(&r50);
+        self.put_file(d, record)?;
+        self.write_dir(Which::Ufd(a))?;
+        self.write_dir(Which::Ufd(d))?;
+        self.write_satt()
+    }
+
+    fn set_protected(&mut self, name: &str, protected: bool) -> anyhow::Result<()> {
+        self.set_status(name, |_, status| if protected { *status |= US_NOK } else { *status &= !US_NOK })
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+impl<B: BlockDevice> Debug for RstsFs<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RstsFs")
+            .field("label", &self.label)
+            .field("dcs", &self.dcs)
+            .field("accounts", &self.accounts.iter().map(|a| a.ppn.to_string()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+// Project,programmer number: [p,pn], both decimal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ppn {
+    pub project: u8,
+    pub programmer: u8,
+}
+
+impl Ppn {
+    pub fn from_word(word: u16) -> Ppn {
+        Ppn { project: (word >> 8) as u8, programmer: word as u8 }
+    }
+
+    pub fn word(&self) -> u16 {
+        (self.project as u16) << 8 | self.programmer as u16
+    }
+
+    // "[200,200]FOO.BAS" -> ([200,200], "FOO.BAS"). Names without an account are in [1,2].
+    pub fn split_path(path: &str) -> anyhow::Result<(Ppn, &str)> {
+        let path = path.trim_start_matches('/');
+        let Some(rest) = path.strip_prefix('[') else { return Ok((LIBRARY_ACCOUNT, path)) };
+        let Some((ppn, name)) = rest.split_once(']') else { return Err(anyhow!("Missing ] in {}", path)) };
+        let bad = || anyhow!("Bad account [{}] (should be [project,programmer], both from 0 to 254)", ppn);
+        let Some((project, programmer)) = ppn.split_once(',') else { return Err(bad()) };
+        let parse = |n: &str| n.trim().parse::<u8>().ok().filter(|n| *n != 255).ok_or_else(bad);
+        Ok((Ppn { project: parse(project)?, programmer: parse(programmer)? }, name))
+    }
+}
+
+impl Display for Ppn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{},{}]", self.project, self.programmer)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PackLabel {
+    pub first_link: u16, // RDS 0.0: the start of the MFD's name entry chain
+    pub mfd_dcn: u16,    // RDS 1.x
+    pub level: u16,
+    pub pcs: usize,      // Pack cluster size
+    pub status: u16,
+    pub id: String,
+}
+
+impl PackLabel {
+    fn from_entry(e: &Entry) -> anyhow::Result<PackLabel> {
+        if e[1] != LABEL_MARKER { return Err(anyhow!("No pack label")) }
+        if ![RDS0, RDS11, RDS12].contains(&e[3]) { return Err(anyhow!("Unknown RDS level {:#06x}", e[3])) }
+        if !e[4].is_power_of_two() || e[4] > 64 { return Err(anyhow!("Bad pack cluster size {}", e[4])) }
+        if e[3] != RDS0 && e[2] == 0 { return Err(anyhow!("No MFD in pack label")) }
+        Ok(PackLabel {
+            first_link: e[0],
+            mfd_dcn: e[2],
+            level: e[3],
+            pcs: e[4] as usize,
+            status: e[5],
+            id: radix50::pdp11::decode(&e[6..8]).trim().to_string(),
+        })
+    }
+
+    fn entry(&self) -> Entry {
+        let id = radix50::pdp11::encode(&format!("{:<6}", self.id)).unwrap_or(vec![0, 0]);
+        [self.first_link, LABEL_MARKER, self.mfd_dcn, self.level, self.pcs as u16, self.status, id[0], id[1]]
+    }
+}
+
+// A directory (MFD, GFD or UFD), all of its blocks kept in memory.
+#[derive(Clone, Debug)]
+pub struct Dir {
+    cluster_size: usize,
+    clusters: Vec<u16>,
+    tables: usize, // Blocks after the first that hold tables instead of entries (RDS 1.x MFD and GFDs)
+    blocks: Vec<Vec<u8>>,
+}
+
+impl Dir {
+    fn new(cluster_size: usize, dcn: u16, tables: usize) -> Dir {
+        let mut dir = Dir { cluster_size, clusters: vec![], tables, blocks: vec![] };
+        dir.add_cluster(dcn);
+        dir
+    }
+
+    fn read<B: BlockDevice>(image: &B, dcs: usize, dcn: u16, tables: usize) -> anyhow::Result<Dir> {
+        if dcn == 0 || (dcn as usize + 1) * dcs > image.blocks() { return Err(anyhow!("Bad directory DCN {}", dcn)) }
+        let first = image.read_blocks(dcn as usize * dcs, 1)?;
+        let map = entry_from(&first.as_bytes()[MAP_OFFSET..]);
+        let cluster_size = map[0] as usize;
+        if !cluster_size.is_power_of_two() || cluster_size > 16 || cluster_size <= tables { return Err(anyhow!("Bad directory cluster size {}", cluster_size)) }
+        let clusters: Vec<u16> = map[1..].iter().copied().take_while(|c| *c != 0).collect();
+        if clusters.first() != Some(&dcn) { return Err(anyhow!("Directory at DCN {} has a bad cluster map {:?}", dcn, map)) }
+        let mut blocks = vec![];
+        for c in clusters.iter() {
+            if *c as usize * dcs + cluster_size > image.blocks() { return Err(anyhow!("Bad directory cluster {}", c)) }
+            blocks.extend(image.read_blocks(*c as usize * dcs, cluster_size)?.as_bytes().chunks(BLOCK_SIZE).map(|b| b.to_vec()));
+        }
+        Ok(Dir { cluster_size, clusters, tables, blocks })
+    }
+
+    fn write<B: BlockDevice>(&self, image: &mut B, dcs: usize) -> anyhow::Result<()> {
+        for (c, dcn) in self.clusters.iter().enumerate() {
+            let data = self.blocks[c * self.cluster_size..(c + 1) * self.cluster_size].concat();
+            image.write_blocks(*dcn as usize * dcs, self.cluster_size, &data)?;
+        }
+        Ok(())
+    }
+
+    fn is_table(&self, block: usize) -> bool {
+        (1..=self.tables).contains(&block)
+    }
+
+    fn add_cluster(&mut self, dcn: u16) {
+        self.clusters.push(dcn);
+        self.blocks.extend((0..self.cluster_size).map(|_| vec![0; BLOCK_SIZE]));
+        let mut map = vec![self.cluster_size as u16];
+        map.extend(self.clusters.iter());
+        map.resize(ENTRY_WORDS, 0);
+        let map: Vec<u8> = map.iter().flat_map(|w| w.to_le_bytes()).collect();
+        for b in 0..self.blocks.len() {
+            if !self.is_table(b) { self.blocks[b][MAP_OFFSET..].copy_from_slice(&map) }
+        }
+    }
+
+    // Link bits: block in the cluster (4), cluster (3), entry (5), flags (4).
+    fn link(&self, block: usize, entry: usize) -> u16 {
+        ((block % self.cluster_size) << 12 | (block / self.cluster_size) << 9 | entry << 4) as u16
+    }
+
+    fn locate(&self, link: u16) -> anyhow::Result<(usize, usize)> {
+        let (block, cluster, entry) = ((link >> 12) as usize, (link >> 9 & 0o7) as usize, (link >> 4 & 0o37) as usize);
+        let block = cluster * self.cluster_size + block;
+        if block >= self.blocks.len() || entry >= ENTRIES_PER_BLOCK || self.is_table(block) { return Err(anyhow!("Bad directory link {:#o}", link)) }
+        Ok((block, entry * ENTRY_WORDS * 2))
+    }
+
+    fn entry(&self, link: u16) -> anyhow::Result<Entry> {
+        let (block, offset) = self.locate(link)?;
+        Ok(entry_from(&self.blocks[block][offset..offset + ENTRY_WORDS * 2]))
+    }
+
+    fn set_entry(&mut self, link: u16, entry: &Entry) -> anyhow::Result<()> {
+        let (block, offset) = self.locate(link)?;
+        for (i, w) in entry.iter().enumerate() {
+            self.blocks[block][offset + i * 2..offset + i * 2 + 2].copy_from_slice(&w.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn table(&self, table: usize, index: usize) -> u16 {
+        u16::from_le_bytes([self.blocks[table][index * 2], self.blocks[table][index * 2 + 1]])
+    }
+
+    fn set_table(&mut self, table: usize, index: usize, value: u16) {
+        self.blocks[table][index * 2..index * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn free_entry(&self) -> Option<u16> {
+        (0..self.blocks.len()).filter(|b| !self.is_table(*b))
+            .flat_map(|b| (0..ENTRIES_PER_BLOCK).map(move |e| (b, e)))
+            .filter(|(b, e)| (*b, *e) != (0, 0)) // The label
+            .find(|(b, e)| self.blocks[*b][e * ENTRY_WORDS * 2..(e + 1) * ENTRY_WORDS * 2].iter().all(|b| *b == 0))
+            .map(|(b, e)| self.link(b, e))
+    }
+
+    // Follows the links (the first word of each entry) starting with `link`.
+    fn chain(&self, link: u16) -> anyhow::Result<Vec<u16>> {
+        let mut links = vec![];
+        let mut link = link & !LINK_FLAGS;
+        while link != 0 {
+            if links.len() > self.blocks.len() * ENTRIES_PER_BLOCK { return Err(anyhow!("Directory link loop at {:#o}", link)) }
+            links.push(link);
+            link = self.entry(link)?[0] & !LINK_FLAGS;
+        }
+        Ok(links)
+    }
+
+    fn set_link(&mut self, at: u16, to: u16) -> anyhow::Result<()> {
+        let mut entry = self.entry(at)?;
+        entry[0] = entry[0] & LINK_FLAGS | to;
+        self.set_entry(at, &entry)
+    }
+
+    // Adds a name entry to the end of the chain that starts at the label.
+    fn append(&mut self, link: u16) -> anyhow::Result<()> {
+        let last = self.chain(self.entry(0)?[0])?.last().copied().unwrap_or(0);
+        self.set_link(last, link)
+    }
+
+    fn unlink(&mut self, link: u16) -> anyhow::Result<()> {
+        let next = self.entry(link)?[0] & !LINK_FLAGS;
+        let prev = self.chain(self.entry(0)?[0])?.into_iter().take_while(|l| *l != link).last().unwrap_or(0);
+        self.set_link(prev, next)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub ppn: Ppn,
+    pub ufd: Dir,
+}
+
+// A file's entries, out of their directory.
+struct FileRecord {
+    ne: Entry,
+    ae: Entry,
+    attrs: Vec<Entry>,
+    clusters: Vec<u16>,
+}
+
+#[derive(Clone)]
+pub struct FileEntry {
+    path: String,
+    name: String,
+    link: u16,
+    pub status: u8,
+    pub protection: u8,
+    pub size: usize, // Blocks
+    pub cluster_size: usize,
+    pub created: Option<NaiveDate>,
+    pub created_time: Option<NaiveTime>,
+    pub accessed: Option<NaiveDate>,
+    pub runtime_system: String,
+    pub clusters: Vec<u16>,
+}
+
+impl FileEntry {
+    fn from_ufd(ufd: &Dir, ppn: Ppn, link: u16) -> anyhow::Result<FileEntry> {
+        let ne = ufd.entry(link)?;
+        let ae = ufd.entry(ne[6])?;
+        let raw = radix50::pdp11::decode(&ne[1..4]);
+        let (name, ext) = raw.split_at(6);
+        let name = format!("{}.{}", name.trim(), ext.trim());
+        let large = ae[5] == 0;
+        let cluster_size = ae[7] as usize;
+        let minutes = 24 * 60 - (ae[4] & 0o3777) as u32; // Minutes until midnight
+        Ok(FileEntry {
+            path: format!("{}{}", ppn, name),
+            name,
+            link,
+            status: ne[4] as u8,
+            protection: (ne[4] >> 8) as u8,
+            size: if large { (ae[6] as usize) << 16 | ae[2] as usize } else { ae[2] as usize },
+            cluster_size,
+            created: xxdp::DirEntry::decode_date(ae[3]).ok().flatten(),
+            created_time: NaiveTime::from_hms_opt(minutes / 60 % 24, minutes % 60, 0),
+            accessed: xxdp::DirEntry::decode_date(ae[1]).ok().flatten(),
+            runtime_system: if large { String::new() } else { radix50::pdp11::decode(&ae[5..7]).trim().to_string() },
+            clusters: ufd.chain(ne[7])?.into_iter().map(|l| ufd.entry(l)).collect::<anyhow::Result<Vec<_>>>()?
+                         .into_iter().flat_map(|re| re[1..].to_vec()).filter(|dcn| *dcn != 0).collect(),
+        })
+    }
+
+    fn protected(&self) -> bool {
+        self.status & US_NOK != 0
+    }
+
+    fn contiguous(&self) -> bool {
+        self.status & US_NOX != 0
+    }
+}
+
+impl Debug for FileEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = self.created.map(|d| d.to_string()).unwrap_or(" No Date".to_string());
+        if f.alternate() {
+            write!(f, "{:<10} {:5} {:<10} {:6}{}{} <{:3}> {:4} {:6} {}",
+                   date, self.created_time.map(|t| t.format("%H:%M").to_string()).unwrap_or_default(),
+                   self.accessed.map(|d| d.to_string()).unwrap_or_default(),
+                   self.size, if self.contiguous() { "C" } else { " " }, if self.protected() { "P" } else { " " },
+                   self.protection, self.cluster_size, self.runtime_system, self.path)
+        } else {
+            write!(f, "{:10} {:6}{} <{:3}> {}",
+                   date, self.size, if self.contiguous() { "C" } else { " " }, self.protection, self.path)
+        }
+    }
+}
+
+impl super::DirEntry for FileEntry {
+    fn path(&self)       -> &str                             { &self.path }
+    fn file_name(&self)  -> &str                             { &self.name }
+    fn is_dir(&self)     -> bool                             { false }
+    fn is_file(&self)    -> bool                             { true }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { (self.size * BLOCK_SIZE) as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { self.accessed.map(super::Timestamp::Date).ok_or(anyhow!("Bad Date")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> {
+        let date = self.created.ok_or(anyhow!("Bad Date"))?;
+        Ok(match self.created_time {
+            Some(time) => super::Timestamp::DateTime(NaiveDateTime::new(date, time)),
+            None       => super::Timestamp::Date(date),
+        })
+    }
+    fn blocks(&self)     -> u64                              { self.size as u64 }
+    fn readonly(&self)   -> bool                             { false }
+    fn protected(&self)  -> bool                             { self.protected() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::test::*;
+
+    fn names(fs: &RstsFs<TestDev>, path: &str) -> Vec<String> {
+        fs.read_dir(path).unwrap().map(|e| e.path().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!((LIBRARY_ACCOUNT, "FOO.BAS"), Ppn::split_path("FOO.BAS").unwrap());
+        assert_eq!((Ppn { project: 200, programmer: 10 }, "FOO.BAS"), Ppn::split_path("[200,10]FOO.BAS").unwrap());
+        assert_eq!((SYSTEM_ACCOUNT, ""), Ppn::split_path("/[0, 1]").unwrap());
+        assert!(Ppn::split_path("[1,2FOO.BAS").is_err());
+        assert!(Ppn::split_path("[255,1]FOO.BAS").is_err());
+        assert!(Ppn::split_path("[1,377]FOO.BAS").is_err());
+        assert_eq!("[200,10]", Ppn { project: 200, programmer: 10 }.to_string());
+    }
+
+    #[test]
+    fn test_mkfs() {
+        for level in [RDS0, RDS11] {
+            let fs = RstsFs::mkfs_with_level(TestDev(vec![0; 512*4800]), level).expect("Create RSTS/E FS");
+            let fs = RstsFs::new(TestDev(fs.image.0.clone())).expect("Reopen RSTS/E FS");
+            assert_eq!((level, 1, 1), (fs.label.level, fs.label.pcs, fs.dcs));
+            assert_eq!(vec![SYSTEM_ACCOUNT, LIBRARY_ACCOUNT], fs.accounts.iter().map(|a| a.ppn).collect::<Vec<_>>());
+            assert_eq!(vec!["[0,1]SATT.SYS", "[0,1]BADB.SYS"], names(&fs, "[0,1]"));
+            assert!(fs.stat("[0,1]SATT.SYS").unwrap().protected());
+            assert_eq!(2, fs.stat("[0,1]SATT.SYS").unwrap().blocks()); // 4799 pack clusters
+            let dirs = if level == RDS0 { 3 * 4 } else { 1 + 5 * 4 }; // (The pack label,) MFD, (2 GFDs,) 2 UFDs
+            assert_eq!(4799 - dirs - 2, fs.free_blocks()); // Less SATT.SYS
+            assert!(RstsFs::image_is(&fs.image));
+            assert!(!crate::fs::dos11::Dos11Fs::image_is(&fs.image));
+            assert!(!crate::fs::rt11::RT11FS::image_is(&fs.image));
+        }
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut fs = RstsFs::mkfs(TestDev(vec![0; 512*70000])).expect("Create RSTS/E FS");
+        assert_eq!((2, 2), (fs.dcs, fs.label.pcs)); // Too many blocks for 1 block clusters
+        let big = incrementing(60 * 512 + 100); // 31 clusters, so 5 retrieval entries
+        fs.write_file("[200,10]BIG.DAT", &big).expect("write_file failed");
+        fs.write_file_with_options("CONTIG.SAV", &incrementing(2048), &CreateOptions { contiguous: true, ..CreateOptions::default() })
+            .expect("write_file failed");
+
+        let fs = RstsFs::new(TestDev(fs.image.0.clone())).expect("Reopen RSTS/E FS");
+        let mut padded = big.clone();
+        padded.resize(61 * 512, 0);
+        assert_eq!(padded, fs.read_file("[200,10]BIG.DAT").unwrap().into_vec());
+        assert_eq!(incrementing(2048), fs.read_file("[1,2]CONTIG.SAV").unwrap().into_vec());
+        assert!(fs.read_file("BIG.DAT").is_err());
+        let (a, big) = fs.raw_stat("[200,10]BIG.DAT").unwrap();
+        assert_eq!((61, 31, 60, "RT11"), (big.size, big.clusters.len(), big.protection, big.runtime_system.as_str()));
+        assert_eq!(5, fs.accounts[a].ufd.chain(fs.accounts[a].ufd.entry(big.link).unwrap()[7]).unwrap().len());
+        let contig = fs.raw_stat("CONTIG.SAV").unwrap().1;
+        assert!(contig.contiguous());
+        assert_eq!(contig.clusters[0] + 1, contig.clusters[1]); // DCNs
+        assert_eq!(contig.clusters[0] as usize * 2 + 2, fs.file_blocks(&contig)[2]);
+        assert!(matches!(super::super::DirEntry::created(&contig), Ok(super::super::Timestamp::DateTime(d)) if d.to_string() == "2023-01-19 12:13:00"));
+        assert_eq!(vec!["[1,2]CONTIG.SAV"], names(&fs, "[1,2]"));
+        assert!(fs.read_dir("[100,100]").is_err());
+    }
+
+    #[test]
+    fn test_delete_and_rename() {
+        for level in [RDS0, RDS11] {
+            let mut fs = RstsFs::mkfs_with_level(TestDev(vec![0; 512*4800]), level).expect("Create RSTS/E FS");
+            let free = fs.free_blocks();
+            fs.write_file("A.TXT", &incrementing(1024)).expect("write_file failed");
+            fs.write_file("B.TXT", &incrementing(2000)).expect("write_file failed");
+            fs.rename("B.TXT", "[10,20]C.TXT").expect("rename failed");
+            fs.rename("A.TXT", "D.TXT").expect("rename failed");
+            assert_eq!(vec!["[1,2]D.TXT", "[10,20]C.TXT"], names(&fs, "/").into_iter().filter(|n| !n.starts_with("[0,1]")).collect::<Vec<_>>());
+            assert_eq!(incrementing(2000), fs.read_file("[10,20]C.TXT").unwrap().as_bytes()[..2000]);
+
+            let free_with_account = free - 4 - if level == RDS0 { 0 } else { 4 }; // A new UFD (and GFD)
+            fs.delete("D.TXT").expect("delete failed");
+            fs.delete("[10,20]C.TXT").expect("delete failed");
+            assert_eq!(free_with_account, fs.free_blocks());
+            let fs = RstsFs::new(TestDev(fs.image.0.clone())).expect("Reopen RSTS/E FS");
+            assert_eq!(free_with_account, fs.free_blocks());
+            assert_eq!(0, fs.read_dir("[10,20]").unwrap().count());
+        }
+    }
+
+    #[test]
+    fn test_directory_growth_and_protection() {
+        let mut fs = RstsFs::mkfs(TestDev(vec![0; 512*4800])).expect("Create RSTS/E FS");
+        for i in 0..100 {
+            fs.write_file(&format!("F{}.TXT", i), &incrementing(10)).expect("write_file failed");
+        }
+        let fs2 = RstsFs::new(TestDev(fs.image.0.clone())).expect("Reopen RSTS/E FS");
+        assert_eq!(100, fs2.read_dir("[1,2]").unwrap().count());
+        assert_eq!(3, fs2.accounts[1].ufd.clusters.len());
+
+        fs.set_protected("F7.TXT", true).expect("set_protected failed");
+        assert!(fs.delete("F7.TXT").is_err());
+        assert!(fs.write_file("F7.TXT", &incrementing(20)).is_err());
+        fs.set_protected("F7.TXT", false).expect("set_protected failed");
+        fs.delete("F7.TXT").expect("delete failed");
+
+        fs.set_protection_code("F8.TXT", 40).expect("set_protection_code failed");
+        fs.write_file("F8.TXT", &incrementing(20)).expect("write_file failed");
+        let fs = RstsFs::new(TestDev(fs.image.0.clone())).expect("Reopen RSTS/E FS");
+        assert_eq!((40, false), fs.raw_stat("F8.TXT").map(|(_, f)| (f.protection, f.protected())).unwrap());
+        assert!(fs.raw_stat("F9.TXT").is_some_and(|(_, f)| f.protection == DEFAULT_PROTECTION));
+    }
+
+    #[test]
+    fn test_overwrite_when_full() {
+        let mut fs = RstsFs::mkfs(TestDev(vec![0; 512*200])).expect("Create RSTS/E FS");
+        fs.write_file("FILL.DAT", &vec![1; fs.free_blocks() * 512 - 10 * 512]).expect("write_file failed");
+        fs.write_file("OLD.DAT", &incrementing(10 * 512)).expect("write_file failed");
+        assert_eq!(0, fs.free_blocks());
+        fs.write_file("OLD.DAT", &incrementing(5 * 512)).expect("Overwrite with the old file's space failed");
+        assert!(fs.write_file("OLD.DAT", &incrementing(11 * 512)).is_err());
+        assert_eq!(incrementing(5 * 512), fs.read_file("OLD.DAT").unwrap().into_vec()); // Still there
+
+        // Enough room all together, but not in one piece
+        fs.write_file("GAP.DAT", &incrementing(512)).expect("write_file failed");
+        assert_eq!(4, fs.free_blocks());
+        assert!(fs.write_file_with_options("OLD.DAT", &incrementing(9 * 512), &CreateOptions { contiguous: true, ..CreateOptions::default() }).is_err());
+        assert_eq!(incrementing(5 * 512), fs.read_file("OLD.DAT").unwrap().into_vec());
+        assert_eq!(4, fs.free_blocks());
+
+        // A damaged entry doesn't stop the others being listed
+        let (a, gap) = fs.raw_stat("GAP.DAT").unwrap();
+        let mut ne = fs.accounts[a].ufd.entry(gap.link).unwrap();
+        ne[6] = 0o177760; // Link to an attribute entry that can't be there
+        fs.accounts[a].ufd.set_entry(gap.link, &ne).unwrap();
+        assert_eq!(vec!["[1,2]FILL.DAT", "[1,2]OLD.DAT"], names(&fs, "[1,2]"));
+    }
+}
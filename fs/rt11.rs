@@ -1,6 +1,6 @@
 // Copyright © 2023 David Caldwell <david@porkrind.org>
 
-use std::{cmp::min, fmt::Debug, io::{self, ErrorKind}, ops::Range};
+use std::{cmp::{max, min}, fmt::Debug, io::{self, ErrorKind}, ops::Range};
 
 use anyhow::{Context,anyhow};
 use bytebuffer::{Endian, ByteBuffer};
@@ -9,7 +9,7 @@ use pretty_hex::PrettyHex;
 use serde::Serialize;
 
 use crate::block::{BlockDevice, BLOCK_SIZE};
-use super::FileSystem;
+use super::{CreateOptions, FileSystem, Placement};
 
 // Things we override to make testing easier
 #[cfg(not(test))] use chrono::Local;
@@ -23,6 +23,7 @@ pub struct RT11FS<B: BlockDevice> {
     pub image: B,
     pub home: HomeBlock,
     pub dir: Vec<DirSegment>,
+    salvaged: bool,
 }
 
 impl<B: BlockDevice> RT11FS<B> {
@@ -33,9 +34,94 @@ impl<B: BlockDevice> RT11FS<B> {
             image,
             home,
             dir,
+            salvaged: false,
         })
     }
 
+    // For damaged volumes that new() refuses to open: Builds a best effort directory out of whatever directory
+    // segments still parse so that files can be copied off. The segments are put in data block order (since that's
+    // where their files' extents come from) and any blocks that none of the surviving entries account for are made
+    // into `Bnnnnn.BAD` files (nnnnn being the starting block) so they can be extracted and looked at, too. Returns
+    // descriptions of everything that looked wrong along the way. The salvaged filesystem is read only.
+    pub fn salvage(image: B) -> anyhow::Result<(RT11FS<B>, Vec<String>)> {
+        let mut problems = vec![];
+        let home = Self::read_homeblock(&image).unwrap_or_else(|e| {
+            problems.push(format!("Bad home block ({:#}), assuming the directory is at the usual place", e));
+            HomeBlock::new()
+        });
+        let start = home.directory_start_block;
+        let read_segment = |segment: u16| -> anyhow::Result<(DirSegment, Option<anyhow::Error>)> {
+            let block = DirSegment::segment_block(start, segment);
+            if block as usize + 2 > image.blocks() { return Err(anyhow!("Segment is off the end of the volume (@ {})", block)) }
+            DirSegment::salvage_from_repr(segment, block, image.read_blocks(block as usize, 2)?, image.blocks())
+        };
+
+        // Every segment header has the total number of segments so any sane one will tell us where the data starts.
+        let Some(segments) = (1..=MAX_DIR_SEGMENTS).find_map(|s| read_segment(s).ok().map(|(seg, _)| seg.segments)) else {
+            return Err(anyhow!("Couldn't find any usable directory segments"));
+        };
+        let found: Vec<_> = (1..=segments).map(|s| (s, read_segment(s))).collect();
+        // Segments past the first segment's last_segment were never allocated (or were freed by a squeeze), so any
+        // directory entries in them are stale. If the first segment is gone, go by the others (which have what
+        // last_segment was when they were allocated).
+        let last = found.iter().find(|(s, _)| *s == 1).and_then(|(_, r)| r.as_ref().ok()).map(|(seg, _)| seg.last_segment)
+            .or_else(|| found.iter().filter_map(|(_, r)| r.as_ref().ok()).map(|(seg, _)| seg.last_segment).max())
+            .unwrap_or(segments);
+        let mut dir = vec![];
+        for (s, segment) in found.into_iter().filter(|(s, _)| *s <= last) {
+            match segment {
+                Err(e) => problems.push(format!("Directory segment #{} is unusable: {:#}", s, e)),
+                Ok((seg, damage)) => {
+                    if let Some(e) = damage {
+                        problems.push(format!("Directory segment #{} is damaged after {} entries: {:#}", s, seg.entries.len(), e));
+                    }
+                    if !seg.entries.is_empty() { dir.push(seg) }
+                },
+            }
+        }
+        if dir.is_empty() { return Err(anyhow!("Couldn't salvage any directory entries")) }
+
+        dir.sort_by_key(|seg| seg.data_block);
+        let mut next_block = DirSegment::segment_block(start, segments + 1) as usize;
+        let gap = |problems: &mut Vec<String>, block: usize, end: usize, extra_bytes: u16| -> DirEntry {
+            let gap = DirEntry::new_salvaged_gap(block, end - block, extra_bytes);
+            problems.push(format!("Blocks {}..{} aren't in any directory entry, salvaged as {}", block, end, gap.name));
+            gap
+        };
+        for i in 0..dir.len() {
+            let data_block = dir[i].data_block as usize;
+            if data_block > next_block {
+                let g = gap(&mut problems, next_block, data_block, dir[i].extra_bytes);
+                if i == 0 {
+                    dir[0].entries.insert(0, g);
+                    dir[0].data_block = next_block as u16;
+                } else {
+                    dir[i-1].entries.push(g);
+                }
+            } else if data_block < next_block {
+                problems.push(format!("Directory segment #{} starts at block {}, which overlaps the entries before it (up to block {})",
+                                      dir[i].segment, data_block, next_block));
+            }
+            next_block = next_block.max(dir[i].block_range().end as usize);
+        }
+        if next_block < image.blocks() {
+            let last = dir.len()-1;
+            let g = gap(&mut problems, next_block, image.blocks(), dir[last].extra_bytes);
+            dir[last].entries.push(g);
+        }
+        // Relink the segments in the order we've put them.
+        for i in 0..dir.len() {
+            dir[i].next_segment = dir.get(i+1).map(|seg| seg.segment).unwrap_or(0);
+        }
+
+        Ok((RT11FS {
+            image,
+            home,
+            dir,
+            salvaged: true,
+        }, problems))
+    }
+
     pub fn image_is(image: &B) -> bool {
         let Ok(home) = Self::read_homeblock(&image) else { return false };
         let Ok(dir) = Self::read_directory(&image, home.directory_start_block).collect::<anyhow::Result<Vec<DirSegment>>>() else {
@@ -43,8 +129,9 @@ impl<B: BlockDevice> RT11FS<B> {
         };
         for s in dir.into_iter() {
             for e in s.entries.iter() {
-                // Do a sanity check on all the directory entries. They should have sane block numbers.
-                if e.length >= image.blocks() || e.block >= image.blocks() {
+                // Do a sanity check on all the directory entries. They should have sane block numbers. (A full
+                // volume ends with a zero length empty entry just past the last block.)
+                if e.length >= image.blocks() || e.block > image.blocks() || (e.block == image.blocks() && e.length != 0) {
                     return false;
                 }
             }
@@ -53,7 +140,13 @@ impl<B: BlockDevice> RT11FS<B> {
     }
 
     // Initialize a filesystem on this image
-    pub fn mkfs(mut image: B) -> anyhow::Result<RT11FS<B>> {
+    pub fn mkfs(image: B) -> anyhow::Result<RT11FS<B>> {
+        Self::mkfs_with_extra_bytes(image, 0)
+    }
+
+    // Like mkfs(), but reserves `extra_bytes` of extra information in every directory entry (RT-11's `INIT/Z:n`).
+    pub fn mkfs_with_extra_bytes(mut image: B, extra_bytes: u16) -> anyhow::Result<RT11FS<B>> {
+        if extra_bytes & 1 == 1 { return Err(anyhow!("Extra bytes must be even: {}", extra_bytes)) }
         let home = HomeBlock::new();
         image.write_blocks(1, 1, &home.repr()?)?;
         let segment_count = 4; // This is RT-11's default. Should it be configurable like it is there?
@@ -61,6 +154,7 @@ impl<B: BlockDevice> RT11FS<B> {
         let dir_segment = DirSegment::new(1,
                                           home.directory_start_block,
                                           1..segment_count,
+                                          extra_bytes,
                                           first_data_block..image.blocks() as u16);
         image.write_blocks(home.directory_start_block as usize, 2, &dir_segment.repr()?)?;
         return Self::new(image);
@@ -107,6 +201,10 @@ impl<B: BlockDevice> RT11FS<B> {
         Ok(hb)
     }
 
+    pub fn write_homeblock(&mut self) -> anyhow::Result<()> {
+        self.image.write_blocks(1, 1, &self.home.repr()?)
+    }
+
     pub fn read_directory<'a>(image: &'a B, directory_start_block: u16) -> DirSegmentIterator<'a, B> {
         DirSegmentIterator {
             image,
@@ -127,19 +225,84 @@ impl<B: BlockDevice> RT11FS<B> {
         None
     }
 
-    fn find_empty_space<'a>(&'a self, blocks: usize) -> Option<(usize, usize)> {
-        self.find(|f| f.kind == EntryKind::Empty && f.length >= blocks)
+    fn find_best<F, K>(&self, predicate: F, key: impl Fn(&DirEntry) -> K) -> Option<(usize, usize)>
+    where F: Fn(&DirEntry) -> bool,
+          K: Ord,
+    {
+        self.dir.iter().enumerate()
+            .flat_map(|(s, seg)| seg.entries.iter().enumerate().map(move |(e, f)| (s, e, f)))
+            .filter(|(_, _, f)| predicate(f))
+            .min_by_key(|(_, _, f)| key(f))
+            .map(|(s, e, _)| (s, e))
+    }
+
+    fn find_empty_space(&self, blocks: usize, placement: Placement) -> Option<(usize, usize)> {
+        let fits = |f: &DirEntry| f.kind == EntryKind::Empty && f.length >= blocks;
+        match placement {
+            // Save deleted files for as long as we can in case someone wants them back
+            Placement::FirstFit => self.find(|f| fits(f) && !f.is_deleted_file()).or_else(|| self.find(fits)),
+            Placement::BestFit  => self.find_best(fits, |f| (f.is_deleted_file(), f.length)),
+            Placement::Largest  => self.find_best(fits, |f| std::cmp::Reverse(f.length)),
+            Placement::At(block) => self.find(|f| f.kind == EntryKind::Empty && f.block <= block && block + blocks <= f.block + f.length),
+        }
+    }
+
+    // Whether a file of `blocks` blocks at `block` overlaps the file at `old` and would fit there once the old one
+    // was deleted. Errors if it overlaps but won't fit, since then nothing should get touched.
+    fn fits_over(&self, old: (usize, usize), block: usize, blocks: usize) -> anyhow::Result<bool> {
+        let (segment, old_entry) = old;
+        let end = block + blocks;
+        let in_the_way = |f: &DirEntry| f.block < end && block < f.block + f.length;
+        if !in_the_way(&self.dir[segment].entries[old_entry]) { return Ok(false) }
+        let not_free = || anyhow!("Blocks {}..{} aren't free", block, end);
+        // Only the old file and empty space in its own segment can be in the way (consolidating can't merge across
+        // segments), and together they have to cover every block we asked for.
+        if self.full_dir_iter(None).filter(|f| in_the_way(f)).count() !=
+           self.dir[segment].entries.iter().filter(|f| in_the_way(f)).count() { return Err(not_free()) }
+        let mut covered = 0;
+        let mut count = 0;
+        for (e, f) in self.dir[segment].entries.iter().enumerate().filter(|(_, f)| in_the_way(f)) {
+            if f.kind != EntryKind::Empty && e != old_entry { return Err(not_free()) }
+            covered += min(end, f.block + f.length) - max(block, f.block);
+            count += 1;
+        }
+        if covered != blocks { return Err(not_free()) }
+        // Those entries become one after consolidating, and splitting it up again takes at most 2 more.
+        if self.dir[segment].entries.len() - (count - 1) + 2 > self.dir[segment].max_entries() &&
+           self.dir[0].last_segment == self.dir[0].segments {
+            return Err(anyhow!("Out of directory segments"));
+        }
+        Ok(true)
+    }
+
+    // Makes sure `segment` has room for `entries` more directory entries (by coalescing or splitting it).
+    fn make_room(&mut self, segment: usize, entries: usize) -> anyhow::Result<()> {
+        if self.dir[segment].entries.len() + entries <= self.dir[segment].max_entries() { return Ok(()) }
+        self.consolidate_segment(segment)?;
+        if self.dir[segment].entries.len() + entries <= self.dir[segment].max_entries() { return Ok(()) }
+        self.split_directory(segment)
     }
 
     fn find_file_named(&self, name: &str) -> Option<(usize, usize)> {
         self.find(|f| f.kind == EntryKind::Permanent && f.name == name)
     }
 
+    fn find_deleted_file_named(&self, name: &str) -> Option<(usize, usize)> {
+        self.find(|f| f.is_deleted_file() && f.name == name)
+    }
+
     fn raw_stat<'a>(&'a self, name: &str) -> Option<&'a DirEntry> {
         self.find_file_named(name).map(|(segment, entry)| &self.dir[segment].entries[entry])
     }
 
+    fn check_writable(&self) -> anyhow::Result<()> {
+        // Writing a salvaged directory back would make whatever we guessed wrong permanent.
+        if self.salvaged { return Err(anyhow!("Salvaged filesystems are read only")) }
+        Ok(())
+    }
+
     fn write_directory_segment(&mut self, segment: usize) -> anyhow::Result<()> {
+        self.check_writable()?;
         self.image.write_blocks(self.dir[segment].block as usize, 2, &self.dir[segment].repr()?)
     }
 
@@ -150,8 +313,34 @@ impl<B: BlockDevice> RT11FS<B> {
             return;
         }
 
-        self.dir[segment].entries[entry].length += self.dir[segment].entries[entry+1].length;
-        self.dir[segment].entries.drain(entry+1..=entry+1);
+        let next = self.dir[segment].entries.remove(entry+1);
+        let this = &mut self.dir[segment].entries[entry];
+        // Zero length entries can go without losing track of any deleted file.
+        if this.length == 0 { *this = next; return }
+        if next.length == 0 { return }
+        this.length += next.length;
+        // The combined area isn't any one deleted file anymore, so it can't be undeleted.
+        this.forget_name();
+    }
+
+    // Deleted files are left alone (so they can be undeleted) until we actually need their space or directory entries.
+    fn consolidate_segment(&mut self, segment: usize) -> anyhow::Result<()> {
+        let entries = self.dir[segment].entries.len();
+        let mut entry = 0;
+        while entry < self.dir[segment].entries.len() {
+            let before = self.dir[segment].entries.len();
+            self.coalesce_empty(segment, entry);
+            if self.dir[segment].entries.len() == before { entry += 1 }
+        }
+        if self.dir[segment].entries.len() == entries { return Ok(()) }
+        self.write_directory_segment(segment)
+    }
+
+    fn consolidate(&mut self) -> anyhow::Result<()> {
+        for segment in 0..self.dir.len() {
+            self.consolidate_segment(segment)?;
+        }
+        Ok(())
     }
 
     fn full_dir_iter<'a>(&'a self, kind: Option<EntryKind>) -> DirEntryIterator<'a, B> {
@@ -163,40 +352,174 @@ impl<B: BlockDevice> RT11FS<B> {
         }
     }
 
+    #[allow(unused)]
     fn create<'a>(&'a mut self, name: &str, bytes: usize) -> anyhow::Result<RT11FileWriter<'a, B>> {
-        let blocks = (bytes + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        self.create_with_options(name, bytes, &CreateOptions::default())
+    }
+
+    pub fn create_with_options<'a>(&'a mut self, name: &str, bytes: usize, options: &CreateOptions) -> anyhow::Result<RT11FileWriter<'a, B>> {
+        self.enter_with_options(name, Some(bytes.div_ceil(BLOCK_SIZE)), options)
+    }
+
+    // Like RT-11's .ENTER: Allocates a tentative file of `blocks` blocks (or the largest empty area if None). The
+    // file only becomes permanent (replacing any existing file of the same name) when the writer is closed.
+    #[allow(unused)]
+    pub fn enter<'a>(&'a mut self, name: &str, blocks: Option<usize>) -> anyhow::Result<RT11FileWriter<'a, B>> {
+        self.enter_with_options(name, blocks, &CreateOptions::default())
+    }
+
+    pub fn enter_with_options<'a>(&'a mut self, name: &str, blocks: Option<usize>, options: &CreateOptions) -> anyhow::Result<RT11FileWriter<'a, B>> {
+        self.check_writable()?;
         DirEntry::encode_filename(name)?;
-        _ = self.delete(name); // Can only fail because file-not-found, which is a no-op here.
-        let Some((segment, entry)) = self.find_empty_space(blocks) else { return Err(anyhow!("No space available in image")) };
-        let (segment, entry) =
-            if self.dir[segment].entries.len() + 1 > self.dir[segment].max_entries() {
-                // Too many entries to fit in segment.
-                self.split_directory(segment)?;
-                // The segment we found may have moved so look for it again
-                let Some((segment, entry)) = self.find_empty_space(blocks) else { return Err(anyhow!("No space available in image")) };
-                (segment, entry)
-            } else { (segment, entry) };
+        if self.raw_stat(name).is_some_and(|f| f.protected) { return Err(anyhow!("{} is protected", name)) }
+        let prefix_blocks = match options.prefix.len() {
+            0   => 0,
+            len => (PREFIX_COUNT_BYTES + len).div_ceil(BLOCK_SIZE),
+        };
+        if prefix_blocks > MAX_PREFIX_BLOCKS {
+            return Err(anyhow!("Prefix is too long: {} bytes (can't be more than {})", options.prefix.len(), MAX_PREFIX_BLOCKS * BLOCK_SIZE - PREFIX_COUNT_BYTES));
+        }
+        let blocks = blocks.map(|blocks| blocks + prefix_blocks);
+        let placement = options.placement;
+        // Putting a file back at its own blocks (boot images live at fixed blocks) can't wait for the close to get
+        // rid of the old one. So once we know the new file will fit there, the old one goes now.
+        if let (Placement::At(block), Some(old)) = (placement, self.find_file_named(name)) {
+            if self.fits_over(old, block, blocks.unwrap_or(1))? {
+                self.delete(name)?;
+            }
+        }
+        if blocks.is_none_or(|blocks| self.find_empty_space(blocks, placement).is_none()) {
+            self.consolidate()?;
+        }
+        let blocks = match (blocks, placement) {
+            (Some(blocks), _) => blocks,
+            // Everything from the requested block to the end of the empty area it's in
+            (None, Placement::At(block)) => self.find_empty_space(0, placement).map(|(s, e)| &self.dir[s].entries[e])
+                                                .map(|f| f.block + f.length - block).unwrap_or(0),
+            (None, _) => self.full_dir_iter(Some(EntryKind::Empty)).map(|e| e.length).max().unwrap_or(0),
+        };
+        let find = |fs: &Self| fs.find_empty_space(blocks, placement).ok_or_else(|| match placement {
+            Placement::At(block) => anyhow!("Blocks {}..{} aren't free", block, block + blocks),
+            _                    => anyhow!("No space available in image"),
+        });
+        let (segment, entry) = find(self)?;
+        // Starting in the middle of an empty area means splitting off the part in front of us, too.
+        let split_head = matches!(placement, Placement::At(block) if block > self.dir[segment].entries[entry].block);
+        self.make_room(segment, if split_head { 2 } else { 1 })?;
+        // The entry we found may have moved so look for it again
+        let (segment, mut entry) = find(self)?;
+        let head = match placement {
+            Placement::At(block) => block - self.dir[segment].entries[entry].block,
+            _                    => 0,
+        };
+        if head > 0 {
+            let mut front = self.dir[segment].entries[entry].clone();
+            front.forget_name();
+            front.length = head;
+            self.dir[segment].entries[entry].block += head;
+            self.dir[segment].entries[entry].length -= head;
+            self.dir[segment].entries.insert(entry, front);
+            entry += 1;
+        }
         let mut new_free = self.dir[segment].entries[entry].clone();
+        if blocks > 0 {
+            new_free.forget_name(); // Whatever was deleted here is getting overwritten
+        }
         self.dir[segment].entries[entry].name = name.to_owned();
         self.dir[segment].entries[entry].length = blocks;
-        self.dir[segment].entries[entry].kind = EntryKind::Permanent;
+        self.dir[segment].entries[entry].kind = EntryKind::Tentative;
         self.dir[segment].entries[entry].read_only = false;
         self.dir[segment].entries[entry].protected = false;
+        self.dir[segment].entries[entry].prefix_block = prefix_blocks > 0;
         self.dir[segment].entries[entry].job = 0;
         self.dir[segment].entries[entry].channel = 0;
         self.dir[segment].entries[entry].creation_date = Some(Local::now().date_naive());
+        self.dir[segment].entries[entry].extra = vec![0; self.dir[segment].extra_bytes as usize / 2];
         new_free.block += blocks;
         new_free.length -= blocks;
         self.dir[segment].entries.insert(entry+1, new_free);
         self.write_directory_segment(segment)?;
-        Ok(RT11FileWriter{
-            image: &mut self.image,
-            direntry: &self.dir[segment].entries[entry],
+        let block = self.dir[segment].entries[entry].block;
+        let mut writer = RT11FileWriter{
+            fs: self,
+            name: name.to_owned(),
+            block,
+            length: blocks,
             residue: vec![],
             pos: 0,
+            closed: false,
+        };
+        if prefix_blocks > 0 {
+            use std::io::Write;
+            let mut prefix = vec![prefix_blocks as u8, 0];
+            prefix.extend_from_slice(&options.prefix);
+            prefix.resize(prefix_blocks * BLOCK_SIZE, 0);
+            writer.write_all(&prefix)?;
+        }
+        Ok(writer)
+    }
+
+    // Files with the prefix block bit set start with some blocks of information for whatever program made them,
+    // which aren't part of the file's data. The low byte of the first word of the file says how many there are.
+    fn file_prefix_blocks(&self, file: &DirEntry) -> anyhow::Result<usize> {
+        if !file.prefix_block { return Ok(0) }
+        let count = self.image.read_blocks(file.block, 1)?.read_u8()? as usize;
+        if count == 0 || count > file.length {
+            return Err(anyhow!("{} has a bad prefix block count: {} (but it's {} blocks long)", file.name, count, file.length));
+        }
+        Ok(count)
+    }
+
+    pub fn prefix_blocks(&self, name: &str) -> anyhow::Result<usize> {
+        let Some(file) = self.raw_stat(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.file_prefix_blocks(file)
+    }
+
+    // The contents of the prefix blocks, not including the count word (so it's empty if there aren't any).
+    pub fn read_prefix(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let Some(file) = self.raw_stat(name) else { return Err(anyhow!("File not found: {}", name)) };
+        Ok(match self.file_prefix_blocks(file)? {
+            0      => ByteBuffer::new(),
+            blocks => ByteBuffer::from_bytes(&self.image.read_blocks(file.block, blocks)?.as_bytes()[PREFIX_COUNT_BYTES..]),
         })
     }
 
+    // The whole file, prefix blocks and all
+    fn read_raw(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let Some(file) = self.raw_stat(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.image.read_blocks(file.block, file.length)
+    }
+
+    fn find_tentative(&self, block: usize) -> anyhow::Result<(usize, usize)> {
+        self.find(|f| f.kind == EntryKind::Tentative && f.block == block)
+            .ok_or_else(|| anyhow!("Tentative file @ {} disappeared", block))
+    }
+
+    // Like RT-11's .CLOSE: Replaces any existing permanent file with the same name, and gives the unused end of
+    // the tentative file back to the empty area that follows it.
+    fn close_tentative(&mut self, name: &str, block: usize, blocks_used: usize) -> anyhow::Result<()> {
+        if self.raw_stat(name).is_some() {
+            self.delete(name)?;
+        }
+        let (segment, entry) = self.find_tentative(block)?; // delete() may have moved it
+        let unused = self.dir[segment].entries[entry].length - blocks_used;
+        // enter() always leaves an empty entry after the tentative one, even if it's zero length.
+        self.dir[segment].entries[entry+1].block -= unused;
+        self.dir[segment].entries[entry+1].length += unused;
+        self.dir[segment].entries[entry].length = blocks_used;
+        self.dir[segment].entries[entry].kind = EntryKind::Permanent;
+        self.write_directory_segment(segment)
+    }
+
+    // What happens to tentative files that are never closed (RT-11 reclaims these when the job exits).
+    fn discard_tentative(&mut self, block: usize) -> anyhow::Result<()> {
+        let (segment, entry) = self.find_tentative(block)?;
+        self.dir[segment].entries[entry].kind = EntryKind::Empty;
+        self.dir[segment].entries[entry].forget_name(); // Half written files aren't worth undeleting
+        self.coalesce_empty(segment, entry);
+        self.write_directory_segment(segment)
+    }
+
     // As per the "RT–11 Volume and File Formats Manual" section 1.1.5
     fn split_directory(&mut self, segment: usize) -> anyhow::Result<()> {
         let block_range = self.dir[segment].block_range();
@@ -219,8 +542,141 @@ impl<B: BlockDevice> RT11FS<B> {
         self.dir[0].last_segment += 1;
         Ok(DirSegment::new(self.dir[0].last_segment, self.home.directory_start_block,
                            self.dir[0].last_segment..self.dir[0].segments,
+                           self.dir[0].extra_bytes, // Every segment in a directory has the same number of extra bytes
                            data_block))
     }
+
+    // Like RT-11's COPY/BOOT: Puts the primary bootstrap from `handler` in block 0 and the secondary bootstrap from
+    // `monitor` in blocks 2-5, then tells the secondary bootstrap which monitor and device it's booting.
+    pub fn copy_boot(&mut self, monitor: &str, handler: &str, unit: u16) -> anyhow::Result<()> {
+        let mut handler_data = self.read_file(handler).with_context(|| format!("Reading handler {}", handler))?;
+        handler_data.set_endian(Endian::LittleEndian);
+        let mut handler_word = |offset| -> anyhow::Result<usize> { handler_data.set_rpos(offset); Ok(handler_data.read_u16()? as usize) };
+        let (boot_ptr, boot_len, read_ptr) = (handler_word(HANDLER_BOOT_PTR)?, handler_word(HANDLER_BOOT_LEN)?, handler_word(HANDLER_BOOT_READ)?);
+        if boot_len == 0 || boot_len > BLOCK_SIZE || boot_ptr + boot_len > handler_data.len() || !(boot_ptr..boot_ptr+boot_len).contains(&read_ptr) {
+            return Err(anyhow!("{} doesn't have a usable bootstrap (boot @ {:#o}, {} bytes, read routine @ {:#o})", handler, boot_ptr, boot_len, read_ptr));
+        }
+        let mut primary = handler_data.as_bytes()[boot_ptr..boot_ptr+boot_len].to_vec();
+        primary.resize(BLOCK_SIZE, 0);
+
+        let monitor_data = self.read_file(monitor).with_context(|| format!("Reading monitor {}", monitor))?;
+        let secondary_bytes = SECONDARY_BOOT_BLOCK..SECONDARY_BOOT_BLOCK+SECONDARY_BOOT_BLOCKS;
+        if monitor_data.len() < secondary_bytes.end * BLOCK_SIZE {
+            return Err(anyhow!("{} is too short to be a monitor ({} blocks)", monitor, monitor_data.len() / BLOCK_SIZE));
+        }
+        // The secondary bootstrap lives in blocks 1-4 of the monitor file.
+        let mut secondary = ByteBuffer::from_bytes(&monitor_data.as_bytes()[BLOCK_SIZE..(SECONDARY_BOOT_BLOCKS+1)*BLOCK_SIZE]);
+        secondary.set_endian(Endian::LittleEndian);
+        let (device, suffix) = BootInfo::handler_device(handler)?;
+        let Some((monitor_name, _)) = monitor.split_once(".") else { return Err(anyhow!("monitor filename missing extension")) };
+        let fnam = radix50::pdp11::encode(&format!("{:<6}", monitor_name))?;
+        for (addr, word) in [(B_DEVN,   radix50::pdp11::encode_word(&format!("{:<3}", device))?),
+                             (B_DEVS,   radix50::pdp11::encode_word(&format!("{:<3}", suffix))?),
+                             (B_DEVU,   unit),
+                             (B_FNAM,   fnam[0]),
+                             (B_FNAM+2, fnam[1]),
+                             // The primary bootstrap is loaded at address 0, so offsets into it are addresses.
+                             (B_READ,   (read_ptr - boot_ptr) as u16)] {
+            secondary.set_wpos(addr - SECONDARY_BOOT_ADDR);
+            secondary.write_u16(word);
+        }
+
+        self.image.write_blocks(0, 1, &primary)?;
+        self.image.write_blocks(SECONDARY_BOOT_BLOCK, SECONDARY_BOOT_BLOCKS, secondary.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn read_boot_info(image: &B) -> anyhow::Result<Option<BootInfo>> {
+        if image.read_blocks(0, 1)?.as_bytes().iter().all(|b| *b == 0) { return Ok(None) }
+        let mut secondary = image.read_blocks(SECONDARY_BOOT_BLOCK, SECONDARY_BOOT_BLOCKS)?;
+        secondary.set_endian(Endian::LittleEndian);
+        let mut word = |addr| -> anyhow::Result<u16> { secondary.set_rpos(addr - SECONDARY_BOOT_ADDR); Ok(secondary.read_u16()?) };
+        let (devn, devs, unit, fnam) = (word(B_DEVN)?, word(B_DEVS)?, word(B_DEVU)?, [word(B_FNAM)?, word(B_FNAM+2)?]);
+        if devn == 0 || fnam == [0, 0] { return Ok(None) } // Probably INIT's "no boot on volume" bootstrap
+        Ok(Some(BootInfo {
+            device: radix50::pdp11::decode(&[devn]).trim().to_string(),
+            suffix: radix50::pdp11::decode(&[devs]).trim().to_string(),
+            unit,
+            monitor: format!("{}.SYS", radix50::pdp11::decode(&fnam).trim()),
+        }))
+    }
+
+    // The blocks a file occupies. RT-11 files are always contiguous.
+    pub fn file_extent(&self, name: &str) -> Option<Range<usize>> {
+        self.raw_stat(name).map(|f| f.block..f.block + f.length)
+    }
+
+    #[allow(unused)]
+    pub fn extra_words(&self, name: &str) -> anyhow::Result<&[u16]> {
+        let Some(file) = self.raw_stat(name) else { return Err(anyhow!("File not found: {}", name)) };
+        Ok(&file.extra)
+    }
+
+    // The entry after (segment, entry), which might be at the start of the next segment.
+    fn next_entry(&self, segment: usize, entry: usize) -> Option<(usize, usize)> {
+        if entry + 1 < self.dir[segment].entries.len() { return Some((segment, entry + 1)) }
+        if segment + 1 < self.dir.len() { return Some((segment + 1, 0)) }
+        None
+    }
+
+    // Adds `data` to the end of a file, starting at the block after its current last block (RT-11 files don't know
+    // where their data ends within a block). The file grows in place if there's enough empty space right after it,
+    // otherwise it gets moved somewhere that has room.
+    pub fn append(&mut self, name: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.check_writable()?;
+        let Some((segment, entry)) = self.find_file_named(name) else { return Err(anyhow!("File not found: {}", name)) };
+        let file = &self.dir[segment].entries[entry];
+        if file.protected { return Err(anyhow!("{} is protected", name)) }
+        if file.read_only { return Err(anyhow!("{} is read-only", name)) }
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+        let mut buf = data.to_vec();
+        buf.resize(blocks * BLOCK_SIZE, 0);
+
+        match self.next_entry(segment, entry) {
+            Some((ns, ne)) if self.dir[ns].entries[ne].kind == EntryKind::Empty && self.dir[ns].entries[ne].length >= blocks => {
+                let end = file.block + file.length;
+                self.image.write_blocks(end, blocks, &buf)?;
+                self.dir[segment].entries[entry].length += blocks;
+                let next = &mut self.dir[ns].entries[ne];
+                next.block += blocks;
+                next.length -= blocks;
+                if blocks > 0 { next.forget_name() }
+                if ns != segment {
+                    self.dir[ns].data_block += blocks as u16;
+                    self.write_directory_segment(ns)?;
+                }
+                self.write_directory_segment(segment)
+            },
+            _ => {
+                use std::io::Write;
+                let (extra, date, prefix_block) = (file.extra.clone(), file.creation_date, file.prefix_block);
+                let old = self.read_raw(name)?;
+                let mut fh = self.enter(name, Some(old.len() / BLOCK_SIZE + blocks))?;
+                fh.write_all(old.as_bytes())?;
+                fh.write_all(&buf)?;
+                fh.close()?;
+                // It's still the same file, just in a new place.
+                let (segment, entry) = self.find_file_named(name).unwrap(/*we just closed it*/);
+                self.dir[segment].entries[entry].extra = extra;
+                self.dir[segment].entries[entry].creation_date = date;
+                self.dir[segment].entries[entry].prefix_block = prefix_block;
+                self.write_directory_segment(segment)
+            },
+        }
+    }
+
+    pub fn set_extra_words(&mut self, name: &str, words: &[u16]) -> anyhow::Result<()> {
+        let Some((segment, entry)) = self.find_file_named(name) else { return Err(anyhow!("File not found: {}", name)) };
+        let extra_words = self.dir[segment].extra_bytes as usize / 2;
+        if words.len() > extra_words {
+            return Err(anyhow!("Too many extra words: {} (directory only has room for {})", words.len(), extra_words));
+        }
+        // Short lists are zero filled so that every entry in the segment stays the same size
+        let mut extra = words.to_vec();
+        extra.resize(extra_words, 0);
+        self.dir[segment].entries[entry].extra = extra;
+        self.write_directory_segment(segment)
+    }
 }
 
 impl<B: BlockDevice> FileSystem for RT11FS<B> {
@@ -256,23 +712,26 @@ impl<B: BlockDevice> FileSystem for RT11FS<B> {
         let Some(file) = self.raw_stat(&name) else {
             return Err(anyhow!("File not found: {}", name));
         };
-        self.image.read_blocks(file.block, file.length)
+        let prefix = self.file_prefix_blocks(file)?;
+        self.image.read_blocks(file.block + prefix, file.length - prefix)
     }
 
     fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
         use std::io::Write;
-        let mut fh = self.create(name, contents.len() as usize)?;
-        fh.write(&contents)?;
-        Ok(())
+        let mut fh = self.create_with_options(name, contents.len(), options)?;
+        fh.write_all(contents)?;
+        fh.close()
     }
 
     fn delete(&mut self, name: &str) -> anyhow::Result<()> {
         let Some((segment, entry)) = self.find_file_named(name) else { return Err(anyhow!("File not found")) };
+        if self.dir[segment].entries[entry].protected { return Err(anyhow!("{} is protected", name)) }
+        // Like RT-11, the empty entry keeps the file's name, which is what lets us undelete it later.
         self.dir[segment].entries[entry].kind = EntryKind::Empty;
-        self.coalesce_empty(segment, entry);
-        if entry > 0 {
-            self.coalesce_empty(segment, entry-1);
-        }
         self.write_directory_segment(segment)?;
         Ok(())
     }
@@ -280,11 +739,51 @@ impl<B: BlockDevice> FileSystem for RT11FS<B> {
     fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
         DirEntry::encode_filename(dest)?;
         let (segment, entry) = self.find_file_named(src).unwrap(/*we already checked*/);
+        if self.dir[segment].entries[entry].protected { return Err(anyhow!("{} is protected", src)) }
         self.dir[segment].entries[entry].name = dest.to_owned();
         self.write_directory_segment(segment)?;
         Ok(())
     }
 
+    fn set_protected(&mut self, name: &str, protected: bool) -> anyhow::Result<()> {
+        let Some((segment, entry)) = self.find_file_named(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.dir[segment].entries[entry].protected = protected;
+        self.write_directory_segment(segment)
+    }
+
+    fn set_readonly(&mut self, name: &str, readonly: bool) -> anyhow::Result<()> {
+        let Some((segment, entry)) = self.find_file_named(name) else { return Err(anyhow!("File not found: {}", name)) };
+        self.dir[segment].entries[entry].read_only = readonly;
+        self.write_directory_segment(segment)
+    }
+
+    fn deleted_iter<'a>(&'a self) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.full_dir_iter(Some(EntryKind::Empty))
+            .filter(|e| e.is_deleted_file())
+            .map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn undelete(&mut self, name: &str, new_name: &str) -> anyhow::Result<()> {
+        DirEntry::encode_filename(new_name)?;
+        let Some((segment, entry)) = self.find_deleted_file_named(name) else { return Err(anyhow!("No recoverable file named {}", name)) };
+        if self.raw_stat(new_name).is_some() { return Err(anyhow!("{} already exists", new_name)) }
+        self.dir[segment].entries[entry].kind = EntryKind::Permanent;
+        self.dir[segment].entries[entry].name = new_name.to_owned();
+        self.write_directory_segment(segment)
+    }
+
+    fn append_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.append(name, contents)
+    }
+
+    fn boot_info(&self) -> Option<String> {
+        let info = Self::read_boot_info(&self.image).ok()??;
+        Some(match self.raw_stat(&info.monitor) {
+            Some(_) => format!("{}", info),
+            None    => format!("{} (but {} is missing!)", info, info.monitor),
+        })
+    }
+
     fn block_device(&self) -> &B {
         &self.image
     }
@@ -386,6 +885,45 @@ system_id             : {:?},
     }
 }
 
+// Offsets into block 0 of a device handler file (set up by the .DRBOT macro)
+const HANDLER_BOOT_PTR:  usize = 0o62; // Byte offset of the primary bootstrap within the handler file
+const HANDLER_BOOT_LEN:  usize = 0o64; // Length of the primary bootstrap in bytes
+const HANDLER_BOOT_READ: usize = 0o66; // Byte offset of the primary bootstrap's read routine within the handler file
+
+// The primary bootstrap reads the secondary bootstrap (blocks 2-5) into memory at 1000. These are the addresses of the
+// words COPY/BOOT fills in (from BSTRAP.MAC).
+const SECONDARY_BOOT_BLOCK:  usize = 2;
+const SECONDARY_BOOT_BLOCKS: usize = 4;
+const SECONDARY_BOOT_ADDR:   usize = 0o1000;
+const B_DEVN: usize = 0o4716; // Radix-50 device name
+const B_DEVS: usize = 0o4720; // Radix-50 handler suffix (eg, "X" for XM handlers)
+const B_DEVU: usize = 0o4722; // Unit number
+const B_FNAM: usize = 0o4724; // Radix-50 monitor file name (2 words, .SYS is implied)
+const B_READ: usize = 0o4730; // Address of the primary bootstrap's read routine
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootInfo {
+    pub device: String,
+    pub suffix: String,
+    pub unit: u16,
+    pub monitor: String,
+}
+
+impl BootInfo {
+    // "DYX.SYS" -> ("DY", "X")
+    pub fn handler_device(handler: &str) -> anyhow::Result<(&str, &str)> {
+        let Some((name, _)) = handler.split_once(".") else { return Err(anyhow!("handler filename missing extension")) };
+        if name.len() < 2 || name.len() > 3 { return Err(anyhow!("{} doesn't look like a device handler", handler)) }
+        Ok(name.split_at(2))
+    }
+}
+
+impl std::fmt::Display for BootInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} from {}{}: (handler {}{}.SYS)", self.monitor, self.device, self.unit, self.device, self.suffix)
+    }
+}
+
 const STATUS_E_TENT: u16 = 0o000400;
 const STATUS_E_MPTY: u16 = 0o001000;
 const STATUS_E_PERM: u16 = 0o002000;
@@ -394,6 +932,13 @@ const STATUS_E_READ: u16 = 0o040000;
 const STATUS_E_PROT: u16 = 0o100000;
 const STATUS_E_PRE:  u16 = 0o000020;
 
+const MAX_DIR_SEGMENTS: u16 = 31;
+
+const PREFIX_COUNT_BYTES: usize = 2; // The word at the start of the prefix blocks that holds how many there are
+const MAX_PREFIX_BLOCKS: usize = 0xff; // ...but only its low byte counts
+
+const EMPTY_NAME: &str = "EMPTYF.ILE"; // What INIT names empty areas
+
 #[derive(Clone)]
 pub struct DirSegment {
     pub segments: u16,
@@ -411,7 +956,7 @@ pub struct DirSegment {
 impl DirSegment {
     pub fn segment_block(seg_start_block: u16, segment: u16) -> u16 { seg_start_block + (segment-1) * 2 }
 
-    pub fn new(segment: u16, seg_start_block: u16, unused_segs: Range<u16>, data_block: Range<u16>) -> DirSegment {
+    pub fn new(segment: u16, seg_start_block: u16, unused_segs: Range<u16>, extra_bytes: u16, data_block: Range<u16>) -> DirSegment {
         let block = DirSegment::segment_block(seg_start_block, segment);
         DirSegment {
             segment,
@@ -419,9 +964,9 @@ impl DirSegment {
             next_segment: 0,
             last_segment: unused_segs.start,
             segments: unused_segs.end,
-            extra_bytes: 0,
+            extra_bytes,
             data_block: data_block.start,
-            entries: vec![DirEntry::new_empty(data_block.start as usize, (data_block.end - data_block.start) as usize)],
+            entries: vec![DirEntry::new_empty(data_block.start as usize, (data_block.end - data_block.start) as usize, extra_bytes)],
         }
     }
 
@@ -452,6 +997,32 @@ impl DirSegment {
         })
     }
 
+    // Like from_repr(), but keeps all the entries up to the first bad one instead of failing (returning what was
+    // wrong with it). Only fails if the header itself looks bad.
+    pub fn salvage_from_repr(segment: u16, my_block: u16, mut buf: ByteBuffer, blocks: usize) -> anyhow::Result<(DirSegment, Option<anyhow::Error>)> {
+        buf.set_endian(Endian::LittleEndian);
+        let (segments, next_segment, last_segment, extra_bytes, data_block) = (buf.read_u16()?, buf.read_u16()?, buf.read_u16()?, buf.read_u16()?, buf.read_u16()?);
+        if !(1..=MAX_DIR_SEGMENTS).contains(&segments) || next_segment > segments || last_segment > segments || extra_bytes & 1 == 1 || data_block as usize >= blocks {
+            return Err(anyhow!("Bad header: segments {}, next {}, last {}, {} extra bytes, data @ {}",
+                               segments, next_segment, last_segment, extra_bytes, data_block));
+        }
+        let mut entries = vec![];
+        let mut block = data_block as usize;
+        let damage = loop {
+            match DirEntry::from_repr(block, extra_bytes, &mut buf) {
+                Ok(None) => break None,
+                Ok(Some(entry)) if entry.block + entry.length > blocks =>
+                    break Some(anyhow!("{} runs off the end of the volume ({} blocks @ {})", entry.name, entry.length, entry.block)),
+                Ok(Some(entry)) => {
+                    block += entry.length;
+                    entries.push(entry);
+                },
+                Err(e) => break Some(e),
+            }
+        };
+        Ok((DirSegment { segment, block: my_block, segments, next_segment, last_segment, extra_bytes, data_block, entries }, damage))
+    }
+
     pub fn repr(&self) -> anyhow::Result<[u8; 2 * BLOCK_SIZE]> {
         let mut repr = ByteBuffer::new();
         repr.set_endian(Endian::LittleEndian);
@@ -461,6 +1032,9 @@ impl DirSegment {
         repr.write_u16(self.extra_bytes);
         repr.write_u16(self.data_block);
         for entry in self.entries.iter() {
+            if entry.extra.len() * 2 != self.extra_bytes as usize {
+                return Err(anyhow!("Directory entry {} has {} extra bytes but its segment has {}", entry.name, entry.extra.len() * 2, self.extra_bytes));
+            }
             repr.write_bytes(&entry.repr()?);
         }
         repr.write_u16(STATUS_E_EOS);
@@ -574,16 +1148,36 @@ pub enum EntryKind {
 }
 
 impl DirEntry {
-    pub fn new_empty(data_block: usize, blocks: usize) -> DirEntry {
+    pub fn new_empty(data_block: usize, blocks: usize, extra_bytes: u16) -> DirEntry {
         DirEntry {
             kind: EntryKind::Empty,
-            name: "EMPTYF.ILE".to_string(),
+            name: EMPTY_NAME.to_string(),
             length: blocks,
             block: data_block,
-            read_only: false, protected: false, prefix_block: false, job: 0, channel: 0, creation_date: None, extra: vec![],
+            read_only: false, protected: false, prefix_block: false, job: 0, channel: 0, creation_date: None, extra: vec![0; extra_bytes as usize / 2],
         }
     }
 
+    // Blocks that salvage() couldn't find a directory entry for.
+    pub fn new_salvaged_gap(data_block: usize, blocks: usize, extra_bytes: u16) -> DirEntry {
+        DirEntry {
+            kind: EntryKind::Permanent,
+            name: format!("B{:05}.BAD", data_block),
+            ..Self::new_empty(data_block, blocks, extra_bytes)
+        }
+    }
+
+    // Empty entries that still describe exactly the file that was deleted. We forget the name whenever an empty
+    // area gets split or merged so that this stays true.
+    pub fn is_deleted_file(&self) -> bool {
+        self.kind == EntryKind::Empty && self.length > 0 && self.name != EMPTY_NAME && Self::encode_filename(&self.name).is_ok()
+    }
+
+    fn forget_name(&mut self) {
+        self.name = EMPTY_NAME.to_string();
+        self.creation_date = None;
+    }
+
     pub fn from_repr(data_block: usize, extra_bytes: u16, buf: &mut ByteBuffer) -> anyhow::Result<Option<DirEntry>> {
         let status = buf.read_u16()?;
         let length;
@@ -623,7 +1217,7 @@ impl DirEntry {
                                EntryKind::Permanent => STATUS_E_PERM,
                            }
                          | if self.read_only    { STATUS_E_READ } else { 0 }
-                         | if self.read_only    { STATUS_E_PROT } else { 0 }
+                         | if self.protected    { STATUS_E_PROT } else { 0 }
                          | if self.prefix_block { STATUS_E_PRE  } else { 0 });
         for r50 in Self::encode_filename(&self.name)? {
             repr.write_u16(r50);
@@ -714,6 +1308,7 @@ impl super::DirEntry for &DirEntry {
     fn created(&self)    -> anyhow::Result<super::Timestamp> { self.creation_date.map(|d| super::Timestamp::Date(d)).ok_or(anyhow!("Bad Date")) }
     fn blocks(&self)     -> u64                              { self.length as u64}
     fn readonly(&self)   -> bool                             { self.read_only }
+    fn protected(&self)  -> bool                             { self.protected }
 }
 
 pub struct DirEntryIterator<'a, B: BlockDevice> {
@@ -742,33 +1337,41 @@ impl<'a, B: BlockDevice> Iterator for DirEntryIterator<'a, B> {
 }
 
 pub struct RT11FileWriter<'a, B:BlockDevice> {
-    image: &'a mut B,
-    direntry: &'a DirEntry,
+    fs: &'a mut RT11FS<B>,
+    name: String,
+    block: usize,
+    length: usize,
     residue: Vec<u8>,
     pos: usize,
+    closed: bool,
 }
 
 impl <'a, B: BlockDevice> RT11FileWriter<'a, B> {
-    #[allow(unused)]
+    // Makes the file permanent. Dropping the writer without closing it throws away everything written.
     pub fn close(mut self) -> anyhow::Result<()> {
-        return self._close()
-    }
-    fn _close(&mut self) -> anyhow::Result<()> {
-        use std::io::Write;
-        self.flush()?;
+        // The last partial block only gets zero padded now since there might have been more writes to fill it.
+        if !self.residue.is_empty() {
+            if self.pos == self.length { return Err(io::Error::from(ErrorKind::OutOfMemory /*FileTooLarge, once it's stabilized*/))? }
+            self.residue.resize(BLOCK_SIZE, 0);
+            self.fs.image.write_blocks(self.block + self.pos, 1, &self.residue)?;
+            self.pos += 1;
+            self.residue.clear();
+        }
+        self.fs.close_tentative(&self.name, self.block, self.pos)?;
+        self.closed = true;
         Ok(())
     }
 }
 
 impl<'a, B: BlockDevice> std::io::Write for RT11FileWriter<'a, B> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        if self.pos == self.direntry.length { return Err(io::Error::from(ErrorKind::OutOfMemory /*FileTooLarge, once it's stabilized*/)) }
-        let truncated = &buf[0..min(buf.len(), (self.direntry.length - self.pos) * BLOCK_SIZE - self.residue.len())];
+        if self.pos == self.length { return Err(io::Error::from(ErrorKind::OutOfMemory /*FileTooLarge, once it's stabilized*/)) }
+        let truncated = &buf[0..min(buf.len(), (self.length - self.pos) * BLOCK_SIZE - self.residue.len())];
         let remains = if self.residue.len() > 0 {
             let (residue_fill, remains) = truncated.split_at(min(truncated.len(), BLOCK_SIZE - self.residue.len()));
             self.residue.extend_from_slice(&residue_fill);
             if self.residue.len() == BLOCK_SIZE {
-                self.image.write_blocks(self.direntry.block + self.pos, 1, &self.residue).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+                self.fs.image.write_blocks(self.block + self.pos, 1, &self.residue).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
                 self.pos += 1;
                 self.residue.clear();
             }
@@ -779,7 +1382,7 @@ impl<'a, B: BlockDevice> std::io::Write for RT11FileWriter<'a, B> {
         let blocks = remains.len() / BLOCK_SIZE;
         if blocks > 0 {
             let (chunk, residue) = remains.split_at(blocks * BLOCK_SIZE);
-            self.image.write_blocks(self.direntry.block + self.pos, blocks, &chunk).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            self.fs.image.write_blocks(self.block + self.pos, blocks, &chunk).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
             self.pos += blocks;
             self.residue.extend_from_slice(residue);
         } else {
@@ -788,21 +1391,17 @@ impl<'a, B: BlockDevice> std::io::Write for RT11FileWriter<'a, B> {
         Ok(truncated.len())
     }
 
+    // Whole blocks are already on the image and a partial one has to wait for close() (or more writes).
     fn flush(&mut self) -> std::io::Result<()> {
-        if self.residue.len() > 0 {
-            if self.pos == self.direntry.length { return Err(io::Error::from(ErrorKind::OutOfMemory /*FileTooLarge, once it's stabilized*/)) }
-            self.residue.extend_from_slice(&vec![0; BLOCK_SIZE - self.residue.len()]);
-            self.image.write_blocks(self.direntry.block + self.pos, 1, &self.residue).map_err(|e| io::Error::new(ErrorKind::Other, e))?;
-            self.pos += 1;
-            self.residue.clear();
-        }
         Ok(())
     }
 }
 
 impl<'a, B: BlockDevice> Drop for RT11FileWriter<'a, B> {
     fn drop(&mut self) {
-        _ = self._close();
+        if !self.closed {
+            _ = self.fs.discard_tentative(self.block);
+        }
     }
 }
 
@@ -838,7 +1437,9 @@ mod test {
     fn test_write() {
         let dev = TestDev(vec![0;512*20]);
         let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
-        { fs.create("TEST.TXT", 512).expect("write test.txt"); }
+        { let mut f = fs.create("TEST.TXT", 512).expect("write test.txt");
+            f.write(&vec![0; 512]).expect("write");
+            f.close().expect("close"); }
         assert_block_eq!(fs.image, 6,
             vec![0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x04, 0xdb, 0x7d, 0x00, 0x7d,
                  0xd4, 0x80, 0x01, 0x00, 0x00, 0x00, 0x73, 0x46, 0x00, 0x02, 0x58, 0x21, 0xee, 0x80, 0x25, 0x3a,
@@ -848,7 +1449,8 @@ mod test {
         let dev = TestDev(vec![0;512*20]);
         let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
         { let mut f = fs.create("TEST.TXT", 512).expect("write test.txt");
-            f.write(&incrementing(512)).expect("write"); }
+            f.write(&incrementing(512)).expect("write");
+            f.close().expect("close"); }
         assert_block_eq!(fs.image, 14, incrementing(512));
     }
 
@@ -863,6 +1465,7 @@ mod test {
             f.write(&incrementing(256)).expect("write");
             assert_eq!(f.residue, incrementing(256));
             f.write(&incrementing(256)).expect("write");
+            f.close().expect("close");
         }
         assert_block_eq!(fs.image, 14, incrementing(512));
     }
@@ -874,6 +1477,7 @@ mod test {
         {
             let mut f = fs.create("TEST.TXT", 512).expect("write test.txt");
             f.write(&incrementing(256)).expect("write");
+            f.close().expect("close");
         }
         assert_block_eq!(fs.image, 14, incrementing(256), vec![0; 256]);
     }
@@ -885,14 +1489,19 @@ mod test {
         {
             let mut f = fs.create("TEST.TXT", 512).expect("write test.txt");
             f.write(&incrementing(256)).expect("write");
+            f.close().expect("close");
         }
         {
             let mut f = fs.create("TEST.TXT", 1024).expect("write test.txt");
             f.write(&vec![0x55; 1024]).expect("write");
+            f.close().expect("close");
         }
-        assert_eq!(fs.dir[0].entries.len(), 2);
-        assert_block_eq!(fs.image, 14, vec![0x55; 512]);
+        // The old file stays put until the new one is closed, leaving a hole where it was.
+        assert_eq!(fs.dir[0].entries.len(), 3);
+        assert_eq!(fs.dir[0].entries[0].kind, EntryKind::Empty);
+        assert_block_eq!(fs.image, 14, incrementing(256), vec![0; 256]);
         assert_block_eq!(fs.image, 15, vec![0x55; 512]);
+        assert_block_eq!(fs.image, 16, vec![0x55; 512]);
     }
 
     #[test]
@@ -902,14 +1511,17 @@ mod test {
         {
             let mut f = fs.create("TEST.TXT", 512).expect("write test.txt");
             f.write(&incrementing(256)).expect("write");
+            f.close().expect("close");
         }
         fs.delete("TEST.TXT").expect("delete test.txt");
         assert_eq!(fs.stat("TEST.TXT").is_none(), true);
         assert_eq!(fs.used_blocks(), 0);
+        // The empty entry keeps the old name, length and date so it can be undeleted
         assert_block_eq!(fs.image, 6,
-            vec![0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x02, ____, ____, ____, ____,
-                 ____, ____, 0x06, 0x00, 0x00, 0x00, ____, ____, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-            vec![0; 512-32]);
+            vec![0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x02, 0xdb, 0x7d, 0x00, 0x7d,
+                 0xd4, 0x80, 0x01, 0x00, 0x00, 0x00, 0x73, 0x46, 0x00, 0x02, ____, ____, ____, ____, ____, ____,
+                 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08],
+            vec![0; 512-40]);
     }
 
     #[test]
@@ -919,9 +1531,50 @@ mod test {
         {
             let mut f = fs.create("TEST.TXT", 512).expect("write test.txt");
             f.write(&incrementing(256)).expect("write");
+            f.close().expect("close");
         }
         fs.delete("TEST.TXT").expect("delete test.txt");
-        assert_eq!(fs.dir[0].entries.len(), 1);
+        assert_eq!(fs.dir[0].entries.len(), 2);
+        // Only needing the space makes us coalesce
+        fs.write_file("BIG.TXT", &vec![0x55; 512*6]).expect("write big.txt");
+        assert_eq!(fs.dir[0].entries.len(), 2);
+        assert_eq!((fs.dir[0].entries[0].name.as_str(), fs.dir[0].entries[0].block), ("BIG.TXT", 14));
+        assert_eq!((fs.dir[0].entries[1].kind, fs.dir[0].entries[1].length), (EntryKind::Empty, 0));
+    }
+
+    #[test]
+    fn test_undelete() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file("TEST.TXT", &incrementing(1024)).expect("write test.txt");
+        fs.write_file("OTHER.TXT", &incrementing(512)).expect("write other.txt");
+        assert_eq!(fs.deleted_iter().expect("deleted").count(), 0);
+        fs.delete("TEST.TXT").expect("delete test.txt");
+        fs.delete("OTHER.TXT").expect("delete other.txt");
+        assert_eq!(fs.deleted_iter().expect("deleted").map(|f| f.file_name().to_string()).collect::<Vec<_>>(), vec!["TEST.TXT", "OTHER.TXT"]);
+        fs.write_file("OTHER.TXT", &vec![0x55; 512]).expect("write other.txt");
+        assert!(fs.undelete("OTHER.TXT", "OTHER.TXT").is_err());
+        fs.undelete("OTHER.TXT", "OLD.TXT").expect("undelete other.txt");
+        fs.undelete("TEST.TXT", "TEST.TXT").expect("undelete test.txt");
+        assert!(fs.undelete("TEST.TXT", "TEST.TXT").is_err());
+        let fs = RT11FS::new(fs.image).expect("Reopen RT-11 FS");
+        assert_eq!(fs.read_file("TEST.TXT").expect("read test.txt").into_vec(), incrementing(1024));
+        assert_eq!(fs.read_file("OLD.TXT").expect("read old.txt").into_vec(), incrementing(512));
+        assert_eq!(fs.read_file("OTHER.TXT").expect("read other.txt").into_vec(), vec![0x55; 512]);
+    }
+
+    #[test]
+    fn test_undelete_reused_space() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file("TEST.TXT", &incrementing(1024)).expect("write test.txt");
+        fs.write_file("FILL.TXT", &vec![0; 512*4]).expect("write fill.txt");
+        fs.delete("TEST.TXT").expect("delete test.txt");
+        // No other space, so this has to reuse the first block of the deleted file
+        fs.write_file("OTHER.TXT", &vec![0x55; 512]).expect("write other.txt");
+        assert_eq!(fs.raw_stat("OTHER.TXT").expect("stat").block, 14);
+        assert_eq!(fs.deleted_iter().expect("deleted").count(), 0);
+        assert!(fs.undelete("TEST.TXT", "TEST.TXT").is_err());
     }
 
     #[test]
@@ -931,6 +1584,7 @@ mod test {
         for i in 0..75 {
             let mut f = fs.create(&format!("TEST{i}.TXT"), 512).expect("write test.txt");
             f.write(&incrementing(256)).expect("write");
+            f.close().expect("close");
         }
         for seg in fs.dir.iter() {
             println!("{:#?}", seg)
@@ -947,4 +1601,314 @@ mod test {
         assert_block_eq!(fs.image, 0x0e, incrementing(256), vec![0; 256]); // First file in segment 1
         assert_block_eq!(fs.image, 0x31, incrementing(256), vec![0; 256]); // First file in segment 2
     }
+
+    #[test]
+    fn test_split_directory_segment_extra_bytes() {
+        let dev = TestDev(vec![0;512*200]);
+        let mut fs = RT11FS::mkfs_with_extra_bytes(dev, 4).expect("Create RT-11 FS");
+        for i in 0..60 {
+            let mut f = fs.create(&format!("TEST{i}.TXT"), 512).expect("write test.txt");
+            f.write_all(&incrementing(256)).expect("write");
+            f.close().expect("close");
+        }
+        fs.set_extra_words("TEST59.TXT", &[0o1234, 0o5670]).expect("set extra words");
+        assert_eq!(fs.dir.len(), 2);
+        assert_eq!(fs.dir[1].extra_bytes, 4);
+        let fs = RT11FS::new(fs.image).expect("Reopen RT-11 FS");
+        assert_eq!(fs.dir.len(), 2);
+        assert_eq!(fs.extra_words("TEST59.TXT").expect("extra words"), &[0o1234, 0o5670]);
+        assert_eq!(fs.extra_words("TEST0.TXT").expect("extra words"), &[0, 0]);
+        assert!(fs.dir.iter().all(|s| s.entries.iter().all(|e| e.extra.len() == 2)));
+    }
+
+    #[test]
+    fn test_set_extra_words() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs_with_extra_bytes(dev, 2).expect("Create RT-11 FS");
+        fs.write_file("TEST.TXT", &incrementing(512)).expect("write test.txt");
+        assert!(fs.set_extra_words("TEST.TXT", &[1, 2]).is_err());
+        fs.set_extra_words("TEST.TXT", &[0x55aa]).expect("set extra words");
+        assert_block_eq!(fs.image, 6,
+            vec![0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x0e, 0x00, 0x00, 0x04, 0xdb, 0x7d, 0x00, 0x7d,
+                 0xd4, 0x80, 0x01, 0x00, 0x00, 0x00, 0x73, 0x46, 0xaa, 0x55],
+            vec![____; 512-26]);
+        fs.write_file("TEST.TXT", &incrementing(512)).expect("overwrite test.txt");
+        assert_eq!(fs.extra_words("TEST.TXT").expect("extra words"), &[0]);
+    }
+
+    fn holey_fs() -> RT11FS<TestDev> {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        for (name, blocks) in [("A.TXT", 2), ("B.TXT", 1), ("C.TXT", 4), ("D.TXT", 1), ("F.TXT", 18)] {
+            fs.write_file(name, &vec![0x55; 512*blocks]).expect("write");
+        }
+        for name in ["A.TXT", "C.TXT", "F.TXT"] {
+            fs.delete(name).expect("delete");
+        }
+        fs
+    }
+
+    #[test]
+    fn test_placement() {
+        let mut fs = holey_fs();
+        let mut place = |name: &str, blocks: usize, placement: Placement| -> anyhow::Result<usize> {
+            fs.write_file_with_options(name, &incrementing(512*blocks), &CreateOptions { placement, ..Default::default() })?;
+            Ok(fs.raw_stat(name).expect("stat").block)
+        };
+        assert_eq!(place("FIRST.TXT",   3, Placement::FirstFit).expect("first fit"), 17);
+        // The 1 block left over from C.TXT fits best (and isn't a deleted file anymore)
+        assert_eq!(place("BEST.TXT",    1, Placement::BestFit).expect("best fit"), 20);
+        assert_eq!(place("LARGE.TXT",   1, Placement::Largest).expect("largest"), 22);
+        assert!(place("AT.TXT", 1, Placement::At(16)).is_err());
+        assert!(place("AT.TXT", 2, Placement::At(39)).is_err());
+        assert_eq!(place("AT.TXT",      2, Placement::At(30)).expect("at"), 30);
+        let fs = RT11FS::new(fs.image).expect("Reopen RT-11 FS");
+        assert_eq!(fs.read_file("AT.TXT").expect("read at.txt").into_vec(), incrementing(1024));
+        assert_eq!(fs.raw_stat("AT.TXT").expect("stat").block, 30);
+        assert_eq!(fs.raw_stat("D.TXT").expect("stat").block, 21);
+        assert_eq!(fs.free_blocks(), 26 - 1 - 1 - 1 - 1 - 3 - 2);
+    }
+
+    #[test]
+    fn test_replace_at() {
+        let mut fs = holey_fs();
+        let mut place = |name: &str, blocks: usize, block: usize| -> anyhow::Result<usize> {
+            fs.write_file_with_options(name, &vec![blocks as u8; 512*blocks], &CreateOptions { placement: Placement::At(block), ..Default::default() })?;
+            Ok(fs.raw_stat(name).expect("stat").block)
+        };
+        assert_eq!(place("MON.SYS", 2, 24).expect("at"), 24);
+        assert_eq!(place("MON.SYS", 2, 24).expect("same blocks"), 24);
+        assert_eq!(place("MON.SYS", 3, 25).expect("into the empty space after it"), 25);
+        assert_eq!(place("MON.SYS", 2, 24).expect("into the empty space before it"), 24);
+        assert!(place("MON.SYS", 2, 20).is_err()); // D.TXT is in the way
+        assert!(place("B.TXT", 6, 16).is_err()); // So is D.TXT
+        // Failing to fit has to leave the old file alone
+        assert!(place("MON.SYS", 4, 21).is_err()); // D.TXT again
+        assert!(place("MON.SYS", 20, 24).is_err()); // Runs off the end of the disk
+        assert_eq!(fs.raw_stat("MON.SYS").expect("stat").block, 24);
+        let fs = RT11FS::new(fs.image).expect("Reopen RT-11 FS");
+        assert_eq!(fs.read_file("MON.SYS").expect("read mon.sys").into_vec(), vec![2; 1024]);
+        assert_eq!(fs.read_file("B.TXT").expect("read b.txt").into_vec(), vec![0x55; 512]);
+        assert_eq!(fs.full_dir_iter(Some(EntryKind::Permanent)).count(), 3);
+    }
+
+    #[test]
+    fn test_append_in_place() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs_with_extra_bytes(dev, 2).expect("Create RT-11 FS");
+        fs.write_file("LOG.TXT", &incrementing(512)).expect("write log.txt");
+        fs.set_extra_words("LOG.TXT", &[0o1234]).expect("set extra words");
+        fs.append("LOG.TXT", &vec![0x55; 700]).expect("append");
+        assert_eq!(fs.dir[0].entries.len(), 2);
+        assert_eq!((fs.dir[0].entries[1].block, fs.dir[0].entries[1].length), (17, 3));
+        let fs = RT11FS::new(fs.image).expect("Reopen RT-11 FS");
+        let f = fs.raw_stat("LOG.TXT").expect("stat");
+        assert_eq!((f.block, f.length, f.extra.as_slice()), (14, 3, [0o1234].as_slice()));
+        assert_block_eq!(fs.image, 14, incrementing(512));
+        assert_block_eq!(fs.image, 15, vec![0x55; 512]);
+        assert_block_eq!(fs.image, 16, vec![0x55; 700-512], vec![0; 1024-700]);
+    }
+
+    #[test]
+    fn test_append_relocates() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs_with_extra_bytes(dev, 2).expect("Create RT-11 FS");
+        fs.write_file("LOG.TXT", &incrementing(512)).expect("write log.txt");
+        fs.write_file("NEXT.TXT", &vec![0xaa; 512]).expect("write next.txt");
+        fs.set_extra_words("LOG.TXT", &[0o1234]).expect("set extra words");
+        fs.append("LOG.TXT", &vec![0x55; 512]).expect("append");
+        assert_eq!(fs.raw_stat("LOG.TXT").expect("stat").block, 16);
+        assert_eq!(fs.extra_words("LOG.TXT").expect("extra words"), &[0o1234]);
+        assert_eq!(fs.read_file("LOG.TXT").expect("read log.txt").into_vec(), [incrementing(512), vec![0x55; 512]].concat());
+        assert_eq!(fs.read_file("NEXT.TXT").expect("read next.txt").into_vec(), vec![0xaa; 512]);
+        assert!(fs.append("LOG.TXT", &vec![0x55; 512*3]).is_err()); // Doesn't fit anywhere
+        fs.set_protected("NEXT.TXT", true).expect("protect");
+        assert!(fs.append("NEXT.TXT", &[1]).is_err());
+    }
+
+    #[test]
+    fn test_prefix_blocks() {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file_with_options("PRE.SAV", &incrementing(512), &CreateOptions { prefix: vec![0x55; 600], ..Default::default() })
+            .expect("write pre.sav");
+        assert_block_eq!(fs.image, 14, vec![0x02, 0x00], vec![0x55; 510]);
+        assert_block_eq!(fs.image, 15, vec![0x55; 90], vec![0; 512-90]);
+        assert_block_eq!(fs.image, 16, incrementing(512));
+        let mut fs = RT11FS::new(fs.image).expect("Reopen RT-11 FS");
+        let f = fs.raw_stat("PRE.SAV").expect("stat");
+        assert_eq!((f.prefix_block, f.length), (true, 3));
+        assert_eq!(fs.prefix_blocks("PRE.SAV").expect("prefix blocks"), 2);
+        assert_eq!(fs.read_file("PRE.SAV").expect("read pre.sav").into_vec(), incrementing(512));
+        assert_eq!(fs.read_prefix("PRE.SAV").expect("read prefix").into_vec(), [vec![0x55; 600], vec![0; 1024-2-600]].concat());
+        // Moving it keeps the prefix
+        fs.write_file("NEXT.TXT", &incrementing(512)).expect("write next.txt");
+        fs.append("PRE.SAV", &incrementing(512)).expect("append");
+        assert_eq!(fs.read_file("PRE.SAV").expect("read pre.sav").into_vec(), [incrementing(512), incrementing(512)].concat());
+        assert_eq!(fs.read_prefix("PRE.SAV").expect("read prefix").len(), 1022);
+        fs.write_file("PLAIN.TXT", &incrementing(512)).expect("write plain.txt");
+        assert_eq!(fs.prefix_blocks("PLAIN.TXT").expect("prefix blocks"), 0);
+        assert_eq!(fs.read_prefix("PLAIN.TXT").expect("read prefix").len(), 0);
+    }
+
+    #[test]
+    fn test_bad_prefix_count() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file_with_options("PRE.SAV", &incrementing(512), &CreateOptions { prefix: vec![0x55; 10], ..Default::default() })
+            .expect("write pre.sav");
+        fs.image.write_blocks(14, 1, &[0x05; 512]).expect("clobber prefix count");
+        assert!(fs.read_file("PRE.SAV").is_err());
+        assert!(fs.write_file_with_options("BIG.SAV", &[], &CreateOptions { prefix: vec![0; 256*512], ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_salvage_lost_segment() {
+        let dev = TestDev(vec![0;512*200]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        for i in 0..75 {
+            fs.write_file(&format!("TEST{i}.TXT"), &incrementing(512)).expect("write test.txt");
+        }
+        assert_eq!(fs.dir.len(), 2);
+        fs.image.write_blocks(6, 1, &[0xff; 512]).expect("clobber segment 1");
+        assert!(RT11FS::new(TestDev(fs.image.0.clone())).is_err());
+        let (mut fs, problems) = RT11FS::salvage(fs.image).expect("salvage");
+        assert_eq!(problems.len(), 2);
+        // Everything in the first segment is lumped together...
+        assert_eq!(fs.raw_stat("B00014.BAD").expect("gap").length, 0x31 - 14);
+        assert_eq!(fs.read_file("B00014.BAD").expect("read gap").as_bytes()[0..512], incrementing(512));
+        // ...but the second segment's files are fine
+        assert!(fs.raw_stat("TEST0.TXT").is_none());
+        assert_eq!(fs.raw_stat("TEST74.TXT").expect("stat test74.txt").block, 14 + 74);
+        assert_eq!(fs.read_file("TEST74.TXT").expect("read test74.txt").into_vec(), incrementing(512));
+        assert!(fs.write_file("NEW.TXT", &incrementing(512)).is_err());
+    }
+
+    #[test]
+    fn test_salvage_damaged_entry() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file("ONE.TXT", &incrementing(512)).expect("write one.txt");
+        fs.write_file("TWO.TXT", &incrementing(1024)).expect("write two.txt");
+        fs.write_file("THREE.TXT", &incrementing(512)).expect("write three.txt");
+        fs.image.write_blocks(6, 1, &{ let mut b = fs.image.read_blocks(6, 1).unwrap().into_vec(); b[10+14] = 0; b[10+15] = 0; b }).expect("clobber TWO.TXT's status");
+        assert!(RT11FS::new(TestDev(fs.image.0.clone())).is_err());
+        let (fs, problems) = RT11FS::salvage(fs.image).expect("salvage");
+        assert_eq!(problems.len(), 2);
+        assert_eq!(fs.read_file("ONE.TXT").expect("read one.txt").into_vec(), incrementing(512));
+        assert_eq!(fs.dir[0].entries.len(), 2);
+        assert_eq!((fs.dir[0].entries[1].name.as_str(), fs.dir[0].entries[1].block, fs.dir[0].entries[1].length), ("B00015.BAD", 15, 20-15));
+    }
+
+    #[test]
+    fn test_protected_file() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file("TEST.TXT", &incrementing(512)).expect("write test.txt");
+        fs.write_file("OTHER.TXT", &incrementing(512)).expect("write other.txt");
+        fs.set_protected("TEST.TXT", true).expect("protect");
+        assert_block_eq!(fs.image, 6,
+            vec![0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x84],
+            vec![____; 512-12]);
+        assert!(fs.delete("TEST.TXT").is_err());
+        assert!(fs.rename("TEST.TXT", "NEW.TXT").is_err());
+        assert!(fs.rename("OTHER.TXT", "TEST.TXT").is_err());
+        assert!(fs.write_file("TEST.TXT", &vec![0x55; 512]).is_err());
+        assert!(fs.stat("OTHER.TXT").is_some());
+        assert_block_eq!(fs.image, 14, incrementing(512));
+        fs.set_protected("TEST.TXT", false).expect("unprotect");
+        fs.delete("TEST.TXT").expect("delete test.txt");
+    }
+
+    #[test]
+    fn test_readonly_is_not_protected() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file("TEST.TXT", &incrementing(512)).expect("write test.txt");
+        fs.set_readonly("TEST.TXT", true).expect("readonly");
+        assert_block_eq!(fs.image, 6,
+            vec![0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x44],
+            vec![____; 512-12]);
+        let fs = RT11FS::new(fs.image).expect("Reopen RT-11 FS");
+        let f = fs.raw_stat("TEST.TXT").expect("stat");
+        assert_eq!((f.read_only, f.protected), (true, false));
+    }
+
+    #[test]
+    fn test_tentative_file() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file("TEST.TXT", &incrementing(512)).expect("write test.txt");
+        {
+            let mut f = fs.enter("TEST.TXT", None).expect("enter test.txt");
+            f.write_all(&[0x55; 600]).expect("write");
+            f.flush().expect("flush"); // Mustn't pad out the partial block
+            f.write_all(&[0x55; 100]).expect("write");
+            // Still only visible as a tentative file--the old one is still there
+            assert_eq!(f.fs.dir[0].entries[1].kind, EntryKind::Tentative);
+            assert_eq!(f.fs.dir[0].entries[1].length, 20 - 15);
+            assert_eq!(f.fs.read_file("TEST.TXT").expect("read test.txt").into_vec(), incrementing(512));
+            f.close().expect("close");
+        }
+        assert_eq!(fs.read_file("TEST.TXT").expect("read test.txt").into_vec(), [vec![0x55; 700], vec![0; 1024-700]].concat());
+        assert_eq!(fs.raw_stat("TEST.TXT").expect("stat").block, 15);
+        assert_eq!(fs.free_blocks(), 20 - 14 - 2);
+        let fs = RT11FS::new(fs.image).expect("Reopen RT-11 FS");
+        assert_eq!(fs.read_dir("/").expect("read_dir").count(), 1);
+    }
+
+    #[test]
+    fn test_drop_tentative_file() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file("TEST.TXT", &incrementing(512)).expect("write test.txt");
+        {
+            let mut f = fs.enter("TEST.TXT", Some(3)).expect("enter test.txt");
+            f.write_all(&vec![0x55; 512]).expect("write");
+        }
+        assert_eq!(fs.dir[0].entries.len(), 2);
+        assert_eq!(fs.free_blocks(), 20 - 14 - 1);
+        assert_eq!(fs.read_file("TEST.TXT").expect("read test.txt").into_vec(), incrementing(512));
+        assert_block_eq!(fs.image, 6,
+            vec![0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x04, ____, ____, ____, ____,
+                 ____, ____, 0x01, 0x00, 0x00, 0x00, ____, ____, 0x00, 0x02, ____, ____, ____, ____, ____, ____,
+                 0x05, 0x00, 0x00, 0x00, ____, ____, 0x00, 0x08],
+            vec![0; 512-40]);
+    }
+
+    #[test]
+    fn test_copy_boot() {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        assert_eq!(fs.boot_info(), None);
+        let mut handler = vec![0; 2*512];
+        handler[0o62..0o70].copy_from_slice(&[0x00, 0x02, 0x00, 0x01, 0x40, 0x02]); // boot @ 1000, 400 bytes long, read @ 1100
+        handler[512..768].copy_from_slice(&vec![0xb0; 256]);
+        fs.write_file("DY.SYS", &handler).expect("write handler");
+        fs.write_file("RT11SJ.SYS", &(0..6*512).map(|x| (x / 512) as u8).collect::<Vec<u8>>()).expect("write monitor");
+        fs.copy_boot("RT11SJ.SYS", "DY.SYS", 0).expect("copy_boot");
+        assert_block_eq!(fs.image, 0, vec![0xb0; 256], vec![0; 256]);
+        assert_block_eq!(fs.image, 2, vec![1; 512]);
+        assert_block_eq!(fs.image, 4, vec![3; 512]);
+        assert_block_eq!(fs.image, 5,
+                         vec![4; 0o716],
+                         vec![0xe8, 0x1c, 0x00, 0x00, 0x00, 0x00, 0xbf, 0x73, 0xc2, 0xc4, 0x40, 0x00],
+                         vec![4; 512-0o716-12]);
+        assert_eq!(RT11FS::read_boot_info(&fs.image).expect("boot info"),
+                   Some(BootInfo { device: "DY".into(), suffix: "".into(), unit: 0, monitor: "RT11SJ.SYS".into() }));
+        assert_eq!(fs.boot_info().as_deref(), Some("RT11SJ.SYS from DY0: (handler DY.SYS)"));
+        fs.delete("RT11SJ.SYS").expect("delete monitor");
+        assert_eq!(fs.boot_info().as_deref(), Some("RT11SJ.SYS from DY0: (handler DY.SYS) (but RT11SJ.SYS is missing!)"));
+    }
+
+    #[test]
+    fn test_copy_boot_bad_handler() {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = RT11FS::mkfs(dev).expect("Create RT-11 FS");
+        fs.write_file("DY.SYS", &vec![0; 2*512]).expect("write handler");
+        fs.write_file("RT11SJ.SYS", &vec![0; 6*512]).expect("write monitor");
+        assert!(fs.copy_boot("RT11SJ.SYS", "DY.SYS", 0).is_err());
+        assert!(fs.copy_boot("RT11SJ.SYS", "DX.SYS", 0).is_err());
+        assert_block_eq!(fs.image, 0, vec![0; 512]);
+    }
 }
@@ -0,0 +1,866 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+use bytebuffer::{Endian, ByteBuffer};
+use chrono::{DateTime, NaiveDateTime};
+
+// Things we override to make testing easier
+#[cfg(not(test))] use chrono::Local;
+#[cfg    (test)]  use super::test::Local;
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use super::FileSystem;
+
+// Sixth Edition Unix filesystem. See filsys(5) and ino(5) in the V6 manual, and alloc.c, iget.c and subr.c in the
+// kernel (Lions' Commentary covers them line by line).
+//
+// Block 0 is the boot block and block 1 the superblock. The i-nodes start at block 2 (16 to a block, numbered from
+// 1, with the root directory being i-node 1) and everything after them is data. Free blocks are kept in a chain of
+// lists of up to 100 blocks: the first list is in the superblock, and the first block in each list holds the next
+// list.
+
+const SUPERBLOCK: usize = 1;
+const FIRST_INODE_BLOCK: usize = 2;
+const INODE_SIZE: usize = 32;
+const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+const ROOT_INODE: u16 = 1;
+const NICFREE: usize = 100; // Free blocks the superblock holds
+const NICINOD: usize = 100; // Free i-nodes the superblock holds
+const DIR_ENTRY_SIZE: usize = 16;
+const MAX_NAME: usize = 14;
+const ADDRS_PER_BLOCK: usize = BLOCK_SIZE / 2;
+const SMALL_FILE_BLOCKS: usize = 8;
+const INDIRECT_ADDRS: usize = 7; // The 8th address in a large file is a double indirect block
+const MAX_FILE_BLOCKS: usize = 0o100000;
+
+// i_mode bits
+const IALLOC: u16 = 0o100000;
+const IFMT:   u16 = 0o060000;
+const IFDIR:  u16 = 0o040000;
+const IFCHR:  u16 = 0o020000;
+const IFBLK:  u16 = 0o060000;
+const ILARG:  u16 = 0o010000;
+const ISUID:  u16 = 0o004000;
+const ISGID:  u16 = 0o002000;
+const ISVTX:  u16 = 0o001000;
+
+#[derive(Clone)]
+pub struct UnixV6Fs<B: BlockDevice> {
+    pub image: B,
+    pub superblock: SuperBlock,
+}
+
+impl<B: BlockDevice> UnixV6Fs<B> {
+    pub fn new(image: B) -> anyhow::Result<UnixV6Fs<B>> {
+        let superblock = Self::try_new(&image)?;
+        Ok(UnixV6Fs { image, superblock })
+    }
+
+    pub fn image_is(image: &B) -> bool {
+        Self::try_new(image).is_ok()
+    }
+
+    // There's no magic number, so make sure the superblock is sane and the root directory looks like one.
+    fn try_new(image: &B) -> anyhow::Result<SuperBlock> {
+        let superblock = SuperBlock::from_repr(&mut image.read_blocks(SUPERBLOCK, 1)?)?;
+        let sb = &superblock;
+        if sb.isize == 0 || sb.fsize as usize > image.blocks() || sb.fsize as usize <= FIRST_INODE_BLOCK + sb.isize as usize {
+            return Err(anyhow!("Bad filesystem size {} (i-list {}, device {})", sb.fsize, sb.isize, image.blocks()));
+        }
+        if sb.nfree as usize > NICFREE || sb.ninode as usize > NICINOD { return Err(anyhow!("Bad superblock free lists")) }
+        if sb.free[0..sb.nfree as usize].iter().any(|b| *b != 0 && !sb.data_blocks().contains(&(*b as usize))) {
+            return Err(anyhow!("Bad block in superblock free list"));
+        }
+        let root = Inode::from_repr(ROOT_INODE, &image.read_blocks(FIRST_INODE_BLOCK, 1)?.as_bytes()[0..INODE_SIZE])?;
+        if !root.is_dir() || (root.size as usize) < 2 * DIR_ENTRY_SIZE || !(root.size as usize).is_multiple_of(DIR_ENTRY_SIZE) {
+            return Err(anyhow!("I-node 1 isn't a directory"));
+        }
+        let first = if root.is_large() { words(image.read_blocks(root.addr[0] as usize, 1)?.as_bytes())[0] } else { root.addr[0] };
+        if !sb.data_blocks().contains(&(first as usize)) { return Err(anyhow!("Bad root directory block {}", first)) }
+        if image.read_blocks(first as usize, 1)?.as_bytes()[0..2 * DIR_ENTRY_SIZE] != dir_repr(&[(ROOT_INODE, ".".to_string()), (ROOT_INODE, "..".to_string())]) {
+            return Err(anyhow!("Root directory doesn't start with . and .."));
+        }
+        Ok(superblock)
+    }
+
+    // Roughly what V6's mkfs does without a prototype file: about 1 i-node for every 4 blocks, and an empty root.
+    pub fn mkfs(image: B) -> anyhow::Result<UnixV6Fs<B>> {
+        let fsize = std::cmp::min(image.blocks(), u16::MAX as usize) as u16;
+        let isize = std::cmp::min((fsize as usize).div_ceil(4 * INODES_PER_BLOCK), u16::MAX as usize / INODES_PER_BLOCK) as u16;
+        let mut fs = UnixV6Fs {
+            image,
+            superblock: SuperBlock {
+                isize,
+                fsize,
+                nfree: 0,
+                free: [0; NICFREE],
+                ninode: 0,
+                inode: [0; NICINOD],
+                flock: 0,
+                ilock: 0,
+                fmod: 0,
+                ronly: 0,
+                time: now(),
+            },
+        };
+        fs.image.write_blocks(0, 1, &[0; BLOCK_SIZE])?;
+        for b in 0..isize as usize {
+            fs.image.write_blocks(FIRST_INODE_BLOCK + b, 1, &[0; BLOCK_SIZE])?;
+        }
+        // Freed from the top down, so blocks get handed out from the bottom up.
+        for b in fs.superblock.data_blocks().rev() {
+            fs.free_block(b as u16)?;
+        }
+        let mut root = Inode::new(ROOT_INODE, IFDIR | 0o755);
+        root.nlink = 2;
+        fs.write_contents(&mut root, &dir_repr(&[(ROOT_INODE, ".".to_string()), (ROOT_INODE, "..".to_string())]))?;
+        fs.write_superblock()?;
+        Ok(fs)
+    }
+
+    fn write_superblock(&mut self) -> anyhow::Result<()> {
+        self.image.write_blocks(SUPERBLOCK, 1, &self.superblock.repr())
+    }
+
+    fn inode_location(&self, num: u16) -> anyhow::Result<(usize, usize)> {
+        if num == 0 || num as usize > self.superblock.isize as usize * INODES_PER_BLOCK { return Err(anyhow!("Bad i-node number {}", num)) }
+        let i = num as usize - 1;
+        Ok((FIRST_INODE_BLOCK + i / INODES_PER_BLOCK, i % INODES_PER_BLOCK * INODE_SIZE))
+    }
+
+    pub fn read_inode(&self, num: u16) -> anyhow::Result<Inode> {
+        let (block, offset) = self.inode_location(num)?;
+        let buf = self.image.read_blocks(block, 1)?;
+        Inode::from_repr(num, &buf.as_bytes()[offset..offset + INODE_SIZE])
+    }
+
+    fn write_inode(&mut self, inode: &Inode) -> anyhow::Result<()> {
+        let (block, offset) = self.inode_location(inode.num)?;
+        let mut buf = self.image.read_blocks(block, 1)?.into_vec();
+        buf[offset..offset + INODE_SIZE].copy_from_slice(&inode.repr());
+        self.image.write_blocks(block, 1, &buf)
+    }
+
+    fn read_addrs(&self, block: u16) -> anyhow::Result<Vec<u16>> {
+        if block == 0 { return Ok(vec![0; ADDRS_PER_BLOCK]) }
+        Ok(words(self.image.read_blocks(block as usize, 1)?.as_bytes()))
+    }
+
+    // The data blocks of the file in order. Holes are 0.
+    fn data_blocks(&self, inode: &Inode) -> anyhow::Result<Vec<u16>> {
+        let blocks = (inode.size as usize).div_ceil(BLOCK_SIZE);
+        if !inode.is_large() {
+            if blocks > SMALL_FILE_BLOCKS { return Err(anyhow!("I-node {} is too big for a small file", inode.num)) }
+            return Ok(inode.addr[0..blocks].to_vec());
+        }
+        let double = if blocks > INDIRECT_ADDRS * ADDRS_PER_BLOCK { self.read_addrs(inode.addr[INDIRECT_ADDRS])? } else { vec![] };
+        let mut list = Vec::with_capacity(blocks);
+        for (i, start) in (0..blocks).step_by(ADDRS_PER_BLOCK).enumerate() {
+            let indirect = if i < INDIRECT_ADDRS { inode.addr[i] } else { double.get(i - INDIRECT_ADDRS).copied().ok_or_else(|| anyhow!("I-node {} is too big", inode.num))? };
+            list.extend(self.read_addrs(indirect)?.into_iter().take(blocks - start));
+        }
+        Ok(list)
+    }
+
+    // The indirect (and double indirect) blocks of a large file.
+    fn indirect_blocks(&self, inode: &Inode) -> anyhow::Result<Vec<u16>> {
+        if !inode.is_large() { return Ok(vec![]) }
+        let mut list: Vec<u16> = inode.addr[0..INDIRECT_ADDRS].to_vec();
+        if inode.addr[INDIRECT_ADDRS] != 0 {
+            list.extend(self.read_addrs(inode.addr[INDIRECT_ADDRS])?);
+            list.push(inode.addr[INDIRECT_ADDRS]);
+        }
+        Ok(list.into_iter().filter(|b| *b != 0).collect())
+    }
+
+    fn read_contents(&self, inode: &Inode) -> anyhow::Result<Vec<u8>> {
+        let mut contents = Vec::with_capacity(inode.size as usize);
+        for b in self.data_blocks(inode)? {
+            match b {
+                0 => contents.extend_from_slice(&[0; BLOCK_SIZE]),
+                b => contents.extend_from_slice(self.image.read_blocks(b as usize, 1)?.as_bytes()),
+            }
+        }
+        contents.truncate(inode.size as usize);
+        Ok(contents)
+    }
+
+    // alloc() from alloc.c. The last block in the superblock's list is handed out first. When that's the link to
+    // the next list, the next list gets read into the superblock.
+    fn alloc_block(&mut self) -> anyhow::Result<u16> {
+        let sb = &mut self.superblock;
+        if sb.nfree == 0 { return Err(anyhow!("No space left on device")) }
+        sb.nfree -= 1;
+        let block = sb.free[sb.nfree as usize];
+        if block == 0 { return Err(anyhow!("No space left on device")) }
+        if !sb.data_blocks().contains(&(block as usize)) { return Err(anyhow!("Bad block {} in free list", block)) }
+        if sb.nfree == 0 {
+            let next = words(self.image.read_blocks(block as usize, 1)?.as_bytes());
+            if next[0] as usize > NICFREE { return Err(anyhow!("Bad free list block {}", block)) }
+            self.superblock.nfree = next[0];
+            self.superblock.free.copy_from_slice(&next[1..=NICFREE]);
+        }
+        self.image.write_blocks(block as usize, 1, &[0; BLOCK_SIZE])?;
+        Ok(block)
+    }
+
+    // free() from alloc.c. When the superblock's list is full it gets written to the freed block, which becomes the
+    // start of a new list.
+    fn free_block(&mut self, block: u16) -> anyhow::Result<()> {
+        if self.superblock.nfree == 0 {
+            self.superblock.nfree = 1;
+            self.superblock.free[0] = 0;
+        }
+        if self.superblock.nfree as usize >= NICFREE {
+            let mut list = vec![self.superblock.nfree];
+            list.extend_from_slice(&self.superblock.free);
+            let mut buf = bytes(&list);
+            buf.resize(BLOCK_SIZE, 0);
+            self.image.write_blocks(block as usize, 1, &buf)?;
+            self.superblock.nfree = 0;
+        }
+        self.superblock.free[self.superblock.nfree as usize] = block;
+        self.superblock.nfree += 1;
+        Ok(())
+    }
+
+    // Counting means following the whole chain of lists.
+    fn count_free_blocks(&self) -> anyhow::Result<usize> {
+        let (mut nfree, mut list) = (self.superblock.nfree as usize, self.superblock.free.to_vec());
+        let mut count = 0;
+        for _ in 0..self.superblock.fsize {
+            if nfree > NICFREE { return Err(anyhow!("Bad free list")) }
+            count += list[0..nfree].iter().filter(|b| **b != 0).count();
+            if nfree == 0 || list[0] == 0 { return Ok(count) }
+            let next = words(self.image.read_blocks(list[0] as usize, 1)?.as_bytes());
+            (nfree, list) = (next[0] as usize, next[1..=NICFREE].to_vec());
+        }
+        Err(anyhow!("Free list loops"))
+    }
+
+    // ialloc() from alloc.c. When the superblock runs out of free i-nodes, the i-list is searched for more.
+    fn alloc_inode(&mut self) -> anyhow::Result<Inode> {
+        loop {
+            while self.superblock.ninode > 0 {
+                self.superblock.ninode -= 1;
+                let num = self.superblock.inode[self.superblock.ninode as usize];
+                let inode = self.read_inode(num)?;
+                if inode.mode == 0 { return Ok(inode) }
+            }
+            for num in 1..=(self.superblock.isize as usize * INODES_PER_BLOCK) as u16 {
+                if self.read_inode(num)?.mode != 0 { continue }
+                self.superblock.inode[self.superblock.ninode as usize] = num;
+                self.superblock.ninode += 1;
+                if self.superblock.ninode as usize >= NICINOD { break }
+            }
+            if self.superblock.ninode == 0 { return Err(anyhow!("Out of i-nodes")) }
+        }
+    }
+
+    fn free_inode(&mut self, inode: &Inode) -> anyhow::Result<()> {
+        self.truncate(&mut inode.clone())?;
+        self.write_inode(&Inode { mode: 0, ..Inode::new(inode.num, 0) })?;
+        if (self.superblock.ninode as usize) < NICINOD {
+            self.superblock.inode[self.superblock.ninode as usize] = inode.num;
+            self.superblock.ninode += 1;
+        }
+        Ok(())
+    }
+
+    // itrunc() from iget.c
+    fn truncate(&mut self, inode: &mut Inode) -> anyhow::Result<()> {
+        let mut blocks = self.data_blocks(inode)?;
+        blocks.extend(self.indirect_blocks(inode)?);
+        for b in blocks.into_iter().rev().filter(|b| *b != 0) {
+            self.free_block(b)?;
+        }
+        inode.addr = [0; SMALL_FILE_BLOCKS];
+        inode.mode &= !ILARG;
+        inode.size = 0;
+        Ok(())
+    }
+
+    // Replaces the file's contents (writing the i-node and superblock). Files over 8 blocks become large files,
+    // with each block being allocated just before the blocks it points to.
+    fn write_contents(&mut self, inode: &mut Inode, data: &[u8]) -> anyhow::Result<()> {
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+        if blocks > MAX_FILE_BLOCKS || data.len() >= 1 << 24 { return Err(anyhow!("File is too big ({} bytes)", data.len())) }
+        self.truncate(inode)?;
+        let mut chunks = data.chunks(BLOCK_SIZE);
+        let mut write_next = |fs: &mut Self| -> anyhow::Result<u16> {
+            let block = fs.alloc_block()?;
+            let mut buf = chunks.next().expect("can't happen").to_vec();
+            buf.resize(BLOCK_SIZE, 0);
+            fs.image.write_blocks(block as usize, 1, &buf)?;
+            Ok(block)
+        };
+        if blocks <= SMALL_FILE_BLOCKS {
+            for i in 0..blocks {
+                inode.addr[i] = write_next(self)?;
+            }
+        } else {
+            inode.mode |= ILARG;
+            let mut doubles = vec![];
+            for (i, start) in (0..blocks).step_by(ADDRS_PER_BLOCK).enumerate() {
+                let indirect = self.alloc_block()?;
+                let mut addrs = vec![];
+                for _ in start..std::cmp::min(blocks, start + ADDRS_PER_BLOCK) {
+                    addrs.push(write_next(self)?);
+                }
+                self.write_addrs(indirect, &addrs)?;
+                if i < INDIRECT_ADDRS { inode.addr[i] = indirect } else { doubles.push(indirect) }
+            }
+            if !doubles.is_empty() {
+                let double = self.alloc_block()?;
+                self.write_addrs(double, &doubles)?;
+                inode.addr[INDIRECT_ADDRS] = double;
+            }
+        }
+        inode.size = data.len() as u32;
+        inode.mtime = now();
+        self.write_inode(inode)?;
+        self.write_superblock()
+    }
+
+    fn write_addrs(&mut self, block: u16, addrs: &[u16]) -> anyhow::Result<()> {
+        let mut buf = bytes(addrs);
+        buf.resize(BLOCK_SIZE, 0);
+        self.image.write_blocks(block as usize, 1, &buf)
+    }
+
+    // Every slot in the directory, including the empty ones (i-number 0).
+    fn dir_records(&self, dir: &Inode) -> anyhow::Result<Vec<(u16, String)>> {
+        Ok(self.read_contents(dir)?.chunks_exact(DIR_ENTRY_SIZE).map(|e| {
+            let name = &e[2..];
+            (u16::from_le_bytes([e[0], e[1]]),
+             String::from_utf8_lossy(&name[0..name.iter().position(|c| *c == 0).unwrap_or(MAX_NAME)]).to_string())
+        }).collect())
+    }
+
+    fn add_dir_entry(&mut self, dir: &mut Inode, name: &str, num: u16) -> anyhow::Result<()> {
+        let mut records = self.dir_records(dir)?;
+        match records.iter_mut().find(|(n, _)| *n == 0) {
+            Some(slot) => *slot = (num, name.to_string()),
+            None       => records.push((num, name.to_string())),
+        }
+        self.write_contents(dir, &dir_repr(&records))
+    }
+
+    fn remove_dir_entry(&mut self, dir: &mut Inode, name: &str) -> anyhow::Result<()> {
+        let mut records = self.dir_records(dir)?;
+        for r in records.iter_mut().filter(|(n, rname)| *n != 0 && rname == name) {
+            *r = (0, String::new());
+        }
+        self.write_contents(dir, &dir_repr(&records))
+    }
+
+    fn find(&self, dir: &Inode, name: &str) -> anyhow::Result<Option<Inode>> {
+        if !dir.is_dir() { return Err(anyhow!("Not a directory")) }
+        match self.dir_records(dir)?.into_iter().find(|(n, rname)| *n != 0 && rname == name) {
+            Some((num, _)) => Ok(Some(self.read_inode(num)?)),
+            None           => Ok(None),
+        }
+    }
+
+    // Paths are relative to the root whether they start with a / or not.
+    fn lookup(&self, path: &str) -> anyhow::Result<Inode> {
+        let mut inode = self.read_inode(ROOT_INODE)?;
+        for name in components(path) {
+            inode = self.find(&inode, name)?.ok_or_else(|| anyhow!("{}: No such file or directory", path))?;
+        }
+        Ok(inode)
+    }
+
+    // The directory a new file goes in and the new file's name. Missing directories get made along the way.
+    fn parent_for_create(&mut self, path: &str) -> anyhow::Result<(Inode, String)> {
+        let mut names = components(path);
+        let name = names.pop().ok_or_else(|| anyhow!("{}: Bad filename", path))?;
+        if name == ".." || name.len() > MAX_NAME { return Err(anyhow!("{}: Bad filename (names are 1 to {} characters)", path, MAX_NAME)) }
+        let mut dir = self.read_inode(ROOT_INODE)?;
+        for n in names {
+            dir = match self.find(&dir, n)? {
+                Some(d) => d,
+                None    => self.make_dir(&mut dir, n)?,
+            };
+            if !dir.is_dir() { return Err(anyhow!("{}: {} isn't a directory", path, n)) }
+        }
+        Ok((dir, name.to_string()))
+    }
+
+    fn make_dir(&mut self, parent: &mut Inode, name: &str) -> anyhow::Result<Inode> {
+        if name.len() > MAX_NAME { return Err(anyhow!("{}: Bad directory name (names are 1 to {} characters)", name, MAX_NAME)) }
+        let num = self.alloc_inode()?.num;
+        let mut dir = Inode { nlink: 2, ..Inode::new(num, IFDIR | 0o755) };
+        self.write_contents(&mut dir, &dir_repr(&[(num, ".".to_string()), (parent.num, "..".to_string())]))?;
+        parent.nlink += 1;
+        self.add_dir_entry(parent, name, dir.num)?;
+        Ok(dir)
+    }
+
+    fn entry(&self, path: String, inode: Inode) -> DirEntry {
+        DirEntry {
+            file_name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            path,
+            inode,
+        }
+    }
+
+    fn entries(&self, path: &str, all: bool) -> anyhow::Result<Vec<DirEntry>> {
+        let inode = self.lookup(path)?;
+        let dir_path = format!("/{}", components(path).join("/"));
+        if !inode.is_dir() { return Ok(vec![self.entry(dir_path, inode)]) }
+        let mut entries = vec![];
+        for (num, name) in self.dir_records(&inode)? {
+            if num == 0 || (!all && (name == "." || name == "..")) { continue }
+            let path = if dir_path == "/" { format!("/{}", name) } else { format!("{}/{}", dir_path, name) };
+            entries.push(self.entry(path, self.read_inode(num)?));
+        }
+        Ok(entries)
+    }
+}
+
+impl<B: BlockDevice> FileSystem for UnixV6Fs<B> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        "Unix V6"
+    }
+
+    fn case_sensitive(&self) -> bool {
+        true
+    }
+
+    fn dir_iter<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.entries(path, true)?.into_iter().map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.entries(path, false)?.into_iter().map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        let inode = self.lookup(name).ok()?;
+        Some(Box::new(self.entry(format!("/{}", components(name).join("/")), inode)))
+    }
+
+    fn free_blocks(&self) -> usize {
+        self.count_free_blocks().unwrap_or(0)
+    }
+
+    fn used_blocks(&self) -> usize {
+        self.superblock.fsize as usize - self.free_blocks()
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let inode = self.lookup(name)?;
+        if inode.is_dir() { return Err(anyhow!("{}: Is a directory", name)) }
+        Ok(ByteBuffer::from_vec(self.read_contents(&inode)?))
+    }
+
+    // Existing files keep their i-node (and so their links and mode) like they would with creat(2).
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let (mut dir, file) = self.parent_for_create(name)?;
+        match self.find(&dir, &file)? {
+            Some(inode) if inode.is_dir() => Err(anyhow!("{}: Is a directory", name)),
+            Some(mut inode) => self.write_contents(&mut inode, contents),
+            None => {
+                let num = self.alloc_inode()?.num;
+                let mut inode = Inode::new(num, 0o644);
+                self.write_contents(&mut inode, contents)?;
+                self.add_dir_entry(&mut dir, &file, num)
+            },
+        }
+    }
+
+    fn append_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let mut inode = self.lookup(name)?;
+        if inode.is_dir() { return Err(anyhow!("{}: Is a directory", name)) }
+        let mut data = self.read_contents(&inode)?;
+        data.extend_from_slice(contents);
+        self.write_contents(&mut inode, &data)
+    }
+
+    // Like unlink(2), except directories can go too when they're empty.
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut names = components(name);
+        let Some(file) = names.pop() else { return Err(anyhow!("Can't delete the root directory")) };
+        if file == ".." { return Err(anyhow!("{}: Can't delete ..", name)) }
+        let mut dir = self.lookup(&names.join("/"))?;
+        let mut inode = self.find(&dir, file)?.ok_or_else(|| anyhow!("{}: No such file or directory", name))?;
+        if inode.is_dir() {
+            if self.dir_records(&inode)?.iter().any(|(n, name)| *n != 0 && name != "." && name != "..") {
+                return Err(anyhow!("{}: Directory not empty", name));
+            }
+            dir.nlink -= 1; // Its ..
+            inode.nlink = 0;
+        } else {
+            inode.nlink = inode.nlink.saturating_sub(1);
+        }
+        self.remove_dir_entry(&mut dir, file)?;
+        match inode.nlink {
+            0 => self.free_inode(&inode)?,
+            _ => self.write_inode(&inode)?,
+        }
+        self.write_superblock()
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let mut src_names = components(src);
+        let src_file = src_names.pop().ok_or_else(|| anyhow!("Can't rename the root directory"))?;
+        let inode = self.lookup(src)?;
+        if inode.is_dir() && format!("{}/", components(dest).join("/")).starts_with(&format!("{}/", components(src).join("/"))) {
+            return Err(anyhow!("Can't move {} inside itself", src));
+        }
+        let (mut dest_dir, dest_file) = self.parent_for_create(dest)?;
+        if self.find(&dest_dir, &dest_file)?.is_some() { return Err(anyhow!("{} already exists", dest)) }
+        self.add_dir_entry(&mut dest_dir, &dest_file, inode.num)?;
+
+        let mut src_dir = self.lookup(&src_names.join("/"))?;
+        self.remove_dir_entry(&mut src_dir, src_file)?;
+        if inode.is_dir() && src_dir.num != dest_dir.num {
+            // The .. link moves to the new parent.
+            let mut records = self.dir_records(&inode)?;
+            for r in records.iter_mut().filter(|(_, name)| name == "..") { r.0 = dest_dir.num }
+            self.write_contents(&mut self.read_inode(inode.num)?, &dir_repr(&records))?;
+            src_dir.nlink -= 1;
+            self.write_inode(&src_dir)?;
+            let mut dest_dir = self.read_inode(dest_dir.num)?;
+            dest_dir.nlink += 1;
+            self.write_inode(&dest_dir)?;
+        }
+        Ok(())
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+impl<B: BlockDevice> Debug for UnixV6Fs<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UnixV6Fs")
+            .field("superblock", &self.superblock)
+            .finish()
+    }
+}
+
+pub(super) fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".").collect()
+}
+
+fn dir_repr(records: &[(u16, String)]) -> Vec<u8> {
+    let mut repr = vec![];
+    for (num, name) in records {
+        repr.extend_from_slice(&num.to_le_bytes());
+        let mut name = name.as_bytes().to_vec();
+        name.resize(MAX_NAME, 0);
+        repr.extend_from_slice(&name);
+    }
+    repr
+}
+
+fn words(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect()
+}
+
+fn bytes(words: &[u16]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+pub(super) fn now() -> u32 {
+    Local::now().timestamp() as u32
+}
+
+// Longs are stored high word first, and times are seconds since 1970 GMT.
+pub(super) fn read_long(buf: &mut ByteBuffer) -> anyhow::Result<u32> {
+    let high = buf.read_u16()? as u32;
+    Ok(high << 16 | buf.read_u16()? as u32)
+}
+
+pub(super) fn write_long(buf: &mut ByteBuffer, long: u32) {
+    buf.write_u16((long >> 16) as u16);
+    buf.write_u16(long as u16);
+}
+
+pub(super) fn datetime(time: u32) -> Option<NaiveDateTime> {
+    DateTime::from_timestamp(time as i64, 0).map(|d| d.naive_utc())
+}
+
+#[derive(Clone, Debug)]
+pub struct SuperBlock {
+    pub isize: u16,        // Blocks of i-nodes
+    pub fsize: u16,        // Blocks in the filesystem
+    pub nfree: u16,
+    pub free: [u16; NICFREE],
+    pub ninode: u16,
+    pub inode: [u16; NICINOD],
+    pub flock: u8,
+    pub ilock: u8,
+    pub fmod: u8,
+    pub ronly: u8,
+    pub time: u32,
+}
+
+impl SuperBlock {
+    pub fn from_repr(buf: &mut ByteBuffer) -> anyhow::Result<SuperBlock> {
+        buf.set_endian(Endian::LittleEndian);
+        let read_list = |buf: &mut ByteBuffer| -> anyhow::Result<[u16; 100]> {
+            let mut list = [0; 100];
+            for w in list.iter_mut() { *w = buf.read_u16()? }
+            Ok(list)
+        };
+        Ok(SuperBlock {
+            isize: buf.read_u16()?,
+            fsize: buf.read_u16()?,
+            nfree: buf.read_u16()?,
+            free: read_list(buf)?,
+            ninode: buf.read_u16()?,
+            inode: read_list(buf)?,
+            flock: buf.read_u8()?,
+            ilock: buf.read_u8()?,
+            fmod: buf.read_u8()?,
+            ronly: buf.read_u8()?,
+            time: read_long(buf)?,
+        })
+    }
+
+    pub fn repr(&self) -> [u8; BLOCK_SIZE] {
+        let mut repr = ByteBuffer::new();
+        repr.set_endian(Endian::LittleEndian);
+        repr.write_u16(self.isize);
+        repr.write_u16(self.fsize);
+        repr.write_u16(self.nfree);
+        repr.write_bytes(&bytes(&self.free));
+        repr.write_u16(self.ninode);
+        repr.write_bytes(&bytes(&self.inode));
+        repr.write_bytes(&[self.flock, self.ilock, self.fmod, self.ronly]);
+        write_long(&mut repr, self.time);
+        repr.write_bytes(&vec![0; BLOCK_SIZE - repr.len()]);
+        repr.into_vec().try_into().expect("can't happen")
+    }
+
+    fn data_blocks(&self) -> std::ops::Range<usize> {
+        FIRST_INODE_BLOCK + self.isize as usize..self.fsize as usize
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Inode {
+    pub num: u16,
+    pub mode: u16,
+    pub nlink: u8,
+    pub uid: u8,
+    pub gid: u8,
+    pub size: u32, // 24 bits
+    pub addr: [u16; SMALL_FILE_BLOCKS],
+    pub atime: u32,
+    pub mtime: u32,
+}
+
+impl Inode {
+    fn new(num: u16, mode: u16) -> Inode {
+        let now = now();
+        Inode { num, mode: IALLOC | mode, nlink: 1, uid: 0, gid: 0, size: 0, addr: [0; SMALL_FILE_BLOCKS], atime: now, mtime: now }
+    }
+
+    pub fn from_repr(num: u16, raw: &[u8]) -> anyhow::Result<Inode> {
+        let mut buf = ByteBuffer::from_bytes(raw);
+        buf.set_endian(Endian::LittleEndian);
+        Ok(Inode {
+            num,
+            mode: buf.read_u16()?,
+            nlink: buf.read_u8()?,
+            uid: buf.read_u8()?,
+            gid: buf.read_u8()?,
+            size: { let high = buf.read_u8()? as u32; high << 16 | buf.read_u16()? as u32 },
+            addr: { let mut addr = [0; SMALL_FILE_BLOCKS]; for a in addr.iter_mut() { *a = buf.read_u16()? } addr },
+            atime: read_long(&mut buf)?,
+            mtime: read_long(&mut buf)?,
+        })
+    }
+
+    pub fn repr(&self) -> [u8; INODE_SIZE] {
+        let mut repr = ByteBuffer::new();
+        repr.set_endian(Endian::LittleEndian);
+        repr.write_u16(self.mode);
+        repr.write_bytes(&[self.nlink, self.uid, self.gid, (self.size >> 16) as u8]);
+        repr.write_u16(self.size as u16);
+        repr.write_bytes(&bytes(&self.addr));
+        write_long(&mut repr, self.atime);
+        write_long(&mut repr, self.mtime);
+        repr.into_vec().try_into().expect("can't happen")
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & IALLOC != 0 && self.mode & IFMT == IFDIR
+    }
+
+    fn is_large(&self) -> bool {
+        self.mode & ILARG != 0
+    }
+
+    // Like ls -l: "drwxr-xr-x"
+    pub fn mode_string(&self) -> String {
+        let kind = match self.mode & IFMT { IFDIR => 'd', IFCHR => 'c', IFBLK => 'b', _ => '-' };
+        format!("{}{}", kind, permission_string(self.mode))
+    }
+}
+
+// The "rwxr-xr-x" part of ls -l. Every Unix since V6 keeps these bits in the same place.
+pub(super) fn permission_string(mode: u16) -> String {
+    let bit = |mask: u16, c: char| if mode & mask != 0 { c } else { '-' };
+    let exec = |mask: u16, special: u16, set: char| match (mode & mask != 0, mode & special != 0) {
+        (true,  true ) => set,
+        (false, true ) => set.to_ascii_uppercase(),
+        (true,  false) => 'x',
+        (false, false) => '-',
+    };
+    format!("{}{}{}{}{}{}{}{}{}",
+            bit(0o400, 'r'), bit(0o200, 'w'), exec(0o100, ISUID, 's'),
+            bit(0o040, 'r'), bit(0o020, 'w'), exec(0o010, ISGID, 's'),
+            bit(0o004, 'r'), bit(0o002, 'w'), exec(0o001, ISVTX, 't'))
+}
+
+pub struct DirEntry {
+    path: String,
+    file_name: String,
+    inode: Inode,
+}
+
+impl Debug for DirEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let i = &self.inode;
+        let mtime = datetime(i.mtime);
+        if f.alternate() {
+            write!(f, "{:5} {} {:2} {:3} {:3} {:8} {:<19} {}",
+                   i.num, i.mode_string(), i.nlink, i.uid, i.gid, i.size,
+                   mtime.map(|d| d.to_string()).unwrap_or("Bad Date".to_string()), self.path)
+        } else {
+            write!(f, "{} {:8} {:10} {}",
+                   i.mode_string(), i.size, mtime.map(|d| d.date().to_string()).unwrap_or("Bad Date".to_string()), self.path)
+        }
+    }
+}
+
+impl super::DirEntry for DirEntry {
+    fn path(&self)       -> &str                             { &self.path }
+    fn file_name(&self)  -> &str                             { &self.file_name }
+    fn is_dir(&self)     -> bool                             { self.inode.is_dir() }
+    fn is_file(&self)    -> bool                             { self.inode.mode & IFMT == 0 }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { self.inode.size as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { datetime(self.inode.mtime).map(super::Timestamp::DateTime).ok_or(anyhow!("Bad Date")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { datetime(self.inode.atime).map(super::Timestamp::DateTime).ok_or(anyhow!("Bad Date")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn blocks(&self)     -> u64                              { (self.inode.size as u64).div_ceil(BLOCK_SIZE as u64) }
+    fn readonly(&self)   -> bool                             { self.inode.mode & 0o222 == 0 }
+    fn protected(&self)  -> bool                             { false }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::test::*;
+
+    fn names(fs: &UnixV6Fs<TestDev>, path: &str) -> Vec<String> {
+        fs.read_dir(path).unwrap().map(|e| e.path().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_mkfs() {
+        let fs = UnixV6Fs::mkfs(TestDev(vec![0; 512*4872])).expect("Create V6 FS");
+        let fs = UnixV6Fs::new(TestDev(fs.image.0.clone())).expect("Reopen V6 FS");
+        assert_eq!((77, 4872), (fs.superblock.isize, fs.superblock.fsize));
+        assert_eq!(4872 - 2 - 77 - 1, fs.free_blocks());
+        assert_eq!(Vec::<String>::new(), names(&fs, "/"));
+        assert_eq!(vec!["/.", "/.."], fs.dir_iter("/").unwrap().map(|e| e.path().to_owned()).collect::<Vec<_>>());
+        let root = fs.read_inode(ROOT_INODE).unwrap();
+        assert_eq!("drwxr-xr-x", root.mode_string());
+        assert_eq!(79, root.addr[0]); // The first data block
+        assert!(UnixV6Fs::image_is(&fs.image));
+        assert!(!UnixV6Fs::image_is(&crate::fs::rt11::RT11FS::mkfs(TestDev(vec![0; 512*4872])).unwrap().image));
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut fs = UnixV6Fs::mkfs(TestDev(vec![0; 512*4872])).expect("Create V6 FS");
+        fs.write_file("hello", &incrementing(100)).expect("write_file failed");
+        fs.write_file("/usr/src/big", &incrementing(300*1024)).expect("write_file failed"); // Large file
+        fs.write_file("usr/src/Small", &incrementing(4096)).expect("write_file failed");   // Exactly 8 blocks
+
+        let fs = UnixV6Fs::new(TestDev(fs.image.0.clone())).expect("Reopen V6 FS");
+        assert_eq!(vec!["/hello", "/usr"], names(&fs, "/"));
+        assert_eq!(vec!["/usr/src/big", "/usr/src/Small"], names(&fs, "/usr/src"));
+        assert_eq!(vec!["/usr/src/.", "/usr/src/..", "/usr/src/big", "/usr/src/Small"],
+                   fs.dir_iter("/usr/src").unwrap().map(|e| e.path().to_owned()).collect::<Vec<_>>());
+        assert_eq!(incrementing(100), fs.read_file("/hello").unwrap().into_vec());
+        assert_eq!(incrementing(300*1024), fs.read_file("/usr/src/big").unwrap().into_vec());
+        assert_eq!(incrementing(4096), fs.read_file("/usr/../usr/src/Small").unwrap().into_vec());
+        assert!(fs.read_file("/usr/src/small").is_err());
+        assert!(fs.read_inode(fs.lookup("usr/src/big").unwrap().num).unwrap().is_large());
+        assert!(!fs.lookup("usr/src/Small").unwrap().is_large());
+        assert_eq!(3, fs.lookup("usr").unwrap().nlink);
+        assert_eq!(3, fs.read_inode(ROOT_INODE).unwrap().nlink);
+        let e = fs.stat("usr/src/big").unwrap();
+        assert_eq!((600, 300*1024), (e.blocks(), e.len()));
+        assert!(matches!(e.modified().unwrap(), crate::fs::Timestamp::DateTime(d) if d.to_string() == "2023-01-19 04:13:14"));
+    }
+
+    #[test]
+    fn test_huge_file() {
+        let mut fs = UnixV6Fs::mkfs(TestDev(vec![0; 512*4872])).expect("Create V6 FS");
+        let data: Vec<u8> = (0..2000 * BLOCK_SIZE).map(|b| (b / BLOCK_SIZE) as u8 ^ b as u8).collect();
+        let free = fs.free_blocks();
+        fs.write_file("huge", &data).expect("write_file failed");
+        let inode = fs.lookup("huge").unwrap();
+        assert_ne!(0, inode.addr[INDIRECT_ADDRS]); // Needed the double indirect block
+        assert_eq!(free - 2000 - 8 - 1, fs.free_blocks()); // 8 indirect blocks and a double indirect one
+        assert_eq!(data, fs.read_file("huge").unwrap().into_vec());
+        fs.delete("huge").expect("delete failed");
+        assert_eq!(free, fs.free_blocks());
+    }
+
+    #[test]
+    fn test_free_list() {
+        let mut fs = UnixV6Fs::mkfs(TestDev(vec![0; 512*1000])).expect("Create V6 FS");
+        let free = fs.free_blocks();
+        for i in 0..150 {
+            fs.write_file(&format!("f{}", i), &incrementing(1000)).expect("write_file failed");
+        }
+        assert_eq!(free - 300 - 4, fs.free_blocks()); // The root directory grew to 5 blocks
+        for i in 0..150 {
+            fs.delete(&format!("f{}", i)).expect("delete failed");
+        }
+        let fs = UnixV6Fs::new(TestDev(fs.image.0.clone())).expect("Reopen V6 FS");
+        assert_eq!(free - 4, fs.free_blocks()); // Directories don't shrink
+        assert!(fs.superblock.nfree > 0);
+        assert_eq!(Vec::<String>::new(), names(&fs, "/"));
+    }
+
+    #[test]
+    fn test_delete_and_rename() {
+        let mut fs = UnixV6Fs::mkfs(TestDev(vec![0; 512*4872])).expect("Create V6 FS");
+        let free = fs.free_blocks();
+        fs.write_file("a/b/c", &incrementing(1000)).expect("write_file failed");
+        assert!(fs.delete("a/b").is_err()); // Not empty
+        fs.rename("a/b/c", "d").expect("rename failed");
+        assert_eq!(incrementing(1000), fs.read_file("d").unwrap().into_vec());
+        fs.rename("a/b", "e").expect("rename failed");
+        assert_eq!(2, fs.lookup("a").unwrap().nlink);
+        assert_eq!(4, fs.read_inode(ROOT_INODE).unwrap().nlink);
+        assert_eq!(ROOT_INODE, fs.lookup("e/..").unwrap().num);
+        assert!(fs.rename("a", "a/x").is_err());
+        let d = fs.lookup("d").unwrap().num;
+        fs.delete("e").expect("delete failed");
+        fs.delete("a").expect("delete failed");
+        fs.delete("d").expect("delete failed");
+        assert_eq!(2, fs.read_inode(ROOT_INODE).unwrap().nlink);
+        assert_eq!(free, fs.free_blocks());
+
+        // The last i-node freed is the first one reused
+        fs.write_file("f", &incrementing(10)).expect("write_file failed");
+        assert_eq!(d, fs.lookup("f").unwrap().num);
+    }
+}
@@ -0,0 +1,908 @@
+// Copyright © 2023 David Caldwell <david@porkrind.org>
+
+use std::{fmt::Debug, marker::PhantomData};
+
+use anyhow::anyhow;
+use bytebuffer::{Endian, ByteBuffer};
+
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use super::FileSystem;
+use super::unixv6::{components, datetime, now, permission_string, read_long, write_long};
+
+// Seventh Edition Unix filesystem, and the ones descended from it. See filsys(5) and dir(5) in the V7 manual, and
+// alloc.c, iget.c and subr.c in the kernel.
+//
+// It's V6's layout grown up: block 0 is the boot block, block 1 the superblock and the i-nodes start at block 2.
+// Block numbers are longs now, so i-nodes are 64 bytes and hold 13 3-byte block addresses (10 direct, then single,
+// double and triple indirect blocks), and the superblock's free list holds 50 blocks instead of 100. The superblock
+// also has room for a count of free blocks and i-nodes, but V7 doesn't keep it up to date ("remainder not maintained
+// by this version of the system"), so the free list gets counted instead. The root directory is i-node 2 (1 was for
+// bad blocks).
+//
+// 2.11BSD (see bsd211.rs) kept all of that but changed the block size, the block addresses and the directories, so
+// those are left to a `Flavor`.
+
+const SUPERBLOCK: usize = 1;
+const FIRST_INODE_BLOCK: usize = 2;
+const INODE_SIZE: usize = 64;
+const ROOT_INODE: u16 = 2;
+const NICFREE: usize = 50;  // Free blocks the superblock holds
+const NICINOD: usize = 100; // Free i-nodes the superblock holds
+const INDIRECT_LEVELS: usize = 3;
+const ADDR_AREA: std::ops::Range<usize> = 12..52; // Where the block addresses go in the i-node
+
+// di_mode bits
+pub(super) const IFMT:   u16 = 0o170000;
+pub(super) const IFDIR:  u16 = 0o040000;
+pub(super) const IFCHR:  u16 = 0o020000;
+pub(super) const IFBLK:  u16 = 0o060000;
+pub(super) const IFREG:  u16 = 0o100000;
+pub(super) const IFLNK:  u16 = 0o120000; // 2.11BSD
+pub(super) const IFSOCK: u16 = 0o140000; // 2.11BSD
+
+// What differs between the V7 filesystem and its descendants.
+pub trait Flavor: Clone + Send + Sync + 'static {
+    const NAME: &'static str;
+    const BLOCK_SIZE: usize;   // Filesystem block size, a multiple of the device's 512 bytes
+    const DIRECT_ADDRS: usize; // The rest of the addresses are the single, double and triple indirect blocks
+    const MAX_NAME: usize;
+    const KEEPS_TFREE: bool;   // Whether the superblock's free block count can be trusted
+    // The i-node's block addresses, from (and to) the 40 bytes after di_size.
+    fn decode_addrs(raw: &[u8]) -> Vec<u32>;
+    fn encode_addrs(addrs: &[u32], raw: &mut [u8]);
+    // Every slot in a directory. Empty slots have i-number 0 and get reused by dir_repr() if the flavor has slots.
+    fn parse_dir(raw: &[u8]) -> Vec<(u16, String)>;
+    fn dir_repr(records: &[(u16, String)]) -> Vec<u8>;
+}
+
+#[derive(Clone, Debug)]
+pub struct V7;
+
+impl Flavor for V7 {
+    const NAME: &'static str = "Unix V7";
+    const BLOCK_SIZE: usize = 512;
+    const DIRECT_ADDRS: usize = 10;
+    const MAX_NAME: usize = 14;
+    const KEEPS_TFREE: bool = false;
+
+    // 3 byte addresses, laid out the way l3tol() unpacks them on a PDP-11: the high byte, then the low word.
+    fn decode_addrs(raw: &[u8]) -> Vec<u32> {
+        raw.chunks_exact(3).take(Self::DIRECT_ADDRS + INDIRECT_LEVELS)
+            .map(|a| (a[0] as u32) << 16 | u16::from_le_bytes([a[1], a[2]]) as u32).collect()
+    }
+
+    fn encode_addrs(addrs: &[u32], raw: &mut [u8]) {
+        for (a, r) in addrs.iter().zip(raw.chunks_exact_mut(3)) {
+            r[0] = (a >> 16) as u8;
+            r[1..3].copy_from_slice(&(*a as u16).to_le_bytes());
+        }
+    }
+
+    fn parse_dir(raw: &[u8]) -> Vec<(u16, String)> {
+        raw.chunks_exact(16).map(|e| (u16::from_le_bytes([e[0], e[1]]), name_from(&e[2..]))).collect()
+    }
+
+    fn dir_repr(records: &[(u16, String)]) -> Vec<u8> {
+        let mut repr = vec![];
+        for (num, name) in records {
+            repr.extend_from_slice(&num.to_le_bytes());
+            let mut name = name.as_bytes().to_vec();
+            name.resize(Self::MAX_NAME, 0);
+            repr.extend_from_slice(&name);
+        }
+        repr
+    }
+}
+
+pub type UnixV7Fs<B> = UnixFs<B, V7>;
+
+#[derive(Clone)]
+pub struct UnixFs<B: BlockDevice, F: Flavor> {
+    pub image: B,
+    pub superblock: SuperBlock,
+    flavor: PhantomData<F>,
+}
+
+impl<B: BlockDevice, F: Flavor> UnixFs<B, F> {
+    const DEVICE_BLOCKS: usize = F::BLOCK_SIZE / BLOCK_SIZE; // Per filesystem block
+    const ADDRS_PER_BLOCK: usize = F::BLOCK_SIZE / 4;
+    const INODES_PER_BLOCK: usize = F::BLOCK_SIZE / INODE_SIZE;
+
+    pub fn new(image: B) -> anyhow::Result<UnixFs<B, F>> {
+        let superblock = Self::try_new(&image)?;
+        let mut fs = UnixFs { image, superblock, flavor: PhantomData };
+        // From here on alloc_block() and free_block() keep it right.
+        if !F::KEEPS_TFREE { fs.superblock.tfree = fs.count_free_blocks()? as u32 }
+        Ok(fs)
+    }
+
+    pub fn image_is(image: &B) -> bool {
+        Self::try_new(image).is_ok()
+    }
+
+    // No magic number here either, so check the superblock is sane and the root directory starts with . and ..
+    fn try_new(image: &B) -> anyhow::Result<SuperBlock> {
+        let read = |block: usize| image.read_blocks(block * Self::DEVICE_BLOCKS, Self::DEVICE_BLOCKS);
+        let superblock = SuperBlock::from_repr(&mut read(SUPERBLOCK)?)?;
+        let sb = &superblock;
+        if (sb.isize as usize) <= FIRST_INODE_BLOCK || sb.fsize as usize * Self::DEVICE_BLOCKS > image.blocks() || sb.fsize <= sb.isize as u32 {
+            return Err(anyhow!("Bad filesystem size {} (i-list ends at {}, device {})", sb.fsize, sb.isize, image.blocks()));
+        }
+        if sb.nfree as usize > NICFREE || sb.ninode as usize > NICINOD { return Err(anyhow!("Bad superblock free lists")) }
+        if sb.free[0..sb.nfree as usize].iter().any(|b| *b != 0 && !sb.data_blocks().contains(b)) {
+            return Err(anyhow!("Bad block in superblock free list"));
+        }
+        let (block, offset) = Self::inode_location_in(sb, ROOT_INODE)?;
+        let root = Inode::from_repr::<F>(ROOT_INODE, &read(block)?.as_bytes()[offset..offset + INODE_SIZE])?;
+        if root.mode & IFMT != IFDIR || root.size == 0 { return Err(anyhow!("I-node {} isn't a directory", ROOT_INODE)) }
+        if !sb.data_blocks().contains(&root.addr[0]) { return Err(anyhow!("Bad root directory block {}", root.addr[0])) }
+        let records = F::parse_dir(read(root.addr[0] as usize)?.as_bytes());
+        if records.first() != Some(&(ROOT_INODE, ".".to_string())) || records.get(1) != Some(&(ROOT_INODE, "..".to_string())) {
+            return Err(anyhow!("Root directory doesn't start with . and .."));
+        }
+        Ok(superblock)
+    }
+
+    // About 1 i-node for every 2KB, and an empty root directory.
+    pub fn mkfs(image: B) -> anyhow::Result<UnixFs<B, F>> {
+        let fsize = image.blocks() / Self::DEVICE_BLOCKS;
+        let inodes = std::cmp::min(fsize * F::BLOCK_SIZE / 2048, u16::MAX as usize);
+        // I-node numbers are 16 bits so the i-list can't have a block that goes past 65535.
+        let isize = FIRST_INODE_BLOCK + std::cmp::min(inodes.div_ceil(Self::INODES_PER_BLOCK), u16::MAX as usize / Self::INODES_PER_BLOCK);
+        let mut rest = vec![0; F::BLOCK_SIZE - SuperBlock::REST_OFFSET];
+        rest[0..4].copy_from_slice(&[1, 0, 1, 0]); // V7's s_m and s_n (2.11BSD's fs_step and fs_cyl): no interleave
+        let mut fs = UnixFs {
+            image,
+            superblock: SuperBlock {
+                isize: isize as u16,
+                fsize: fsize as u32,
+                nfree: 0,
+                free: [0; NICFREE],
+                ninode: 0,
+                inode: [0; NICINOD],
+                locks: [0; 4],
+                time: now(),
+                tfree: 0,
+                tinode: ((isize - FIRST_INODE_BLOCK) * Self::INODES_PER_BLOCK - 2) as u16,
+                rest,
+            },
+            flavor: PhantomData,
+        };
+        for b in FIRST_INODE_BLOCK..isize { // The boot block is left alone since it might hold a disk label
+            fs.write_block(b as u32, &vec![0; F::BLOCK_SIZE])?;
+        }
+        for b in fs.superblock.data_blocks().rev() {
+            fs.free_block(b)?;
+        }
+        fs.write_inode(&Inode { nlink: 0, ..Inode::new(1, IFREG) })?; // The old bad block file
+        let mut root = Inode { nlink: 2, ..Inode::new(ROOT_INODE, IFDIR | 0o755) };
+        fs.write_contents(&mut root, &F::dir_repr(&[(ROOT_INODE, ".".to_string()), (ROOT_INODE, "..".to_string())]))?;
+        Ok(fs)
+    }
+
+    fn read_block(&self, block: u32) -> anyhow::Result<Vec<u8>> {
+        Ok(self.image.read_blocks(block as usize * Self::DEVICE_BLOCKS, Self::DEVICE_BLOCKS)?.into_vec())
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8]) -> anyhow::Result<()> {
+        self.image.write_blocks(block as usize * Self::DEVICE_BLOCKS, Self::DEVICE_BLOCKS, data)
+    }
+
+    fn write_superblock(&mut self) -> anyhow::Result<()> {
+        let repr = self.superblock.repr();
+        self.write_block(SUPERBLOCK as u32, &repr)
+    }
+
+    fn inode_location_in(sb: &SuperBlock, num: u16) -> anyhow::Result<(usize, usize)> {
+        let i = num as usize;
+        if i == 0 || i > (sb.isize as usize - FIRST_INODE_BLOCK) * Self::INODES_PER_BLOCK { return Err(anyhow!("Bad i-node number {}", num)) }
+        Ok((FIRST_INODE_BLOCK + (i - 1) / Self::INODES_PER_BLOCK, (i - 1) % Self::INODES_PER_BLOCK * INODE_SIZE))
+    }
+
+    pub fn read_inode(&self, num: u16) -> anyhow::Result<Inode> {
+        let (block, offset) = Self::inode_location_in(&self.superblock, num)?;
+        Inode::from_repr::<F>(num, &self.read_block(block as u32)?[offset..offset + INODE_SIZE])
+    }
+
+    fn write_inode(&mut self, inode: &Inode) -> anyhow::Result<()> {
+        let (block, offset) = Self::inode_location_in(&self.superblock, inode.num)?;
+        let mut buf = self.read_block(block as u32)?;
+        buf[offset..offset + INODE_SIZE].copy_from_slice(&inode.repr::<F>());
+        self.write_block(block as u32, &buf)
+    }
+
+    // Indirect blocks are full of longs.
+    fn read_addrs(&self, block: u32) -> anyhow::Result<Vec<u32>> {
+        if block == 0 { return Ok(vec![0; Self::ADDRS_PER_BLOCK]) }
+        let mut buf = ByteBuffer::from_vec(self.read_block(block)?);
+        buf.set_endian(Endian::LittleEndian);
+        (0..Self::ADDRS_PER_BLOCK).map(|_| read_long(&mut buf)).collect()
+    }
+
+    fn write_addrs(&mut self, block: u32, addrs: &[u32]) -> anyhow::Result<()> {
+        let mut buf = ByteBuffer::new();
+        buf.set_endian(Endian::LittleEndian);
+        for a in addrs { write_long(&mut buf, *a) }
+        let mut buf = buf.into_vec();
+        buf.resize(F::BLOCK_SIZE, 0);
+        self.write_block(block, &buf)
+    }
+
+    // The first `want` addresses under an indirect block that's `level` levels above the data.
+    fn collect_addrs(&self, block: u32, level: usize, want: usize, list: &mut Vec<u32>) -> anyhow::Result<()> {
+        let end = list.len() + want;
+        for a in self.read_addrs(block)? {
+            if list.len() >= end { break }
+            match level {
+                0 => list.push(a),
+                _ => self.collect_addrs(a, level - 1, end - list.len(), list)?,
+            }
+        }
+        Ok(())
+    }
+
+    // The data blocks of the file in order. Holes are 0.
+    fn data_blocks(&self, inode: &Inode) -> anyhow::Result<Vec<u32>> {
+        let blocks = (inode.size as usize).div_ceil(F::BLOCK_SIZE);
+        let mut list: Vec<u32> = inode.addr.iter().take(std::cmp::min(blocks, F::DIRECT_ADDRS)).copied().collect();
+        for level in 0..INDIRECT_LEVELS {
+            if list.len() >= blocks { break }
+            self.collect_addrs(inode.addr[F::DIRECT_ADDRS + level], level, blocks - list.len(), &mut list)?;
+        }
+        if list.len() < blocks { return Err(anyhow!("I-node {} is too big", inode.num)) }
+        Ok(list)
+    }
+
+    // Every indirect block the file uses.
+    fn indirect_blocks(&self, inode: &Inode) -> anyhow::Result<Vec<u32>> {
+        fn walk<B: BlockDevice, F: Flavor>(fs: &UnixFs<B, F>, block: u32, level: usize, list: &mut Vec<u32>) -> anyhow::Result<()> {
+            if block == 0 { return Ok(()) }
+            if level > 0 {
+                for a in fs.read_addrs(block)? { walk(fs, a, level - 1, list)? }
+            }
+            list.push(block);
+            Ok(())
+        }
+        let mut list = vec![];
+        for level in 0..INDIRECT_LEVELS {
+            walk(self, inode.addr[F::DIRECT_ADDRS + level], level, &mut list)?;
+        }
+        Ok(list)
+    }
+
+    fn read_contents(&self, inode: &Inode) -> anyhow::Result<Vec<u8>> {
+        let mut contents = Vec::with_capacity(inode.size as usize);
+        for b in self.data_blocks(inode)? {
+            match b {
+                0 => contents.extend_from_slice(&vec![0; F::BLOCK_SIZE]),
+                b => contents.extend_from_slice(&self.read_block(b)?),
+            }
+        }
+        contents.truncate(inode.size as usize);
+        Ok(contents)
+    }
+
+    // alloc() and free() work just like V6's, just with longs.
+    fn alloc_block(&mut self) -> anyhow::Result<u32> {
+        let sb = &mut self.superblock;
+        if sb.nfree == 0 { return Err(anyhow!("No space left on device")) }
+        sb.nfree -= 1;
+        let block = sb.free[sb.nfree as usize];
+        if block == 0 { return Err(anyhow!("No space left on device")) }
+        if !sb.data_blocks().contains(&block) { return Err(anyhow!("Bad block {} in free list", block)) }
+        if sb.nfree == 0 {
+            let mut next = ByteBuffer::from_vec(self.read_block(block)?);
+            next.set_endian(Endian::LittleEndian);
+            let nfree = next.read_u16()?;
+            if nfree as usize > NICFREE { return Err(anyhow!("Bad free list block {}", block)) }
+            self.superblock.nfree = nfree;
+            for f in self.superblock.free.iter_mut() { *f = read_long(&mut next)? }
+        }
+        self.superblock.tfree = self.superblock.tfree.saturating_sub(1);
+        self.write_block(block, &vec![0; F::BLOCK_SIZE])?;
+        Ok(block)
+    }
+
+    // Like V6's, for the flavors whose tfree can't be trusted.
+    fn count_free_blocks(&self) -> anyhow::Result<usize> {
+        let (mut nfree, mut list) = (self.superblock.nfree as usize, self.superblock.free.to_vec());
+        let mut count = 0;
+        for _ in 0..self.superblock.fsize {
+            if nfree > NICFREE { return Err(anyhow!("Bad free list")) }
+            count += list[0..nfree].iter().filter(|b| **b != 0).count();
+            if nfree == 0 || list[0] == 0 { return Ok(count) }
+            let mut next = ByteBuffer::from_vec(self.read_block(list[0])?);
+            next.set_endian(Endian::LittleEndian);
+            nfree = next.read_u16()? as usize;
+            for f in list.iter_mut() { *f = read_long(&mut next)? }
+        }
+        Err(anyhow!("Free list loops"))
+    }
+
+    fn free_block(&mut self, block: u32) -> anyhow::Result<()> {
+        self.superblock.tfree += 1;
+        if self.superblock.nfree == 0 {
+            self.superblock.nfree = 1;
+            self.superblock.free[0] = 0;
+        }
+        if self.superblock.nfree as usize >= NICFREE {
+            let mut buf = ByteBuffer::new();
+            buf.set_endian(Endian::LittleEndian);
+            buf.write_u16(self.superblock.nfree);
+            for f in self.superblock.free { write_long(&mut buf, f) }
+            let mut buf = buf.into_vec();
+            buf.resize(F::BLOCK_SIZE, 0);
+            self.write_block(block, &buf)?;
+            self.superblock.nfree = 0;
+        }
+        self.superblock.free[self.superblock.nfree as usize] = block;
+        self.superblock.nfree += 1;
+        Ok(())
+    }
+
+    // ialloc(): the superblock's list first, then a search of the i-list.
+    fn alloc_inode(&mut self) -> anyhow::Result<Inode> {
+        loop {
+            while self.superblock.ninode > 0 {
+                self.superblock.ninode -= 1;
+                let num = self.superblock.inode[self.superblock.ninode as usize];
+                let inode = self.read_inode(num)?;
+                if inode.mode == 0 {
+                    self.superblock.tinode = self.superblock.tinode.saturating_sub(1);
+                    return Ok(inode);
+                }
+            }
+            let inodes = (self.superblock.isize as usize - FIRST_INODE_BLOCK) * Self::INODES_PER_BLOCK;
+            for num in 1..=std::cmp::min(inodes, u16::MAX as usize) {
+                let num = num as u16;
+                if self.read_inode(num)?.mode != 0 { continue }
+                self.superblock.inode[self.superblock.ninode as usize] = num;
+                self.superblock.ninode += 1;
+                if self.superblock.ninode as usize >= NICINOD { break }
+            }
+            if self.superblock.ninode == 0 { return Err(anyhow!("Out of i-nodes")) }
+        }
+    }
+
+    fn free_inode(&mut self, inode: &Inode) -> anyhow::Result<()> {
+        self.truncate(&mut inode.clone())?;
+        self.write_inode(&Inode { mode: 0, ..Inode::new(inode.num, 0) })?;
+        self.superblock.tinode += 1;
+        if (self.superblock.ninode as usize) < NICINOD {
+            self.superblock.inode[self.superblock.ninode as usize] = inode.num;
+            self.superblock.ninode += 1;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, inode: &mut Inode) -> anyhow::Result<()> {
+        let mut blocks = self.data_blocks(inode)?;
+        blocks.extend(self.indirect_blocks(inode)?);
+        for b in blocks.into_iter().rev().filter(|b| *b != 0) {
+            self.free_block(b)?;
+        }
+        inode.addr = vec![0; F::DIRECT_ADDRS + INDIRECT_LEVELS];
+        inode.size = 0;
+        Ok(())
+    }
+
+    // Allocates an indirect block `level` levels above the data along with the data under it (or as much of
+    // the rest of the data as fits). Returns the block and how many data blocks went under it.
+    fn build_indirect(&mut self, level: usize, blocks: usize, write_next: &mut dyn FnMut(&mut Self) -> anyhow::Result<u32>) -> anyhow::Result<(u32, usize)> {
+        let indirect = self.alloc_block()?;
+        let (mut addrs, mut used) = (vec![], 0);
+        while addrs.len() < Self::ADDRS_PER_BLOCK && used < blocks {
+            if level == 0 {
+                addrs.push(write_next(self)?);
+                used += 1;
+            } else {
+                let (a, u) = self.build_indirect(level - 1, blocks - used, write_next)?;
+                addrs.push(a);
+                used += u;
+            }
+        }
+        self.write_addrs(indirect, &addrs)?;
+        Ok((indirect, used))
+    }
+
+    // Replaces the file's contents (writing the i-node and superblock).
+    fn write_contents(&mut self, inode: &mut Inode, data: &[u8]) -> anyhow::Result<()> {
+        if data.len() > i32::MAX as usize { return Err(anyhow!("File is too big ({} bytes)", data.len())) }
+        self.truncate(inode)?;
+        let blocks = data.len().div_ceil(F::BLOCK_SIZE);
+        let mut chunks = data.chunks(F::BLOCK_SIZE);
+        let mut write_next = |fs: &mut Self| -> anyhow::Result<u32> {
+            let block = fs.alloc_block()?;
+            let mut buf = chunks.next().expect("can't happen").to_vec();
+            buf.resize(F::BLOCK_SIZE, 0);
+            fs.write_block(block, &buf)?;
+            Ok(block)
+        };
+        let direct = std::cmp::min(blocks, F::DIRECT_ADDRS);
+        for i in 0..direct {
+            inode.addr[i] = write_next(self)?;
+        }
+        let mut left = blocks - direct;
+        for level in 0..INDIRECT_LEVELS {
+            if left == 0 { break }
+            let (indirect, used) = self.build_indirect(level, left, &mut write_next)?;
+            inode.addr[F::DIRECT_ADDRS + level] = indirect;
+            left -= used;
+        }
+        if left > 0 { return Err(anyhow!("File is too big ({} bytes)", data.len())) }
+        inode.size = data.len() as u32;
+        inode.mtime = now();
+        inode.ctime = inode.mtime;
+        self.write_inode(inode)?;
+        self.write_superblock()
+    }
+
+    fn dir_records(&self, dir: &Inode) -> anyhow::Result<Vec<(u16, String)>> {
+        Ok(F::parse_dir(&self.read_contents(dir)?))
+    }
+
+    fn add_dir_entry(&mut self, dir: &mut Inode, name: &str, num: u16) -> anyhow::Result<()> {
+        let mut records = self.dir_records(dir)?;
+        match records.iter_mut().find(|(n, _)| *n == 0) {
+            Some(slot) => *slot = (num, name.to_string()),
+            None       => records.push((num, name.to_string())),
+        }
+        self.write_contents(dir, &F::dir_repr(&records))
+    }
+
+    fn remove_dir_entry(&mut self, dir: &mut Inode, name: &str) -> anyhow::Result<()> {
+        let mut records = self.dir_records(dir)?;
+        for r in records.iter_mut().filter(|(n, rname)| *n != 0 && rname == name) {
+            *r = (0, String::new());
+        }
+        self.write_contents(dir, &F::dir_repr(&records))
+    }
+
+    fn find(&self, dir: &Inode, name: &str) -> anyhow::Result<Option<Inode>> {
+        if !dir.is_dir() { return Err(anyhow!("Not a directory")) }
+        match self.dir_records(dir)?.into_iter().find(|(n, rname)| *n != 0 && rname == name) {
+            Some((num, _)) => Ok(Some(self.read_inode(num)?)),
+            None           => Ok(None),
+        }
+    }
+
+    fn lookup(&self, path: &str) -> anyhow::Result<Inode> {
+        let mut inode = self.read_inode(ROOT_INODE)?;
+        for name in components(path) {
+            inode = self.find(&inode, name)?.ok_or_else(|| anyhow!("{}: No such file or directory", path))?;
+        }
+        Ok(inode)
+    }
+
+    fn parent_for_create(&mut self, path: &str) -> anyhow::Result<(Inode, String)> {
+        let mut names = components(path);
+        let name = names.pop().ok_or_else(|| anyhow!("{}: Bad filename", path))?;
+        if name == ".." || name.len() > F::MAX_NAME { return Err(anyhow!("{}: Bad filename (names are 1 to {} characters)", path, F::MAX_NAME)) }
+        let mut dir = self.read_inode(ROOT_INODE)?;
+        for n in names {
+            dir = match self.find(&dir, n)? {
+                Some(d) => d,
+                None    => self.make_dir(&mut dir, n)?,
+            };
+            if !dir.is_dir() { return Err(anyhow!("{}: {} isn't a directory", path, n)) }
+        }
+        Ok((dir, name.to_string()))
+    }
+
+    fn make_dir(&mut self, parent: &mut Inode, name: &str) -> anyhow::Result<Inode> {
+        if name.len() > F::MAX_NAME { return Err(anyhow!("{}: Bad directory name (names are 1 to {} characters)", name, F::MAX_NAME)) }
+        let num = self.alloc_inode()?.num;
+        let mut dir = Inode { nlink: 2, uid: parent.uid, gid: parent.gid, ..Inode::new(num, IFDIR | 0o755) };
+        self.write_contents(&mut dir, &F::dir_repr(&[(num, ".".to_string()), (parent.num, "..".to_string())]))?;
+        parent.nlink += 1;
+        self.add_dir_entry(parent, name, num)?;
+        Ok(dir)
+    }
+
+    fn entry(&self, path: String, inode: Inode) -> DirEntry {
+        DirEntry {
+            file_name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            path,
+            inode,
+        }
+    }
+
+    fn entries(&self, path: &str, all: bool) -> anyhow::Result<Vec<DirEntry>> {
+        let inode = self.lookup(path)?;
+        let dir_path = format!("/{}", components(path).join("/"));
+        if !inode.is_dir() { return Ok(vec![self.entry(dir_path, inode)]) }
+        let mut entries = vec![];
+        for (num, name) in self.dir_records(&inode)? {
+            if num == 0 || (!all && (name == "." || name == "..")) { continue }
+            let path = if dir_path == "/" { format!("/{}", name) } else { format!("{}/{}", dir_path, name) };
+            entries.push(self.entry(path, self.read_inode(num)?));
+        }
+        Ok(entries)
+    }
+}
+
+impl<B: BlockDevice, F: Flavor> FileSystem for UnixFs<B, F> {
+    type BlockDevice=B;
+
+    fn filesystem_name(&self) -> &str {
+        F::NAME
+    }
+
+    fn case_sensitive(&self) -> bool {
+        true
+    }
+
+    fn dir_iter<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.entries(path, true)?.into_iter().map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn read_dir<'a>(&'a self, path: &str) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.entries(path, false)?.into_iter().map(|e| -> Box<dyn super::DirEntry> { Box::new(e) })))
+    }
+
+    fn stat<'a>(&'a self, name: &str) -> Option<Box<dyn super::DirEntry + 'a>> {
+        let inode = self.lookup(name).ok()?;
+        Some(Box::new(self.entry(format!("/{}", components(name).join("/")), inode)))
+    }
+
+    // In 512 byte blocks, like everything else here.
+    fn free_blocks(&self) -> usize {
+        self.superblock.tfree as usize * Self::DEVICE_BLOCKS
+    }
+
+    fn used_blocks(&self) -> usize {
+        self.superblock.fsize as usize * Self::DEVICE_BLOCKS - self.free_blocks()
+    }
+
+    fn read_file(&self, name: &str) -> anyhow::Result<ByteBuffer> {
+        let inode = self.lookup(name)?;
+        if inode.is_dir() { return Err(anyhow!("{}: Is a directory", name)) }
+        Ok(ByteBuffer::from_vec(self.read_contents(&inode)?))
+    }
+
+    fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let (mut dir, file) = self.parent_for_create(name)?;
+        match self.find(&dir, &file)? {
+            Some(inode) if inode.is_dir() => Err(anyhow!("{}: Is a directory", name)),
+            Some(mut inode) => self.write_contents(&mut inode, contents),
+            None => {
+                let num = self.alloc_inode()?.num;
+                let mut inode = Inode { uid: dir.uid, gid: dir.gid, ..Inode::new(num, IFREG | 0o644) };
+                self.write_contents(&mut inode, contents)?;
+                self.add_dir_entry(&mut dir, &file, num)
+            },
+        }
+    }
+
+    fn append_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let mut inode = self.lookup(name)?;
+        if inode.is_dir() { return Err(anyhow!("{}: Is a directory", name)) }
+        let mut data = self.read_contents(&inode)?;
+        data.extend_from_slice(contents);
+        self.write_contents(&mut inode, &data)
+    }
+
+    fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut names = components(name);
+        let Some(file) = names.pop() else { return Err(anyhow!("Can't delete the root directory")) };
+        if file == ".." { return Err(anyhow!("{}: Can't delete ..", name)) }
+        let mut dir = self.lookup(&names.join("/"))?;
+        let mut inode = self.find(&dir, file)?.ok_or_else(|| anyhow!("{}: No such file or directory", name))?;
+        if inode.is_dir() {
+            if self.dir_records(&inode)?.iter().any(|(n, name)| *n != 0 && name != "." && name != "..") {
+                return Err(anyhow!("{}: Directory not empty", name));
+            }
+            dir.nlink -= 1; // Its ..
+            inode.nlink = 0;
+        } else {
+            inode.nlink = inode.nlink.saturating_sub(1);
+        }
+        self.remove_dir_entry(&mut dir, file)?;
+        match inode.nlink {
+            0 => self.free_inode(&inode)?,
+            _ => self.write_inode(&inode)?,
+        }
+        self.write_superblock()
+    }
+
+    fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        let mut src_names = components(src);
+        let src_file = src_names.pop().ok_or_else(|| anyhow!("Can't rename the root directory"))?;
+        let inode = self.lookup(src)?;
+        if inode.is_dir() && format!("{}/", components(dest).join("/")).starts_with(&format!("{}/", components(src).join("/"))) {
+            return Err(anyhow!("Can't move {} inside itself", src));
+        }
+        let (mut dest_dir, dest_file) = self.parent_for_create(dest)?;
+        if self.find(&dest_dir, &dest_file)?.is_some() { return Err(anyhow!("{} already exists", dest)) }
+        self.add_dir_entry(&mut dest_dir, &dest_file, inode.num)?;
+
+        let mut src_dir = self.lookup(&src_names.join("/"))?;
+        self.remove_dir_entry(&mut src_dir, src_file)?;
+        if inode.is_dir() && src_dir.num != dest_dir.num {
+            // The .. link moves to the new parent.
+            let mut records = self.dir_records(&inode)?;
+            for r in records.iter_mut().filter(|(_, name)| name == "..") { r.0 = dest_dir.num }
+            self.write_contents(&mut self.read_inode(inode.num)?, &F::dir_repr(&records))?;
+            src_dir.nlink -= 1;
+            self.write_inode(&src_dir)?;
+            let mut dest_dir = self.read_inode(dest_dir.num)?;
+            dest_dir.nlink += 1;
+            self.write_inode(&dest_dir)?;
+        }
+        Ok(())
+    }
+
+    fn block_device(&self) -> &Self::BlockDevice {
+        &self.image
+    }
+}
+
+impl<B: BlockDevice, F: Flavor> Debug for UnixFs<B, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct(F::NAME)
+            .field("superblock", &self.superblock)
+            .finish()
+    }
+}
+
+// Names are NUL padded (but don't have to be NUL terminated).
+pub(super) fn name_from(raw: &[u8]) -> String {
+    String::from_utf8_lossy(&raw[0..raw.iter().position(|c| *c == 0).unwrap_or(raw.len())]).to_string()
+}
+
+#[derive(Clone, Debug)]
+pub struct SuperBlock {
+    pub isize: u16,     // The first block after the i-list
+    pub fsize: u32,     // Blocks in the filesystem
+    pub nfree: u16,
+    pub free: [u32; NICFREE],
+    pub ninode: u16,
+    pub inode: [u16; NICINOD],
+    pub locks: [u8; 4], // flock, ilock, fmod and ronly (2.11BSD swaps the middle two)
+    pub time: u32,
+    pub tfree: u32,     // Free blocks
+    pub tinode: u16,    // Free i-nodes
+    pub rest: Vec<u8>,  // Everything after that is different in each flavor
+}
+
+impl SuperBlock {
+    const REST_OFFSET: usize = 424;
+
+    pub fn from_repr(buf: &mut ByteBuffer) -> anyhow::Result<SuperBlock> {
+        buf.set_endian(Endian::LittleEndian);
+        Ok(SuperBlock {
+            isize: buf.read_u16()?,
+            fsize: read_long(buf)?,
+            nfree: buf.read_u16()?,
+            free: { let mut free = [0; NICFREE]; for f in free.iter_mut() { *f = read_long(buf)? } free },
+            ninode: buf.read_u16()?,
+            inode: { let mut inode = [0; NICINOD]; for i in inode.iter_mut() { *i = buf.read_u16()? } inode },
+            locks: buf.read_bytes(4)?.try_into().expect("can't happen"),
+            time: read_long(buf)?,
+            tfree: read_long(buf)?,
+            tinode: buf.read_u16()?,
+            rest: buf.read_bytes(buf.len() - Self::REST_OFFSET)?,
+        })
+    }
+
+    pub fn repr(&self) -> Vec<u8> {
+        let mut repr = ByteBuffer::new();
+        repr.set_endian(Endian::LittleEndian);
+        repr.write_u16(self.isize);
+        write_long(&mut repr, self.fsize);
+        repr.write_u16(self.nfree);
+        for f in self.free { write_long(&mut repr, f) }
+        repr.write_u16(self.ninode);
+        for i in self.inode { repr.write_u16(i) }
+        repr.write_bytes(&self.locks);
+        write_long(&mut repr, self.time);
+        write_long(&mut repr, self.tfree);
+        repr.write_u16(self.tinode);
+        repr.write_bytes(&self.rest);
+        repr.into_vec()
+    }
+
+    fn data_blocks(&self) -> std::ops::Range<u32> {
+        self.isize as u32..self.fsize
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Inode {
+    pub num: u16,
+    pub mode: u16,
+    pub nlink: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u32,
+    pub addr: Vec<u32>,
+    addr_area: [u8; 40], // The raw addresses, plus whatever else the flavor keeps in there
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+}
+
+impl Inode {
+    fn new(num: u16, mode: u16) -> Inode {
+        let now = now();
+        Inode { num, mode, nlink: 1, uid: 0, gid: 0, size: 0, addr: vec![0; 16], addr_area: [0; 40], atime: now, mtime: now, ctime: now }
+    }
+
+    pub fn from_repr<F: Flavor>(num: u16, raw: &[u8]) -> anyhow::Result<Inode> {
+        let mut buf = ByteBuffer::from_bytes(raw);
+        buf.set_endian(Endian::LittleEndian);
+        Ok(Inode {
+            num,
+            mode: buf.read_u16()?,
+            nlink: buf.read_u16()?,
+            uid: buf.read_u16()?,
+            gid: buf.read_u16()?,
+            size: read_long(&mut buf)?,
+            addr: F::decode_addrs(&raw[ADDR_AREA]),
+            addr_area: { buf.read_bytes(ADDR_AREA.len())?.try_into().expect("can't happen") },
+            atime: read_long(&mut buf)?,
+            mtime: read_long(&mut buf)?,
+            ctime: read_long(&mut buf)?,
+        })
+    }
+
+    pub fn repr<F: Flavor>(&self) -> [u8; INODE_SIZE] {
+        let mut repr = ByteBuffer::new();
+        repr.set_endian(Endian::LittleEndian);
+        repr.write_u16(self.mode);
+        repr.write_u16(self.nlink);
+        repr.write_u16(self.uid);
+        repr.write_u16(self.gid);
+        write_long(&mut repr, self.size);
+        let mut addr_area = self.addr_area;
+        F::encode_addrs(&self.addr[0..F::DIRECT_ADDRS + INDIRECT_LEVELS], &mut addr_area);
+        repr.write_bytes(&addr_area);
+        write_long(&mut repr, self.atime);
+        write_long(&mut repr, self.mtime);
+        write_long(&mut repr, self.ctime);
+        repr.into_vec().try_into().expect("can't happen")
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & IFMT == IFDIR
+    }
+
+    // Like ls -l: "drwxr-xr-x"
+    pub fn mode_string(&self) -> String {
+        let kind = match self.mode & IFMT { IFDIR => 'd', IFCHR => 'c', IFBLK => 'b', IFLNK => 'l', IFSOCK => 's', _ => '-' };
+        format!("{}{}", kind, permission_string(self.mode))
+    }
+}
+
+pub struct DirEntry {
+    path: String,
+    file_name: String,
+    inode: Inode,
+}
+
+impl Debug for DirEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let i = &self.inode;
+        let mtime = datetime(i.mtime);
+        if f.alternate() {
+            write!(f, "{:5} {} {:2} {:5} {:5} {:8} {:<19} {}",
+                   i.num, i.mode_string(), i.nlink, i.uid, i.gid, i.size,
+                   mtime.map(|d| d.to_string()).unwrap_or("Bad Date".to_string()), self.path)
+        } else {
+            write!(f, "{} {:8} {:10} {}",
+                   i.mode_string(), i.size, mtime.map(|d| d.date().to_string()).unwrap_or("Bad Date".to_string()), self.path)
+        }
+    }
+}
+
+impl super::DirEntry for DirEntry {
+    fn path(&self)       -> &str                             { &self.path }
+    fn file_name(&self)  -> &str                             { &self.file_name }
+    fn is_dir(&self)     -> bool                             { self.inode.is_dir() }
+    fn is_file(&self)    -> bool                             { self.inode.mode & IFMT == IFREG }
+    fn is_symlink(&self) -> bool                             { self.inode.mode & IFMT == IFLNK }
+    fn len(&self)        -> u64                              { self.inode.size as u64 }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { datetime(self.inode.mtime).map(super::Timestamp::DateTime).ok_or(anyhow!("Bad Date")) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { datetime(self.inode.atime).map(super::Timestamp::DateTime).ok_or(anyhow!("Bad Date")) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { Err(anyhow!("Not available")) }
+    fn blocks(&self)     -> u64                              { (self.inode.size as u64).div_ceil(BLOCK_SIZE as u64) }
+    fn readonly(&self)   -> bool                             { self.inode.mode & 0o222 == 0 }
+    fn protected(&self)  -> bool                             { false }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::test::*;
+
+    fn names(fs: &UnixV7Fs<TestDev>, path: &str) -> Vec<String> {
+        fs.read_dir(path).unwrap().map(|e| e.path().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_addrs() {
+        let raw = [0x01, 0x34, 0x12, 0, 0, 0];
+        assert_eq!(vec![0x11234, 0], V7::decode_addrs(&raw));
+        let mut again = [0; 6];
+        V7::encode_addrs(&[0x11234, 0], &mut again);
+        assert_eq!(raw, again);
+    }
+
+    #[test]
+    fn test_mkfs() {
+        let fs = UnixV7Fs::mkfs(TestDev(vec![0; 512*4872])).expect("Create V7 FS");
+        let fs = UnixV7Fs::new(TestDev(fs.image.0.clone())).expect("Reopen V7 FS");
+        assert_eq!((2 + 153, 4872), (fs.superblock.isize, fs.superblock.fsize));
+        assert_eq!(4872 - 155 - 1, fs.free_blocks());
+        assert_eq!(153 * 8 - 2, fs.superblock.tinode as usize);
+        let mut fs = fs;
+        fs.superblock.tfree = 0; // V7 doesn't keep it up to date, so it could say anything
+        fs.write_superblock().expect("write_superblock failed");
+        let fs = UnixV7Fs::new(TestDev(fs.image.0.clone())).expect("Reopen V7 FS");
+        assert_eq!(4872 - 155 - 1, fs.free_blocks());
+        assert_eq!(155 + 1, fs.used_blocks());
+        assert_eq!(vec!["/.", "/.."], fs.dir_iter("/").unwrap().map(|e| e.path().to_owned()).collect::<Vec<_>>());
+        assert!(UnixV7Fs::image_is(&fs.image));
+        assert!(!crate::fs::unixv6::UnixV6Fs::image_is(&fs.image));
+        assert!(!UnixV7Fs::image_is(&crate::fs::unixv6::UnixV6Fs::mkfs(TestDev(vec![0; 512*4872])).unwrap().image));
+
+        let mut fs = fs;
+        let mut next = fs.read_block(fs.superblock.free[0]).unwrap();
+        next[0..2].copy_from_slice(&[0xff, 0xff]); // Bad count in the next block of the free list
+        fs.write_block(fs.superblock.free[0], &next).unwrap();
+        assert!(UnixV7Fs::new(TestDev(fs.image.0.clone())).is_err());
+    }
+
+    #[test]
+    fn test_mkfs_most_inodes() {
+        // Big enough to want more i-nodes than 16 bits can number
+        let mut fs = UnixV7Fs::mkfs(TestDev(vec![0; 512*270000])).expect("Create V7 FS");
+        assert_eq!(2 + 65535 / 8, fs.superblock.isize as usize);
+        assert_eq!(65535 / 8 * 8 - 2, fs.superblock.tinode as usize);
+        fs.write_file("/a", &incrementing(100)).expect("write_file failed");
+        assert_eq!(incrementing(100), fs.read_file("/a").unwrap().into_vec());
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut fs = UnixV7Fs::mkfs(TestDev(vec![0; 512*20000])).expect("Create V7 FS");
+        let small = incrementing(100);
+        let big: Vec<u8> = (0..(10 + 128 + 10) * BLOCK_SIZE).map(|b| (b / BLOCK_SIZE) as u8 ^ b as u8).collect(); // Needs a double indirect block
+        fs.write_file("etc/passwd", &small).expect("write_file failed");
+        fs.write_file("/unix", &big).expect("write_file failed");
+
+        let mut fs = UnixV7Fs::new(TestDev(fs.image.0.clone())).expect("Reopen V7 FS");
+        assert_eq!(vec!["/etc", "/unix"], names(&fs, "/"));
+        assert_eq!(small, fs.read_file("etc/passwd").unwrap().into_vec());
+        assert_eq!(big, fs.read_file("unix").unwrap().into_vec());
+        let unix = fs.lookup("unix").unwrap();
+        assert_ne!(0, unix.addr[V7::DIRECT_ADDRS + 1]);
+        assert_eq!(0, unix.addr[V7::DIRECT_ADDRS + 2]);
+        assert_eq!("-rw-r--r--", unix.mode_string());
+
+        let free = fs.free_blocks();
+        fs.delete("unix").expect("delete failed");
+        assert_eq!(free + 148 + 1 + 2, fs.free_blocks()); // Data, the single indirect block and the double's 2
+        let free = fs.free_blocks();
+        fs.write_file("unix", &big).expect("write_file failed");
+        fs.delete("unix").expect("delete failed");
+        assert_eq!(free, fs.free_blocks());
+    }
+
+    #[test]
+    fn test_delete_and_rename() {
+        let mut fs = UnixV7Fs::mkfs(TestDev(vec![0; 512*4872])).expect("Create V7 FS");
+        let (free, inodes) = (fs.free_blocks(), fs.superblock.tinode);
+        fs.write_file("a/b/c", &incrementing(1000)).expect("write_file failed");
+        assert!(fs.delete("a/b").is_err()); // Not empty
+        fs.rename("a/b", "e").expect("rename failed");
+        assert_eq!(vec!["/e/c"], names(&fs, "e"));
+        assert_eq!(ROOT_INODE, fs.lookup("e/..").unwrap().num);
+        assert_eq!(4, fs.read_inode(ROOT_INODE).unwrap().nlink);
+        fs.delete("e/c").expect("delete failed");
+        fs.delete("e").expect("delete failed");
+        fs.delete("a").expect("delete failed");
+        assert_eq!(2, fs.read_inode(ROOT_INODE).unwrap().nlink);
+        assert_eq!((free, inodes), (fs.free_blocks(), fs.superblock.tinode));
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::{mem::size_of, fmt::Debug, ops::Range};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use bytebuffer::{Endian, ByteBuffer};
 use chrono::{NaiveDate, Datelike};
 
@@ -11,7 +11,7 @@ use chrono::{NaiveDate, Datelike};
 #[cfg    (test)]  use super::test::Local;
 
 use crate::block::{BlockDevice,BLOCK_SIZE};
-use super::FileSystem;
+use super::{CreateOptions, FileSystem, Placement};
 
 
 // CHQF SAO XXDP+ FILE STRUCT DOC: Oct 84: https://archive.org/details/bitsavers_decpdp11xx6BMCCHQFSB0XXDPFileStructDocOct84_497378/page/n3/mode/2up
@@ -23,9 +23,9 @@ use super::FileSystem;
 // The date format, in particular, is defined there. The rest is useful to fill in blanks left by the XXDP
 // manual, which is light on detail in some areas.
 
-const USABLE_BLOCK_SIZE : usize = BLOCK_SIZE - size_of::<u16>(); // For blocks with the next pointer in them (most data blocks)
-const BITMAP_WORDS_PER_MAP_BLOCK: usize = 64 - 4; // XXDP+ File Struct Doc (April 1981), Section 4.1.3
-const ENTRIES_PER_UFD_BLOCK: usize = 28; // XXDP+ File Struct Doc (April 1981), Section 4.1.2
+pub(super) const USABLE_BLOCK_SIZE : usize = BLOCK_SIZE - size_of::<u16>(); // For blocks with the next pointer in them (most data blocks)
+pub(super) const BITMAP_WORDS_PER_MAP_BLOCK: usize = 64 - 4; // XXDP+ File Struct Doc (April 1981), Section 4.1.3
+pub(super) const ENTRIES_PER_UFD_BLOCK: usize = 28; // XXDP+ File Struct Doc (April 1981), Section 4.1.2
 
 #[derive(Clone)]
 pub struct XxdpFs<B: BlockDevice> {
@@ -35,12 +35,77 @@ pub struct XxdpFs<B: BlockDevice> {
     pub ufd_block_list: Vec<u16>,
     pub bitmap: Vec<bool>,
     pub bitmap_block_list: Vec<u16>,
+    salvaged: bool,
 }
 
-fn round_up(total: usize, step: usize) -> usize {
+pub(super) fn round_up(total: usize, step: usize) -> usize {
     (total + step - 1) / step * step
 }
 
+// "3, 7-9, 12" (for messages).
+fn block_ranges(blocks: &[usize]) -> String {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for b in blocks {
+        match ranges.last_mut() {
+            Some(r) if r.end == *b => r.end += 1,
+            _                      => ranges.push(*b..*b+1),
+        }
+    }
+    ranges.iter().map(|r| if r.len() == 1 { format!("{}", r.start) } else { format!("{}-{}", r.start, r.end-1) }).collect::<Vec<_>>().join(", ")
+}
+
+// If we cared about speed something like this would be the native data structure.
+pub(super) fn free_spans(bitmap: &[bool]) -> Vec<Range<u16>> {
+    let mut spans = vec![];
+    let mut start = None;
+    for (i, b) in bitmap.iter().enumerate().map(|(i,b)| (i as u16, b)) {
+        match (start, b) {
+            (None,    false) => start = Some(i),
+            (Some(s), true ) => { spans.push(s..i);
+                                  start = None; },
+            (_,       _,   ) => {},
+        }
+    }
+    if let Some(s) = start {
+        spans.push(s..bitmap.len() as u16)
+    }
+    spans
+}
+
+pub(super) fn allocate_blocks_in(bitmap: &mut [bool], blocks: u16) -> anyhow::Result<Vec<u16>> {
+    let mut spans = free_spans(bitmap);
+    fn span_sort_key(span: &Range<u16>, desired_len: u16) -> u16 { if span.len() as u16 == desired_len { u16::MAX } else { span.len() as u16 } }
+    spans.sort_by_key(|s| span_sort_key(s, blocks));
+    let mut list = vec![];
+    let mut count = blocks;
+    while count > 0 {
+        let Some(mut s) = spans.pop() else {
+            return Err(anyhow!("No space for {} blocks", blocks));
+        };
+        s.end = std::cmp::min(s.end, s.start + count); // don't overrun
+        for b in s {
+            list.push(b);
+            bitmap[b as usize] = true;
+            count -= 1;
+        }
+    }
+    Ok(list)
+}
+
+// One run of free blocks. Takes the smallest span that fits so the big ones are left for later.
+pub(super) fn allocate_contiguous_blocks_in(bitmap: &mut [bool], blocks: u16) -> anyhow::Result<Vec<u16>> {
+    let Some(span) = free_spans(bitmap).into_iter()
+                                       .filter(|s| s.len() >= blocks as usize)
+                                       .min_by_key(|s| s.len()) else {
+        return Err(anyhow!("No contiguous space for {} blocks", blocks));
+    };
+    let list: Vec<u16> = (span.start..span.start + blocks).collect();
+    for b in list.iter() {
+        bitmap[*b as usize] = true;
+    }
+    Ok(list)
+}
+
 impl<B: BlockDevice> XxdpFs<B> {
     pub fn new(image: B) -> anyhow::Result<XxdpFs<B>> {
         let (mfd, ufd, ufd_block_list, bitmap, bitmap_block_list) = Self::try_new(&image)?;
@@ -51,9 +116,211 @@ impl<B: BlockDevice> XxdpFs<B> {
             ufd_block_list,
             bitmap,
             bitmap_block_list,
+            salvaged: false,
         })
     }
 
+    // Opens a volume whose MFD or UFD is damaged, read only. Whatever can be found of the directory is used,
+    // and chains of blocks that are in use but not in any file show up as `Bnnnnn.BAD` files (nnnnn being the
+    // first block). Returns the problems found along the way.
+    pub fn salvage(image: B) -> anyhow::Result<(XxdpFs<B>, Vec<String>)> {
+        let (mfd, ufd, ufd_block_list, bitmap, bitmap_block_list, problems) = Self::try_salvage(&image)?;
+        Ok((XxdpFs {
+            image,
+            mfd,
+            ufd,
+            ufd_block_list,
+            bitmap,
+            bitmap_block_list,
+            salvaged: true,
+        }, problems))
+    }
+
+    pub fn image_is_salvageable(image: &B) -> bool {
+        Self::try_salvage(image).is_ok()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn try_salvage(image: &B) -> anyhow::Result<(Mfd, Vec<DirEntry>, Vec<u16>, Vec<bool>, Vec<u16>, Vec<String>)> {
+        let blocks = image.blocks();
+        let mut problems = vec![];
+        let mfd = Self::salvage_mfd(image);
+        if mfd.is_none() { problems.push("Couldn't find a usable MFD".to_string()) }
+
+        // Follow the UFD chain from the MFD as far as it goes.
+        let (ufd_start, ufd_count, bitmap_start) = match mfd {
+            Some(Mfd::VariantOne(ref v1)) => (v1.ufd_block as usize, None, v1.bitmap_block),
+            Some(Mfd::VariantTwo(ref v2)) => (v2.ufd_block as usize, Some(v2.ufd_block_count as usize), v2.bitmap_block),
+            None                          => (0, None, 0),
+        };
+        let (mut ufd_blocks, ufd_problem) = Self::chain_in(image, ufd_start);
+        if let Some(ref problem) = ufd_problem { problems.push(format!("UFD block chain {}", problem)) }
+        let ufd_incomplete = mfd.is_none() || ufd_problem.is_some() || ufd_count.is_some_and(|c| c > ufd_blocks.len());
+
+        let mut entries = vec![];
+        for b in ufd_blocks.iter() {
+            entries.extend(Self::salvage_ufd_block(image, *b, &mut problems).unwrap_or_default());
+        }
+        // If the chain is broken, the rest of the UFD is still out there somewhere.
+        if ufd_incomplete {
+            for b in 2..blocks {
+                if ufd_blocks.contains(&b) { continue }
+                let Some(found) = Self::salvage_ufd_block(image, b, &mut vec![]) else { continue };
+                if !found.iter().any(|e| e.name.is_some()) { continue }
+                problems.push(format!("Found a UFD block at block {} that isn't in the UFD chain", b));
+                // Follow the fragment's own chain too. The rest of it might not have any files in it yet.
+                for c in Self::chain_in(image, b).0 {
+                    if ufd_blocks.contains(&c) { break }
+                    let Some(found) = Self::salvage_ufd_block(image, c, &mut vec![]) else { break };
+                    ufd_blocks.push(c);
+                    entries.extend(found);
+                }
+            }
+        }
+        if mfd.is_none() && ufd_blocks.is_empty() {
+            return Err(anyhow!("Doesn't look like an XXDP volume (no MFD or UFD blocks found)"));
+        }
+
+        let read_bitmap = |start: u16| -> anyhow::Result<(Vec<bool>, Vec<u16>)> {
+            let (bitmap, list) = Self::read_bitmap(image, start)?;
+            if bitmap.len() < blocks { return Err(anyhow!("Bitmap is too short {} < {}", bitmap.len(), blocks)) }
+            Ok((bitmap[..blocks].to_vec(), list))
+        };
+        let from_mfd = match mfd {
+            Some(_) => read_bitmap(bitmap_start).map_err(|e| problems.push(format!("Bitmap is unusable: {:#}", e))).ok(),
+            None    => None,
+        };
+        let bitmap = match from_mfd {
+            Some(bitmap) => Some(bitmap),
+            None => {
+                // Bitmap blocks are easy to spot, so look for the first one.
+                let first = (2..blocks).find(|b| image.read_blocks(*b, 1).ok().and_then(|mut buf| {
+                    buf.set_rpos(size_of::<u16>());
+                    BitmapBlock::from_repr(&mut buf).ok()
+                }).is_some_and(|bb| bb.map_number == 0 && bb.first_bitmap as usize == *b));
+                let found = first.and_then(|b| read_bitmap(b as u16).ok());
+                if let Some((_, ref list)) = found { problems.push(format!("Found the bitmap at block {}", list[0])) }
+                found
+            },
+        };
+
+        let mut claimed = vec![false; blocks];
+        let other_mfd = match mfd {
+            Some(Mfd::VariantOne(ref v1)) => v1.mfd2_block as usize,
+            Some(Mfd::VariantTwo(ref v2)) => v2.other_mfd_block as usize,
+            None                          => 0,
+        };
+        for b in [0, 1, other_mfd].into_iter().chain(ufd_blocks.iter().copied())
+                                  .chain(bitmap.iter().flat_map(|(_, list)| list.iter().map(|b| *b as usize))) {
+            if b < blocks { claimed[b] = true }
+        }
+
+        let mut ufd = vec![];
+        for mut e in entries.into_iter() {
+            let Some(ref name) = e.name else { continue };
+            if ufd.iter().any(|f: &DirEntry| f.name == e.name) {
+                problems.push(format!("{}: found more than one entry, ignoring the one at block {}", name, e.first_block));
+                continue;
+            }
+            let (mut chain, problem) = Self::chain_in(image, e.first_block);
+            if let Some(problem) = problem { problems.push(format!("{}: block chain {}", name, problem)) }
+            if let Some(i) = chain.iter().position(|b| claimed[*b]) {
+                problems.push(format!("{}: block {} is already in use, truncating the file there", name, chain[i]));
+                chain.truncate(i);
+            }
+            if chain.len() != e.length {
+                problems.push(format!("{}: length is {} blocks but only {} could be recovered", name, e.length, chain.len()));
+            }
+            let Some(last) = chain.last() else { continue };
+            e.length = chain.len();
+            e.last_block = *last;
+            for b in chain { claimed[b] = true }
+            ufd.push(e);
+        }
+
+        // Last resort: chains of blocks nothing claims. When we have a bitmap it tells us which blocks were in use
+        // (so we don't dredge up deleted files).
+        let candidate = |b: usize| -> Option<usize> {
+            if claimed[b] || bitmap.as_ref().is_some_and(|(bm, _)| !bm[b]) { return None }
+            let data = image.read_blocks(b, 1).ok()?;
+            if data.as_bytes().iter().all(|x| *x == 0) { return None }
+            let next = u16::from_le_bytes([data.as_bytes()[0], data.as_bytes()[1]]) as usize;
+            (next < blocks && next != b).then_some(next)
+        };
+        let next: Vec<Option<usize>> = (0..blocks).map(candidate).collect();
+        let mut referenced = vec![false; blocks];
+        for n in next.iter().flatten() { referenced[*n] = true }
+        for head in (0..blocks).filter(|b| next[*b].is_some() && !referenced[*b]) {
+            let mut chain = vec![head];
+            while let Some(n) = next[*chain.last().unwrap()] {
+                if n == 0 || next[n].is_none() || chain.contains(&n) { break }
+                chain.push(n);
+            }
+            let entry = DirEntry {
+                name: Some(format!("B{:05}.BAD", head)),
+                date: None,
+                first_block: head,
+                length: chain.len(),
+                last_block: *chain.last().unwrap(),
+            };
+            problems.push(format!("Blocks {} aren't in any file, salvaged as {}", block_ranges(&chain), entry.name.as_deref().unwrap()));
+            for b in chain { claimed[b] = true }
+            ufd.push(entry);
+        }
+        if ufd.is_empty() { return Err(anyhow!("Couldn't salvage any files")) }
+
+        let (bitmap, bitmap_block_list) = bitmap.unwrap_or_else(|| (claimed, vec![]));
+        Ok((mfd.unwrap_or(Mfd::VariantTwo(MfdVariantTwo::default())),
+            ufd,
+            ufd_blocks.iter().map(|b| *b as u16).collect(),
+            bitmap,
+            bitmap_block_list,
+            problems))
+    }
+
+    // Tries both MFD layouts, then the copy of a Variety #2 MFD (which is usually in block 2).
+    fn salvage_mfd(image: &B) -> Option<Mfd> {
+        let read = |block: usize| -> Option<ByteBuffer> {
+            let mut buf = image.read_blocks(block, 1).ok()?;
+            buf.set_endian(Endian::LittleEndian);
+            Some(buf)
+        };
+        let buf1 = read(1)?;
+        let next = u16::from_le_bytes([buf1.as_bytes()[0], buf1.as_bytes()[1]]) as usize;
+        if next != 0 && next < image.blocks() {
+            if let Some(v1) = read(next).and_then(|buf2| MfdVariantOne::from_repr(1, [buf1.clone(), buf2]).ok()) {
+                return Some(Mfd::VariantOne(v1));
+            }
+        }
+        [1, 2].into_iter().find_map(|b| MfdVariantTwo::from_repr(b as u16, image.blocks(), [read(b)?, ByteBuffer::new()]).ok())
+                          .map(Mfd::VariantTwo)
+    }
+
+    // The entries in a UFD block, if it looks like one (every entry is either empty or plausible).
+    fn salvage_ufd_block(image: &B, block: usize, problems: &mut Vec<String>) -> Option<Vec<DirEntry>> {
+        let blocks = image.blocks();
+        let mut buf = image.read_blocks(block, 1).ok()?;
+        buf.set_endian(Endian::LittleEndian);
+        if buf.read_u16().ok()? as usize >= blocks { return None }
+        let mut entries = vec![];
+        for i in 0..ENTRIES_PER_UFD_BLOCK {
+            buf.set_rpos(size_of::<u16>() + i * size_of::<[u16; 9]>());
+            match DirEntry::from_repr(&mut buf) {
+                Ok(Some(e)) if e.name.is_none() => {},
+                Ok(Some(e)) if e.first_block > 0 && e.first_block < blocks && e.last_block < blocks && e.length <= blocks => entries.push(e),
+                Ok(_)  => { problems.push(format!("UFD block {} entry {} is damaged", block, i)); return None },
+                Err(e) => { problems.push(format!("UFD block {} entry {} is damaged: {:#}", block, i, e)); return None },
+            }
+        }
+        Some(entries)
+    }
+
+    fn check_writable(&self) -> anyhow::Result<()> {
+        // Writing a salvaged directory back would make whatever we guessed wrong permanent.
+        if self.salvaged { return Err(anyhow!("Salvaged filesystems are read only")) }
+        Ok(())
+    }
+
     pub fn image_is(image: &B) -> bool {
         Self::try_new(image).is_ok()
     }
@@ -79,7 +346,12 @@ impl<B: BlockDevice> XxdpFs<B> {
             bitmap_block_list))
     }
 
+    #[allow(unused)]
     pub fn mkfs(image: B) -> anyhow::Result<XxdpFs<B>> {
+        Self::mkfs_with_variant(image, MfdVariant::One)
+    }
+
+    pub fn mkfs_with_variant(image: B, variant: MfdVariant) -> anyhow::Result<XxdpFs<B>> {
         let bitmap_entries = round_up(image.blocks(), 16 * BITMAP_WORDS_PER_MAP_BLOCK);
         let bitmap_blocks = bitmap_entries / (16 * BITMAP_WORDS_PER_MAP_BLOCK);
         const AVE_FILE_BLOCKS: usize = 4; // Basd on XXDP+ File Struct Doc Apr81 table 4.1.4, specifically the RX01 entry. The ratios seem random:
@@ -96,12 +368,31 @@ impl<B: BlockDevice> XxdpFs<B> {
             b
         };
         let _mfd1_block = blocks(1);
-        let mfd = MfdVariantOne {
-            interleave_factor: 1,
-            mfd2_block: blocks(1),
-            ufd_block: blocks(ufd_blocks as u16),
-            bitmap_block: blocks(bitmap_blocks as u16),
-            bitmap_pointer: ((blocks(0)-bitmap_blocks as u16)..blocks(0)).collect(),
+        let mfd2_block = blocks(1);
+        let ufd_block = blocks(ufd_blocks as u16);
+        let bitmap_block = blocks(bitmap_blocks as u16);
+        let mfd = match variant {
+            MfdVariant::One => Mfd::VariantOne(MfdVariantOne {
+                interleave_factor: 1,
+                mfd2_block,
+                ufd_block,
+                bitmap_block,
+                bitmap_pointer: (bitmap_block..blocks(0)).collect(),
+            }),
+            MfdVariant::Two => Mfd::VariantTwo(MfdVariantTwo {
+                ufd_block,
+                ufd_block_count: ufd_blocks as u16,
+                bitmap_block,
+                bitmap_block_count: bitmap_blocks as u16,
+                other_mfd_block: mfd2_block,
+                support_blocks: image.blocks() as u16,
+                preallocated_blocks: blocks(0),
+                interleave_factor: 1,
+                monitor_core_image_block: 0,
+                bad_sector_file_track: 0,
+                bad_sector_file_sector: 0,
+                bad_sector_file_cylinder: 0,
+            }),
         };
         let entries = (0..ufd_entries).map(|_| DirEntry::default()).collect();
         let mut bitmap = Vec::new();
@@ -112,10 +403,11 @@ impl<B: BlockDevice> XxdpFs<B> {
         let mut fs = XxdpFs {
             image,
             bitmap,
-            bitmap_block_list: mfd.bitmap_pointer.clone(),
+            bitmap_block_list: (bitmap_block..blocks(0)).collect(),
             ufd: entries,
-            ufd_block_list: (mfd.ufd_block..mfd.bitmap_block).collect(),
-            mfd: Mfd::VariantOne(mfd),
+            ufd_block_list: (ufd_block..bitmap_block).collect(),
+            mfd,
+            salvaged: false,
         };
         fs.write_ufd()?;
         fs.write_bitmap()?;
@@ -130,6 +422,11 @@ impl<B: BlockDevice> XxdpFs<B> {
         let next = buf1.read_u16()? as usize;
         buf1.set_rpos(0);
 
+        // Variety #1 starts with a link to MFD2. Variety #2 is a single block with no link.
+        if next == 0 {
+            return Ok(Mfd::VariantTwo(MfdVariantTwo::from_repr(mfd_block as u16, image.blocks(), [buf1, ByteBuffer::new()])?));
+        }
+
         let mut buf2 = image.read_blocks(next, 1)?;
         buf2.set_endian(Endian::LittleEndian);
 
@@ -194,7 +491,9 @@ impl<B: BlockDevice> XxdpFs<B> {
     }
 
     fn allocate_dir_entry(&mut self) -> anyhow::Result<usize> {
-        if let Some((i, _)) = self.full_dir_iter().enumerate().find(|(_,e)| e.name.is_none()) {
+        // Leave deleted files' entries alone as long as we can so they can still be undeleted.
+        if let Some((i, _)) = self.full_dir_iter().enumerate().find(|(_,e)| e.name.is_none() && e.first_block == 0)
+                         .or_else(|| self.full_dir_iter().enumerate().find(|(_,e)| e.name.is_none())) {
             return Ok(i);
         }
         // No more room! Need to allocate a new UFD block
@@ -204,48 +503,189 @@ impl<B: BlockDevice> XxdpFs<B> {
         self.ufd.extend((0..ENTRIES_PER_UFD_BLOCK).map(|_| DirEntry::default()));
         self.write_bitmap()?;
         self.write_ufd()?;
+        if let Mfd::VariantTwo(ref mut v2) = self.mfd {
+            v2.ufd_block_count = self.ufd_block_list.len() as u16;
+            self.write_mfd()?;
+        }
         Ok(new_dir_entry)
     }
 
-    // If we cared about speed something like this would be the native data structure.
+    #[allow(unused)]
     fn calculate_bitmap_free_spans(&self) -> Vec<Range<u16>> {
-        let mut spans = vec![];
-        let mut start = None;
-        for (i, b) in self.bitmap.iter().enumerate().map(|(i,b)| (i as u16, b)) {
-            match (start, b) {
-                (None,    false) => start = Some(i),
-                (Some(s), true ) => { spans.push(s..i);
-                                      start = None; },
-                (_,       _,   ) => {},
-            }
+        free_spans(&self.bitmap)
+    }
+
+    fn allocate_blocks(&mut self, blocks: u16) -> anyhow::Result<Vec<u16>> {
+        allocate_blocks_in(&mut self.bitmap, blocks)
+    }
+
+    // For files that have to be contiguous (the monitor and its drivers).
+    fn allocate_contiguous_blocks(&mut self, blocks: u16) -> anyhow::Result<Vec<u16>> {
+        allocate_contiguous_blocks_in(&mut self.bitmap, blocks)
+    }
+
+    pub fn is_contiguous(&self, name: &str) -> anyhow::Result<bool> {
+        let Some((_, entry)) = self.raw_stat(name) else {
+            return Err(anyhow!("File not found: {}", name));
+        };
+        let chain = Self::read_chain_raw(&self.image, entry.first_block as u16)?;
+        Ok(chain.windows(2).all(|w| w[0].0 + 1 == w[1].0))
+    }
+
+    // Rewrites `name` into a single run of blocks if it isn't in one already.
+    pub fn make_contiguous(&mut self, name: &str) -> anyhow::Result<()> {
+        if self.is_contiguous(name)? { return Ok(()) }
+        let (_, entry) = self.raw_stat(name).unwrap(/*is_contiguous() checked*/);
+        let date = entry.date;
+        let contents = self.read_file(name)?;
+        self.write_file_with_options(name, contents.as_bytes(), &CreateOptions { contiguous: true, ..Default::default() })?;
+        let (entry_num, _) = self.raw_stat(name).unwrap(/*we just wrote it*/);
+        self.ufd[entry_num].date = date;
+        self.write_ufd()
+    }
+
+    // Makes the volume bootable: the first block of `boot` goes in the boot block, and `monitor` and `driver`
+    // are made contiguous so the bootstrap can load them. The MFD records where the monitor starts, so this
+    // needs a Variety #2 MFD.
+    pub fn install_boot(&mut self, boot: &str, monitor: &str, driver: &str) -> anyhow::Result<()> {
+        self.check_writable()?;
+        if !matches!(self.mfd, Mfd::VariantTwo(_)) {
+            return Err(anyhow!("Variety #1 MFDs have nowhere to record the monitor. Use an image made with `mkfs --bootable`"));
         }
-        if let Some(s) = start {
-            spans.push(s..self.bitmap.len() as u16)
+        let boot_data = self.read_file(boot).with_context(|| format!("Reading bootstrap {}", boot))?;
+        let mut boot_block = boot_data.as_bytes()[..std::cmp::min(BLOCK_SIZE, boot_data.len())].to_vec();
+        if boot_block.iter().all(|b| *b == 0) {
+            return Err(anyhow!("{} doesn't have a bootstrap in it", boot));
+        }
+        boot_block.resize(BLOCK_SIZE, 0);
+
+        for name in [monitor, driver] {
+            self.make_contiguous(name).with_context(|| format!("Making {} contiguous", name))?;
         }
-        spans
+        let monitor_block = self.raw_stat(monitor).unwrap(/*make_contiguous() checked*/).1.first_block as u16;
+
+        self.image.write_blocks(0, 1, &boot_block)?;
+        if let Mfd::VariantTwo(ref mut v2) = self.mfd {
+            v2.monitor_core_image_block = monitor_block;
+        }
+        self.write_mfd()
     }
 
-    fn allocate_blocks(&mut self, blocks: u16) -> anyhow::Result<Vec<u16>> {
-        let mut spans = self.calculate_bitmap_free_spans();
-        fn span_sort_key(span: &Range<u16>, desired_len: u16) -> u16 { if span.len() as u16 == desired_len { u16::MAX } else { span.len() as u16 } }
-        spans.sort_by(|a,b| span_sort_key(a, blocks).cmp(&span_sort_key(b,blocks)));
-        let mut list = vec![];
-        let mut count = blocks;
-        while count > 0 {
-            let Some(mut s) = spans.pop() else {
-                return Err(anyhow!("No space for {} blocks", blocks));
+    // Like read_chain_raw() but for checking: stops (and says why) at loops and bad pointers instead of failing.
+    fn follow_chain(&self, start_block: usize) -> (Vec<usize>, Option<String>) {
+        Self::chain_in(&self.image, start_block)
+    }
+
+    fn chain_in(image: &B, start_block: usize) -> (Vec<usize>, Option<String>) {
+        let mut chain = vec![];
+        let mut seen = std::collections::HashSet::new();
+        let mut block = start_block;
+        while block != 0 {
+            if block >= image.blocks() { return (chain, Some(format!("points past the end of the image (block {})", block))) }
+            if !seen.insert(block)      { return (chain, Some(format!("loops back to block {}", block))) }
+            let next = match image.read_blocks(block, 1) {
+                Ok(mut buf) => { buf.set_endian(Endian::LittleEndian); buf.read_u16().unwrap_or(0) },
+                Err(e)      => return (chain, Some(format!("can't read block {}: {}", block, e))),
             };
-            s.end = std::cmp::min(s.end, s.start + count); // don't overrun
-            for b in s {
-                list.push(b);
-                self.bitmap[b as usize] = true;
-                count -= 1;
+            chain.push(block);
+            block = next as usize;
+        }
+        (chain, None)
+    }
+
+    // Who uses each block (the filesystem structures and each file's chain), plus any problems found while working
+    // that out. Blocks with more than one user are cross-linked.
+    fn block_users(&self) -> (Vec<Vec<String>>, Vec<String>) {
+        let blocks = self.image.blocks();
+        let mut users: Vec<Vec<String>> = vec![vec![]; blocks];
+        let mut problems = vec![];
+
+        let (mfd_pointers, other_mfd, preallocated) = match self.mfd {
+            Mfd::VariantOne(ref v1) => ([vec![("MFD2", v1.mfd2_block), ("UFD", v1.ufd_block), ("Bitmap", v1.bitmap_block)],
+                                         v1.bitmap_pointer.iter().map(|b| ("Bitmap pointer", *b)).collect()].concat(),
+                                        v1.mfd2_block, 0),
+            Mfd::VariantTwo(ref v2) => {
+                if v2.ufd_block_count as usize != self.ufd_block_list.len() {
+                    problems.push(format!("MFD says the UFD is {} blocks but its chain is {} blocks", v2.ufd_block_count, self.ufd_block_list.len()));
+                }
+                if v2.bitmap_block_count as usize != self.bitmap_block_list.len() {
+                    problems.push(format!("MFD says the bitmap is {} blocks but its chain is {} blocks", v2.bitmap_block_count, self.bitmap_block_list.len()));
+                }
+                let mut pointers = vec![("Other MFD", v2.other_mfd_block), ("UFD", v2.ufd_block), ("Bitmap", v2.bitmap_block)];
+                if v2.monitor_core_image_block != 0 { pointers.push(("Monitor", v2.monitor_core_image_block)) }
+                (pointers, v2.other_mfd_block, v2.preallocated_blocks as usize)
+            },
+        };
+        for (what, block) in mfd_pointers.iter() {
+            if *block == 0 || *block as usize >= blocks {
+                problems.push(format!("MFD {} pointer is out of range: {}", what, block));
             }
         }
-        Ok(list)
+
+        users[0].push("boot block".to_string());
+        users[1].push("MFD".to_string());
+        if other_mfd != 0 && (other_mfd as usize) < blocks {
+            users[other_mfd as usize].push("MFD".to_string());
+        }
+        for b in self.ufd_block_list.iter()    { users[*b as usize].push("UFD".to_string()) }
+        for b in self.bitmap_block_list.iter() { users[*b as usize].push("bitmap".to_string()) }
+        for u in users.iter_mut().take(preallocated).filter(|u| u.is_empty()) {
+            u.push("preallocated area".to_string());
+        }
+
+        for e in self.ufd.iter() {
+            let Some(ref name) = e.name else { continue };
+            let (chain, problem) = self.follow_chain(e.first_block);
+            if let Some(problem) = problem {
+                problems.push(format!("{}: block chain {}", name, problem));
+            }
+            if chain.len() != e.length {
+                problems.push(format!("{}: length is {} blocks but its chain is {} blocks", name, e.length, chain.len()));
+            }
+            if chain.last().is_some_and(|last| *last != e.last_block) {
+                problems.push(format!("{}: last block is {} but its chain ends at {}", name, e.last_block, chain.last().unwrap()));
+            }
+            for b in chain {
+                users[b].push(name.clone());
+            }
+        }
+        (users, problems)
     }
 
-    fn write_block_chain(image: &mut B, block_list: &[u16], data: &[u8]) -> anyhow::Result<()> {
+    // A deleted file can come back if its chain is intact and none of its blocks have been allocated since.
+    fn deleted_chain(&self, e: &DirEntry) -> Option<Vec<usize>> {
+        if e.name.is_some() || e.first_block == 0 || e.length == 0 { return None }
+        let (chain, problem) = self.follow_chain(e.first_block);
+        if problem.is_some() || chain.len() != e.length || chain.last() != Some(&e.last_block) { return None }
+        if chain.iter().any(|b| self.bitmap[*b]) { return None }
+        Some(chain)
+    }
+
+    // Checks the directory, file chains and bitmap against each other. Returns a description of each problem found.
+    pub fn check(&self) -> Vec<String> {
+        let (users, mut problems) = self.block_users();
+        for (b, u) in users.iter().enumerate().filter(|(_, u)| u.len() > 1) {
+            problems.push(format!("Block {} is cross-linked: it's used by {}", b, u.join(" and ")));
+        }
+        for (b, u) in users.iter().enumerate().filter(|(b, u)| !u.is_empty() && !self.bitmap[*b]) {
+            problems.push(format!("Block {} is used by {} but is free in the bitmap (it could be allocated twice)", b, u.join(" and ")));
+        }
+        let lost: Vec<usize> = users.iter().enumerate().filter(|(b, u)| u.is_empty() && self.bitmap[*b]).map(|(b, _)| b).collect();
+        if !lost.is_empty() {
+            problems.push(format!("Lost blocks (in use in the bitmap but not used by anything): {}", block_ranges(&lost)));
+        }
+        problems
+    }
+
+    // Repairs the bitmap by marking exactly the blocks that are used by something. Doesn't fix cross-links.
+    pub fn rebuild_bitmap(&mut self) -> anyhow::Result<()> {
+        self.check_writable()?;
+        let (users, _) = self.block_users();
+        self.bitmap = users.iter().map(|u| !u.is_empty()).collect();
+        self.write_bitmap()
+    }
+
+    pub(super) fn write_block_chain(image: &mut B, block_list: &[u16], data: &[u8]) -> anyhow::Result<()> {
         let mut iter = block_list.into_iter().map(|b| *b).peekable();
         let mut buf = Vec::with_capacity(BLOCK_SIZE);
         let mut remaining = &data[..];
@@ -278,18 +718,22 @@ impl<B: BlockDevice> XxdpFs<B> {
     }
 
     fn write_bitmap(&mut self) -> anyhow::Result<()> {
+        Self::write_bitmap_chain(&mut self.image, &self.bitmap, &self.bitmap_block_list)
+    }
+
+    pub(super) fn write_bitmap_chain(image: &mut B, bitmap: &[bool], block_list: &[u16]) -> anyhow::Result<()> {
         let mut buf = ByteBuffer::new();
         buf.set_endian(Endian::LittleEndian);
-        for (i, bits) in self.bitmap.chunks(BITMAP_WORDS_PER_MAP_BLOCK * 16/*bits/word*/).enumerate() {
+        for (i, bits) in bitmap.chunks(BITMAP_WORDS_PER_MAP_BLOCK * 16/*bits/word*/).enumerate() {
             buf.write_bytes(&BitmapBlock {
                                  map_number: i as u16,
-                                 first_bitmap: self.bitmap_block_list[0],
+                                 first_bitmap: block_list[0],
                                  entries: bits.chunks(16).map(|w| {
                                      w.iter().enumerate().fold(0, |acc, (n, b)| if *b { acc | 1<<n } else { acc })
                                  }).collect(),
                             }.repr()?);
         }
-        Self::write_block_chain(&mut self.image, &self.bitmap_block_list, buf.as_bytes())
+        Self::write_block_chain(image, block_list, buf.as_bytes())
     }
 
     fn write_mfd(&mut self) -> anyhow::Result<()> {
@@ -340,6 +784,13 @@ impl<B: BlockDevice> FileSystem for XxdpFs<B> {
             return Err(anyhow!("File not found: {}", name));
         };
         let mut contents = Vec::with_capacity(entry.length * BLOCK_SIZE);
+        if self.salvaged {
+            // Salvaged chains can be broken or run into other files, so only read the blocks we recovered.
+            for b in self.follow_chain(entry.first_block).0.into_iter().take(entry.length) {
+                contents.extend_from_slice(&self.image.read_blocks(b, 1)?.as_bytes()[2..]);
+            }
+            return Ok(ByteBuffer::from_vec(contents));
+        }
         for (_, b) in Self::read_chain_raw(&self.image, entry.first_block as u16)? {
             contents.extend_from_slice(&b.as_bytes()[2..]);
         }
@@ -347,11 +798,28 @@ impl<B: BlockDevice> FileSystem for XxdpFs<B> {
     }
 
     fn write_file(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        self.write_file_with_options(name, contents, &CreateOptions::default())
+    }
+
+    fn write_file_with_options(&mut self, name: &str, contents: &[u8], options: &CreateOptions) -> anyhow::Result<()> {
+        self.check_writable()?;
+        if options.placement != Placement::default() || !options.prefix.is_empty() {
+            return Err(anyhow!("{}: XXDP filesystems don't support placement or prefix blocks", name));
+        }
         DirEntry::encode_filename(name)?;
-        _ = self.delete(name); // Can only fail because file-not-found, which is a no-op here.
-        let entry_num = self.allocate_dir_entry()?;
+        let old_entry = self.raw_stat(name).map(|(i, e)| (i, e.first_block));
+        let entry_num = match old_entry { Some((i, _)) => i, None => self.allocate_dir_entry()? };
+        // The old file's blocks can be reused, but it mustn't go anywhere until we know the new one fits.
+        let old_bitmap = self.bitmap.clone();
+        if let Some((_, first_block)) = old_entry {
+            for (block_num, _) in Self::read_chain_raw(&self.image, first_block as u16)?.into_iter() {
+                self.bitmap[block_num as usize] = false;
+            }
+        }
         let blocks = (contents.len() + USABLE_BLOCK_SIZE - 1) / USABLE_BLOCK_SIZE;
-        let block_list = self.allocate_blocks(blocks as u16)?;
+        let block_list = if options.contiguous { self.allocate_contiguous_blocks(blocks as u16) }
+                         else                  { self.allocate_blocks(blocks as u16) };
+        let block_list = block_list.inspect_err(|_| self.bitmap = old_bitmap)?;
 
         self.ufd[entry_num] = DirEntry {
             name: Some(name.to_owned()),
@@ -369,6 +837,7 @@ impl<B: BlockDevice> FileSystem for XxdpFs<B> {
     }
 
     fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        self.check_writable()?;
         let Some((entry_num, _)) = self.raw_stat(name) else {
             return Err(anyhow!("File not found: {}", name));
         };
@@ -382,6 +851,7 @@ impl<B: BlockDevice> FileSystem for XxdpFs<B> {
     }
 
     fn rename_unchecked(&mut self, src: &str, dest: &str) -> anyhow::Result<()> {
+        self.check_writable()?;
         DirEntry::encode_filename(dest)?;
         let (entry_num, _) = self.raw_stat(src).unwrap(/*we already checked*/);
         self.ufd[entry_num].name = Some(dest.to_owned());
@@ -393,6 +863,41 @@ impl<B: BlockDevice> FileSystem for XxdpFs<B> {
     fn block_device(&self) -> &Self::BlockDevice {
         &self.image
     }
+
+    fn deleted_iter<'a>(&'a self) -> anyhow::Result<Box<dyn Iterator<Item=Box<dyn super::DirEntry + 'a>> + 'a>> {
+        Ok(Box::new(self.full_dir_iter().enumerate()
+            .filter(|(_, e)| self.deleted_chain(e).is_some())
+            .map(|(slot, e)| -> Box<dyn super::DirEntry> { Box::new(DeletedEntry { slot_name: format!("#{}", slot), entry: e }) })))
+    }
+
+    fn undelete(&mut self, name: &str, new_name: &str) -> anyhow::Result<()> {
+        self.check_writable()?;
+        let Some(slot) = name.strip_prefix('#').and_then(|n| n.parse::<usize>().ok()) else {
+            return Err(anyhow!("Deleted XXDP files are named by their directory slot (like #3), not {}", name));
+        };
+        if new_name == name { return Err(anyhow!("XXDP doesn't keep the names of deleted files. Please give {} a new name", name)) }
+        DirEntry::encode_filename(new_name)?;
+        if self.raw_stat(new_name).is_some() { return Err(anyhow!("{} already exists", new_name)) }
+        let Some(chain) = self.ufd.get(slot).and_then(|e| self.deleted_chain(e)) else {
+            return Err(anyhow!("No recoverable file in slot {}", name))
+        };
+        for b in chain {
+            self.bitmap[b] = true;
+        }
+        self.ufd[slot].name = Some(new_name.to_owned());
+        self.write_ufd()?;
+        self.write_bitmap()
+    }
+
+    fn boot_info(&self) -> Option<String> {
+        let Mfd::VariantTwo(ref v2) = self.mfd else { return None };
+        if v2.monitor_core_image_block == 0 { return None }
+        if self.image.read_blocks(0, 1).ok()?.as_bytes().iter().all(|b| *b == 0) { return None }
+        Some(match self.full_dir_iter().find(|e| e.name.is_some() && e.first_block == v2.monitor_core_image_block as usize) {
+            Some(monitor) => format!("{} (block {})", monitor.name.as_deref().unwrap(), v2.monitor_core_image_block),
+            None          => format!("monitor at block {} (but no file starts there!)", v2.monitor_core_image_block),
+        })
+    }
 }
 
 
@@ -425,7 +930,14 @@ UFD:
     }
 }
 
-#[allow(dead_code)]
+// Which MFD layout to create. XXDP+ uses Variety #1 (a 2 block MFD) on floppies and Variety #2 (a single
+// block MFD that also records the UFD and bitmap sizes) on larger disks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MfdVariant {
+    One,
+    Two,
+}
+
 #[derive(Clone, Debug)]
 pub enum Mfd {
     VariantOne(MfdVariantOne),
@@ -450,7 +962,7 @@ pub struct MfdVariantOne {
     ufd_block: u16,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct MfdVariantTwo {
     ufd_block: u16,
     ufd_block_count: u16,
@@ -529,11 +1041,20 @@ impl MfdVariantOne {
 }
 
 impl MfdVariantTwo {
-    #[allow(dead_code)]
-    pub fn from_repr(_my_block: u16, mut buf: [ByteBuffer; 2]) -> anyhow::Result<MfdVariantTwo> {
+    pub fn from_repr(_my_block: u16, image_blocks: usize, mut buf: [ByteBuffer; 2]) -> anyhow::Result<MfdVariantTwo> {
         buf[0].set_endian(Endian::LittleEndian);
         buf[1].set_endian(Endian::LittleEndian);
 
+        // There are no constants to check in Variety #2, so make sure the pointers are sane instead. That
+        // keeps things like an RT-11 home block (which is mostly zeros) from looking like an MFD.
+        buf[0].set_rpos(2);
+        for (what, block, count) in [("UFD",    buf[0].read_u16()?, buf[0].read_u16()?),
+                                     ("Bitmap", buf[0].read_u16()?, buf[0].read_u16()?)] {
+            if block == 0 || count == 0 || block as usize >= image_blocks || count as usize > image_blocks {
+                return Err(anyhow!("Bad {} pointer in MFD: {}+{}", what, block, count));
+            }
+        }
+
         buf[0].set_rpos(2);
         Ok(MfdVariantTwo {
             ufd_block: buf[0].read_u16()?,
@@ -541,7 +1062,7 @@ impl MfdVariantTwo {
             bitmap_block: buf[0].read_u16()?,
             bitmap_block_count: buf[0].read_u16()?,
             other_mfd_block: buf[0].read_u16()?,
-            support_blocks: { buf[0].set_rpos(6 * size_of::<u16>()); buf[0].read_u16()? },
+            support_blocks: { buf[0].set_rpos(7 * size_of::<u16>()); buf[0].read_u16()? },
             preallocated_blocks: buf[0].read_u16()?,
             interleave_factor: buf[0].read_u16()?,
             monitor_core_image_block: { buf[0].set_rpos(11 * size_of::<u16>()); buf[0].read_u16()? },
@@ -766,6 +1287,35 @@ impl super::DirEntry for &DirEntry {
     fn created(&self)    -> anyhow::Result<super::Timestamp> { self.date.map(|d| super::Timestamp::Date(d)).ok_or(anyhow!("Bad Date")) }
     fn blocks(&self)     -> u64                              { self.length as u64}
     fn readonly(&self)   -> bool                             { false }
+    fn protected(&self)  -> bool                             { false }
+}
+
+// Deleted entries lose their names, so they go by their UFD slot instead ("#3").
+struct DeletedEntry<'a> {
+    slot_name: String,
+    entry: &'a DirEntry,
+}
+
+impl Debug for DeletedEntry<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<5} ", self.slot_name)?;
+        self.entry.fmt(f)
+    }
+}
+
+impl super::DirEntry for DeletedEntry<'_> {
+    fn path(&self)       -> &str                             { &self.slot_name }
+    fn file_name(&self)  -> &str                             { &self.slot_name }
+    fn is_dir(&self)     -> bool                             { false }
+    fn is_file(&self)    -> bool                             { true }
+    fn is_symlink(&self) -> bool                             { false }
+    fn len(&self)        -> u64                              { super::DirEntry::len(&self.entry) }
+    fn modified(&self)   -> anyhow::Result<super::Timestamp> { super::DirEntry::modified(&self.entry) }
+    fn accessed(&self)   -> anyhow::Result<super::Timestamp> { super::DirEntry::accessed(&self.entry) }
+    fn created(&self)    -> anyhow::Result<super::Timestamp> { super::DirEntry::created(&self.entry) }
+    fn blocks(&self)     -> u64                              { super::DirEntry::blocks(&self.entry) }
+    fn readonly(&self)   -> bool                             { false }
+    fn protected(&self)  -> bool                             { false }
 }
 
 
@@ -862,6 +1412,157 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_mkfs_variant_two() {
+        let dev = TestDev(vec![0;512*20]);
+        let fs = XxdpFs::mkfs_with_variant(dev, MfdVariant::Two).expect("Create XXDP FS");
+        assert_block_eq!(fs.image, 1,  // MFD1
+                         words(&[0x0000, 0x0003, 0x0001, 0x0004, 0x0001, 0x0002, 0x0000, 0x0014, 0x0005, 0x0001]),
+                         vec![0; 512-20]);
+        assert_block_eq!(fs.image, 2,  // MFD2
+                         words(&[0x0000, 0x0003, 0x0001, 0x0004, 0x0001, 0x0001, 0x0000, 0x0014, 0x0005, 0x0001]),
+                         vec![0; 512-20]);
+        assert_block_eq!(fs.image, 4,  // Bitmap
+                         vec![0x00, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 0x00, 0x1F, 0x00],
+                         vec![0; 512-10]);
+        let fs = XxdpFs::new(TestDev(fs.image.0.clone())).expect("Reopen XXDP FS");
+        let Mfd::VariantTwo(ref v2) = fs.mfd else { panic!("Expected a Variety #2 MFD") };
+        assert_eq!((0x14, 5, 1), (v2.support_blocks, v2.preallocated_blocks, v2.interleave_factor));
+        assert_eq!(vec![3], fs.ufd_block_list);
+        assert_eq!(vec![4], fs.bitmap_block_list);
+    }
+
+    #[test]
+    fn test_extend_directory_variant_two() {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = XxdpFs::mkfs_with_variant(dev, MfdVariant::Two).expect("Create XXDP FS");
+        for i in 0..29 {
+            fs.write_file(&format!("TEST{}.TST",i), &incrementing(510)).expect("write_file failed");
+        }
+        assert_block_eq!(fs.image, 1,  // MFD1
+                         words(&[0x0000, 0x0003, 0x0002, 0x0004, 0x0001]),
+                         vec![____; 512-10]);
+        assert_block_eq!(fs.image, 2,  // MFD2
+                         words(&[0x0000, 0x0003, 0x0002, 0x0004, 0x0001]),
+                         vec![____; 512-10]);
+        let fs = XxdpFs::new(TestDev(fs.image.0.clone())).expect("Reopen XXDP FS");
+        assert_eq!(vec![3, 0x21], fs.ufd_block_list);
+        assert_eq!(29, fs.read_dir("/").expect("read_dir failed").count());
+    }
+
+    #[test]
+    fn test_contiguous() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = XxdpFs::mkfs(dev).expect("Create XXDP FS");
+        for i in 0..15 {
+            fs.write_file(&format!("F{}.TST",i), &incrementing(510)).expect("write_file failed");
+        }
+        for f in ["F1.TST", "F3.TST", "F10.TST", "F11.TST", "F12.TST"] {
+            fs.delete(f).expect("Delete failed");
+        }
+        assert_eq!(vec![6..7, 8..9, 15..18], fs.calculate_bitmap_free_spans());
+        fs.write_file_with_options("C.TST", &incrementing(510*3), &CreateOptions { contiguous: true, ..Default::default() }).expect("write_file failed");
+        assert_eq!(15, fs.raw_stat("C.TST").unwrap().1.first_block);
+        assert!(fs.is_contiguous("C.TST").expect("is_contiguous failed"));
+
+        fs.write_file("N.TST", &incrementing(510*2)).expect("write_file failed");
+        assert!(!fs.is_contiguous("N.TST").expect("is_contiguous failed"));
+        assert!(fs.make_contiguous("N.TST").is_err());
+        assert!(fs.write_file_with_options("N.TST", &incrementing(510*2), &CreateOptions { contiguous: true, ..Default::default() }).is_err());
+        assert_eq!(incrementing(510*2), fs.read_file("N.TST").expect("read_file failed").into_vec());
+        assert!(fs.calculate_bitmap_free_spans().is_empty()); // Its blocks are still its own
+        fs.delete("F2.TST").expect("Delete failed");
+        fs.make_contiguous("N.TST").expect("make_contiguous failed");
+        assert!(fs.is_contiguous("N.TST").expect("is_contiguous failed"));
+        assert_eq!(6, fs.raw_stat("N.TST").unwrap().1.first_block);
+        assert_eq!(incrementing(510*2), fs.read_file("N.TST").expect("read_file failed").into_vec());
+    }
+
+    #[test]
+    fn test_install_boot() {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = XxdpFs::mkfs_with_variant(dev, MfdVariant::Two).expect("Create XXDP FS");
+        fs.write_file("BOOT.SYS", &incrementing(510)).expect("write_file failed");
+        fs.write_file("DY.SYS", &incrementing(510)).expect("write_file failed");
+        assert_eq!(None, fs.boot_info());
+
+        // Fragment the free space so the monitor gets scattered
+        for b in (8..40).filter(|b| ![10, 20, 21].contains(b)) {
+            fs.bitmap[b] = true;
+        }
+        fs.write_file("XXDPSM.SYS", &incrementing(510*4)).expect("write_file failed");
+        assert!(!fs.is_contiguous("XXDPSM.SYS").expect("is_contiguous failed"));
+        for b in 22..40 {
+            fs.bitmap[b] = false;
+        }
+
+        fs.install_boot("BOOT.SYS", "XXDPSM.SYS", "DY.SYS").expect("install_boot failed");
+        assert!(fs.is_contiguous("XXDPSM.SYS").expect("is_contiguous failed"));
+        assert_eq!(incrementing(510*4), fs.read_file("XXDPSM.SYS").expect("read_file failed").into_vec());
+        assert_block_eq!(fs.image, 0, incrementing(510), vec![0; 2]);
+        assert_block_eq!(fs.image, 1,  // MFD1
+                         words(&[0x0000, ______, ______, ______, ______, ______, ______, ______, ______, ______,
+                                 ______, 0x0014]),
+                         vec![____; 512-24]);
+        assert_eq!(Some("XXDPSM.SYS (block 20)"), fs.boot_info().as_deref());
+
+        let mut fs = XxdpFs::mkfs(TestDev(vec![0;512*40])).expect("Create XXDP FS");
+        fs.write_file("BOOT.SYS", &incrementing(510)).expect("write_file failed");
+        fs.write_file("XXDPSM.SYS", &incrementing(510*4)).expect("write_file failed");
+        fs.write_file("DY.SYS", &incrementing(510)).expect("write_file failed");
+        assert!(fs.install_boot("BOOT.SYS", "XXDPSM.SYS", "DY.SYS").is_err());
+    }
+
+    #[test]
+    fn test_install_boot_small_media() {
+        // What `mkfs --bootable` makes on the devices that default to Variety #1
+        for (geometry, driver) in [(crate::block::rx::RX01_GEOMETRY, "DX.SYS"), (crate::block::flat::TU58_GEOMETRY, "DD.SYS")] {
+            let dev = TestDev(vec![0; geometry.bytes() / BLOCK_SIZE * BLOCK_SIZE]);
+            let mut fs = XxdpFs::mkfs_with_variant(dev, MfdVariant::Two).expect("Create XXDP FS");
+            fs.write_file("BOOT.SYS", &incrementing(510)).expect("write_file failed");
+            fs.write_file("XXDPSM.SYS", &incrementing(510*30)).expect("write_file failed");
+            fs.write_file(driver, &incrementing(510*2)).expect("write_file failed");
+            fs.install_boot("BOOT.SYS", "XXDPSM.SYS", driver).expect("install_boot failed");
+            let fs = XxdpFs::new(TestDev(fs.image.0.clone())).expect("Reopen XXDP FS");
+            let monitor = fs.raw_stat("XXDPSM.SYS").unwrap().1.first_block;
+            assert_eq!(Some(format!("XXDPSM.SYS (block {})", monitor)), fs.boot_info());
+            assert!(fs.is_contiguous(driver).expect("is_contiguous failed"));
+            assert_eq!(Vec::<String>::new(), fs.check());
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = XxdpFs::mkfs(dev).expect("Create XXDP FS");
+        fs.write_file("A.TST", &incrementing(510*2)).expect("write_file failed");
+        fs.write_file("B.TST", &incrementing(510)).expect("write_file failed");
+        fs.write_file("C.TST", &incrementing(510)).expect("write_file failed");
+        assert_eq!(Vec::<String>::new(), fs.check());
+
+        fs.bitmap[10] = true;
+        fs.bitmap[6] = false;
+        let b = fs.ufd.iter().position(|e| e.name.as_deref() == Some("B.TST")).unwrap();
+        fs.ufd[b].first_block = 6;
+        let mut c = fs.image.read_blocks(8, 1).unwrap().into_vec();
+        c[0] = 8; // C.TST points back at itself
+        fs.image.write_blocks(8, 1, &c).unwrap();
+        assert_eq!(vec!["B.TST: last block is 7 but its chain ends at 6",
+                        "C.TST: block chain loops back to block 8",
+                        "Block 6 is cross-linked: it's used by A.TST and B.TST",
+                        "Block 6 is used by A.TST and B.TST but is free in the bitmap (it could be allocated twice)",
+                        "Lost blocks (in use in the bitmap but not used by anything): 7, 10"],
+                   fs.check());
+
+        fs.rebuild_bitmap().expect("rebuild_bitmap failed");
+        assert_eq!(vec!["B.TST: last block is 7 but its chain ends at 6",
+                        "C.TST: block chain loops back to block 8",
+                        "Block 6 is cross-linked: it's used by A.TST and B.TST"],
+                   fs.check());
+        let fs = XxdpFs::new(TestDev(fs.image.0.clone())).expect("Reopen XXDP FS");
+        assert_eq!([true, true, true, true, true, true, true, false, true, false, false, false], fs.bitmap[0..12]);
+    }
+
     pub(crate) const ______: u32 = 0xffff0000;
     fn words(words: &[u32]) -> Vec<u16> {
         let mut bytes = vec![];
@@ -963,6 +1664,96 @@ mod test {
                          vec![0; 512-18*2]);
     }
 
+    #[test]
+    fn test_undelete() {
+        let dev = TestDev(vec![0;512*20]);
+        let mut fs = XxdpFs::mkfs(dev).expect("Create XXDP FS");
+        fs.write_file("A.TST", &incrementing(510*2)).expect("write_file failed");
+        fs.write_file("B.TST", &incrementing(510)).expect("write_file failed");
+        fs.delete("A.TST").expect("Delete failed");
+        fs.write_file("C.TST", &incrementing(510)).expect("write_file failed");
+        assert_eq!(Some("C.TST"), fs.ufd[2].name.as_deref()); // Didn't reuse A's entry
+        assert_eq!(vec!["#0"], fs.deleted_iter().expect("deleted_iter failed").map(|e| e.file_name().to_string()).collect::<Vec<_>>());
+
+        assert!(fs.undelete("A.TST", "A.TST").is_err());
+        assert!(fs.undelete("#0", "#0").is_err());
+        assert!(fs.undelete("#0", "B.TST").is_err());
+        assert!(fs.undelete("#1", "D.TST").is_err());
+        fs.undelete("#0", "A2.TST").expect("undelete failed");
+        assert_eq!(incrementing(510*2), fs.read_file("A2.TST").expect("read_file failed").into_vec());
+        assert_eq!(Vec::<String>::new(), fs.check());
+        assert_eq!(0, fs.deleted_iter().expect("deleted_iter failed").count());
+
+        // Once its blocks get reused it's gone for good
+        fs.delete("B.TST").expect("Delete failed");
+        assert_eq!(1, fs.deleted_iter().expect("deleted_iter failed").count());
+        fs.write_file("BIG.TST", &incrementing(510*12)).expect("write_file failed");
+        assert_eq!(0, fs.deleted_iter().expect("deleted_iter failed").count());
+        assert!(fs.undelete("#1", "B.TST").is_err());
+    }
+
+    fn salvaged_names(fs: &XxdpFs<TestDev>) -> Vec<String> {
+        fs.read_dir("/").expect("read_dir failed").map(|e| e.file_name().to_string()).collect()
+    }
+
+    #[test]
+    fn test_salvage_lost_mfd() {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = XxdpFs::mkfs(dev).expect("Create XXDP FS");
+        fs.write_file("A.TST", &incrementing(510*2)).expect("write_file failed");
+        fs.write_file("B.TST", &incrementing(510)).expect("write_file failed");
+        fs.image.write_blocks(1, 2, &[0; 1024]).unwrap(); // MFD1 and MFD2
+        assert!(XxdpFs::new(TestDev(fs.image.0.clone())).is_err());
+
+        let (mut fs, problems) = XxdpFs::salvage(TestDev(fs.image.0.clone())).expect("salvage failed");
+        assert_eq!(vec!["Couldn't find a usable MFD",
+                        "Found a UFD block at block 3 that isn't in the UFD chain",
+                        "Found the bitmap at block 4"],
+                   problems);
+        assert_eq!(vec!["A.TST", "B.TST"], salvaged_names(&fs));
+        assert_eq!(incrementing(510*2), fs.read_file("A.TST").expect("read_file failed").into_vec());
+        assert_eq!(vec![4], fs.bitmap_block_list);
+        assert!(fs.write_file("C.TST", &incrementing(510)).is_err());
+        assert!(fs.delete("A.TST").is_err());
+    }
+
+    #[test]
+    fn test_salvage_broken_ufd_chain() {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = XxdpFs::mkfs_with_variant(dev, MfdVariant::Two).expect("Create XXDP FS");
+        for i in 0..29 {
+            fs.write_file(&format!("TEST{}.TST",i), &incrementing(510)).expect("write_file failed");
+        }
+        let mut ufd = fs.image.read_blocks(3, 1).unwrap().into_vec();
+        ufd[0] = 0; // Lose the link to the 2nd UFD block
+        fs.image.write_blocks(3, 1, &ufd).unwrap();
+
+        let (fs, problems) = XxdpFs::salvage(TestDev(fs.image.0.clone())).expect("salvage failed");
+        assert_eq!(vec!["Found a UFD block at block 33 that isn't in the UFD chain"], problems);
+        assert_eq!(29, salvaged_names(&fs).len());
+        assert_eq!(Some("TEST28.TST"), salvaged_names(&fs).last().map(|n| n.as_str()));
+    }
+
+    #[test]
+    fn test_salvage_lost_chain() {
+        let dev = TestDev(vec![0;512*40]);
+        let mut fs = XxdpFs::mkfs(dev).expect("Create XXDP FS");
+        fs.write_file("A.TST", &incrementing(510*2)).expect("write_file failed");
+        fs.write_file("B.TST", &incrementing(510)).expect("write_file failed");
+        let mut ufd = fs.image.read_blocks(3, 1).unwrap().into_vec();
+        ufd[2..8].fill(0); // A.TST's name is gone, but the bitmap still has its blocks
+        fs.image.write_blocks(3, 1, &ufd).unwrap();
+
+        let (fs, problems) = XxdpFs::salvage(TestDev(fs.image.0.clone())).expect("salvage failed");
+        assert_eq!(vec!["Blocks 5-6 aren't in any file, salvaged as B00005.BAD"], problems);
+        assert_eq!(vec!["B.TST", "B00005.BAD"], salvaged_names(&fs));
+        assert_eq!(incrementing(510*2), fs.read_file("B00005.BAD").expect("read_file failed").into_vec());
+
+        // An RT-11 volume isn't an XXDP volume, no matter how damaged
+        let rt11 = crate::fs::rt11::RT11FS::mkfs(TestDev(vec![0;512*40])).expect("Create RT-11 FS");
+        assert!(!XxdpFs::image_is_salvageable(&rt11.image));
+    }
+
     #[test]
     fn test_rename() {
         let dev = TestDev(vec![0;512*20]);
@@ -3,13 +3,27 @@
 // Various operations we can do on disk image file systems
 
 use crate::block::{BlockDevice, PhysicalBlockDevice, BLOCK_SIZE, Geometry};
-use crate::block::flat::Flat;
+use crate::block::flat::{Flat, RK05_GEOMETRY, RL02_GEOMETRY, TU56_GEOMETRY, TU58_GEOMETRY};
+use crate::block::disklabel::{DiskLabel, Partition};
+use crate::block::ld::LogicalDisk;
 use crate::block::imd::IMD;
 use crate::block::img::IMG;
-use crate::block::rx::{RX, RX01_GEOMETRY, RX02_GEOMETRY};
-use crate::fs::xxdp::XxdpFs;
-use crate::fs::{FileSystem,DirEntry};
+use crate::block::rx::{RX, RX12, RX01_GEOMETRY, RX02_GEOMETRY};
+use crate::block::tap::{Tap, CASSETTE_GEOMETRY, TAPE_GEOMETRY};
+use crate::fs::ansitape::AnsiTapeFs;
+use crate::fs::bsd211::Bsd211Fs;
+use crate::fs::bup;
+use crate::fs::caps11::Caps11Fs;
+use crate::fs::dos11::Dos11Fs;
+use crate::fs::ibm3740::Ibm3740Fs;
+use crate::fs::ods1::Ods1Fs;
+use crate::fs::os8::Os8Fs;
+use crate::fs::rsts::RstsFs;
+use crate::fs::xxdp::{MfdVariant, XxdpFs};
+use crate::fs::{CreateOptions, FileSystem, Placement};
 use crate::fs::rt11::{DirSegment,RT11FS};
+use crate::fs::unixv6::UnixV6Fs;
+use crate::fs::unixv7::UnixV7Fs;
 
 use std::cmp::min;
 use std::fs::rename;
@@ -22,20 +36,62 @@ use serde::Deserialize;
 use strum::{EnumVariantNames, EnumString, Display};
 pub use strum;
 
-#[derive(Debug, Deserialize, EnumVariantNames, EnumString, Display)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, EnumVariantNames, EnumString, Display)]
 #[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum DeviceType {
     RX01,
     RX02,
+    RK05,
+    RL02,
+    TU56,
+    TU58,
+    TU10,
+    TU60,
     Flat(usize),
 }
 
 impl DeviceType {
+    // Images don't record what kind of drive they came from, so go by the size.
+    pub fn from_geometry(geometry: &Geometry) -> DeviceType {
+        match geometry.bytes() {
+            0 if geometry.sector_size == CASSETTE_GEOMETRY.sector_size => DeviceType::TU60,
+            bytes if bytes == TAPE_GEOMETRY.bytes() => DeviceType::TU10,
+            bytes if bytes == RX01_GEOMETRY.bytes() => DeviceType::RX01,
+            bytes if bytes == RX02_GEOMETRY.bytes() => DeviceType::RX02,
+            bytes if bytes == RK05_GEOMETRY.bytes() => DeviceType::RK05,
+            bytes if bytes == RL02_GEOMETRY.bytes() => DeviceType::RL02,
+            bytes if bytes == TU56_GEOMETRY.bytes() => DeviceType::TU56,
+            bytes if bytes == TU58_GEOMETRY.bytes() => DeviceType::TU58,
+            bytes => DeviceType::Flat(bytes),
+        }
+    }
+
+    // XXDP+ uses the single block MFD on the big disks and the original 2 block one on floppies.
+    pub fn xxdp_mfd_variant(&self) -> MfdVariant {
+        match self {
+            DeviceType::RK05 |
+            DeviceType::RL02 => MfdVariant::Two,
+            DeviceType::RX01 |
+            DeviceType::RX02 |
+            DeviceType::TU56 |
+            DeviceType::TU58 |
+            DeviceType::TU10 |
+            DeviceType::TU60 |
+            DeviceType::Flat(_) => MfdVariant::One,
+        }
+    }
+
     pub fn geometry(&self) -> Geometry {
         match self {
             DeviceType::RX01 => RX01_GEOMETRY,
             DeviceType::RX02 => RX02_GEOMETRY,
+            DeviceType::RK05 => RK05_GEOMETRY,
+            DeviceType::RL02 => RL02_GEOMETRY,
+            DeviceType::TU56 => TU56_GEOMETRY,
+            DeviceType::TU58 => TU58_GEOMETRY,
+            DeviceType::TU10 => TAPE_GEOMETRY,
+            DeviceType::TU60 => CASSETTE_GEOMETRY,
             DeviceType::Flat(size) => Geometry {
                 cylinders: 1,
                 heads: 1,
@@ -44,6 +100,36 @@ impl DeviceType {
             },
         }
     }
+
+    // The RT-11 device handler that can boot this kind of device (without the XM suffix or .SYS extension)
+    pub fn rt11_handler(&self) -> anyhow::Result<&'static str> {
+        match self {
+            DeviceType::RX01    => Ok("DX"),
+            DeviceType::RX02    => Ok("DY"),
+            DeviceType::RK05    => Ok("RK"),
+            DeviceType::RL02    => Ok("DL"),
+            DeviceType::TU56    => Ok("DT"),
+            DeviceType::TU58    => Ok("DD"),
+            DeviceType::TU10    => Err(anyhow!("RT-11 can't be booted from a file structured magtape")),
+            DeviceType::TU60    => Err(anyhow!("RT-11 can't be booted from a cassette")),
+            DeviceType::Flat(_) => Err(anyhow!("Don't know which handler boots this device. Please specify one.")),
+        }
+    }
+
+    // The XXDP+ driver for this kind of device (without the .SYS extension)
+    pub fn xxdp_driver(&self) -> anyhow::Result<&'static str> {
+        match self {
+            DeviceType::RX01    => Ok("DX"),
+            DeviceType::RX02    => Ok("DY"),
+            DeviceType::RL02    => Ok("DL"),
+            DeviceType::TU56    => Ok("DT"),
+            DeviceType::TU58    => Ok("DD"),
+            DeviceType::RK05    |
+            DeviceType::Flat(_) => Err(anyhow!("Don't know which driver boots this device. Please specify one.")),
+            DeviceType::TU10    => Err(anyhow!("XXDP+ can't be booted from a file structured magtape")),
+            DeviceType::TU60    => Err(anyhow!("XXDP+ can't be booted from a cassette")),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, Copy, EnumVariantNames, EnumString, Display)]
@@ -52,6 +138,7 @@ impl DeviceType {
 pub enum ImageType {
     IMD,
     IMG,
+    Tap,
 }
 
 impl ImageType {
@@ -60,6 +147,7 @@ impl ImageType {
         match ext {
             Some("img") => Ok(ImageType::IMG),
             Some("imd") => Ok(ImageType::IMD),
+            Some("tap") => Ok(ImageType::Tap),
             Some(ext) => Err(anyhow!("Unknown image type for extention {}", ext)),
             None        => Err(anyhow!("Unknown image type for {}", path.display())),
         }
@@ -72,10 +160,46 @@ impl ImageType {
 pub enum FileSystemType {
     RT11,
     XXDP,
+    DOS11,
+    ODS1,
+    Rsts,
+    Os8,
+    Ibm3740,
+    UnixV6,
+    UnixV7,
+    Bsd211,
+    AnsiTape,
+    Caps11,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, EnumVariantNames, EnumString, Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum AllocationPolicy {
+    FirstFit,
+    BestFit,
+    Largest,
+}
+
+impl From<AllocationPolicy> for Placement {
+    fn from(policy: AllocationPolicy) -> Placement {
+        match policy {
+            AllocationPolicy::FirstFit => Placement::FirstFit,
+            AllocationPolicy::BestFit  => Placement::BestFit,
+            AllocationPolicy::Largest  => Placement::Largest,
+        }
+    }
 }
 
 pub fn open_device(image_file: &Path) -> anyhow::Result<Box<dyn BlockDevice>> {
     let image = std::fs::read(image_file)?;
+    // SimH tapes have no magic number, so go by the extension.
+    if ImageType::from_file_ext(image_file).is_ok_and(|t| matches!(t, ImageType::Tap)) {
+        let mut tap = Tap::from_bytes(&image).with_context(|| "Malformed SimH tape image")?;
+        // Cassettes start with a 32 byte CAPS-11 file header where magtapes have an 80 byte VOL1 label.
+        if Caps11Fs::image_is(&tap) { tap.geometry = CASSETTE_GEOMETRY }
+        return Ok(Box::new(tap));
+    }
     Ok(match (&image[0..3], image.len()) {
         (magic, _) if magic == "IMD".as_bytes() => {
             let imd = IMD::from_bytes(&image).with_context(|| "Malformed IMD file")?;
@@ -86,6 +210,8 @@ pub fn open_device(image_file: &Path) -> anyhow::Result<Box<dyn BlockDevice>> {
         },
         (_, 256256) => Box::new(RX(IMG::from_vec(image, RX01_GEOMETRY))),
         (_, 512512) => Box::new(RX(IMG::from_vec(image, RX02_GEOMETRY))),
+        (_, len) if len == TU56_GEOMETRY.bytes() => Box::new(Flat(IMG::from_vec(image, TU56_GEOMETRY))),
+        (_, len) if len == TU58_GEOMETRY.bytes() => Box::new(Flat(IMG::from_vec(image, TU58_GEOMETRY))),
         (_, len) if len >= 1024*1024 => Box::new(Flat(IMG::from_vec(image, Geometry {
             cylinders: 1,
             heads: 1,
@@ -97,20 +223,112 @@ pub fn open_device(image_file: &Path) -> anyhow::Result<Box<dyn BlockDevice>> {
 }
 
 pub fn open_fs(dev: Box<dyn BlockDevice>) -> anyhow::Result<Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>> {
+    // Tapes are records, not blocks, so none of the disk filesystems can make any sense of them.
+    match DeviceType::from_geometry(dev.physical_device().geometry()) {
+        DeviceType::TU10 => {
+            if !AnsiTapeFs::image_is(&dev) { return Err(anyhow!("Unknown filesystem on tape (it has no VOL1 label)")) }
+            return Ok(Box::new(AnsiTapeFs::new(dev)?));
+        },
+        DeviceType::TU60 => return Ok(Box::new(Caps11Fs::new(dev)?)),
+        _ => {},
+    }
+    // OS/8 runs floppies in 12 bit mode, so they have to be looked at through RX12 to see anything.
+    let dev = if dev.sector_size() < BLOCK_SIZE {
+        let rx12 = RX12(dev);
+        if Os8Fs::image_is(&rx12) { return Ok(Box::new(Os8Fs::new(Box::new(rx12) as Box<dyn BlockDevice>)?)) }
+        rx12.0
+    } else {
+        dev
+    };
     let fs: Box<dyn FileSystem<BlockDevice=Box<dyn BlockDevice>>> =
-        if XxdpFs::image_is(&dev) {
+        if UnixV6Fs::image_is(&dev) {
+            Box::new(UnixV6Fs::new(dev)?)
+        } else if UnixV7Fs::image_is(&dev) {
+            Box::new(UnixV7Fs::new(dev)?)
+        } else if Bsd211Fs::image_is(&dev) {
+            Box::new(Bsd211Fs::new(dev)?)
+        } else if Ods1Fs::image_is(&dev) {
+            Box::new(Ods1Fs::new(dev)?)
+        } else if RstsFs::image_is(&dev) {
+            Box::new(RstsFs::new(dev)?)
+        } else if Os8Fs::image_is(&dev) {
+            Box::new(Os8Fs::new(dev)?)
+        } else if Dos11Fs::image_is(&dev) { // Before XXDP, which can read most DOS-11 disks but only sees [1,1]
+            Box::new(Dos11Fs::new(dev)?)
+        } else if XxdpFs::image_is(&dev) {
             Box::new(XxdpFs::new(dev)?)
         } else if RT11FS::image_is(&dev) {
             Box::new(RT11FS::new(dev)?)
+        } else if Ibm3740Fs::image_is(&dev) { // After the rest, since their floppies can have an unused VOL1 label in track 0
+            Box::new(Ibm3740Fs::new(dev)?)
+        } else if DiskLabel::read(&dev).is_ok_and(|l| l.partition('a').is_some_and(|a| a.offset != 0)) { // Try the root partition
+            return open_fs(open_partition(dev, 'a')?);
         } else {
             return Err(anyhow!("Unknown filesystem on image"));
         };
     Ok(fs)
 }
 
-pub fn ls(fs: &impl FileSystem, long: bool, all: bool) {
-    for f in if all { Box::new(fs.dir_iter("/").expect("fixme")) as Box<dyn Iterator<Item = Box<dyn DirEntry>>> }
-             else   { fs.read_dir("/").expect("fixme") } {
+// For RT-11 volumes with damaged directories that open_fs() gives up on. Complains about what it finds on stderr
+// (so `cat` output stays clean).
+pub fn salvage_fs(dev: Box<dyn BlockDevice>) -> anyhow::Result<Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>> {
+    // RT-11 salvage copes with a bad home block, so only go with XXDP when there's no sign of RT-11 (the system id at
+    // the end of its home block) and XXDP salvage actually finds an XXDP directory.
+    let rt11_home = dev.read_blocks(1, 1).is_ok_and(|b| b.as_bytes()[0o760..0o770] == *b"DECRT11A");
+    let (fs, problems): (Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>, _) =
+        if !rt11_home && XxdpFs::image_is_salvageable(&dev) {
+            let (fs, problems) = XxdpFs::salvage(dev)?;
+            (Box::new(fs), problems)
+        } else {
+            let (fs, problems) = RT11FS::salvage(dev)?;
+            (Box::new(fs), problems)
+        };
+    for problem in problems {
+        eprintln!("Warning: {}", problem);
+    }
+    Ok(fs)
+}
+
+// Opens a volume kept in a file on an RT-11 volume (like the LD handler). The result can be handed to open_fs().
+pub fn open_logical_disk(dev: Box<dyn BlockDevice>, name: &str) -> anyhow::Result<Box<dyn BlockDevice>> {
+    let outer = RT11FS::new(dev).with_context(|| format!("Logical disk {} must be on an RT-11 volume", name))?;
+    Ok(Box::new(LogicalDisk::new(outer, &name.to_uppercase())?))
+}
+
+// Opens one partition of a 2.11BSD labeled disk. The result can be handed to open_fs().
+pub fn open_partition(dev: Box<dyn BlockDevice>, letter: char) -> anyhow::Result<Box<dyn BlockDevice>> {
+    Ok(Box::new(Partition::new(dev, letter)?))
+}
+
+// "disk.img:e" means partition 'e' of disk.img, but only when disk.img exists and "disk.img:e" doesn't.
+pub fn split_partition_path(p: &Path) -> (PathBuf, Option<char>) {
+    if p.exists() { return (p.to_owned(), None) }
+    let s = p.to_string_lossy();
+    match s.rsplit_once(':') {
+        Some((image, part)) if part.len() == 1 && ('a'..='h').contains(&part.chars().next().unwrap()) && Path::new(image).exists()
+                => (PathBuf::from(image), part.chars().next()),
+        _       => (p.to_owned(), None),
+    }
+}
+
+pub fn disklabel(dev: &impl BlockDevice) -> anyhow::Result<()> {
+    print!("{}", DiskLabel::read(dev)?);
+    Ok(())
+}
+
+// "OUTER.DSK:INNER.MAC" means INNER.MAC inside the logical disk OUTER.DSK. Local paths (which have a `/`) are left alone.
+pub fn split_logical_disk_path(p: &Path) -> (Option<String>, PathBuf) {
+    let s = p.to_string_lossy();
+    if s.chars().any(std::path::is_separator) { return (None, p.to_owned()) }
+    match s.split_once(':') {
+        Some((disk, inner)) => (Some(disk.to_owned()), PathBuf::from(inner)),
+        None                => (None, p.to_owned()),
+    }
+}
+
+pub fn ls(fs: &impl FileSystem, path: &str, long: bool, all: bool) -> anyhow::Result<()> {
+    for f in if all { fs.dir_iter(path)? }
+             else   { fs.read_dir(path)? } {
         match long {
             false => println!("{:?}", f),
             true  => println!("{:#?}", f),
@@ -122,6 +340,16 @@ pub fn ls(fs: &impl FileSystem, long: bool, all: bool) {
              used_blocks, used_blocks * BLOCK_SIZE, used_blocks * 100 / (used_blocks + free_blocks),
              free_blocks, free_blocks * BLOCK_SIZE, free_blocks * 100 / (used_blocks + free_blocks),
              used_blocks + free_blocks, (used_blocks + free_blocks) * BLOCK_SIZE);
+    Ok(())
+}
+
+pub fn info(fs: &impl FileSystem) {
+    let dev = fs.block_device();
+    println!("Filesystem : {}", fs.filesystem_name());
+    println!("Device     : {} ({} blocks)", DeviceType::from_geometry(dev.physical_device().geometry()), dev.blocks());
+    println!("Used       : {} blocks", fs.used_blocks());
+    println!("Free       : {} blocks", fs.free_blocks());
+    println!("Bootable   : {}", fs.boot_info().unwrap_or("No".to_string()));
 }
 
 pub fn cp_from_image(fs: &impl FileSystem, src: &Path, dest: &Path) -> anyhow::Result<()> {
@@ -130,8 +358,7 @@ pub fn cp_from_image(fs: &impl FileSystem, src: &Path, dest: &Path) -> anyhow::R
         (true, Err(e)) => Err(e).with_context(|| format!("{}", dest.to_string_lossy()))?,
         (_, _) => dest.to_owned(),
     };
-    let source_file = src.to_str().ok_or(anyhow!("Bad filename: {}", src.to_string_lossy()))?
-        .to_uppercase();
+    let source_file = path_to_image_filename(fs, src)?;
     let data = fs.read_file(&source_file)?;
     let file = fs.stat(&source_file).unwrap();
     print!("{} -> {}", file.file_name(), local_dest.to_string_lossy());
@@ -140,16 +367,39 @@ pub fn cp_from_image(fs: &impl FileSystem, src: &Path, dest: &Path) -> anyhow::R
     Ok(())
 }
 
-pub fn cp_into_image(fs: &mut impl FileSystem, src: &Path, dest: &Path) -> anyhow::Result<()> {
-    let dest = path_to_rt11_filename(match dest {
+#[allow(unused)] // Used by the viewer
+pub fn cp_into_image(fs: &mut impl FileSystem, src: &Path, dest: &Path, force: bool) -> anyhow::Result<()> {
+    cp_into_image_with_options(fs, src, dest, force, &CreateOptions::default())
+}
+
+fn image_dest_filename(fs: &impl FileSystem, src: &Path, dest: &Path) -> anyhow::Result<String> {
+    path_to_image_filename(fs, match dest {
         d if d == Path::new(".") => Path::new(src.file_name().ok_or_else(|| anyhow!("Need source filename to use '.'"))?),
         d => d,
-    })?;
+    })
+}
+
+pub fn cp_into_image_with_options(fs: &mut impl FileSystem, src: &Path, dest: &Path, force: bool, options: &CreateOptions) -> anyhow::Result<()> {
+    let dest = image_dest_filename(fs, src, dest)?;
     let buf = std::fs::read(src).with_context(|| format!("Reading \"{}\" failed", src.display()))?;
-    fs.write_file(&dest, &buf).with_context(|| format!("Creating \"{}\" on disk image failed", dest))?;
+    unprotect_if_forced(fs, &dest, force)?;
+    fs.write_file_with_options(&dest, &buf, options).with_context(|| format!("Creating \"{}\" on disk image failed", dest))?;
     Ok(())
 }
 
+// Like cp_into_image(), but adds to the end of an existing file on the image. With `force`, protected files can be
+// appended to (and stay protected).
+pub fn append_into_image(fs: &mut impl FileSystem, src: &Path, dest: &Path, force: bool) -> anyhow::Result<()> {
+    let dest = image_dest_filename(fs, src, dest)?;
+    let buf = std::fs::read(src).with_context(|| format!("Reading \"{}\" failed", src.display()))?;
+    let protected = unprotect_if_forced(fs, &dest, force)?;
+    let appended = fs.append_file(&dest, &buf).with_context(|| format!("Appending to \"{}\" on disk image failed", dest));
+    if protected && force {
+        fs.set_protected(&dest, true)?;
+    }
+    appended
+}
+
 pub fn save_image(dev: Box<&dyn PhysicalBlockDevice>, filename: &Path) -> anyhow::Result<()> {
     let new_image = dev.as_vec()?;
     let newname = filename.append(".new");
@@ -178,7 +428,7 @@ pub fn dump(image: &Box<dyn BlockDevice>, by_sector: bool, range: Option<Range<u
 
 pub fn dump_file(fs: &impl FileSystem, file: &Path, by_sector: bool, range: Option<Range<usize>>) -> anyhow::Result<()> {
     let range = range.unwrap_or(0..usize::MAX);
-    let file = path_to_rt11_filename(&file)?;
+    let file = path_to_image_filename(fs, file)?;
     let data = fs.read_file(&file)?;
     let chunk_size = if by_sector { fs.block_device().sector_size() } else { crate::block::BLOCK_SIZE };
     for c in range.start..min(range.end, data.len()/chunk_size) {
@@ -237,34 +487,248 @@ pub fn rt11_dump_dir(image: &Box<dyn BlockDevice>) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn rm(fs: &mut impl FileSystem, file: &Path) -> anyhow::Result<()> {
-    fs.delete(&path_to_rt11_filename(file)?)
+pub fn rt11_copy_boot(fs: &mut RT11FS<Box<dyn BlockDevice>>, monitor: &Path, handler: Option<&Path>) -> anyhow::Result<()> {
+    let monitor = path_to_rt11_filename(monitor)?;
+    let handler = match handler {
+        Some(handler) => path_to_rt11_filename(handler)?,
+        None => {
+            let dtype = DeviceType::from_geometry(fs.block_device().physical_device().geometry());
+            // The XM monitor uses its own flavor of the handlers
+            let suffix = if monitor.split_once('.').is_some_and(|(name, _)| name.ends_with("XM")) { "X" } else { "" };
+            format!("{}{}.SYS", dtype.rt11_handler()?, suffix)
+        },
+    };
+    fs.copy_boot(&monitor, &handler, 0)
+}
+
+// Saves the whole image across as many new `dtype` images as it takes, named like `backup-1.img`, `backup-2.img`...
+pub fn rt11_backup(image: &dyn BlockDevice, dtype: DeviceType, dest: &Path) -> anyhow::Result<()> {
+    let imtype = ImageType::from_file_ext(dest)?;
+    let data = image.read_blocks(0, image.blocks())?;
+    let volumes = bup::backup(data.as_bytes(), || create_device(imtype, dtype))?;
+    for (i, v) in volumes.iter().enumerate() {
+        let name = numbered_path(dest, i + 1);
+        println!("{} ({} blocks)", name.display(), v.stat(bup::SAVESET_NAME).map(|f| f.blocks()).unwrap_or(0));
+        save_image(v.block_device().physical_device(), &name)?;
+    }
+    Ok(())
+}
+
+// "backup.img", 2 -> "backup-2.img"
+fn numbered_path(p: &Path, n: usize) -> PathBuf {
+    let stem = p.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match p.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+        None      => format!("{}-{}", stem, n),
+    };
+    p.with_file_name(name)
+}
+
+fn read_backup_set(volumes: &[PathBuf]) -> anyhow::Result<Box<dyn BlockDevice>> {
+    let volumes = volumes.iter().map(|v| open_device(v).and_then(RT11FS::new).with_context(|| format!("{}", v.display())))
+                                .collect::<anyhow::Result<Vec<_>>>()?;
+    let data = bup::restore(&volumes)?;
+    // Only the logical blocks get saved, which on the floppies isn't the whole disk. So look for the drive with
+    // exactly that many instead of going by the size.
+    let blocks = data.len() / BLOCK_SIZE;
+    let dtype = [DeviceType::RX01, DeviceType::RX02, DeviceType::RK05, DeviceType::RL02, DeviceType::TU56, DeviceType::TU58].into_iter()
+        .find(|d| create_device(ImageType::IMG, *d).is_ok_and(|dev| dev.blocks() == blocks))
+        .unwrap_or(DeviceType::Flat(data.len()));
+    let mut dev = create_device(ImageType::IMG, dtype)?;
+    dev.write_blocks(0, data.len() / BLOCK_SIZE, &data)?;
+    Ok(dev)
+}
+
+// Puts the volume saved in the backup set back together as `dest`.
+pub fn rt11_restore(volumes: &[PathBuf], dest: &Path) -> anyhow::Result<()> {
+    let dev = read_backup_set(volumes)?;
+    let (geometry, data) = dev.physical_device().to_raw()?;
+    match ImageType::from_file_ext(dest)? {
+        ImageType::IMG => save_image(Box::new(&IMG::from_raw(data, geometry)), dest),
+        ImageType::IMD => save_image(Box::new(&IMD::from_raw(data, geometry)), dest),
+        ImageType::Tap => Err(anyhow!("Backup sets hold disks, not tapes")),
+    }
+}
+
+// Copies all the files on the volume saved in the backup set into `dest_dir`.
+pub fn rt11_extract(volumes: &[PathBuf], dest_dir: &Path) -> anyhow::Result<()> {
+    let fs = open_fs(read_backup_set(volumes)?)?;
+    let names: Vec<String> = fs.read_dir("/")?.filter(|f| f.is_file()).map(|f| f.path().to_string()).collect();
+    for name in names {
+        cp_from_image(&fs, Path::new(&name), dest_dir)?;
+    }
+    Ok(())
+}
+
+pub fn xxdp_install_boot(fs: &mut XxdpFs<Box<dyn BlockDevice>>, boot: &Path, monitor: &Path, driver: Option<&Path>) -> anyhow::Result<()> {
+    let driver = match driver {
+        Some(driver) => path_to_rt11_filename(driver)?,
+        None => format!("{}.SYS", DeviceType::from_geometry(fs.block_device().physical_device().geometry()).xxdp_driver()?),
+    };
+    fs.install_boot(&path_to_rt11_filename(boot)?, &path_to_rt11_filename(monitor)?, &driver)
+}
+
+pub fn xxdp_check(fs: &mut XxdpFs<Box<dyn BlockDevice>>, repair: bool) -> anyhow::Result<()> {
+    let problems = fs.check();
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    if !repair {
+        return Err(anyhow!("Found {} problem{}", problems.len(), if problems.len() == 1 { "" } else { "s" }));
+    }
+    fs.rebuild_bitmap()?;
+    let remaining = fs.check();
+    println!("Rebuilt the bitmap. {} problem{} left", remaining.len(), if remaining.len() == 1 { "" } else { "s" });
+    for problem in remaining.iter() {
+        println!("{}", problem);
+    }
+    Ok(())
+}
+
+pub fn rt11_set_extra(fs: &mut RT11FS<Box<dyn BlockDevice>>, file: &Path, words: &[String]) -> anyhow::Result<()> {
+    let words = words.iter().map(|w| parse_word(w)).collect::<anyhow::Result<Vec<u16>>>()?;
+    fs.set_extra_words(&path_to_rt11_filename(file)?, &words)
+}
+
+// Protection codes are decimal, like RSTS/E shows them (<60>).
+pub fn rsts_set_protection(fs: &mut RstsFs<Box<dyn BlockDevice>>, file: &Path, code: &str) -> anyhow::Result<()> {
+    let code = parse_word(code)?;
+    if code > 0xff { return Err(anyhow!("Bad protection code {}: must be 0 to 255", code)) }
+    fs.set_protection_code(&path_to_image_filename(fs, file)?, code as u8)
+}
+
+pub fn rt11_prefix(fs: &RT11FS<Box<dyn BlockDevice>>, file: &Path) -> anyhow::Result<()> {
+    use std::io::Write;
+    let file = path_to_rt11_filename(file)?;
+    if fs.prefix_blocks(&file)? == 0 { return Err(anyhow!("{} doesn't have any prefix blocks", file)) }
+    std::io::stdout().write_all(fs.read_prefix(&file)?.as_bytes())?;
+    Ok(())
+}
+
+// Accepts 0x (hex) and 0o (octal) prefixes, since PDP-11 folks think in octal but hex dumps are everywhere.
+pub fn parse_word(s: &str) -> anyhow::Result<u16> {
+    let (digits, radix) = match s {
+        s if s.starts_with("0x") => (&s[2..], 16),
+        s if s.starts_with("0o") => (&s[2..], 8),
+        s                        => (s, 10),
+    };
+    u16::from_str_radix(digits, radix).with_context(|| format!("Bad word: {:?}", s))
+}
+
+pub fn rm(fs: &mut impl FileSystem, file: &Path, force: bool) -> anyhow::Result<()> {
+    let file = path_to_image_filename(fs, file)?;
+    unprotect_if_forced(fs, &file, force)?;
+    fs.delete(&file)
 }
 
+// Overwriting the destination is already forceful, so `overwrite_dest` also lets us rename protected files (and
+// overwrite protected destinations). The source keeps its protection after the rename, like RT-11's PIP.
 pub fn mv(fs: &mut impl FileSystem, src: &Path, dest: &Path, overwrite_dest: bool) -> anyhow::Result<()> {
-    if !overwrite_dest && fs.stat(&path_to_rt11_filename(dest)?).is_some() { return Err(anyhow!("Destination file already exists")) }
-    fs.rename(&path_to_rt11_filename(src)?, &path_to_rt11_filename(dest)?)
+    let (src, dest) = (path_to_image_filename(fs, src)?, path_to_image_filename(fs, dest)?);
+    if !overwrite_dest && fs.stat(&dest).is_some() { return Err(anyhow!("Destination file already exists")) }
+    if src == dest { return Ok(()) }
+    let src_protected = unprotect_if_forced(fs, &src, overwrite_dest)?;
+    unprotect_if_forced(fs, &dest, overwrite_dest)?;
+    let renamed = fs.rename(&src, &dest);
+    if src_protected {
+        fs.set_protected(if renamed.is_ok() { &dest } else { &src }, true)?;
+    }
+    renamed
+}
+
+// Protected files can't be deleted, renamed or overwritten. Forcing means taking the protection off first.
+// Returns whether the file was protected.
+fn unprotect_if_forced(fs: &mut impl FileSystem, file: &str, force: bool) -> anyhow::Result<bool> {
+    let protected = fs.stat(file).map(|f| f.protected()).unwrap_or(false);
+    if protected && force {
+        fs.set_protected(file, false)?;
+    }
+    Ok(protected)
+}
+
+pub fn protect(fs: &mut impl FileSystem, file: &Path, protected: bool) -> anyhow::Result<()> {
+    fs.set_protected(&path_to_image_filename(fs, file)?, protected)
+}
+
+pub fn readonly(fs: &mut impl FileSystem, file: &Path, readonly: bool) -> anyhow::Result<()> {
+    fs.set_readonly(&path_to_image_filename(fs, file)?, readonly)
+}
+
+pub fn ls_deleted(fs: &impl FileSystem) -> anyhow::Result<()> {
+    let mut found = false;
+    for f in fs.deleted_iter()? {
+        println!("{:#?}", f);
+        found = true;
+    }
+    if !found { println!("No recoverable files") }
+    Ok(())
+}
+
+pub fn undelete(fs: &mut impl FileSystem, file: &Path, new_name: Option<&Path>) -> anyhow::Result<()> {
+    let file = path_to_image_filename(fs, file)?;
+    let new_name = match new_name { Some(n) => path_to_image_filename(fs, n)?, None => file.clone() };
+    fs.undelete(&file, &new_name)
 }
 
+#[allow(unused)] // Used by the viewer
 pub fn create_image(imtype: ImageType, dtype: DeviceType, fstype: FileSystemType) -> anyhow::Result<Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>> {
+    create_image_with_mfd_variant(imtype, dtype, fstype, None)
+}
+
+// `mfd_variant` only matters for XXDP. When it's None the device type picks it.
+pub fn create_image_with_mfd_variant(imtype: ImageType, dtype: DeviceType, fstype: FileSystemType, mfd_variant: Option<MfdVariant>) -> anyhow::Result<Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>> {
+    if (dtype == DeviceType::TU10) != matches!(fstype, FileSystemType::AnsiTape) {
+        return Err(anyhow!("ANSI magtape filesystems only go on tapes (tu10), and tapes can only hold ANSI magtape filesystems"));
+    }
+    if (dtype == DeviceType::TU60) != matches!(fstype, FileSystemType::Caps11) {
+        return Err(anyhow!("CAPS-11 filesystems only go on cassettes (tu60), and cassettes can only hold CAPS-11 filesystems"));
+    }
+    let dev = create_device(imtype, dtype)?;
+
+    Ok(match fstype {
+        FileSystemType::RT11 => Box::new(RT11FS::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::XXDP => Box::new(XxdpFs::mkfs_with_variant(dev, mfd_variant.unwrap_or(dtype.xxdp_mfd_variant()))?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::DOS11 => Box::new(Dos11Fs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::ODS1 => Box::new(Ods1Fs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::Rsts => Box::new(RstsFs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::Ibm3740 => Box::new(Ibm3740Fs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::Os8 => Box::new(Os8Fs::mkfs(if dev.sector_size() < BLOCK_SIZE { Box::new(RX12(dev)) } else { dev })?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::UnixV6 => Box::new(UnixV6Fs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::UnixV7 => Box::new(UnixV7Fs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::Bsd211 => Box::new(Bsd211Fs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::AnsiTape => Box::new(AnsiTapeFs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+        FileSystemType::Caps11 => Box::new(Caps11Fs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+    })
+}
+
+// A blank (zeroed) device
+pub fn create_device(imtype: ImageType, dtype: DeviceType) -> anyhow::Result<Box<dyn BlockDevice>> {
     let geometry = dtype.geometry();
 
-    fn create_device<'a, P: PhysicalBlockDevice + 'a>(dtype: DeviceType, phys: P) -> Box<dyn BlockDevice+'a> {
+    fn logical_device<'a, P: PhysicalBlockDevice + 'a>(dtype: DeviceType, phys: P) -> Box<dyn BlockDevice+'a> {
         match dtype {
             DeviceType::RX01    |
             DeviceType::RX02    => Box::new(RX(phys)),
+            DeviceType::RK05    |
+            DeviceType::RL02    |
+            DeviceType::TU56    |
+            DeviceType::TU58    |
             DeviceType::Flat(_) => Box::new(Flat(phys)),
+            DeviceType::TU10    |
+            DeviceType::TU60    => unreachable!("Tapes aren't made from disk images"),
         }
     }
 
-    let dev = match imtype {
-        ImageType::IMD => create_device(dtype, IMD::from_raw(vec![0; geometry.bytes()], geometry)),
-        ImageType::IMG => create_device(dtype, IMG::from_raw(vec![0; geometry.bytes()], geometry)),
-    };
-
-    Ok(match fstype {
-        FileSystemType::RT11 => Box::new(RT11FS::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
-        FileSystemType::XXDP => Box::new(XxdpFs::mkfs(dev)?) as Box<dyn FileSystem<BlockDevice = Box<dyn BlockDevice>>>,
+    Ok(match (imtype, dtype) {
+        (ImageType::Tap, DeviceType::TU10) => Box::new(Tap::new()),
+        (ImageType::Tap, DeviceType::TU60) => Box::new(Tap::cassette()),
+        (ImageType::Tap, _) | (_, DeviceType::TU10 | DeviceType::TU60) => return Err(anyhow!("Tapes (tu10) and cassettes (tu60) have to be .tap images, and .tap images can only hold tapes and cassettes")),
+        (ImageType::IMD, _) => logical_device(dtype, IMD::from_raw(vec![0; geometry.bytes()], geometry)),
+        (ImageType::IMG, _) => logical_device(dtype, IMG::from_raw(vec![0; geometry.bytes()], geometry)),
     })
 }
 
@@ -273,10 +737,17 @@ pub fn convert(image: &Box<dyn BlockDevice>, image_type: ImageType, dest: &Path)
     match image_type {
         ImageType::IMG => save_image(Box::new(&IMG::from_raw(data, geometry)), dest)?,
         ImageType::IMD => save_image(Box::new(&IMD::from_raw(data, geometry)), dest)?,
+        ImageType::Tap => return Err(anyhow!("Disk images can't be converted to tapes")),
     }
     Ok(())
 }
 
+// Like path_to_rt11_filename(), but leaves the case alone on filesystems that care about it.
+pub fn path_to_image_filename(fs: &impl FileSystem, p: &Path) -> anyhow::Result<String> {
+    if !fs.case_sensitive() { return path_to_rt11_filename(p) }
+    Ok(p.to_str().ok_or(anyhow!("Bad filename: {}", p.to_string_lossy()))?.to_owned())
+}
+
 pub fn path_to_rt11_filename(p: &Path) -> anyhow::Result<String> {
     Ok(p.to_str().ok_or(anyhow!("Bad filename: {}", p.to_string_lossy()))?
         .to_uppercase())
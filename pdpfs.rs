@@ -4,43 +4,72 @@ mod block;
 mod fs;
 mod ops;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use block::BlockDevice;
 use ops::*;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use docopt::Docopt;
 use serde::Deserialize;
 use strum::VariantNames;
 
-use crate::fs::FileSystem;
+use crate::fs::{CreateOptions, FileSystem, Placement};
+use crate::fs::xxdp::MfdVariant;
 
 fn usage() -> String {
     format!(r#"
 Usage:
   pdpfs -h
-  pdpfs [-h] -i <image> ls [-l] [-a]
-  pdpfs [-h] -i <image> cp <source-file> <dest-file>
+  pdpfs [-h] -i <image> [--salvage] ls [-l] [-a] [<dir>]
+  pdpfs [-h] -i <image> [--salvage] info
+  pdpfs [-h] -i <image> [--salvage] cp [-f] [--append | --at <block> | --policy <policy>] [--contiguous] [--prefix <prefix-file>] <source-file> <dest-file>
   pdpfs [-h] -i <image> mv [-f] <source-file> <dest-file>
-  pdpfs [-h] -i <image> rm <file>
-  pdpfs [-h] -i <image> cat <file>
-  pdpfs [-h] -i <image> mkfs <device-type> <filesystem>
+  pdpfs [-h] -i <image> rm [-f] <file>
+  pdpfs [-h] -i <image> undelete [<file> [<new-name>]]
+  pdpfs [-h] -i <image> protect <file>
+  pdpfs [-h] -i <image> unprotect <file>
+  pdpfs [-h] -i <image> readonly [--clear] <file>
+  pdpfs [-h] -i <image> [--salvage] cat <file>
+  pdpfs [-h] -i <image> mkfs [--mfd-variant <variant> | --bootable] <device-type> <filesystem>
   pdpfs [-h] -i <image> convert <image-type> <dest-file>
   pdpfs [-h] -i <image> dump [--range <range>] [--sector] [<file>]
+  pdpfs [-h] -i <image> disklabel
   pdpfs [-h] -i <image> rt11 dump-home
   pdpfs [-h] -i <image> rt11 dump-dir
+  pdpfs [-h] -i <image> rt11 set-extra <file> [<word>...]
+  pdpfs [-h] -i <image> rt11 boot <monitor-file> [<handler-file>]
+  pdpfs [-h] -i <image> rt11 prefix <file>
+  pdpfs [-h] -i <image> rt11 backup <device-type> <backup-image>
+  pdpfs [-h] -i <image> rt11 restore <backup-volume>...
+  pdpfs [-h] rt11 restore --extract <dir> <backup-volume>...
+  pdpfs [-h] -i <image> rsts set-protection <file> <code>
+  pdpfs [-h] -i <image> xxdp boot <boot-file> <monitor-file> [<driver-file>]
+  pdpfs [-h] -i <image> xxdp check [--repair]
 
 Options:
   -h --help              Show this screen.
-  -i --image <image>     Use <image> as the disk image.
+  -i --image <image>     Use <image> as the disk image. A 2.11BSD disk's partitions
+                         can be picked with a suffix like `disk.img:e` (partition
+                         'a' is used when the start of the disk has no filesystem).
+  --salvage              Open an RT-11 or XXDP image with a damaged directory using
+                         whatever of the directory can still be read. Blocks that no
+                         surviving directory entry covers show up as `Bnnnnn.BAD` files
+                         (nnnnn being the starting block). On XXDP these are chains of
+                         linked blocks that are in use but not in any file. The image
+                         can only be read.
 
  ls:
    -a --all              List all entries, not just 'permanents'
    -l --long             Give a more detailed output. All directory entry fields in
                          the filesystem are printed and not just the most useful.
 
-   List files in the image.
+   List files in the image. <dir> can name a logical disk (see below) to list
+   the files inside it, or a DOS-11 or Files-11 UIC or RSTS/E account (like
+   `[200,200]`) to list just its files.
+
+ info:
+   Show the filesystem type, device, space used and whether the image is bootable.
 
  cp:
    <source-file> and <dest-file> specify local (host) filesystem paths if they
@@ -61,8 +90,65 @@ Options:
      # This copies 'FILE.TXT' from the image into './file.txt' on the local machine:
      pdpfs -i my_image.img cp file.txt ./
 
+   -f --force            Overwrite the destination file on the image even if it
+                         is protected.
+   --append              Add the local file to the end of <dest-file> on the image
+                         instead of replacing it. RT-11 files are a whole number of
+                         blocks long, so the new data starts on a block boundary.
+   --at <block>          Put the file on the image starting at <block>, which (along
+                         with the rest of the file) must be free or part of the
+                         file being replaced.
+   --policy <policy>     How to choose where the file goes on the image. <policy> must
+                         be one of: {}
+                         (first-fit is the default).
+   --contiguous          Put the whole file in one run of blocks (XXDP, DOS-11,
+                         Files-11 and RSTS/E only--RT-11 files are always
+                         contiguous).
+   --prefix <prefix-file>
+                         Give the new file prefix blocks holding the contents of the
+                         local file <prefix-file> (RT-11 only).
+
+   Files inside a volume that is kept in a file on an RT-11 image (the kind RT-11's
+   LD handler mounts) are named like `OUTER.DSK:INNER.MAC`. This works for every
+   command that takes image files, and changes are written back into OUTER.DSK.
+
+     # Copy INNER.MAC out of the logical disk OUTER.DSK:
+     pdpfs -i my_image.img cp outer.dsk:inner.mac ./
+
+   DOS-11 files belong to a UIC, which goes in front of the name like
+   `[200,200]FILE.MAC`. Names without one are in [1,1]. Copying a file to a UIC
+   that isn't on the image yet adds it.
+
+   Files-11 (ODS-1) names work the same way and can also have a version number,
+   like `[200,200]FILE.MAC;3`. Without one, reading gets the newest version and
+   writing makes a new one.
+
+   RSTS/E files belong to an account, like `[200,200]FILE.BAS`. Names without one
+   are in [1,2]. Copying a file to an account that isn't on the image yet adds it.
+
+   OS/8 files are unpacked 2 12 bit words to 3 bytes, the way OS/8 packs text, so
+   text files come out as plain text and other files survive the trip back in.
+
+   IBM 3740 data sets are text: each record is a line. They are translated between
+   EBCDIC and ASCII on the way in and out, and lines can be up to 128 characters.
+
+   ANSI labelled magtapes (SimH .tap images, made with `mkfs tu10 ansitape`) are
+   written in 512 byte blocks, the way RT-11 writes them. New files go on the end
+   of the tape. Replacing, renaming or deleting a file rewrites the rest of the
+   tape after it.
+
+   CAPS-11 cassettes (SimH TA11 .tap images, made with `mkfs tu60 caps11`) work the
+   same way, in 128 byte records. Copying over an existing file deletes the old
+   one and puts the new one on the end. `cp --append` works too.
+
+   Unix (V6, V7 and 2.11BSD) paths are case sensitive and can have directories, like
+   `usr/src/foo.c`. When both <source-file> and <dest-file> have a `/`, the one
+   that exists (on the image or locally) is the source. Directories that don't
+   exist yet are made when copying into the image.
+
  mv:
-   -f --force            Overwrite destination file if it exists.
+   -f --force            Overwrite destination file if it exists, even if it is
+                         protected. Also allows renaming a protected file.
 
    Move (rename) files on the image. <source-file> and <dest-file> specify files
    on the image.
@@ -71,14 +157,40 @@ Options:
    the --force option is used.
 
  rm:
+   -f --force            Delete the file even if it is protected.
+
    <file> will be deleted from the image.
 
+ undelete:
+   With no <file>, lists the deleted files that can still be recovered (their
+   space hasn't been reused). Otherwise restores <file>, as <new-name> if given
+   (useful when a new file with the same name has been created since).
+   XXDP doesn't keep the names of deleted files, so they are listed by their
+   directory slot (like `#3`) and need a <new-name> to be restored.
+
+ protect, unprotect:
+   Sets or clears the protection on <file>. Protected files can't be deleted,
+   renamed or overwritten without --force.
+
+ readonly:
+   --clear               Make the file writable again.
+
+   Marks <file> as read-only.
+
  cat:
    Prints the contents of <file> to stdout.
 
  mkfs:
+   --mfd-variant <variant>
+                         Which XXDP MFD layout to create: 1 (the 2 block MFD) or 2 (the
+                         single block MFD). Defaults to 2 on RK05 and RL02 and 1 elsewhere.
+   --bootable            Make an XXDP image that `xxdp boot` can make bootable. This
+                         picks MFD variant 2, even on RX01, RX02 and TU58.
+
    Initializes a new image. The <image> file specified by `-i` will be created
-   and must _not_ already exist.
+   and must _not_ already exist. Tapes (tu10) need a .tap <image> and the
+   ansitape <filesystem>. Cassettes (tu60) need a .tap <image> and the caps11
+   <filesystem>.
 
    <device-type> must be one of: {}
 
@@ -102,7 +214,67 @@ Options:
    Dumps the image, de-interleaving floppy images.
 
    If <file> is specified, dumps the file instead of the whole image.
+
+ disklabel:
+   Prints the 2.11BSD disk label and its partitions.
+
+ rt11 set-extra:
+   Sets the extra directory entry words of <file>. Each <word> is decimal, or
+   hex/octal with a `0x`/`0o` prefix. Words that aren't given are set to zero,
+   so leaving them all off clears them. The directory must have been
+   initialized with extra bytes. `ls -l` shows the current values.
+
+ rt11 boot:
+   Makes the image bootable, like RT-11's COPY/BOOT. <monitor-file> (eg,
+   RT11SJ.SYS) and <handler-file> must already be on the image. If
+   <handler-file> isn't given, it is chosen from the device type (DX.SYS for
+   RX01, DY.SYS for RX02, RK.SYS for RK05, DL.SYS for RL02, DD.SYS for TU58,
+   with an X suffix for the XM monitor).
+
+ rt11 prefix:
+   Prints the contents of <file>'s prefix blocks to stdout (not including the
+   word that holds the number of prefix blocks). `cat` and `cp` leave the
+   prefix blocks out.
+
+ rt11 backup:
+   Saves the whole image across as many new <device-type> images as it takes,
+   like RT-11's BACKUP (BUP). They are named after <backup-image> with the
+   volume number added (`backup.img` becomes `backup-1.img`, `backup-2.img`...).
+
+ rt11 restore:
+   --extract <dir>       Copy the files on the saved volume into <dir> instead of
+                         making an image of it.
+
+   Puts a volume saved by BACKUP back together from its backup volumes, which
+   must be given in order. The <image> file specified by `-i` is created. The
+   backup volumes are checked to make sure they are all from the same set and
+   are numbered in order.
+
+ rsts set-protection:
+   Sets the RSTS/E protection code of <file> (like PIP's /PR switch). <code> is
+   decimal, like RSTS/E shows it: add 1 and 2 to stop the owner reading and
+   writing, 4 and 8 for the group and 16 and 32 for everyone else, 64 to make
+   the file executable and 128 to make it privileged. New files get 60.
+
+ xxdp boot:
+   Makes the image bootable without a running XXDP+ system. The first block of
+   <boot-file> becomes the boot block, <monitor-file> (eg, XXDPSM.SYS) and
+   <driver-file> are moved into contiguous blocks if they aren't already, and
+   the MFD is pointed at the monitor. All three must already be on the image,
+   which needs a Variety #2 MFD (see `mkfs --bootable`). If <driver-file>
+   isn't given, it is chosen from the device type (DX.SYS for RX01, DY.SYS for
+   RX02, DD.SYS for TU58, DL.SYS for RL02).
+
+ xxdp check:
+   --repair              Rebuild the bitmap from the directory and file chains
+                         and save the image.
+
+   Checks that each file's block chain matches its directory entry, that no
+   chains are cross-linked or loop, that the bitmap agrees with the blocks in
+   use (reporting lost and unallocated blocks) and that the MFD pointers are in
+   range.
 "#,
+    AllocationPolicy::VARIANTS.join(", "),
     DeviceType::VARIANTS.iter().map(|s| *s).filter(|t| *t != "flat").collect::<Vec<&str>>().join(", "),
     FileSystemType::VARIANTS.join(", "),
     ImageType::VARIANTS.join(", "))
@@ -116,38 +288,122 @@ struct Args {
     flag_long:        bool,
     flag_all:         bool,
     flag_force:       bool,
+    flag_clear:       bool,
+    flag_salvage:     bool,
+    flag_append:      bool,
+    flag_at:          Option<usize>,
+    flag_policy:      Option<AllocationPolicy>,
+    flag_prefix:      Option<PathBuf>,
+    flag_mfd_variant: Option<u8>,
+    flag_bootable:    bool,
+    flag_contiguous:  bool,
+    flag_repair:      bool,
+    flag_extract:     Option<PathBuf>,
     cmd_ls:           bool,
+    cmd_info:         bool,
     cmd_cp:           bool,
     cmd_mv:           bool,
     cmd_rm:           bool,
+    cmd_undelete:     bool,
+    cmd_protect:      bool,
+    cmd_unprotect:    bool,
+    cmd_readonly:     bool,
     cmd_dump:         bool,
     cmd_dump_home:    bool,
     cmd_dump_dir:     bool,
+    cmd_set_extra:    bool,
+    cmd_boot:         bool,
+    cmd_prefix:       bool,
+    cmd_backup:       bool,
+    cmd_restore:      bool,
+    cmd_check:        bool,
     cmd_mkfs:         bool,
     cmd_cat:          bool,
     cmd_convert:      bool,
+    cmd_disklabel:    bool,
     cmd_rt11:         bool,
+    cmd_xxdp:         bool,
+    cmd_rsts:         bool,
+    cmd_set_protection: bool,
     arg_source_file:  PathBuf,
     arg_dest_file:    PathBuf,
     arg_file:         Option<PathBuf>,
+    arg_new_name:     Option<PathBuf>,
     arg_device_type:  Option<DeviceType>,
     arg_image_type:   Option<ImageType>,
     arg_filesystem:   Option<FileSystemType>,
+    arg_dir:          Option<String>,
+    arg_word:         Vec<String>,
+    arg_code:         Option<String>,
+    arg_monitor_file: Option<PathBuf>,
+    arg_handler_file: Option<PathBuf>,
+    arg_backup_image: Option<PathBuf>,
+    arg_backup_volume: Vec<PathBuf>,
+    arg_boot_file:    Option<PathBuf>,
+    arg_driver_file:  Option<PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
-    let args: Args = Docopt::new(usage())
+    let mut args: Args = Docopt::new(usage())
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
     // Do this very early since we normally die if the image file doesn't exist
     if args.cmd_mkfs {
-        let fs = create_image(ImageType::from_file_ext(&args.flag_image)?, args.arg_device_type.unwrap(), args.arg_filesystem.unwrap())?;
+        let mfd_variant = match args.flag_mfd_variant {
+            None if args.flag_bootable => Some(MfdVariant::Two), // Only Variety #2 MFDs can point at the monitor
+            None    => None,
+            Some(1) => Some(MfdVariant::One),
+            Some(2) => Some(MfdVariant::Two),
+            Some(v) => return Err(anyhow!("Bad MFD variant {}: must be 1 or 2", v)),
+        };
+        if args.flag_bootable && !matches!(args.arg_filesystem, Some(FileSystemType::XXDP)) {
+            return Err(anyhow!("--bootable only works with XXDP filesystems (see `rt11 boot` for RT-11)"));
+        }
+        let fs = create_image_with_mfd_variant(ImageType::from_file_ext(&args.flag_image)?, args.arg_device_type.unwrap(), args.arg_filesystem.unwrap(), mfd_variant)?;
         return save_image(fs.block_device().physical_device(), &args.flag_image);
     }
 
+    // The image doesn't exist yet, either
+    if args.cmd_rt11 && args.cmd_restore {
+        return match args.flag_extract {
+            Some(dir) => rt11_extract(&args.arg_backup_volume, &dir),
+            None      => rt11_restore(&args.arg_backup_volume, &args.flag_image),
+        };
+    }
+
+    let partition;
+    (args.flag_image, partition) = split_partition_path(&args.flag_image);
+
+    let mut logical_disk = None;
+    let mut dir = args.arg_dir.take().map(PathBuf::from);
+    for path in [Some(&mut args.arg_source_file), Some(&mut args.arg_dest_file), args.arg_file.as_mut(), dir.as_mut()].into_iter().flatten() {
+        let (disk, inner) = split_logical_disk_path(path);
+        let Some(disk) = disk else { continue };
+        if logical_disk.as_ref().is_some_and(|ld| *ld != disk) { return Err(anyhow!("Only one logical disk can be used at a time")) }
+        logical_disk = Some(disk);
+        *path = inner;
+    }
+    if args.arg_file.as_ref().is_some_and(|f| f.as_os_str().is_empty()) {
+        args.arg_file = None; // "dump OUTER.DSK:" dumps the whole logical disk
+    }
+    let dir = dir.map(|d| d.to_string_lossy().into_owned()).filter(|d| !d.is_empty()).unwrap_or("/".to_string());
+
     let dev = open_device(&args.flag_image)?;
 
+    if args.cmd_disklabel {
+        return disklabel(&dev);
+    }
+
+    let dev = match partition {
+        Some(letter) => open_partition(dev, letter)?,
+        None         => dev,
+    };
+    let dev = match logical_disk {
+        Some(disk) => open_logical_disk(dev, &disk)?,
+        None       => dev,
+    };
+
     // Do this early so we can dump corrupt images (since RT11FS::new() might die).
     if args.cmd_dump && args.arg_file.is_none() {
         return dump(&dev, args.flag_sector, args.flag_range.map(|r| r.into()));
@@ -161,29 +417,120 @@ fn main() -> anyhow::Result<()> {
         return rt11_dump_dir(&dev);
     }
 
+    if args.cmd_rt11 && args.cmd_set_extra {
+        let mut fs = fs::rt11::RT11FS::new(dev)?;
+        rt11_set_extra(&mut fs, &args.arg_file.unwrap(), &args.arg_word)?;
+        return save_image(fs.block_device().physical_device(), &args.flag_image);
+    }
+
+    if args.cmd_rt11 && args.cmd_boot {
+        let mut fs = fs::rt11::RT11FS::new(dev)?;
+        rt11_copy_boot(&mut fs, &args.arg_monitor_file.unwrap(), args.arg_handler_file.as_deref())?;
+        return save_image(fs.block_device().physical_device(), &args.flag_image);
+    }
+
+    if args.cmd_rt11 && args.cmd_prefix {
+        let fs = fs::rt11::RT11FS::new(dev)?;
+        return rt11_prefix(&fs, &args.arg_file.unwrap());
+    }
+
+    if args.cmd_rt11 && args.cmd_backup {
+        return rt11_backup(&dev, args.arg_device_type.unwrap(), &args.arg_backup_image.unwrap());
+    }
+
+    if args.cmd_rsts && args.cmd_set_protection {
+        let mut fs = fs::rsts::RstsFs::new(dev)?;
+        rsts_set_protection(&mut fs, &args.arg_file.unwrap(), &args.arg_code.unwrap())?;
+        return save_image(fs.block_device().physical_device(), &args.flag_image);
+    }
+
+    if args.cmd_xxdp && args.cmd_boot {
+        let mut fs = fs::xxdp::XxdpFs::new(dev)?;
+        xxdp_install_boot(&mut fs, &args.arg_boot_file.unwrap(), &args.arg_monitor_file.unwrap(), args.arg_driver_file.as_deref())?;
+        return save_image(fs.block_device().physical_device(), &args.flag_image);
+    }
+
+    if args.cmd_xxdp && args.cmd_check {
+        let mut fs = fs::xxdp::XxdpFs::new(dev)?;
+        xxdp_check(&mut fs, args.flag_repair)?;
+        if args.flag_repair {
+            save_image(fs.block_device().physical_device(), &args.flag_image)?;
+        }
+        return Ok(());
+    }
+
     if args.cmd_convert {
         return convert(&dev, args.arg_image_type.unwrap(), &args.arg_dest_file);
     }
 
-    let mut fs = open_fs(dev)?;
+    let mut fs = if args.flag_salvage { salvage_fs(dev)? } else { open_fs(dev)? };
 
     if args.cmd_ls {
-        ls(&fs, args.flag_long, args.flag_all);
+        ls(&fs, &dir, args.flag_long, args.flag_all)?;
+    }
+
+    if args.cmd_info {
+        info(&fs);
     }
 
     if args.cmd_cp {
-        match (args.arg_source_file.to_string_lossy().chars().find(|c| std::path::is_separator(*c)).is_some(),
-               args.arg_dest_file  .to_string_lossy().chars().find(|c| std::path::is_separator(*c)).is_some()) {
+        let has_separator = |p: &Path| p.to_string_lossy().chars().any(std::path::is_separator);
+        let local = match (has_separator(&args.arg_source_file), has_separator(&args.arg_dest_file)) {
+            // Unix paths on the image have slashes too, so go by where the source is.
+            (true, true) => match (args.arg_source_file.exists(), fs.stat(&ops::path_to_image_filename(&fs, &args.arg_source_file)?).is_some()) {
+                (true,  false) => (true, false),
+                (false, true)  => (false, true),
+                (true,  true)  => Err(anyhow!("{} is on both the image and the local filesystem", args.arg_source_file.display()))?,
+                (false, false) => Err(anyhow!("{}: No such file", args.arg_source_file.display()))?,
+            },
+            local => local,
+        };
+        match local {
             (false, true)  => cp_from_image(&fs, &args.arg_source_file, &args.arg_dest_file)?,
-            (true,  false) => { cp_into_image(&mut fs, &args.arg_source_file, &args.arg_dest_file)?;
+            (true,  false) if args.flag_append => {
+                                // The usage already keeps --at and --policy away from --append.
+                                if args.flag_prefix.is_some() { return Err(anyhow!("--prefix can't be used with --append")) }
+                                if args.flag_contiguous       { return Err(anyhow!("--contiguous can't be used with --append")) }
+                                append_into_image(&mut fs, &args.arg_source_file, &args.arg_dest_file, args.flag_force)?;
+                                save_image(fs.block_device().physical_device(), &args.flag_image)? },
+            (true,  false) => { let placement = match (args.flag_at, args.flag_policy) {
+                                    (Some(block), _)      => Placement::At(block),
+                                    (None, Some(policy))  => policy.into(),
+                                    (None, None)          => Placement::default(),
+                                };
+                                let prefix = match &args.flag_prefix {
+                                    Some(file) => std::fs::read(file).with_context(|| format!("Reading \"{}\" failed", file.display()))?,
+                                    None       => vec![],
+                                };
+                                cp_into_image_with_options(&mut fs, &args.arg_source_file, &args.arg_dest_file, args.flag_force,
+                                                           &CreateOptions { placement, prefix, contiguous: args.flag_contiguous })?;
                                 save_image(fs.block_device().physical_device(), &args.flag_image)? },
             (false, false) => Err(anyhow!("Image to image copy is not supported yet."))?,
-            (true,  true)  => Err(anyhow!("Either the source or destination file needs to be on the image"))?,
+            (true,  true)  => unreachable!(),
         }
     }
 
     if args.cmd_rm {
-        rm(&mut fs, &args.arg_file.unwrap())?;
+        rm(&mut fs, &args.arg_file.unwrap(), args.flag_force)?;
+        save_image(fs.block_device().physical_device(), &args.flag_image)?;
+        return Ok(())
+    }
+
+    if args.cmd_undelete {
+        let Some(file) = args.arg_file else { return ls_deleted(&fs) };
+        undelete(&mut fs, &file, args.arg_new_name.as_deref())?;
+        save_image(fs.block_device().physical_device(), &args.flag_image)?;
+        return Ok(())
+    }
+
+    if args.cmd_protect || args.cmd_unprotect {
+        protect(&mut fs, &args.arg_file.unwrap(), args.cmd_protect)?;
+        save_image(fs.block_device().physical_device(), &args.flag_image)?;
+        return Ok(())
+    }
+
+    if args.cmd_readonly {
+        readonly(&mut fs, &args.arg_file.unwrap(), !args.flag_clear)?;
         save_image(fs.block_device().physical_device(), &args.flag_image)?;
         return Ok(())
     }
@@ -200,7 +547,7 @@ fn main() -> anyhow::Result<()> {
 
     if args.cmd_cat {
         use std::io::Write;
-        let data = fs.read_file(&ops::path_to_rt11_filename(&args.arg_file.unwrap())?)?;
+        let data = fs.read_file(&ops::path_to_image_filename(&fs, &args.arg_file.unwrap())?)?;
         std::io::stdout().write_all(data.as_bytes())?;
     }
 
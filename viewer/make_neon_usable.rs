@@ -189,6 +189,7 @@ impl<'a,'b> ToJs<'a,'b> for Box<dyn DirEntry + 'b> {
     fn to(&'b self, cx: &mut FunctionContext<'a>) -> NeonResult<Handle<'a,Self::Output>> {
         let obj = cx.empty_object();
         obj_set_bool(cx, &obj, "read_only", self.readonly())?;
+        obj_set_bool(cx, &obj, "protected", self.protected())?;
         obj_set_string(cx, &obj, "name", &self.file_name())?;
         obj_set_number(cx, &obj, "length", self.len() as u32)?;
         match self.created() {
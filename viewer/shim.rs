@@ -143,7 +143,7 @@ fn cp_from_image(mut cx: FunctionContext) -> JsResult<JsNull> {
 fn cp_into_image(mut cx: FunctionContext) -> JsResult<JsNull> {
     js_args!(&mut cx, id: u32, path: PathBuf);
     with_image_id(id, |image| {
-        pdpfs::ops::cp_into_image(&mut image.fs, &path, Path::new("."))
+        pdpfs::ops::cp_into_image(&mut image.fs, &path, Path::new("."), false)
             .map_err(|e| format!("{}", nice_err(e)))
             .and_then(|_| { image.dirty = true; Ok(()) })
     }).into_jserr(&mut cx)?;
@@ -163,7 +163,7 @@ fn mv(mut cx: FunctionContext) -> JsResult<JsNull> {
 fn rm(mut cx: FunctionContext) -> JsResult<JsNull> {
     js_args!(&mut cx, id: u32, file: PathBuf);
     with_image_id(id, |image| {
-        pdpfs::ops::rm(&mut image.fs, &file)
+        pdpfs::ops::rm(&mut image.fs, &file, false)
             .map_err(|e| format!("rm failed for {}: {}", file.display(), e))
             .and_then(|_| { image.dirty = true; Ok(()) })
     }).into_jserr(&mut cx)?;